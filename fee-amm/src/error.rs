@@ -24,4 +24,12 @@ pub enum Error {
     InvalidSwapCalculation = 9,
     /// Slippage tolerance exceeded
     SlippageExceeded = 10,
+    /// Reentrant call into a reserve-mutating function
+    Reentrancy = 11,
+    /// Snapshot was never taken or has been evicted from the ring buffer
+    SnapshotNotFound = 12,
+    /// Spender's LP allowance is missing, expired or too small
+    InsufficientAllowance = 13,
+    /// Token is not one of the pool's two reserve tokens
+    InvalidToken = 14,
 }
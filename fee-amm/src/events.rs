@@ -5,6 +5,12 @@ const MINT: Symbol = symbol_short!("mint");
 const BURN: Symbol = symbol_short!("burn");
 const FEE_SWAP: Symbol = symbol_short!("fee_swap");
 const REBALANCE: Symbol = symbol_short!("rebalance");
+const SNAPSHOT: Symbol = symbol_short!("snapshot");
+const SWEEP: Symbol = symbol_short!("sweep");
+const TRANSFER: Symbol = symbol_short!("transfer");
+const SWAP: Symbol = symbol_short!("swap");
+const FLASH_LOAN: Symbol = symbol_short!("flash");
+const APPROVE: Symbol = symbol_short!("approve");
 
 pub fn emit_mint(
     env: &Env,
@@ -63,3 +69,66 @@ pub fn emit_rebalance_swap(
         (amount_in, amount_out),
     );
 }
+
+pub fn emit_swap(
+    env: &Env,
+    sender: &Address,
+    token_in: &Address,
+    token_out: &Address,
+    amount_in: i128,
+    amount_out: i128,
+    to: &Address,
+) {
+    env.events().publish(
+        (SWAP, sender, token_in, token_out),
+        (amount_in, amount_out, to),
+    );
+}
+
+pub fn emit_flash_loan(
+    env: &Env,
+    borrower: &Address,
+    token: &Address,
+    receiver: &Address,
+    amount: i128,
+    fee: i128,
+) {
+    env.events()
+        .publish((FLASH_LOAN, borrower, token), (receiver, amount, fee));
+}
+
+pub fn emit_snapshot(env: &Env, user_token: &Address, validator_token: &Address, index: u32) {
+    env.events()
+        .publish((SNAPSHOT, user_token, validator_token), index);
+}
+
+pub fn emit_transfer(
+    env: &Env,
+    from: &Address,
+    to: &Address,
+    user_token: &Address,
+    validator_token: &Address,
+    amount: i128,
+) {
+    env.events()
+        .publish((TRANSFER, from, to, user_token, validator_token), amount);
+}
+
+pub fn emit_approve(
+    env: &Env,
+    from: &Address,
+    spender: &Address,
+    user_token: &Address,
+    validator_token: &Address,
+    amount: i128,
+    expiration_ledger: u32,
+) {
+    env.events().publish(
+        (APPROVE, from, spender, user_token, validator_token),
+        (amount, expiration_ledger),
+    );
+}
+
+pub fn emit_sweep(env: &Env, token: &Address, to: &Address, amount: i128) {
+    env.events().publish((SWEEP, token), (to, amount));
+}
@@ -0,0 +1,16 @@
+use soroban_sdk::{contractclient, Address, Bytes, Env};
+
+/// Callback a flash loan receiver implements; it must return `amount + fee`
+/// of `token` to the AMM before the call ends
+#[allow(dead_code)]
+#[contractclient(name = "FlashLoanReceiverClient")]
+pub trait FlashLoanReceiver {
+    fn on_flash_loan(
+        env: Env,
+        initiator: Address,
+        token: Address,
+        amount: i128,
+        fee: i128,
+        data: Bytes,
+    );
+}
@@ -1,12 +1,15 @@
 #![no_std]
+#![allow(clippy::too_many_arguments)]
 
 mod error;
 mod events;
+mod flash_loan;
 mod storage;
 
 use error::Error;
-use soroban_sdk::{contract, contractimpl, token, Address, Env};
-use storage::Pool;
+use flash_loan::FlashLoanReceiverClient;
+use soroban_sdk::{contract, contractimpl, token, Address, Bytes, Env, Vec};
+use storage::{Pool, PoolInfo, PoolSnapshot};
 
 /// Fee multiplier: m = 0.9970 (scaled by 10000)
 /// Used in fee swaps: amount_out = amount_in * M / SCALE
@@ -22,6 +25,15 @@ const SCALE: i128 = 10000;
 /// Minimum liquidity locked forever to prevent division by zero
 const MIN_LIQUIDITY: i128 = 1000;
 
+/// Amount of each token left behind when the last LP exit releases the residual reserves
+const LAST_EXIT_DUST: i128 = 1;
+
+/// Fixed-point scale for reported prices
+const PRICE_SCALE: i128 = 1_000_000_000;
+
+/// Basis points in one whole
+const BPS_SCALE: i128 = 10000;
+
 /// Compute amount out for a fee swap
 /// Returns: amount_in * M / SCALE
 #[inline]
@@ -32,7 +44,111 @@ fn compute_amount_out(amount_in: i128) -> Result<i128, Error> {
         .ok_or(Error::Overflow)
 }
 
+/// Largest rebalance output whose input, `amount_out * N / SCALE + 1`,
+/// is at most `amount_in`: (amount_in * SCALE - 1) / N
+#[inline]
+fn compute_rebalance_output(amount_in: i128) -> Result<i128, Error> {
+    amount_in
+        .checked_mul(SCALE)
+        .and_then(|num| num.checked_sub(1))
+        .and_then(|num| num.checked_div(N))
+        .ok_or(Error::Overflow)
+}
+
+/// Constant-product output for `amount_in` against (`reserve_in`, `reserve_out`),
+/// with the input discounted by the fee multiplier M:
+/// out = in * M * reserve_out / (reserve_in * SCALE + in * M)
+#[inline]
+fn compute_constant_product_out(amount_in: i128, reserve_in: i128, reserve_out: i128) -> Result<i128, Error> {
+    let amount_in_with_fee = amount_in.checked_mul(M).ok_or(Error::Overflow)?;
+    let denominator = reserve_in
+        .checked_mul(SCALE)
+        .and_then(|scaled| scaled.checked_add(amount_in_with_fee))
+        .ok_or(Error::Overflow)?;
+    if denominator == 0 {
+        return Err(Error::DivisionByZero);
+    }
+    amount_in_with_fee
+        .checked_mul(reserve_out)
+        .map(|num| num / denominator)
+        .ok_or(Error::Overflow)
+}
+
+/// Re-express `amount` of a token with `from` decimals in `to` decimals, rounding down
+fn rescale(amount: i128, from: u32, to: u32) -> Result<i128, Error> {
+    if from == to {
+        return Ok(amount);
+    }
+    let factor = 10i128.checked_pow(from.abs_diff(to)).ok_or(Error::Overflow)?;
+    if to > from {
+        amount.checked_mul(factor).ok_or(Error::Overflow)
+    } else {
+        Ok(amount / factor)
+    }
+}
+
+/// Fee swap output in validator token units for `amount_in` user tokens,
+/// given the pool's (user, validator) decimals
+#[inline]
+fn compute_pool_amount_out(amount_in: i128, decimals: (u32, u32)) -> Result<i128, Error> {
+    compute_amount_out(rescale(amount_in, decimals.0, decimals.1)?)
+}
+
+/// Mid price of validator tokens per user token, scaled by PRICE_SCALE
+/// Returns 0 when the pool holds no user tokens
+#[inline]
+fn compute_spot_price(pool: &Pool) -> Result<i128, Error> {
+    if pool.reserve_user_token == 0 {
+        return Ok(0);
+    }
+    pool.reserve_validator_token
+        .checked_mul(PRICE_SCALE)
+        .and_then(|num| num.checked_div(pool.reserve_user_token))
+        .ok_or(Error::Overflow)
+}
+
+/// Largest total pending fee swap input whose output fits within `reserve_validator_token`
+/// Solves floor(P * M / SCALE) <= R for the maximum P in validator units, then
+/// takes the largest user amount that rescales to at most that
+#[inline]
+fn max_pending_for_reserve(reserve_validator_token: i128, decimals: (u32, u32)) -> Result<i128, Error> {
+    let max_validator = reserve_validator_token
+        .checked_add(1)
+        .and_then(|r| r.checked_mul(SCALE))
+        .and_then(|num| num.checked_sub(1))
+        .and_then(|num| num.checked_div(M))
+        .ok_or(Error::Overflow)?;
+    let (user_decimals, validator_decimals) = decimals;
+    if user_decimals <= validator_decimals {
+        return rescale(max_validator, validator_decimals, user_decimals);
+    }
+    // Rescaling down truncates, so every remainder below the factor still fits
+    let factor = 10i128
+        .checked_pow(user_decimals - validator_decimals)
+        .ok_or(Error::Overflow)?;
+    max_validator
+        .checked_mul(factor)
+        .and_then(|num| num.checked_add(factor - 1))
+        .ok_or(Error::Overflow)
+}
+
+/// Validator reserve that fee swaps may commit: all of it except the share
+/// backing the locked MIN_LIQUIDITY, rounded in the locked share's favor
+fn reservable_validator_reserve(pool: &Pool, total_supply: i128) -> Result<i128, Error> {
+    if total_supply == 0 {
+        return Ok(pool.reserve_validator_token);
+    }
+    let locked = pool
+        .reserve_validator_token
+        .checked_mul(MIN_LIQUIDITY)
+        .and_then(|num| num.checked_add(total_supply - 1))
+        .and_then(|num| num.checked_div(total_supply))
+        .ok_or(Error::Overflow)?;
+    Ok(pool.reserve_validator_token - locked.min(pool.reserve_validator_token))
+}
+
 /// Integer square root using Newton's method
+#[allow(dead_code)]
 fn sqrt(x: i128) -> i128 {
     if x == 0 {
         return 0;
@@ -46,6 +162,8 @@ fn sqrt(x: i128) -> i128 {
     y
 }
 
+/// Reserve-mutating entrypoints hold a reentrancy lock and apply every state
+/// change before making token transfers (checks-effects-interactions).
 #[contract]
 pub struct TempoFeeAMM;
 
@@ -67,12 +185,222 @@ impl TempoFeeAMM {
         storage::get_admin(&env)
     }
 
+    /// Let the last LP to exit a pool also claim the reserves backing the locked
+    /// `MIN_LIQUIDITY`, resetting the pool (admin only)
+    pub fn set_last_exit_refund(env: Env, caller: Address, enabled: bool) -> Result<(), Error> {
+        Self::require_admin(&env, &caller)?;
+        storage::extend_instance_ttl(&env);
+        storage::set_last_exit_refund(&env, enabled);
+        Ok(())
+    }
+
+    /// Whether the last LP exit releases the locked residual reserves
+    pub fn last_exit_refund(env: Env) -> bool {
+        storage::extend_instance_ttl(&env);
+        storage::get_last_exit_refund(&env)
+    }
+
+    /// Set the smallest reserve of either token a swap may leave behind
+    /// (admin only, default `MIN_LIQUIDITY`)
+    pub fn set_min_reserve(env: Env, caller: Address, min_reserve: i128) -> Result<(), Error> {
+        Self::require_admin(&env, &caller)?;
+        if min_reserve < 0 {
+            return Err(Error::InvalidAmount);
+        }
+        storage::extend_instance_ttl(&env);
+        storage::set_min_reserve(&env, min_reserve);
+        Ok(())
+    }
+
+    /// Smallest reserve of either token a swap may leave behind
+    pub fn min_reserve(env: Env) -> i128 {
+        storage::extend_instance_ttl(&env);
+        storage::get_min_reserve(&env, MIN_LIQUIDITY)
+    }
+
+    /// Allow `rebalancer` to call `rebalance_swap` (admin only)
+    /// While the whitelist is empty `rebalance_swap` stays permissionless
+    pub fn add_rebalancer(env: Env, caller: Address, rebalancer: Address) -> Result<(), Error> {
+        Self::require_admin(&env, &caller)?;
+        storage::extend_instance_ttl(&env);
+
+        let mut whitelist = storage::get_rebalance_whitelist(&env);
+        if !whitelist.contains(&rebalancer) {
+            whitelist.push_back(rebalancer);
+            storage::set_rebalance_whitelist(&env, &whitelist);
+        }
+        Ok(())
+    }
+
+    /// Remove `rebalancer` from the rebalance whitelist (admin only)
+    pub fn remove_rebalancer(env: Env, caller: Address, rebalancer: Address) -> Result<(), Error> {
+        Self::require_admin(&env, &caller)?;
+        storage::extend_instance_ttl(&env);
+
+        let mut whitelist = storage::get_rebalance_whitelist(&env);
+        if let Some(index) = whitelist.first_index_of(&rebalancer) {
+            whitelist.remove(index);
+            storage::set_rebalance_whitelist(&env, &whitelist);
+        }
+        Ok(())
+    }
+
+    /// Addresses allowed to call `rebalance_swap`; empty means anyone may
+    pub fn rebalance_whitelist(env: Env) -> Vec<Address> {
+        storage::extend_instance_ttl(&env);
+        storage::get_rebalance_whitelist(&env)
+    }
+
+    /// Whether a pool was ever created for the pair; `get_pool` reads a
+    /// never-created pool as zero reserves
+    pub fn pool_exists(env: Env, user_token: Address, validator_token: Address) -> bool {
+        storage::extend_instance_ttl(&env);
+        storage::has_pool(&env, &user_token, &validator_token)
+    }
+
     /// Get pool reserves for a token pair
     pub fn get_pool(env: Env, user_token: Address, validator_token: Address) -> Pool {
         storage::extend_instance_ttl(&env);
         storage::get_pool(&env, &user_token, &validator_token)
     }
 
+    /// Get pool reserves together with supply, pending swaps, effective reserves and spot price
+    pub fn get_pool_info(
+        env: Env,
+        user_token: Address,
+        validator_token: Address,
+    ) -> Result<PoolInfo, Error> {
+        storage::extend_instance_ttl(&env);
+
+        let pool = storage::get_pool(&env, &user_token, &validator_token);
+        let total_supply = storage::get_total_supply(&env, &user_token, &validator_token);
+        let pending = storage::get_pending_fee_swap(&env, &user_token, &validator_token);
+        let decimals = storage::get_pool_decimals(&env, &user_token, &validator_token);
+
+        let effective_reserve_user = pool
+            .reserve_user_token
+            .checked_add(pending)
+            .ok_or(Error::Overflow)?;
+        let effective_reserve_validator = pool
+            .reserve_validator_token
+            .checked_sub(compute_pool_amount_out(pending, decimals)?)
+            .ok_or(Error::Overflow)?;
+
+        Ok(PoolInfo {
+            reserve_user_token: pool.reserve_user_token,
+            reserve_validator_token: pool.reserve_validator_token,
+            total_supply,
+            pending_fee_swap: pending,
+            effective_reserve_user,
+            effective_reserve_validator,
+            spot_price: compute_spot_price(&pool)?,
+        })
+    }
+
+    /// Signed reserve imbalance: `reserve_validator_token - reserve_user_token`
+    /// Negative means the pool is user-token heavy and `rebalance_swap` can move it back
+    pub fn get_imbalance(
+        env: Env,
+        user_token: Address,
+        validator_token: Address,
+    ) -> Result<i128, Error> {
+        storage::extend_instance_ttl(&env);
+
+        let pool = storage::get_pool(&env, &user_token, &validator_token);
+        pool.reserve_validator_token
+            .checked_sub(pool.reserve_user_token)
+            .ok_or(Error::Overflow)
+    }
+
+    /// Constant-product invariant `reserve_user * reserve_validator`, or
+    /// `Overflow` if it does not fit in an i128
+    pub fn get_k(env: Env, user_token: Address, validator_token: Address) -> Result<i128, Error> {
+        storage::extend_instance_ttl(&env);
+
+        let pool = storage::get_pool(&env, &user_token, &validator_token);
+        pool.reserve_user_token
+            .checked_mul(pool.reserve_validator_token)
+            .ok_or(Error::Overflow)
+    }
+
+    /// Share of validator reserves committed to pending fee swaps, in basis
+    /// points: `pending_out * 10000 / reserve_validator_token`, 0 for an empty pool
+    pub fn get_utilization(env: Env, user_token: Address, validator_token: Address) -> Result<i128, Error> {
+        storage::extend_instance_ttl(&env);
+
+        let pool = storage::get_pool(&env, &user_token, &validator_token);
+        if pool.reserve_validator_token == 0 {
+            return Ok(0);
+        }
+
+        let pending = storage::get_pending_fee_swap(&env, &user_token, &validator_token);
+        let decimals = storage::get_pool_decimals(&env, &user_token, &validator_token);
+        compute_pool_amount_out(pending, decimals)?
+            .checked_mul(BPS_SCALE)
+            .and_then(|num| num.checked_div(pool.reserve_validator_token))
+            .ok_or(Error::Overflow)
+    }
+
+    /// Price actually paid by a trade of `amount_in`, fee included: input tokens
+    /// per output token, scaled by PRICE_SCALE
+    ///
+    /// `is_user_in` prices a fee swap (user tokens in, validator tokens out);
+    /// otherwise a rebalance swap (validator tokens in, user tokens out). Unlike
+    /// the reserve-ratio mid in `get_pool_info`, this is the break-even price.
+    pub fn get_effective_price(
+        env: Env,
+        user_token: Address,
+        validator_token: Address,
+        is_user_in: bool,
+        amount_in: i128,
+    ) -> Result<i128, Error> {
+        if amount_in <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let amount_out =
+            Self::quote_amount_out(&env, &user_token, &validator_token, is_user_in, amount_in)?;
+
+        amount_in
+            .checked_mul(PRICE_SCALE)
+            .and_then(|num| num.checked_div(amount_out))
+            .ok_or(Error::Overflow)
+    }
+
+    /// Output of a trade of `amount_in` together with the fee it pays:
+    /// `(amount_out, fee_amount)`, where `fee_amount` is the part of the input
+    /// the pool keeps, `amount_in * (SCALE - M) / SCALE` for fee swaps. A
+    /// rebalance swap pays out more than it takes in, so it keeps no input
+    /// and its `fee_amount` is 0.
+    pub fn get_amount_out_detailed(
+        env: Env,
+        user_token: Address,
+        validator_token: Address,
+        is_user_in: bool,
+        amount_in: i128,
+    ) -> Result<(i128, i128), Error> {
+        if amount_in <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let amount_out =
+            Self::quote_amount_out(&env, &user_token, &validator_token, is_user_in, amount_in)?;
+        let fee_amount = if is_user_in {
+            amount_in
+                .checked_mul(SCALE - M)
+                .and_then(|num| num.checked_div(SCALE))
+                .ok_or(Error::Overflow)?
+        } else {
+            0
+        };
+
+        Ok((amount_out, fee_amount))
+    }
+
     /// Get total LP token supply for a pool
     pub fn get_total_supply(env: Env, user_token: Address, validator_token: Address) -> i128 {
         storage::extend_instance_ttl(&env);
@@ -97,6 +425,7 @@ impl TempoFeeAMM {
     }
 
     /// Mint LP tokens by providing both user and validator tokens
+    /// Reverts with `SlippageExceeded` if fewer than `min_liquidity` LP tokens would be minted
     pub fn mint(
         env: Env,
         sender: Address,
@@ -105,6 +434,7 @@ impl TempoFeeAMM {
         amount_user_token: i128,
         amount_validator_token: i128,
         to: Address,
+        min_liquidity: i128,
     ) -> Result<i128, Error> {
         // Verify sender authorization
         sender.require_auth();
@@ -120,15 +450,19 @@ impl TempoFeeAMM {
         }
 
         storage::extend_instance_ttl(&env);
+        storage::enter_lock(&env)?;
 
         let mut pool = storage::get_pool(&env, &user_token, &validator_token);
         let total_supply = storage::get_total_supply(&env, &user_token, &validator_token);
 
         let liquidity = if total_supply == 0 {
             // First deposit: liquidity = mean(amount_user, amount_validator) - MIN_LIQUIDITY
-            // Using addition mean: (a + b) / 2
-            let mean = amount_user_token
-                .checked_add(amount_validator_token)
+            // Using addition mean over amounts at the finer of the two scales: (a + b) / 2
+            let (user_decimals, validator_decimals) =
+                Self::record_pool_decimals(&env, &user_token, &validator_token);
+            let common = user_decimals.max(validator_decimals);
+            let mean = rescale(amount_user_token, user_decimals, common)?
+                .checked_add(rescale(amount_validator_token, validator_decimals, common)?)
                 .and_then(|sum| sum.checked_div(2))
                 .ok_or(Error::Overflow)?;
 
@@ -168,16 +502,9 @@ impl TempoFeeAMM {
             return Err(Error::InsufficientLiquidity);
         }
 
-        // Transfer tokens from sender to contract
-        let user_token_client = token::Client::new(&env, &user_token);
-        let validator_token_client = token::Client::new(&env, &validator_token);
-
-        user_token_client.transfer(&sender, &env.current_contract_address(), &amount_user_token);
-        validator_token_client.transfer(
-            &sender,
-            &env.current_contract_address(),
-            &amount_validator_token,
-        );
+        if liquidity < min_liquidity {
+            return Err(Error::SlippageExceeded);
+        }
 
         // Update reserves
         pool.reserve_user_token = pool
@@ -211,6 +538,17 @@ impl TempoFeeAMM {
                 .ok_or(Error::Overflow)?,
         );
 
+        // Transfer tokens from sender to contract
+        let user_token_client = token::Client::new(&env, &user_token);
+        let validator_token_client = token::Client::new(&env, &validator_token);
+
+        user_token_client.transfer(&sender, &env.current_contract_address(), &amount_user_token);
+        validator_token_client.transfer(
+            &sender,
+            &env.current_contract_address(),
+            &amount_validator_token,
+        );
+
         // Emit event
         events::emit_mint(
             &env,
@@ -222,10 +560,14 @@ impl TempoFeeAMM {
             liquidity,
         );
 
+        storage::exit_lock(&env);
+
         Ok(liquidity)
     }
 
     /// Mint LP tokens using only validator tokens (single-sided deposit)
+    /// Reverts with `SlippageExceeded` if fewer than `min_liquidity` LP tokens would be minted
+    /// or the pool's spot price (validator per user, scaled by PRICE_SCALE) exceeds `max_implied_price`
     pub fn mint_with_validator_token(
         env: Env,
         sender: Address,
@@ -233,6 +575,8 @@ impl TempoFeeAMM {
         validator_token: Address,
         amount_validator_token: i128,
         to: Address,
+        min_liquidity: i128,
+        max_implied_price: i128,
     ) -> Result<i128, Error> {
         sender.require_auth();
 
@@ -245,15 +589,27 @@ impl TempoFeeAMM {
         }
 
         storage::extend_instance_ttl(&env);
+        storage::enter_lock(&env)?;
 
         let mut pool = storage::get_pool(&env, &user_token, &validator_token);
         let mut total_supply = storage::get_total_supply(&env, &user_token, &validator_token);
 
+        // The deposit prices against current reserves; refuse if they were pushed
+        if compute_spot_price(&pool)? > max_implied_price {
+            return Err(Error::SlippageExceeded);
+        }
+
         let liquidity =
             if pool.reserve_user_token == 0 && pool.reserve_validator_token == 0 {
-                // First deposit: liquidity = (amount / 2) - MIN_LIQUIDITY
-                let half_amount = amount_validator_token
-                    .checked_div(2)
+                // First deposit: liquidity = (amount / 2) - MIN_LIQUIDITY, at the finer scale
+                let (user_decimals, validator_decimals) =
+                    Self::record_pool_decimals(&env, &user_token, &validator_token);
+                let half_amount = rescale(
+                    amount_validator_token,
+                    validator_decimals,
+                    user_decimals.max(validator_decimals),
+                )?
+                .checked_div(2)
                     .ok_or(Error::Overflow)?;
 
                 if half_amount <= MIN_LIQUIDITY {
@@ -269,9 +625,11 @@ impl TempoFeeAMM {
                     .checked_sub(MIN_LIQUIDITY)
                     .ok_or(Error::InsufficientLiquidity)?
             } else {
-                // Subsequent deposits: liquidity = amount * totalSupply / (V + n * U / SCALE)
+                // Subsequent deposits: liquidity = amount * totalSupply / (V + n * U / SCALE),
+                // with U in validator units
+                let decimals = storage::get_pool_decimals(&env, &user_token, &validator_token);
                 let n_times_u = N
-                    .checked_mul(pool.reserve_user_token)
+                    .checked_mul(rescale(pool.reserve_user_token, decimals.0, decimals.1)?)
                     .and_then(|prod| prod.checked_div(SCALE))
                     .ok_or(Error::InvalidSwapCalculation)?;
 
@@ -294,13 +652,10 @@ impl TempoFeeAMM {
             return Err(Error::InsufficientLiquidity);
         }
 
-        // Transfer validator tokens from sender
-        let validator_token_client = token::Client::new(&env, &validator_token);
-        validator_token_client.transfer(
-            &sender,
-            &env.current_contract_address(),
-            &amount_validator_token,
-        );
+        // The share depends on the live reserve ratio, so guard against it moving
+        if liquidity < min_liquidity {
+            return Err(Error::SlippageExceeded);
+        }
 
         // Update reserves (only validator token increases)
         pool.reserve_validator_token = pool
@@ -329,6 +684,14 @@ impl TempoFeeAMM {
                 .ok_or(Error::Overflow)?,
         );
 
+        // Transfer validator tokens from sender
+        let validator_token_client = token::Client::new(&env, &validator_token);
+        validator_token_client.transfer(
+            &sender,
+            &env.current_contract_address(),
+            &amount_validator_token,
+        );
+
         // Emit event
         events::emit_mint(
             &env,
@@ -340,10 +703,14 @@ impl TempoFeeAMM {
             liquidity,
         );
 
+        storage::exit_lock(&env);
+
         Ok(liquidity)
     }
 
     /// Burn LP tokens and withdraw both tokens proportionally
+    /// Reverts with `SlippageExceeded` if either payout is below `min_user_out` / `min_validator_out`;
+    /// the floors are checked after the pending-swap reserve check, before any LP is burned or tokens move
     pub fn burn(
         env: Env,
         sender: Address,
@@ -351,6 +718,8 @@ impl TempoFeeAMM {
         validator_token: Address,
         liquidity: i128,
         to: Address,
+        min_user_out: i128,
+        min_validator_out: i128,
     ) -> Result<(i128, i128), Error> {
         sender.require_auth();
 
@@ -361,103 +730,219 @@ impl TempoFeeAMM {
         if liquidity <= 0 {
             return Err(Error::InvalidAmount);
         }
+        if !storage::has_pool(&env, &user_token, &validator_token) {
+            return Err(Error::PoolNotInitialized);
+        }
 
         storage::extend_instance_ttl(&env);
+        storage::enter_lock(&env)?;
 
-        // Check sender has sufficient LP balance
-        let balance = storage::get_lp_balance(&env, &user_token, &validator_token, &sender);
-        if balance < liquidity {
-            return Err(Error::InsufficientLiquidity);
-        }
-
-        let mut pool = storage::get_pool(&env, &user_token, &validator_token);
-        let total_supply = storage::get_total_supply(&env, &user_token, &validator_token);
+        let amounts = Self::burn_liquidity(
+            &env,
+            &sender,
+            &user_token,
+            &validator_token,
+            liquidity,
+            &to,
+            (min_user_out, min_validator_out),
+            false,
+        )?;
 
-        if total_supply == 0 {
-            return Err(Error::PoolNotInitialized);
-        }
+        storage::exit_lock(&env);
 
-        // Calculate amounts to return
-        let amount_user_token = liquidity
-            .checked_mul(pool.reserve_user_token)
-            .and_then(|prod| prod.checked_div(total_supply))
-            .ok_or(Error::Overflow)?;
+        Ok(amounts)
+    }
 
-        let amount_validator_token = liquidity
-            .checked_mul(pool.reserve_validator_token)
-            .and_then(|prod| prod.checked_div(total_supply))
-            .ok_or(Error::Overflow)?;
+    /// Burn LP tokens and withdraw the whole share in validator tokens
+    ///
+    /// The pro-rata user tokens stay in the pool and are converted at the rebalance
+    /// rate, `amount * N / SCALE`, the price `rebalance_swap` charges for them.
+    /// Reverts with `InsufficientReserves` if the payout would dip into validator
+    /// tokens reserved for pending fee swaps, and with `SlippageExceeded` below
+    /// `min_amount_validator`. Returns the validator tokens paid out.
+    pub fn burn_to_validator_token(
+        env: Env,
+        sender: Address,
+        user_token: Address,
+        validator_token: Address,
+        liquidity: i128,
+        min_amount_validator: i128,
+        to: Address,
+    ) -> Result<i128, Error> {
+        sender.require_auth();
 
-        // Check withdrawal doesn't violate pending swaps
-        let pending = storage::get_pending_fee_swap(&env, &user_token, &validator_token);
-        let pending_out = compute_amount_out(pending)?;
-        let effective_validator_reserve = pool
-            .reserve_validator_token
-            .checked_sub(pending_out)
-            .ok_or(Error::Overflow)?;
+        if user_token == validator_token {
+            return Err(Error::IdenticalAddresses);
+        }
 
-        if amount_validator_token > effective_validator_reserve {
-            return Err(Error::InsufficientReserves);
+        if liquidity <= 0 {
+            return Err(Error::InvalidAmount);
         }
 
-        // Burn LP tokens
-        storage::set_lp_balance(
-            &env,
-            &user_token,
-            &validator_token,
-            &sender,
-            balance.checked_sub(liquidity).ok_or(Error::Overflow)?,
-        );
+        storage::extend_instance_ttl(&env);
+        storage::enter_lock(&env)?;
 
-        storage::set_total_supply(
+        let (_, amount_validator_token) = Self::burn_liquidity(
             &env,
+            &sender,
             &user_token,
             &validator_token,
-            total_supply
-                .checked_sub(liquidity)
-                .ok_or(Error::Overflow)?,
-        );
+            liquidity,
+            &to,
+            (0, min_amount_validator),
+            true,
+        )?;
 
-        // Update reserves
-        pool.reserve_user_token = pool
-            .reserve_user_token
-            .checked_sub(amount_user_token)
-            .ok_or(Error::InsufficientReserves)?;
-        pool.reserve_validator_token = pool
-            .reserve_validator_token
-            .checked_sub(amount_validator_token)
-            .ok_or(Error::InsufficientReserves)?;
+        storage::exit_lock(&env);
 
-        storage::set_pool(&env, &user_token, &validator_token, &pool);
+        Ok(amount_validator_token)
+    }
 
-        // Transfer tokens to recipient
-        if amount_user_token > 0 {
-            let user_token_client = token::Client::new(&env, &user_token);
-            user_token_client.transfer(&env.current_contract_address(), &to, &amount_user_token);
-        }
+    /// Burn as much of the sender's LP as the effective validator reserve allows
+    /// Returns (liquidity burned, user tokens out, validator tokens out)
+    pub fn burn_max(
+        env: Env,
+        sender: Address,
+        user_token: Address,
+        validator_token: Address,
+        to: Address,
+    ) -> Result<(i128, i128, i128), Error> {
+        sender.require_auth();
 
-        if amount_validator_token > 0 {
-            let validator_token_client = token::Client::new(&env, &validator_token);
-            validator_token_client.transfer(
-                &env.current_contract_address(),
-                &to,
-                &amount_validator_token,
-            );
+        if user_token == validator_token {
+            return Err(Error::IdenticalAddresses);
         }
 
-        // Emit event
-        events::emit_burn(
+        storage::extend_instance_ttl(&env);
+        storage::enter_lock(&env)?;
+
+        let balance = storage::get_lp_balance(&env, &user_token, &validator_token, &sender);
+        if balance <= 0 {
+            return Err(Error::InsufficientLiquidity);
+        }
+
+        let pool = storage::get_pool(&env, &user_token, &validator_token);
+        let total_supply = storage::get_total_supply(&env, &user_token, &validator_token);
+        if total_supply == 0 {
+            return Err(Error::PoolNotInitialized);
+        }
+
+        // Largest share whose validator payout fits the unreserved validator tokens:
+        // liquidity * reserve_validator / total_supply <= effective_reserve_validator
+        let liquidity = if pool.reserve_validator_token == 0 {
+            balance
+        } else {
+            let pending = storage::get_pending_fee_swap(&env, &user_token, &validator_token);
+            let decimals = storage::get_pool_decimals(&env, &user_token, &validator_token);
+            let effective_validator_reserve = pool
+                .reserve_validator_token
+                .checked_sub(compute_pool_amount_out(pending, decimals)?)
+                .ok_or(Error::Overflow)?;
+            let max_liquidity = effective_validator_reserve
+                .checked_mul(total_supply)
+                .and_then(|prod| prod.checked_div(pool.reserve_validator_token))
+                .ok_or(Error::Overflow)?;
+            balance.min(max_liquidity)
+        };
+
+        if liquidity <= 0 {
+            return Err(Error::InsufficientReserves);
+        }
+
+        let (amount_user_token, amount_validator_token) = Self::burn_liquidity(
             &env,
             &sender,
             &user_token,
             &validator_token,
-            amount_user_token,
-            amount_validator_token,
             liquidity,
             &to,
-        );
+            (0, 0),
+            false,
+        )?;
 
-        Ok((amount_user_token, amount_validator_token))
+        storage::exit_lock(&env);
+
+        Ok((liquidity, amount_user_token, amount_validator_token))
+    }
+
+    /// Move `amount` LP of a pool from `from` to `to`, like a SEP-41 `transfer`
+    pub fn transfer_lp(
+        env: Env,
+        from: Address,
+        to: Address,
+        user_token: Address,
+        validator_token: Address,
+        amount: i128,
+    ) -> Result<(), Error> {
+        from.require_auth();
+        storage::extend_instance_ttl(&env);
+        Self::move_lp(&env, &from, &to, &user_token, &validator_token, amount)
+    }
+
+    /// Let `spender` move up to `amount` of `from`'s LP in a pool until
+    /// `expiration_ledger`, like a SEP-41 `approve`; replaces any earlier allowance
+    pub fn approve_lp(
+        env: Env,
+        from: Address,
+        spender: Address,
+        user_token: Address,
+        validator_token: Address,
+        amount: i128,
+        expiration_ledger: u32,
+    ) -> Result<(), Error> {
+        from.require_auth();
+        if amount < 0 || (amount > 0 && expiration_ledger < env.ledger().sequence()) {
+            return Err(Error::InvalidAmount);
+        }
+        storage::extend_instance_ttl(&env);
+
+        storage::set_lp_allowance(&env, &user_token, &validator_token, &from, &spender, amount, expiration_ledger);
+        events::emit_approve(&env, &from, &spender, &user_token, &validator_token, amount, expiration_ledger);
+        Ok(())
+    }
+
+    /// LP of `from` in a pool that `spender` may still move
+    pub fn lp_allowance(
+        env: Env,
+        from: Address,
+        spender: Address,
+        user_token: Address,
+        validator_token: Address,
+    ) -> i128 {
+        storage::extend_instance_ttl(&env);
+        storage::get_lp_allowance(&env, &user_token, &validator_token, &from, &spender).0
+    }
+
+    /// Move `amount` LP from `from` to `to` against `spender`'s allowance, like a
+    /// SEP-41 `transfer_from`
+    pub fn transfer_lp_from(
+        env: Env,
+        spender: Address,
+        from: Address,
+        to: Address,
+        user_token: Address,
+        validator_token: Address,
+        amount: i128,
+    ) -> Result<(), Error> {
+        spender.require_auth();
+        storage::extend_instance_ttl(&env);
+
+        let (allowance, expiration_ledger) =
+            storage::get_lp_allowance(&env, &user_token, &validator_token, &from, &spender);
+        if amount > allowance {
+            return Err(Error::InsufficientAllowance);
+        }
+        Self::move_lp(&env, &from, &to, &user_token, &validator_token, amount)?;
+        storage::set_lp_allowance(
+            &env,
+            &user_token,
+            &validator_token,
+            &from,
+            &spender,
+            allowance - amount,
+            expiration_ledger,
+        );
+        Ok(())
     }
 
     /// Reserve liquidity for pending fee swaps
@@ -478,6 +963,9 @@ impl TempoFeeAMM {
         if max_amount <= 0 {
             return Err(Error::InvalidAmount);
         }
+        if !storage::has_pool(&env, &user_token, &validator_token) {
+            return Err(Error::PoolNotInitialized);
+        }
 
         storage::extend_instance_ttl(&env);
 
@@ -488,11 +976,14 @@ impl TempoFeeAMM {
             .checked_add(max_amount)
             .ok_or(Error::Overflow)?;
 
-        // Check that total output needed is within reserves
-        let total_out_needed = compute_amount_out(new_total_pending)?;
+        // Check that total output needed is within the reserves not backing
+        // the locked MIN_LIQUIDITY
+        let decimals = storage::get_pool_decimals(&env, &user_token, &validator_token);
+        let total_out_needed = compute_pool_amount_out(new_total_pending, decimals)?;
 
         let pool = storage::get_pool(&env, &user_token, &validator_token);
-        if total_out_needed > pool.reserve_validator_token {
+        let total_supply = storage::get_total_supply(&env, &user_token, &validator_token);
+        if total_out_needed > reservable_validator_reserve(&pool, total_supply)? {
             return Err(Error::InsufficientLiquidity);
         }
 
@@ -501,6 +992,50 @@ impl TempoFeeAMM {
         Ok(())
     }
 
+    /// Reserve as much liquidity as the pool can back, up to `max_amount`
+    /// Returns the amount actually reserved (0 if the pool is fully committed)
+    pub fn reserve_liquidity_capped(
+        env: Env,
+        user_token: Address,
+        validator_token: Address,
+        max_amount: i128,
+    ) -> Result<i128, Error> {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+
+        if max_amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        if !storage::has_pool(&env, &user_token, &validator_token) {
+            return Err(Error::PoolNotInitialized);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let current_pending =
+            storage::get_pending_fee_swap(&env, &user_token, &validator_token);
+
+        let pool = storage::get_pool(&env, &user_token, &validator_token);
+        let total_supply = storage::get_total_supply(&env, &user_token, &validator_token);
+        let decimals = storage::get_pool_decimals(&env, &user_token, &validator_token);
+        let available = max_pending_for_reserve(reservable_validator_reserve(&pool, total_supply)?, decimals)?
+            .checked_sub(current_pending)
+            .ok_or(Error::Overflow)?
+            .max(0);
+
+        let reserved = max_amount.min(available);
+        if reserved > 0 {
+            storage::set_pending_fee_swap(
+                &env,
+                &user_token,
+                &validator_token,
+                current_pending.checked_add(reserved).ok_or(Error::Overflow)?,
+            );
+        }
+
+        Ok(reserved)
+    }
+
     /// Release reserved liquidity (refund unused reservation)
     pub fn release_liquidity(
         env: Env,
@@ -543,50 +1078,227 @@ impl TempoFeeAMM {
         let admin = storage::get_admin(&env);
         admin.require_auth();
 
+        if !storage::has_pool(&env, &user_token, &validator_token) {
+            return Err(Error::PoolNotInitialized);
+        }
+
         storage::extend_instance_ttl(&env);
+        storage::enter_lock(&env)?;
 
-        let amount_in = storage::get_pending_fee_swap(&env, &user_token, &validator_token);
-        if amount_in == 0 {
-            return Ok(0);
+        let pending_out = Self::settle_pending_fee_swap(&env, &user_token, &validator_token)?;
+
+        storage::exit_lock(&env);
+
+        Ok(pending_out)
+    }
+
+    /// Execute the pending fee swaps of several pools in one call (admin only)
+    /// Returns the validator token output per pool, 0 for pools with nothing pending
+    pub fn execute_pending_fee_swaps_batch(
+        env: Env,
+        caller: Address,
+        pairs: Vec<(Address, Address)>,
+    ) -> Result<Vec<i128>, Error> {
+        Self::require_admin(&env, &caller)?;
+
+        storage::extend_instance_ttl(&env);
+        storage::enter_lock(&env)?;
+
+        let mut outputs = Vec::new(&env);
+        for (user_token, validator_token) in pairs.iter() {
+            if !storage::has_pool(&env, &user_token, &validator_token) {
+                return Err(Error::PoolNotInitialized);
+            }
+            outputs.push_back(Self::settle_pending_fee_swap(
+                &env,
+                &user_token,
+                &validator_token,
+            )?);
+        }
+
+        storage::exit_lock(&env);
+
+        Ok(outputs)
+    }
+
+    /// Swap `amount_in` of `token_in` for `token_out` against the pool's
+    /// x*y=k reserves, charging the fee swap multiplier M on the input
+    ///
+    /// Either side may be sold. Selling validator tokens for user tokens is a
+    /// rebalance, so it is limited to the rebalance whitelist like
+    /// `rebalance_swap`. The output may not take a reserve below
+    /// `min_reserve`, nor pay out validator tokens committed to pending fee
+    /// swaps; reverts with `SlippageExceeded` below `min_amount_out`.
+    pub fn swap_exact_in(
+        env: Env,
+        sender: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_in: i128,
+        min_amount_out: i128,
+        to: Address,
+    ) -> Result<i128, Error> {
+        sender.require_auth();
+
+        if token_in == token_out {
+            return Err(Error::IdenticalAddresses);
+        }
+        if amount_in <= 0 {
+            return Err(Error::InvalidAmount);
         }
 
-        let pending_out = compute_amount_out(amount_in)?;
+        storage::extend_instance_ttl(&env);
+        storage::enter_lock(&env)?;
+
+        // Pools are keyed (user_token, validator_token)
+        let (user_token, validator_token, is_user_in) = if storage::has_pool(&env, &token_in, &token_out) {
+            (&token_in, &token_out, true)
+        } else if storage::has_pool(&env, &token_out, &token_in) {
+            (&token_out, &token_in, false)
+        } else {
+            return Err(Error::PoolNotInitialized);
+        };
+        if !is_user_in {
+            let whitelist = storage::get_rebalance_whitelist(&env);
+            if !whitelist.is_empty() && !whitelist.contains(&sender) {
+                return Err(Error::Unauthorized);
+            }
+        }
+        let mut pool = storage::get_pool(&env, user_token, validator_token);
+
+        let (reserve_in, reserve_out) = if is_user_in {
+            (pool.reserve_user_token, pool.reserve_validator_token)
+        } else {
+            (pool.reserve_validator_token, pool.reserve_user_token)
+        };
+        let amount_out = compute_constant_product_out(amount_in, reserve_in, reserve_out)?;
+        if amount_out <= 0 {
+            return Err(Error::InvalidSwapCalculation);
+        }
+
+        let mut available_out = reserve_out - storage::get_min_reserve(&env, MIN_LIQUIDITY);
+        if is_user_in {
+            let pending = storage::get_pending_fee_swap(&env, user_token, validator_token);
+            let decimals = storage::get_pool_decimals(&env, user_token, validator_token);
+            available_out -= compute_pool_amount_out(pending, decimals)?;
+        }
+        if amount_out > available_out {
+            return Err(Error::InsufficientReserves);
+        }
+        if amount_out < min_amount_out {
+            return Err(Error::SlippageExceeded);
+        }
+
+        let reserve_in = reserve_in.checked_add(amount_in).ok_or(Error::Overflow)?;
+        let reserve_out = reserve_out - amount_out;
+        if is_user_in {
+            pool.reserve_user_token = reserve_in;
+            pool.reserve_validator_token = reserve_out;
+        } else {
+            pool.reserve_validator_token = reserve_in;
+            pool.reserve_user_token = reserve_out;
+        }
+        storage::set_pool(&env, user_token, validator_token, &pool);
+
+        token::Client::new(&env, &token_in).transfer(&sender, &env.current_contract_address(), &amount_in);
+        token::Client::new(&env, &token_out).transfer(&env.current_contract_address(), &to, &amount_out);
+
+        events::emit_swap(&env, &sender, &token_in, &token_out, amount_in, amount_out, &to);
+
+        storage::exit_lock(&env);
+
+        Ok(amount_out)
+    }
+
+    /// Lend `amount` of one of a pool's reserve tokens to `receiver` for the
+    /// length of its `on_flash_loan` callback
+    ///
+    /// The fee is the fee swap rate, `amount * (SCALE - M) / SCALE` rounded up,
+    /// and is added to the pool's reserve. Reverts with `InsufficientReserves`
+    /// unless the AMM's token balance has grown by the fee when the callback
+    /// returns. The reentrancy lock is held throughout. Returns the fee.
+    pub fn flash_loan(
+        env: Env,
+        borrower: Address,
+        user_token: Address,
+        validator_token: Address,
+        token: Address,
+        amount: i128,
+        receiver: Address,
+        data: Bytes,
+    ) -> Result<i128, Error> {
+        borrower.require_auth();
+
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        if token != user_token && token != validator_token {
+            return Err(Error::InvalidToken);
+        }
+        if !storage::has_pool(&env, &user_token, &validator_token) {
+            return Err(Error::PoolNotInitialized);
+        }
+
+        storage::extend_instance_ttl(&env);
+        storage::enter_lock(&env)?;
 
         let mut pool = storage::get_pool(&env, &user_token, &validator_token);
+        let reserve = if token == user_token {
+            pool.reserve_user_token
+        } else {
+            pool.reserve_validator_token
+        };
+        if amount > reserve {
+            return Err(Error::InsufficientReserves);
+        }
 
-        // Update reserves: user tokens go in, validator tokens go out
-        pool.reserve_user_token = pool
-            .reserve_user_token
-            .checked_add(amount_in)
+        let fee = amount
+            .checked_mul(SCALE - M)
+            .map(|num| (num + SCALE - 1) / SCALE)
             .ok_or(Error::Overflow)?;
 
-        pool.reserve_validator_token = pool
-            .reserve_validator_token
-            .checked_sub(pending_out)
-            .ok_or(Error::Overflow)?;
+        let this = env.current_contract_address();
+        let token_client = token::Client::new(&env, &token);
+        let balance_before = token_client.balance(&this);
+        token_client.transfer(&this, &receiver, &amount);
 
+        FlashLoanReceiverClient::new(&env, &receiver).on_flash_loan(
+            &borrower, &token, &amount, &fee, &data,
+        );
+
+        if token_client.balance(&this) < balance_before + fee {
+            return Err(Error::InsufficientReserves);
+        }
+
+        if token == user_token {
+            pool.reserve_user_token += fee;
+        } else {
+            pool.reserve_validator_token += fee;
+        }
         storage::set_pool(&env, &user_token, &validator_token, &pool);
-        storage::clear_pending_fee_swap(&env, &user_token, &validator_token);
 
-        // Emit event
-        events::emit_fee_swap(&env, &user_token, &validator_token, amount_in, pending_out);
+        events::emit_flash_loan(&env, &borrower, &token, &receiver, amount, fee);
 
-        Ok(pending_out)
+        storage::exit_lock(&env);
+
+        Ok(fee)
     }
 
     /// Rebalance swap: exchange validator tokens for user tokens
     /// Used to rebalance pools when they become imbalanced
+    /// Reverts with `SlippageExceeded` if the required input exceeds `max_amount_in`
     ///
     /// NOTE: In the original Tempo implementation, this function may be intended for
-    /// validators or privileged actors to rebalance pools. Currently permissionless -
-    /// any user with validator tokens can call it. Consider adding access control
-    /// if rebalancing should be restricted.
+    /// validators or privileged actors to rebalance pools. Permissionless while the
+    /// rebalance whitelist is empty; otherwise `sender` must be on it or the call
+    /// fails with `Unauthorized`.
     pub fn rebalance_swap(
         env: Env,
         sender: Address,
         user_token: Address,
         validator_token: Address,
         amount_out: i128,
+        max_amount_in: i128,
         to: Address,
     ) -> Result<i128, Error> {
         sender.require_auth();
@@ -594,22 +1306,248 @@ impl TempoFeeAMM {
         if amount_out <= 0 {
             return Err(Error::InvalidAmount);
         }
+        Self::execute_rebalance(
+            &env,
+            &sender,
+            &user_token,
+            &validator_token,
+            amount_out,
+            max_amount_in,
+            &to,
+        )
+    }
+
+    /// Rebalance swap spending at most `amount_in` validator tokens
+    ///
+    /// Pays out `calculate_rebalance_output(amount_in)` user tokens, capped at
+    /// the reserve above `min_reserve`, and charges only the input that output
+    /// requires. Reverts with `SlippageExceeded` below `min_amount_out`.
+    /// Returns the user tokens paid out.
+    pub fn rebalance_swap_exact_in(
+        env: Env,
+        sender: Address,
+        user_token: Address,
+        validator_token: Address,
+        amount_in: i128,
+        min_amount_out: i128,
+        to: Address,
+    ) -> Result<i128, Error> {
+        sender.require_auth();
+
+        if amount_in <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        if !storage::has_pool(&env, &user_token, &validator_token) {
+            return Err(Error::PoolNotInitialized);
+        }
+
+        let pool = storage::get_pool(&env, &user_token, &validator_token);
+        let decimals = storage::get_pool_decimals(&env, &user_token, &validator_token);
+        let amount_out = rescale(compute_rebalance_output(amount_in)?, decimals.1, decimals.0)?;
+        if amount_out == 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        let available = pool.reserve_user_token - storage::get_min_reserve(&env, MIN_LIQUIDITY);
+        if available <= 0 {
+            return Err(Error::InsufficientReserves);
+        }
+        let amount_out = amount_out.min(available);
+        if amount_out < min_amount_out {
+            return Err(Error::SlippageExceeded);
+        }
+
+        Self::execute_rebalance(
+            &env,
+            &sender,
+            &user_token,
+            &validator_token,
+            amount_out,
+            amount_in,
+            &to,
+        )?;
+
+        Ok(amount_out)
+    }
+
+    /// Transfer each pair token's provable surplus to the admin (admin only)
+    ///
+    /// Surplus is the contract's token balance minus what every pool owes in
+    /// that token (reserves plus pending fee swap input), so only tokens no
+    /// pool can claim are moved. Returns (user token swept, validator token swept).
+    pub fn sweep_reserve_dust(
+        env: Env,
+        caller: Address,
+        user_token: Address,
+        validator_token: Address,
+    ) -> Result<(i128, i128), Error> {
+        Self::require_admin(&env, &caller)?;
+
+        if user_token == validator_token {
+            return Err(Error::IdenticalAddresses);
+        }
 
         storage::extend_instance_ttl(&env);
+        storage::enter_lock(&env)?;
 
-        let mut pool = storage::get_pool(&env, &user_token, &validator_token);
+        let swept_user = Self::sweep_surplus(&env, &user_token, &caller);
+        let swept_validator = Self::sweep_surplus(&env, &validator_token, &caller);
+
+        storage::exit_lock(&env);
+
+        Ok((swept_user, swept_validator))
+    }
 
-        // Check sufficient user token reserves
-        if amount_out > pool.reserve_user_token {
+    /// Record a timestamped copy of the pool state (admin only)
+    /// Returns the snapshot index; only the latest `MAX_SNAPSHOTS` are retained
+    pub fn snapshot_pool(
+        env: Env,
+        caller: Address,
+        user_token: Address,
+        validator_token: Address,
+    ) -> Result<u32, Error> {
+        Self::require_admin(&env, &caller)?;
+        storage::extend_instance_ttl(&env);
+
+        let pool = storage::get_pool(&env, &user_token, &validator_token);
+        let index = storage::get_snapshot_count(&env, &user_token, &validator_token);
+
+        let snapshot = PoolSnapshot {
+            index,
+            timestamp: env.ledger().timestamp(),
+            reserve_user_token: pool.reserve_user_token,
+            reserve_validator_token: pool.reserve_validator_token,
+            total_supply: storage::get_total_supply(&env, &user_token, &validator_token),
+        };
+        storage::push_snapshot(&env, &user_token, &validator_token, &snapshot);
+
+        events::emit_snapshot(&env, &user_token, &validator_token, index);
+
+        Ok(index)
+    }
+
+    /// Get a previously recorded pool snapshot by index
+    pub fn get_snapshot(
+        env: Env,
+        user_token: Address,
+        validator_token: Address,
+        index: u32,
+    ) -> Result<PoolSnapshot, Error> {
+        storage::extend_instance_ttl(&env);
+        storage::get_snapshot(&env, &user_token, &validator_token, index)
+            .ok_or(Error::SnapshotNotFound)
+    }
+
+    /// Calculate the output amount for a given input (view function), for
+    /// tokens sharing a scale
+    pub fn calculate_fee_swap_output(amount_in: i128) -> Result<i128, Error> {
+        compute_amount_out(amount_in)
+    }
+
+    /// Debit `from`'s LP balance and credit `to`'s
+    fn move_lp(
+        env: &Env,
+        from: &Address,
+        to: &Address,
+        user_token: &Address,
+        validator_token: &Address,
+        amount: i128,
+    ) -> Result<(), Error> {
+        if amount < 0 {
+            return Err(Error::InvalidAmount);
+        }
+        let from_balance = storage::get_lp_balance(env, user_token, validator_token, from);
+        if amount > from_balance {
+            return Err(Error::InsufficientLiquidity);
+        }
+        storage::set_lp_balance(env, user_token, validator_token, from, from_balance - amount);
+        let to_balance = storage::get_lp_balance(env, user_token, validator_token, to);
+        storage::set_lp_balance(env, user_token, validator_token, to, to_balance + amount);
+
+        events::emit_transfer(env, from, to, user_token, validator_token, amount);
+        Ok(())
+    }
+
+    /// Output of a fee swap (`is_user_in`) or rebalance swap of `amount_in`,
+    /// failing if the pool cannot pay it or it rounds to zero
+    fn quote_amount_out(
+        env: &Env,
+        user_token: &Address,
+        validator_token: &Address,
+        is_user_in: bool,
+        amount_in: i128,
+    ) -> Result<i128, Error> {
+        let pool = storage::get_pool(env, user_token, validator_token);
+        let decimals = storage::get_pool_decimals(env, user_token, validator_token);
+        let amount_out = if is_user_in {
+            let amount_out = compute_pool_amount_out(amount_in, decimals)?;
+            let pending = storage::get_pending_fee_swap(env, user_token, validator_token);
+            let available = pool
+                .reserve_validator_token
+                .checked_sub(compute_pool_amount_out(pending, decimals)?)
+                .ok_or(Error::Overflow)?;
+            if amount_out > available {
+                return Err(Error::InsufficientLiquidity);
+            }
+            amount_out
+        } else {
+            // Same output rebalance_swap_exact_in pays for amount_in, capped
+            // at the reserve above min_reserve
+            let amount_out = rescale(compute_rebalance_output(amount_in)?, decimals.1, decimals.0)?;
+            let available = pool.reserve_user_token - storage::get_min_reserve(env, MIN_LIQUIDITY);
+            if available <= 0 {
+                return Err(Error::InsufficientReserves);
+            }
+            amount_out.min(available)
+        };
+
+        if amount_out == 0 {
+            return Err(Error::InvalidAmount);
+        }
+        Ok(amount_out)
+    }
+
+    /// Pay `amount_out` user tokens to `to` for validator tokens from `sender`
+    /// at `amount_out * N / SCALE + 1`, failing above `max_amount_in`;
+    /// returns the input charged
+    fn execute_rebalance(
+        env: &Env,
+        sender: &Address,
+        user_token: &Address,
+        validator_token: &Address,
+        amount_out: i128,
+        max_amount_in: i128,
+        to: &Address,
+    ) -> Result<i128, Error> {
+        if !storage::has_pool(env, user_token, validator_token) {
+            return Err(Error::PoolNotInitialized);
+        }
+
+        let whitelist = storage::get_rebalance_whitelist(env);
+        if !whitelist.is_empty() && !whitelist.contains(sender) {
+            return Err(Error::Unauthorized);
+        }
+
+        storage::extend_instance_ttl(env);
+        storage::enter_lock(env)?;
+
+        let mut pool = storage::get_pool(env, user_token, validator_token);
+
+        // Check sufficient user token reserves, keeping the configured floor
+        if amount_out > pool.reserve_user_token - storage::get_min_reserve(env, MIN_LIQUIDITY) {
             return Err(Error::InsufficientReserves);
         }
 
-        // Calculate input: amount_in = amount_out * N / SCALE + 1
-        let amount_in = amount_out
+        // Calculate input: amount_in = amount_out * N / SCALE + 1, in validator units
+        let decimals = storage::get_pool_decimals(env, user_token, validator_token);
+        let amount_in = rescale(amount_out, decimals.0, decimals.1)?
             .checked_mul(N)
             .and_then(|prod| prod.checked_div(SCALE))
             .and_then(|res| res.checked_add(1))
             .ok_or(Error::Overflow)?;
+        if amount_in > max_amount_in {
+            return Err(Error::SlippageExceeded);
+        }
 
         // Update reserves: validator tokens in, user tokens out
         pool.reserve_validator_token = pool
@@ -622,34 +1560,217 @@ impl TempoFeeAMM {
             .checked_sub(amount_out)
             .ok_or(Error::InsufficientReserves)?;
 
-        storage::set_pool(&env, &user_token, &validator_token, &pool);
+        storage::set_pool(env, user_token, validator_token, &pool);
 
         // Transfer tokens
-        let validator_token_client = token::Client::new(&env, &validator_token);
-        validator_token_client.transfer(&sender, &env.current_contract_address(), &amount_in);
+        let validator_token_client = token::Client::new(env, validator_token);
+        validator_token_client.transfer(sender, &env.current_contract_address(), &amount_in);
 
-        let user_token_client = token::Client::new(&env, &user_token);
-        user_token_client.transfer(&env.current_contract_address(), &to, &amount_out);
+        let user_token_client = token::Client::new(env, user_token);
+        user_token_client.transfer(&env.current_contract_address(), to, &amount_out);
 
         // Emit event
         events::emit_rebalance_swap(
-            &env,
-            &user_token,
-            &validator_token,
-            &sender,
+            env,
+            user_token,
+            validator_token,
+            sender,
             amount_in,
             amount_out,
         );
 
+        storage::exit_lock(env);
+
         Ok(amount_in)
     }
 
-    /// Calculate the output amount for a given input (view function)
-    pub fn calculate_fee_swap_output(amount_in: i128) -> Result<i128, Error> {
-        compute_amount_out(amount_in)
+    /// Move a pool's pending fee swap input into reserves and pay out of the
+    /// validator reserve; returns the output (0 if nothing is pending)
+    fn settle_pending_fee_swap(
+        env: &Env,
+        user_token: &Address,
+        validator_token: &Address,
+    ) -> Result<i128, Error> {
+        let amount_in = storage::get_pending_fee_swap(env, user_token, validator_token);
+        if amount_in == 0 {
+            return Ok(0);
+        }
+
+        let decimals = storage::get_pool_decimals(env, user_token, validator_token);
+        let pending_out = compute_pool_amount_out(amount_in, decimals)?;
+
+        let mut pool = storage::get_pool(env, user_token, validator_token);
+
+        // Update reserves: user tokens go in, validator tokens go out
+        pool.reserve_user_token = pool
+            .reserve_user_token
+            .checked_add(amount_in)
+            .ok_or(Error::Overflow)?;
+
+        pool.reserve_validator_token = pool
+            .reserve_validator_token
+            .checked_sub(pending_out)
+            .ok_or(Error::Overflow)?;
+
+        storage::set_pool(env, user_token, validator_token, &pool);
+        storage::clear_pending_fee_swap(env, user_token, validator_token);
+
+        // Emit event
+        events::emit_fee_swap(env, user_token, validator_token, amount_in, pending_out);
+
+        Ok(pending_out)
+    }
+
+    /// Burn `liquidity` LP from `sender` and pay out the pro-rata reserves to `to`,
+    /// refusing to touch validator tokens reserved for pending fee swaps
+    ///
+    /// With `into_validator` the user-token share stays in the pool and is paid
+    /// out in validator tokens at the rebalance rate instead
+    fn burn_liquidity(
+        env: &Env,
+        sender: &Address,
+        user_token: &Address,
+        validator_token: &Address,
+        liquidity: i128,
+        to: &Address,
+        min_amounts: (i128, i128),
+        into_validator: bool,
+    ) -> Result<(i128, i128), Error> {
+        // Check sender has sufficient LP balance
+        let balance = storage::get_lp_balance(env, user_token, validator_token, sender);
+        if balance < liquidity {
+            return Err(Error::InsufficientLiquidity);
+        }
+
+        let mut pool = storage::get_pool(env, user_token, validator_token);
+        let total_supply = storage::get_total_supply(env, user_token, validator_token);
+
+        if total_supply == 0 {
+            return Err(Error::PoolNotInitialized);
+        }
+
+        // Calculate amounts to return
+        let mut amount_user_token = liquidity
+            .checked_mul(pool.reserve_user_token)
+            .and_then(|prod| prod.checked_div(total_supply))
+            .ok_or(Error::Overflow)?;
+
+        let mut amount_validator_token = liquidity
+            .checked_mul(pool.reserve_validator_token)
+            .and_then(|prod| prod.checked_div(total_supply))
+            .ok_or(Error::Overflow)?;
+
+        let mut new_total_supply = total_supply
+            .checked_sub(liquidity)
+            .ok_or(Error::Overflow)?;
+
+        // Check withdrawal doesn't violate pending swaps
+        let pending = storage::get_pending_fee_swap(env, user_token, validator_token);
+
+        // The last LP out takes the residual behind the locked MIN_LIQUIDITY, less
+        // dust, and the pool resets. Only when no other shares exist and nothing
+        // is reserved for fee swaps, and never on a single-sided exit, which leaves
+        // its user tokens behind.
+        if new_total_supply == MIN_LIQUIDITY
+            && pending == 0
+            && !into_validator
+            && storage::get_last_exit_refund(env)
+        {
+            amount_user_token = amount_user_token
+                .max(pool.reserve_user_token - LAST_EXIT_DUST)
+                .min(pool.reserve_user_token);
+            amount_validator_token = amount_validator_token
+                .max(pool.reserve_validator_token - LAST_EXIT_DUST)
+                .min(pool.reserve_validator_token);
+            new_total_supply = 0;
+        }
+        let decimals = storage::get_pool_decimals(env, user_token, validator_token);
+
+        // Sell the user-token share to the pool: amount * N / SCALE in validator units
+        if into_validator {
+            let converted = rescale(amount_user_token, decimals.0, decimals.1)?
+                .checked_mul(N)
+                .and_then(|prod| prod.checked_div(SCALE))
+                .ok_or(Error::Overflow)?;
+            amount_validator_token = amount_validator_token
+                .checked_add(converted)
+                .ok_or(Error::Overflow)?;
+            amount_user_token = 0;
+        }
+
+        let pending_out = compute_pool_amount_out(pending, decimals)?;
+        let effective_validator_reserve = pool
+            .reserve_validator_token
+            .checked_sub(pending_out)
+            .ok_or(Error::Overflow)?;
+
+        if amount_validator_token > effective_validator_reserve {
+            return Err(Error::InsufficientReserves);
+        }
+
+        if amount_user_token < min_amounts.0 || amount_validator_token < min_amounts.1 {
+            return Err(Error::SlippageExceeded);
+        }
+
+        // Burn LP tokens
+        storage::set_lp_balance(
+            env,
+            user_token,
+            validator_token,
+            sender,
+            balance.checked_sub(liquidity).ok_or(Error::Overflow)?,
+        );
+
+        storage::set_total_supply(env, user_token, validator_token, new_total_supply);
+
+        // Update reserves; a reset pool leaves its dust as sweepable surplus
+        if new_total_supply == 0 {
+            pool = Pool::default();
+        } else {
+            pool.reserve_user_token = pool
+                .reserve_user_token
+                .checked_sub(amount_user_token)
+                .ok_or(Error::InsufficientReserves)?;
+            pool.reserve_validator_token = pool
+                .reserve_validator_token
+                .checked_sub(amount_validator_token)
+                .ok_or(Error::InsufficientReserves)?;
+        }
+
+        storage::set_pool(env, user_token, validator_token, &pool);
+
+        // Transfer tokens to recipient
+        if amount_user_token > 0 {
+            let user_token_client = token::Client::new(env, user_token);
+            user_token_client.transfer(&env.current_contract_address(), to, &amount_user_token);
+        }
+
+        if amount_validator_token > 0 {
+            let validator_token_client = token::Client::new(env, validator_token);
+            validator_token_client.transfer(
+                &env.current_contract_address(),
+                to,
+                &amount_validator_token,
+            );
+        }
+
+        // Emit event
+        events::emit_burn(
+            env,
+            sender,
+            user_token,
+            validator_token,
+            amount_user_token,
+            amount_validator_token,
+            liquidity,
+            to,
+        );
+
+        Ok((amount_user_token, amount_validator_token))
     }
 
-    /// Calculate the input amount for a rebalance swap (view function)
+    /// Calculate the input amount for a rebalance swap (view function), for
+    /// tokens sharing a scale
     pub fn calculate_rebalance_input(amount_out: i128) -> Result<i128, Error> {
         amount_out
             .checked_mul(N)
@@ -657,6 +1778,43 @@ impl TempoFeeAMM {
             .and_then(|res| res.checked_add(1))
             .ok_or(Error::Overflow)
     }
+
+    /// Calculate the output of a rebalance swap for a given input (view
+    /// function), for tokens sharing a scale; inverse of `calculate_rebalance_input`
+    pub fn calculate_rebalance_output(amount_in: i128) -> Result<i128, Error> {
+        compute_rebalance_output(amount_in)
+    }
+
+    fn sweep_surplus(env: &Env, token: &Address, to: &Address) -> i128 {
+        let token_client = token::Client::new(env, token);
+        let balance = token_client.balance(&env.current_contract_address());
+        let surplus = balance - storage::get_token_obligations(env, token);
+        if surplus <= 0 {
+            return 0;
+        }
+
+        token_client.transfer(&env.current_contract_address(), to, &surplus);
+        events::emit_sweep(env, token, to, surplus);
+        surplus
+    }
+
+    /// Read both tokens' decimals and record them for the pool
+    fn record_pool_decimals(env: &Env, user_token: &Address, validator_token: &Address) -> (u32, u32) {
+        let decimals = (
+            token::Client::new(env, user_token).decimals(),
+            token::Client::new(env, validator_token).decimals(),
+        );
+        storage::set_pool_decimals(env, user_token, validator_token, decimals);
+        decimals
+    }
+
+    fn require_admin(env: &Env, caller: &Address) -> Result<(), Error> {
+        caller.require_auth();
+        if *caller != storage::get_admin(env) {
+            return Err(Error::Unauthorized);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
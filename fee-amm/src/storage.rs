@@ -1,4 +1,6 @@
-use soroban_sdk::{contracttype, Address, Env};
+use soroban_sdk::{contracttype, Address, Env, Vec};
+
+use crate::error::Error;
 
 /// Storage keys for the contract
 #[contracttype]
@@ -14,6 +16,24 @@ pub enum DataKey {
     LPBalance(Address, Address, Address),
     /// Pending fee swap amount for a pool
     PendingFeeSwap(Address, Address),
+    /// Reentrancy guard for reserve-mutating calls
+    Locked,
+    /// Number of snapshots ever taken for a pool
+    SnapshotCount(Address, Address),
+    /// Pool snapshot ring buffer slot (user_token, validator_token, slot)
+    Snapshot(Address, Address, u32),
+    /// Amount of a token owed across all pools: reserves plus pending fee swap input
+    TokenObligations(Address),
+    /// Whether the last LP to exit a pool also receives the locked residual reserves
+    LastExitRefund,
+    /// Smallest reserve of either token a swap may leave behind
+    MinReserve,
+    /// Decimals of a pool's (user_token, validator_token), recorded on first deposit
+    PoolDecimals(Address, Address),
+    /// LP allowance (user_token, validator_token, owner, spender) as (amount, expiration_ledger)
+    LPAllowance(Address, Address, Address, Address),
+    /// Addresses allowed to call `rebalance_swap`; empty means permissionless
+    RebalanceWhitelist,
 }
 
 /// Pool structure storing reserve balances
@@ -24,6 +44,39 @@ pub struct Pool {
     pub reserve_validator_token: i128,
 }
 
+/// Pool snapshot bundling reserves with derived figures for front-ends
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct PoolInfo {
+    pub reserve_user_token: i128,
+    pub reserve_validator_token: i128,
+    pub total_supply: i128,
+    /// User tokens reserved for pending fee swaps
+    pub pending_fee_swap: i128,
+    /// User token reserve once pending fee swaps execute
+    pub effective_reserve_user: i128,
+    /// Validator token reserve not committed to pending fee swaps
+    pub effective_reserve_validator: i128,
+    /// Validator tokens per user token, scaled by PRICE_SCALE (0 if no user reserve)
+    pub spot_price: i128,
+}
+
+/// Timestamped copy of a pool's state for off-chain reconciliation
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolSnapshot {
+    /// Sequential snapshot index for the pool
+    pub index: u32,
+    /// Ledger timestamp when the snapshot was taken
+    pub timestamp: u64,
+    pub reserve_user_token: i128,
+    pub reserve_validator_token: i128,
+    pub total_supply: i128,
+}
+
+/// Number of snapshots retained per pool before the oldest are overwritten
+pub const MAX_SNAPSHOTS: u32 = 16;
+
 // Storage helper functions
 
 const DAY_IN_LEDGERS: u32 = 17280; // ~24 hours at 5 seconds per ledger
@@ -58,8 +111,95 @@ pub fn has_admin(env: &Env) -> bool {
     env.storage().instance().has(&DataKey::Admin)
 }
 
+// Last LP exit policy
+pub fn get_last_exit_refund(env: &Env) -> bool {
+    env.storage()
+        .instance()
+        .get(&DataKey::LastExitRefund)
+        .unwrap_or(false)
+}
+
+pub fn set_last_exit_refund(env: &Env, enabled: bool) {
+    env.storage()
+        .instance()
+        .set(&DataKey::LastExitRefund, &enabled);
+}
+
+// Swap reserve floor
+pub fn get_min_reserve(env: &Env, default: i128) -> i128 {
+    env.storage()
+        .instance()
+        .get(&DataKey::MinReserve)
+        .unwrap_or(default)
+}
+
+pub fn set_min_reserve(env: &Env, min_reserve: i128) {
+    env.storage()
+        .instance()
+        .set(&DataKey::MinReserve, &min_reserve);
+}
+
+// Rebalance whitelist
+pub fn get_rebalance_whitelist(env: &Env) -> Vec<Address> {
+    env.storage()
+        .instance()
+        .get(&DataKey::RebalanceWhitelist)
+        .unwrap_or(Vec::new(env))
+}
+
+pub fn set_rebalance_whitelist(env: &Env, whitelist: &Vec<Address>) {
+    env.storage()
+        .instance()
+        .set(&DataKey::RebalanceWhitelist, whitelist);
+}
+
+// Reentrancy guard
+pub fn enter_lock(env: &Env) -> Result<(), Error> {
+    if env.storage().instance().get(&DataKey::Locked).unwrap_or(false) {
+        return Err(Error::Reentrancy);
+    }
+    env.storage().instance().set(&DataKey::Locked, &true);
+    Ok(())
+}
+
+pub fn exit_lock(env: &Env) {
+    env.storage().instance().remove(&DataKey::Locked);
+}
+
+// Token obligations: kept in step with every reserve and pending fee swap write
+pub fn get_token_obligations(env: &Env, token: &Address) -> i128 {
+    let key = DataKey::TokenObligations(token.clone());
+    let amount = env.storage().persistent().get(&key).unwrap_or(0);
+    if env.storage().persistent().has(&key) {
+        extend_persistent_ttl(env, &key);
+    }
+    amount
+}
+
+fn adjust_token_obligations(env: &Env, token: &Address, delta: i128) {
+    if delta == 0 {
+        return;
+    }
+    let key = DataKey::TokenObligations(token.clone());
+    let amount = get_token_obligations(env, token) + delta;
+    env.storage().persistent().set(&key, &amount);
+    extend_persistent_ttl(env, &key);
+}
+
 // Pool storage
 pub fn set_pool(env: &Env, user_token: &Address, validator_token: &Address, pool: &Pool) {
+    let previous = get_pool(env, user_token, validator_token);
+    adjust_token_obligations(
+        env,
+        user_token,
+        pool.reserve_user_token - previous.reserve_user_token,
+    );
+    adjust_token_obligations(
+        env,
+        validator_token,
+        pool.reserve_validator_token - previous.reserve_validator_token,
+    );
+
     let key = DataKey::Pool(user_token.clone(), validator_token.clone());
     env.storage().persistent().set(&key, pool);
     extend_persistent_ttl(env, &key);
@@ -74,6 +214,28 @@ pub fn get_pool(env: &Env, user_token: &Address, validator_token: &Address) -> P
     pool
 }
 
+// Pool token decimals
+pub fn set_pool_decimals(
+    env: &Env,
+    user_token: &Address,
+    validator_token: &Address,
+    decimals: (u32, u32),
+) {
+    let key = DataKey::PoolDecimals(user_token.clone(), validator_token.clone());
+    env.storage().persistent().set(&key, &decimals);
+    extend_persistent_ttl(env, &key);
+}
+
+/// Pools with no recorded decimals are treated as sharing one scale
+pub fn get_pool_decimals(env: &Env, user_token: &Address, validator_token: &Address) -> (u32, u32) {
+    let key = DataKey::PoolDecimals(user_token.clone(), validator_token.clone());
+    let decimals = env.storage().persistent().get(&key).unwrap_or((0, 0));
+    if env.storage().persistent().has(&key) {
+        extend_persistent_ttl(env, &key);
+    }
+    decimals
+}
+
 pub fn has_pool(env: &Env, user_token: &Address, validator_token: &Address) -> bool {
     let key = DataKey::Pool(user_token.clone(), validator_token.clone());
     env.storage().persistent().has(&key)
@@ -122,6 +284,49 @@ pub fn get_lp_balance(
     balance
 }
 
+// LP allowance storage
+pub fn set_lp_allowance(
+    env: &Env,
+    user_token: &Address,
+    validator_token: &Address,
+    owner: &Address,
+    spender: &Address,
+    amount: i128,
+    expiration_ledger: u32,
+) {
+    let key = DataKey::LPAllowance(
+        user_token.clone(),
+        validator_token.clone(),
+        owner.clone(),
+        spender.clone(),
+    );
+    env.storage().persistent().set(&key, &(amount, expiration_ledger));
+    extend_persistent_ttl(env, &key);
+}
+
+/// Allowance still in force; an expired approval reads as 0
+pub fn get_lp_allowance(
+    env: &Env,
+    user_token: &Address,
+    validator_token: &Address,
+    owner: &Address,
+    spender: &Address,
+) -> (i128, u32) {
+    let key = DataKey::LPAllowance(
+        user_token.clone(),
+        validator_token.clone(),
+        owner.clone(),
+        spender.clone(),
+    );
+    match env.storage().persistent().get::<_, (i128, u32)>(&key) {
+        Some((amount, expiration_ledger)) if expiration_ledger >= env.ledger().sequence() => {
+            extend_persistent_ttl(env, &key);
+            (amount, expiration_ledger)
+        }
+        _ => (0, 0),
+    }
+}
+
 // Pending fee swap storage
 pub fn set_pending_fee_swap(
     env: &Env,
@@ -129,6 +334,9 @@ pub fn set_pending_fee_swap(
     validator_token: &Address,
     amount: i128,
 ) {
+    let previous = get_pending_fee_swap(env, user_token, validator_token);
+    adjust_token_obligations(env, user_token, amount - previous);
+
     let key = DataKey::PendingFeeSwap(user_token.clone(), validator_token.clone());
     env.storage().persistent().set(&key, &amount);
     extend_persistent_ttl(env, &key);
@@ -144,6 +352,54 @@ pub fn get_pending_fee_swap(env: &Env, user_token: &Address, validator_token: &A
 }
 
 pub fn clear_pending_fee_swap(env: &Env, user_token: &Address, validator_token: &Address) {
+    let previous = get_pending_fee_swap(env, user_token, validator_token);
+    adjust_token_obligations(env, user_token, -previous);
+
     let key = DataKey::PendingFeeSwap(user_token.clone(), validator_token.clone());
     env.storage().persistent().set(&key, &0i128);
 }
+
+// Pool snapshot storage
+pub fn get_snapshot_count(env: &Env, user_token: &Address, validator_token: &Address) -> u32 {
+    let key = DataKey::SnapshotCount(user_token.clone(), validator_token.clone());
+    let count = env.storage().persistent().get(&key).unwrap_or(0);
+    if env.storage().persistent().has(&key) {
+        extend_persistent_ttl(env, &key);
+    }
+    count
+}
+
+/// Append a snapshot to the pool's ring buffer, overwriting the oldest once full
+pub fn push_snapshot(
+    env: &Env,
+    user_token: &Address,
+    validator_token: &Address,
+    snapshot: &PoolSnapshot,
+) {
+    let slot_key = DataKey::Snapshot(
+        user_token.clone(),
+        validator_token.clone(),
+        snapshot.index % MAX_SNAPSHOTS,
+    );
+    env.storage().persistent().set(&slot_key, snapshot);
+    extend_persistent_ttl(env, &slot_key);
+
+    let count_key = DataKey::SnapshotCount(user_token.clone(), validator_token.clone());
+    env.storage().persistent().set(&count_key, &(snapshot.index + 1));
+    extend_persistent_ttl(env, &count_key);
+}
+
+pub fn get_snapshot(
+    env: &Env,
+    user_token: &Address,
+    validator_token: &Address,
+    index: u32,
+) -> Option<PoolSnapshot> {
+    let key = DataKey::Snapshot(user_token.clone(), validator_token.clone(), index % MAX_SNAPSHOTS);
+    let snapshot: Option<PoolSnapshot> = env.storage().persistent().get(&key);
+    if snapshot.is_some() {
+        extend_persistent_ttl(env, &key);
+    }
+    // The slot may hold a newer snapshot that evicted the requested one
+    snapshot.filter(|s| s.index == index)
+}
@@ -1,10 +1,122 @@
-use crate::{Error, TempoFeeAMM, TempoFeeAMMClient};
+use crate::{storage, Error, TempoFeeAMM, TempoFeeAMMClient};
 use soroban_sdk::{
-    testutils::Address as _,
+    contract, contractimpl, symbol_short,
+    testutils::{Address as _, Ledger},
     token::{StellarAssetClient, TokenClient},
-    Address, Env,
+    vec, Address, Bytes, Env,
 };
 
+/// Token whose `transfer` tries to re-enter the AMM's `rebalance_swap` once armed
+#[contract]
+pub struct ReentrantToken;
+
+#[contractimpl]
+impl ReentrantToken {
+    pub fn arm(env: Env, amm: Address, validator_token: Address, attacker: Address) {
+        env.storage()
+            .instance()
+            .set(&symbol_short!("target"), &(amm, validator_token, attacker));
+    }
+
+    pub fn transfer(env: Env, _from: Address, _to: Address, _amount: i128) {
+        let target: Option<(Address, Address, Address)> =
+            env.storage().instance().get(&symbol_short!("target"));
+        if let Some((amm, validator_token, attacker)) = target {
+            let result = TempoFeeAMMClient::new(&env, &amm).try_rebalance_swap(
+                &attacker,
+                &env.current_contract_address(),
+                &validator_token,
+                &100,
+                &i128::MAX,
+                &attacker,
+            );
+            env.storage()
+                .instance()
+                .set(&symbol_short!("reentered"), &result.is_ok());
+        }
+    }
+
+    pub fn decimals(_env: Env) -> u32 {
+        7
+    }
+
+    /// Outcome of the re-entry attempt (None if it never ran)
+    pub fn reentered(env: Env) -> Option<bool> {
+        env.storage().instance().get(&symbol_short!("reentered"))
+    }
+}
+
+/// Minimal token with configurable decimals and unchecked balances, in its own
+/// module so its exports do not clash with `ReentrantToken`'s
+mod scaled_token {
+    use soroban_sdk::{contract, contractimpl, symbol_short, Address, Env};
+
+    #[contract]
+    pub struct ScaledToken;
+
+    #[contractimpl]
+    impl ScaledToken {
+        pub fn set_decimals(env: Env, decimals: u32) {
+            env.storage().instance().set(&symbol_short!("decimals"), &decimals);
+        }
+
+        pub fn decimals(env: Env) -> u32 {
+            env.storage().instance().get(&symbol_short!("decimals")).unwrap()
+        }
+
+        pub fn mint(env: Env, to: Address, amount: i128) {
+            let balance = Self::balance(env.clone(), to.clone());
+            env.storage().persistent().set(&to, &(balance + amount));
+        }
+
+        pub fn balance(env: Env, id: Address) -> i128 {
+            env.storage().persistent().get(&id).unwrap_or(0)
+        }
+
+        pub fn transfer(env: Env, from: Address, to: Address, amount: i128) {
+            Self::mint(env.clone(), from, -amount);
+            Self::mint(env, to, amount);
+        }
+    }
+}
+use scaled_token::{ScaledToken, ScaledTokenClient};
+
+/// Flash loan receiver that repays the loan plus fee, less a configurable
+/// shortfall, out of its own balance
+mod flash_receiver {
+    use soroban_sdk::{contract, contractimpl, symbol_short, token, Address, Bytes, Env};
+
+    #[contract]
+    pub struct FlashReceiver;
+
+    #[contractimpl]
+    impl FlashReceiver {
+        pub fn init(env: Env, amm: Address, shortfall: i128) {
+            env.storage()
+                .instance()
+                .set(&symbol_short!("config"), &(amm, shortfall));
+        }
+
+        pub fn on_flash_loan(
+            env: Env,
+            _initiator: Address,
+            token: Address,
+            amount: i128,
+            fee: i128,
+            _data: Bytes,
+        ) {
+            let (amm, shortfall): (Address, i128) =
+                env.storage().instance().get(&symbol_short!("config")).unwrap();
+            token::Client::new(&env, &token).transfer(
+                &env.current_contract_address(),
+                &amm,
+                &(amount + fee - shortfall),
+            );
+        }
+    }
+}
+use flash_receiver::{FlashReceiver, FlashReceiverClient};
+
 fn create_token_contract<'a>(env: &Env, admin: &Address) -> (TokenClient<'a>, StellarAssetClient<'a>) {
     let contract_address = env.register_stellar_asset_contract_v2(admin.clone());
     (
@@ -70,7 +182,7 @@ fn test_initialize() {
 
 #[test]
 fn test_mint_identical_addresses() {
-    let (env, amm_client, _, user, user_token, _, user_token_admin, _) = setup_test_env();
+    let (_env, amm_client, _, user, user_token, _, user_token_admin, _) = setup_test_env();
 
     // Mint tokens to user
     user_token_admin.mint(&user, &10000);
@@ -83,6 +195,7 @@ fn test_mint_identical_addresses() {
         &1000,
         &1000,
         &user,
+        &0,
     );
 
     assert_eq!(result, Err(Ok(Error::IdenticalAddresses)));
@@ -90,7 +203,7 @@ fn test_mint_identical_addresses() {
 
 #[test]
 fn test_mint_first_deposit() {
-    let (env, amm_client, _, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+    let (_env, amm_client, _, user, user_token, validator_token, user_token_admin, validator_token_admin) =
         setup_test_env();
 
     // Mint tokens to user
@@ -105,6 +218,7 @@ fn test_mint_first_deposit() {
         &10_000,
         &10_000,
         &user,
+        &0,
     );
 
     // Expected: (10000 + 10000) / 2 - 1000 = 9000
@@ -145,6 +259,7 @@ fn test_mint_subsequent_deposit() {
         &10_000,
         &10_000,
         &user,
+        &0,
     );
 
     // Second deposit (proportional)
@@ -155,6 +270,7 @@ fn test_mint_subsequent_deposit() {
         &5_000,
         &5_000,
         &user2,
+        &0,
     );
 
     // Expected: 5000 * 10000 / 10000 = 5000
@@ -168,7 +284,7 @@ fn test_mint_subsequent_deposit() {
 
 #[test]
 fn test_mint_with_validator_token_first_deposit() {
-    let (env, amm_client, _, user, user_token, validator_token, _, validator_token_admin) =
+    let (_env, amm_client, _, user, user_token, validator_token, _, validator_token_admin) =
         setup_test_env();
 
     // Mint validator tokens to user
@@ -181,6 +297,8 @@ fn test_mint_with_validator_token_first_deposit() {
         &validator_token.address,
         &10_000,
         &user,
+        &0,
+        &i128::MAX,
     );
 
     // Expected: (10000 / 2) - 1000 = 4000
@@ -192,9 +310,117 @@ fn test_mint_with_validator_token_first_deposit() {
     assert_eq!(pool.reserve_validator_token, 10_000);
 }
 
+#[test]
+fn test_mint_with_validator_token_min_liquidity() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    // Quote: 10_000 * 100_000 / (100_000 + 9985 * 100_000 / 10_000) = 5003
+    let quoted = 10_000 * 100_000 / (100_000 + 9_985 * 100_000 / 10_000);
+    assert_eq!(quoted, 5_003);
+
+    // A fee swap settles before the deposit lands, shifting the reserve ratio
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &50_000);
+    amm_client.execute_pending_fee_swaps(&user_token.address, &validator_token.address);
+
+    let result = amm_client.try_mint_with_validator_token(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &user,
+        &quoted,
+        &i128::MAX,
+    );
+    assert_eq!(result, Err(Ok(Error::SlippageExceeded)));
+
+    // Accepting the new rate succeeds
+    let liquidity = amm_client.mint_with_validator_token(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &user,
+        &(quoted - 10),
+        &i128::MAX,
+    );
+    assert!(liquidity < quoted);
+}
+
+#[test]
+fn test_mint_with_validator_token_max_implied_price() {
+    let (env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    // Depositor expects a price near 1.0 and tolerates 1% drift
+    let max_price = crate::PRICE_SCALE * 101 / 100;
+
+    // A front-runner pulls user tokens out, pushing validator per user up
+    let attacker = Address::generate(&env);
+    validator_token_admin.mint(&attacker, &100_000);
+    amm_client.rebalance_swap(
+        &attacker,
+        &user_token.address,
+        &validator_token.address,
+        &20_000,
+        &i128::MAX,
+        &attacker,
+    );
+    let spot = amm_client
+        .get_pool_info(&user_token.address, &validator_token.address)
+        .spot_price;
+    assert!(spot > max_price);
+
+    let result = amm_client.try_mint_with_validator_token(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &user,
+        &0,
+        &max_price,
+    );
+    assert_eq!(result, Err(Ok(Error::SlippageExceeded)));
+
+    // A bound that covers the current price lets the deposit through
+    amm_client.mint_with_validator_token(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &user,
+        &0,
+        &spot,
+    );
+}
+
 #[test]
 fn test_burn() {
-    let (env, amm_client, _, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+    let (_env, amm_client, _, user, user_token, validator_token, user_token_admin, validator_token_admin) =
         setup_test_env();
 
     // Mint tokens and add liquidity
@@ -208,6 +434,7 @@ fn test_burn() {
         &10_000,
         &10_000,
         &user,
+        &0,
     );
 
     // Burn half the liquidity
@@ -218,6 +445,8 @@ fn test_burn() {
         &validator_token.address,
         &burn_amount,
         &user,
+        &0,
+        &0,
     );
 
     // Should receive proportional amounts
@@ -232,7 +461,7 @@ fn test_burn() {
 
 #[test]
 fn test_burn_identical_addresses() {
-    let (env, amm_client, _, user, user_token, _, _, _) = setup_test_env();
+    let (_env, amm_client, _, user, user_token, _, _, _) = setup_test_env();
 
     let result = amm_client.try_burn(
         &user,
@@ -240,6 +469,8 @@ fn test_burn_identical_addresses() {
         &user_token.address,
         &1000,
         &user,
+        &0,
+        &0,
     );
 
     assert_eq!(result, Err(Ok(Error::IdenticalAddresses)));
@@ -247,7 +478,7 @@ fn test_burn_identical_addresses() {
 
 #[test]
 fn test_burn_insufficient_balance() {
-    let (env, amm_client, _, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+    let (_env, amm_client, _, user, user_token, validator_token, user_token_admin, validator_token_admin) =
         setup_test_env();
 
     // Mint tokens and add liquidity
@@ -261,6 +492,7 @@ fn test_burn_insufficient_balance() {
         &10_000,
         &10_000,
         &user,
+        &0,
     );
 
     // Try to burn more than balance
@@ -270,16 +502,96 @@ fn test_burn_insufficient_balance() {
         &validator_token.address,
         &(liquidity + 1),
         &user,
+        &0,
+        &0,
     );
 
     assert_eq!(result, Err(Ok(Error::InsufficientLiquidity)));
 }
 
 #[test]
-fn test_reserve_and_execute_fee_swap() {
+fn test_last_lp_exit_recovers_residual() {
     let (env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
         setup_test_env();
 
+    amm_client.set_last_exit_refund(&admin, &true);
+    assert!(amm_client.last_exit_refund());
+
+    user_token_admin.mint(&user, &20_000);
+    validator_token_admin.mint(&user, &20_000);
+
+    // Sole LP: 9000 shares, 1000 locked
+    let liquidity = amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &0,
+    );
+    assert_eq!(liquidity, 9_000);
+
+    // Burning every share returns the residual too, less one unit of dust
+    let (amount_user, amount_validator) = amm_client.burn(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &liquidity,
+        &user,
+        &0,
+        &0,
+    );
+    assert_eq!(amount_user, 9_999);
+    assert_eq!(amount_validator, 9_999);
+    assert_eq!(amm_client.get_total_supply(&user_token.address, &validator_token.address), 0);
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(pool.reserve_user_token, 0);
+    assert_eq!(pool.reserve_validator_token, 0);
+
+    // The pool starts over with a fresh first deposit
+    let liquidity = amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &0,
+    );
+    assert_eq!(liquidity, 9_000);
+
+    // With another LP still in, a full exit is an ordinary pro-rata burn
+    let other = Address::generate(&env);
+    user_token_admin.mint(&other, &1_000);
+    validator_token_admin.mint(&other, &1_000);
+    amm_client.mint(
+        &other,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &1_000,
+        &other,
+        &0,
+    );
+    let (amount_user, _) = amm_client.burn(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &liquidity,
+        &user,
+        &0,
+        &0,
+    );
+    assert_eq!(amount_user, 9_000);
+    assert_eq!(amm_client.get_total_supply(&user_token.address, &validator_token.address), 2_000);
+}
+
+#[test]
+fn test_reserve_and_execute_fee_swap() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
     // Setup pool with liquidity
     user_token_admin.mint(&user, &1_000_000);
     validator_token_admin.mint(&user, &1_000_000);
@@ -291,6 +603,7 @@ fn test_reserve_and_execute_fee_swap() {
         &100_000,
         &100_000,
         &user,
+        &0,
     );
 
     // Reserve liquidity for fee swap
@@ -318,10 +631,61 @@ fn test_reserve_and_execute_fee_swap() {
 }
 
 #[test]
-fn test_reserve_liquidity_insufficient() {
+fn test_execute_pending_fee_swaps_batch() {
     let (env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
         setup_test_env();
 
+    let (other_user_token, other_user_token_admin) = create_token_contract(&env, &admin);
+    let (idle_user_token, idle_user_token_admin) = create_token_contract(&env, &admin);
+    user_token_admin.mint(&user, &1_000_000);
+    other_user_token_admin.mint(&user, &1_000_000);
+    idle_user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+
+    for token in [&user_token, &other_user_token, &idle_user_token] {
+        amm_client.mint(
+            &user,
+            &token.address,
+            &validator_token.address,
+            &100_000,
+            &100_000,
+            &user,
+            &0,
+        );
+    }
+
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &10_000);
+    amm_client.reserve_liquidity(&other_user_token.address, &validator_token.address, &20_000);
+
+    let outputs = amm_client.execute_pending_fee_swaps_batch(
+        &admin,
+        &vec![
+            &env,
+            (user_token.address.clone(), validator_token.address.clone()),
+            (idle_user_token.address.clone(), validator_token.address.clone()),
+            (other_user_token.address.clone(), validator_token.address.clone()),
+        ],
+    );
+    // The idle pool has nothing pending and is skipped
+    assert_eq!(outputs, vec![&env, 9_970, 0, 19_940]);
+
+    for (token, swapped) in [(&user_token, 10_000), (&other_user_token, 20_000)] {
+        assert_eq!(amm_client.get_pending_fee_swap(&token.address, &validator_token.address), 0);
+        let pool = amm_client.get_pool(&token.address, &validator_token.address);
+        assert_eq!(pool.reserve_user_token, 100_000 + swapped);
+    }
+    let idle = amm_client.get_pool(&idle_user_token.address, &validator_token.address);
+    assert_eq!(idle.reserve_validator_token, 100_000);
+
+    let result = amm_client.try_execute_pending_fee_swaps_batch(&user, &vec![&env]);
+    assert_eq!(result, Err(Ok(Error::Unauthorized)));
+}
+
+#[test]
+fn test_reserve_liquidity_insufficient() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
     // Setup pool with small liquidity (need > 1000 for MIN_LIQUIDITY)
     user_token_admin.mint(&user, &10_000);
     validator_token_admin.mint(&user, &10_000);
@@ -334,6 +698,7 @@ fn test_reserve_liquidity_insufficient() {
         &5_000,
         &5_000,
         &user,
+        &0,
     );
 
     // Pool has 5000 validator tokens
@@ -349,7 +714,7 @@ fn test_reserve_liquidity_insufficient() {
 
 #[test]
 fn test_release_liquidity() {
-    let (env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
         setup_test_env();
 
     // Setup pool
@@ -363,6 +728,7 @@ fn test_release_liquidity() {
         &100_000,
         &100_000,
         &user,
+        &0,
     );
 
     // Reserve then release
@@ -375,7 +741,7 @@ fn test_release_liquidity() {
 
 #[test]
 fn test_rebalance_swap() {
-    let (env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
         setup_test_env();
 
     // Setup pool
@@ -389,6 +755,7 @@ fn test_rebalance_swap() {
         &100_000,
         &100_000,
         &user,
+        &0,
     );
 
     let pool_before = amm_client.get_pool(&user_token.address, &validator_token.address);
@@ -400,6 +767,7 @@ fn test_rebalance_swap() {
         &user_token.address,
         &validator_token.address,
         &amount_out,
+        &i128::MAX,
         &user,
     );
 
@@ -414,7 +782,7 @@ fn test_rebalance_swap() {
 
 #[test]
 fn test_rebalance_swap_insufficient_reserves() {
-    let (env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
         setup_test_env();
 
     // Setup pool with small reserves
@@ -428,6 +796,7 @@ fn test_rebalance_swap_insufficient_reserves() {
         &5_000,
         &5_000,
         &user,
+        &0,
     );
 
     // Try to swap more than available
@@ -436,6 +805,7 @@ fn test_rebalance_swap_insufficient_reserves() {
         &user_token.address,
         &validator_token.address,
         &10_000,
+        &i128::MAX,
         &user,
     );
 
@@ -443,33 +813,64 @@ fn test_rebalance_swap_insufficient_reserves() {
 }
 
 #[test]
-fn test_calculate_fee_swap_output() {
-    // Test the pure calculation function
-    let amount_in = 10_000i128;
-    let result = TempoFeeAMM::calculate_fee_swap_output(amount_in);
+fn test_rebalance_swap_max_amount_in() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
 
-    // Expected: 10000 * 9970 / 10000 = 9970
-    assert_eq!(result, Ok(9970));
-}
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
 
-#[test]
-fn test_calculate_rebalance_input() {
-    // Test the pure calculation function
-    let amount_out = 10_000i128;
-    let result = TempoFeeAMM::calculate_rebalance_input(amount_out);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
 
-    // Expected: 10000 * 9985 / 10000 + 1 = 9986
-    assert_eq!(result, Ok(9986));
+    let pool_before = amm_client.get_pool(&user_token.address, &validator_token.address);
+    let user_before = user_token.balance(&user);
+    let validator_before = validator_token.balance(&user);
+
+    // 10_000 out requires 9986 in
+    let result = amm_client.try_rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &9985,
+        &user,
+    );
+    assert_eq!(result, Err(Ok(Error::SlippageExceeded)));
+
+    let pool_after = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(pool_after.reserve_user_token, pool_before.reserve_user_token);
+    assert_eq!(pool_after.reserve_validator_token, pool_before.reserve_validator_token);
+    assert_eq!(user_token.balance(&user), user_before);
+    assert_eq!(validator_token.balance(&user), validator_before);
+
+    let amount_in = amm_client.rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &9986,
+        &user,
+    );
+    assert_eq!(amount_in, 9986);
 }
 
 #[test]
-fn test_multiple_fee_swaps() {
+fn test_rebalance_swap_whitelist() {
     let (env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
         setup_test_env();
 
-    // Setup pool
+    let outsider = Address::generate(&env);
     user_token_admin.mint(&user, &1_000_000);
     validator_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&outsider, &1_000_000);
 
     amm_client.mint(
         &user,
@@ -478,59 +879,1929 @@ fn test_multiple_fee_swaps() {
         &100_000,
         &100_000,
         &user,
+        &0,
     );
 
-    // Multiple reservations
-    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &1_000);
-    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &2_000);
-    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &3_000);
+    // Empty whitelist: anyone may rebalance
+    assert_eq!(amm_client.rebalance_whitelist().len(), 0);
+    amm_client.rebalance_swap(
+        &outsider,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &i128::MAX,
+        &outsider,
+    );
 
-    // Check total pending
-    let pending = amm_client.get_pending_fee_swap(&user_token.address, &validator_token.address);
-    assert_eq!(pending, 6_000);
+    amm_client.add_rebalancer(&admin, &user);
+    assert_eq!(amm_client.rebalance_whitelist(), vec![&env, user.clone()]);
 
-    // Execute all at once
-    let total_out = amm_client.execute_pending_fee_swaps(&user_token.address, &validator_token.address);
+    let result = amm_client.try_rebalance_swap(
+        &outsider,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &i128::MAX,
+        &outsider,
+    );
+    assert_eq!(result, Err(Ok(Error::Unauthorized)));
 
-    // Expected: 6000 * 9970 / 10000 = 5982
-    assert_eq!(total_out, 5982);
+    let amount_in = amm_client.rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &i128::MAX,
+        &user,
+    );
+    assert_eq!(amount_in, 999);
 
-    // Verify pending cleared
-    let pending_after = amm_client.get_pending_fee_swap(&user_token.address, &validator_token.address);
-    assert_eq!(pending_after, 0);
+    // Only the admin manages the whitelist
+    let result = amm_client.try_add_rebalancer(&outsider, &outsider);
+    assert_eq!(result, Err(Ok(Error::Unauthorized)));
+
+    // Emptying the whitelist restores permissionless rebalancing
+    amm_client.remove_rebalancer(&admin, &user);
+    assert_eq!(amm_client.rebalance_whitelist().len(), 0);
+    amm_client.rebalance_swap(
+        &outsider,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &i128::MAX,
+        &outsider,
+    );
 }
 
 #[test]
-fn test_burn_blocked_by_pending_swaps() {
+fn test_swap_exact_in_validator_side_respects_whitelist() {
     let (env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
         setup_test_env();
 
-    // Setup pool
+    let outsider = Address::generate(&env);
     user_token_admin.mint(&user, &1_000_000);
+    user_token_admin.mint(&outsider, &1_000_000);
     validator_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&outsider, &1_000_000);
 
-    let liquidity = amm_client.mint(
+    amm_client.mint(
         &user,
         &user_token.address,
         &validator_token.address,
-        &10_000,
-        &10_000,
+        &100_000,
+        &100_000,
         &user,
+        &0,
     );
+    amm_client.add_rebalancer(&admin, &user);
 
-    // Reserve most of the validator tokens
-    // Pool has 10000 validator tokens, reserve 9500 worth of swaps
-    // 9500 * 0.997 = 9471.5 out needed
-    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &9_500);
+    // Selling validator tokens for user tokens is a rebalance
+    let result = amm_client.try_swap_exact_in(
+        &outsider,
+        &validator_token.address,
+        &user_token.address,
+        &1_000,
+        &0,
+        &outsider,
+    );
+    assert_eq!(result, Err(Ok(Error::Unauthorized)));
+    assert_eq!(validator_token.balance(&outsider), 1_000_000);
+
+    let amount_out = amm_client.swap_exact_in(
+        &user,
+        &validator_token.address,
+        &user_token.address,
+        &1_000,
+        &0,
+        &user,
+    );
+    assert!(amount_out > 0);
+
+    // The fee swap direction stays open to everyone
+    let amount_out = amm_client.swap_exact_in(
+        &outsider,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &0,
+        &outsider,
+    );
+    assert!(amount_out > 0);
+}
+
+#[test]
+fn test_uninitialized_pool_rejected() {
+    let (env, amm_client, admin, user, user_token, validator_token, _user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    validator_token_admin.mint(&user, &1_000_000);
+
+    assert!(!amm_client.pool_exists(&user_token.address, &validator_token.address));
 
-    // Try to burn all liquidity - should fail because validator tokens are reserved
     let result = amm_client.try_burn(
         &user,
         &user_token.address,
         &validator_token.address,
-        &liquidity,
+        &1_000,
         &user,
+        &0,
+        &0,
     );
+    assert_eq!(result, Err(Ok(Error::PoolNotInitialized)));
 
-    assert_eq!(result, Err(Ok(Error::InsufficientReserves)));
+    let result = amm_client.try_rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &i128::MAX,
+        &user,
+    );
+    assert_eq!(result, Err(Ok(Error::PoolNotInitialized)));
+
+    let result =
+        amm_client.try_execute_pending_fee_swaps(&user_token.address, &validator_token.address);
+    assert_eq!(result, Err(Ok(Error::PoolNotInitialized)));
+
+    // Nothing can be reserved against, or settled on, a pool that was never created
+    let result =
+        amm_client.try_reserve_liquidity(&user_token.address, &validator_token.address, &1_000);
+    assert_eq!(result, Err(Ok(Error::PoolNotInitialized)));
+    let result = amm_client.try_reserve_liquidity_capped(
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+    );
+    assert_eq!(result, Err(Ok(Error::PoolNotInitialized)));
+    assert_eq!(
+        amm_client.get_pending_fee_swap(&user_token.address, &validator_token.address),
+        0
+    );
+    let result = amm_client.try_execute_pending_fee_swaps_batch(
+        &admin,
+        &vec![&env, (user_token.address.clone(), validator_token.address.clone())],
+    );
+    assert_eq!(result, Err(Ok(Error::PoolNotInitialized)));
+
+    // A pool created with only validator tokens exists despite its zero user reserve
+    amm_client.mint_with_validator_token(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &user,
+        &0,
+        &i128::MAX,
+    );
+    assert!(amm_client.pool_exists(&user_token.address, &validator_token.address));
+    assert!(!amm_client.pool_exists(&validator_token.address, &user_token.address));
+    assert_eq!(
+        amm_client.execute_pending_fee_swaps(&user_token.address, &validator_token.address),
+        0
+    );
+}
+
+#[test]
+fn test_calculate_fee_swap_output() {
+    // Test the pure calculation function
+    let amount_in = 10_000i128;
+    let result = TempoFeeAMM::calculate_fee_swap_output(amount_in);
+
+    // Expected: 10000 * 9970 / 10000 = 9970
+    assert_eq!(result, Ok(9970));
+}
+
+#[test]
+fn test_calculate_rebalance_input() {
+    // Test the pure calculation function
+    let amount_out = 10_000i128;
+    let result = TempoFeeAMM::calculate_rebalance_input(amount_out);
+
+    // Expected: 10000 * 9985 / 10000 + 1 = 9986
+    assert_eq!(result, Ok(9986));
+}
+
+#[test]
+fn test_calculate_rebalance_output_round_trip() {
+    // (9986 * 10000 - 1) / 9985 = 10001, which also costs 9986
+    assert_eq!(TempoFeeAMM::calculate_rebalance_output(9986), Ok(10_001));
+
+    for amount in [1i128, 2, 7, 999, 1_000, 9_985, 9_986, 123_457, 10_000_000] {
+        // The largest output an input buys costs exactly that input
+        let amount_out = TempoFeeAMM::calculate_rebalance_output(amount).unwrap();
+        assert_eq!(TempoFeeAMM::calculate_rebalance_input(amount_out), Ok(amount));
+
+        // The input an output costs buys it back, plus at most the +1 rounding
+        let amount_in = TempoFeeAMM::calculate_rebalance_input(amount).unwrap();
+        let round_trip = TempoFeeAMM::calculate_rebalance_output(amount_in).unwrap();
+        assert!(round_trip >= amount && round_trip <= amount + 1);
+    }
+}
+
+#[test]
+fn test_rebalance_swap_exact_in() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    let amount_out = amm_client.rebalance_swap_exact_in(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &9986,
+        &0,
+        &user,
+    );
+    assert_eq!(amount_out, 10_001);
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(pool.reserve_user_token, 89_999);
+    assert_eq!(pool.reserve_validator_token, 109_986);
+
+    // Output is capped at the reserve above the 1000 floor: 88_999 costs 88_866
+    let result = amm_client.try_rebalance_swap_exact_in(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &200_000,
+        &89_000,
+        &user,
+    );
+    assert_eq!(result, Err(Ok(Error::SlippageExceeded)));
+
+    // The quote applies the same cap, so its amount executes as quoted
+    let (quoted, _) = amm_client.get_amount_out_detailed(
+        &user_token.address,
+        &validator_token.address,
+        &false,
+        &200_000,
+    );
+    assert_eq!(quoted, 88_999);
+
+    let validator_before = validator_token.balance(&user);
+    let amount_out = amm_client.rebalance_swap_exact_in(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &200_000,
+        &quoted,
+        &user,
+    );
+    assert_eq!(amount_out, quoted);
+    assert_eq!(validator_token.balance(&user), validator_before - 88_866);
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(pool.reserve_user_token, 1_000);
+
+    let result = amm_client.try_rebalance_swap_exact_in(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &0,
+        &user,
+    );
+    assert_eq!(result, Err(Ok(Error::InsufficientReserves)));
+    let result = amm_client.try_get_amount_out_detailed(
+        &user_token.address,
+        &validator_token.address,
+        &false,
+        &1_000,
+    );
+    assert_eq!(result, Err(Ok(Error::InsufficientReserves)));
+}
+
+#[test]
+fn test_multiple_fee_swaps() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    // Setup pool
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    // Multiple reservations
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &1_000);
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &2_000);
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &3_000);
+
+    // Check total pending
+    let pending = amm_client.get_pending_fee_swap(&user_token.address, &validator_token.address);
+    assert_eq!(pending, 6_000);
+
+    // Execute all at once
+    let total_out = amm_client.execute_pending_fee_swaps(&user_token.address, &validator_token.address);
+
+    // Expected: 6000 * 9970 / 10000 = 5982
+    assert_eq!(total_out, 5982);
+
+    // Verify pending cleared
+    let pending_after = amm_client.get_pending_fee_swap(&user_token.address, &validator_token.address);
+    assert_eq!(pending_after, 0);
+}
+
+#[test]
+fn test_burn_blocked_by_pending_swaps() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    // Setup pool
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+
+    let liquidity = amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &0,
+    );
+
+    // Reserve most of the validator tokens
+    // Pool has 10000 validator tokens, reserve 8500 worth of swaps
+    // 8500 * 0.997 = 8474.5 out needed
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &8_500);
+
+    // Try to burn all liquidity - should fail because validator tokens are reserved
+    let result = amm_client.try_burn(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &liquidity,
+        &user,
+        &0,
+        &0,
+    );
+
+    assert_eq!(result, Err(Ok(Error::InsufficientReserves)));
+}
+
+#[test]
+fn test_burn_max_partial_when_swaps_pending() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+
+    let liquidity = amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &0,
+    );
+
+    // 8500 * 0.997 = 8474 validator tokens reserved, leaving 1526 withdrawable
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &8_500);
+
+    let (burned, amount_user, amount_validator) =
+        amm_client.burn_max(&user, &user_token.address, &validator_token.address, &user);
+
+    assert_eq!(burned, 1_526);
+    assert_eq!(amount_user, 1_526);
+    assert_eq!(amount_validator, 1_526);
+    assert_eq!(
+        amm_client.get_lp_balance(&user_token.address, &validator_token.address, &user),
+        liquidity - burned
+    );
+
+    // Nothing unreserved is left, so a further burn is blocked
+    let result = amm_client.try_burn_max(&user, &user_token.address, &validator_token.address, &user);
+    assert_eq!(result, Err(Ok(Error::InsufficientReserves)));
+}
+
+#[test]
+fn test_rebalance_swap_blocks_reentrant_token() {
+    let (env, amm_client, _admin, user, _user_token, validator_token, _, validator_token_admin) =
+        setup_test_env();
+
+    let evil_token = env.register(ReentrantToken, ());
+    let evil_client = ReentrantTokenClient::new(&env, &evil_token);
+
+    validator_token_admin.mint(&user, &1_000_000);
+
+    amm_client.mint(
+        &user,
+        &evil_token,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    evil_client.arm(&amm_client.address, &validator_token.address, &user);
+
+    // Paying out the user-token leg calls into the evil token, which tries to
+    // re-enter; the host refuses the call before the AMM's lock is consulted
+    let amount_in = amm_client.rebalance_swap(
+        &user,
+        &evil_token,
+        &validator_token.address,
+        &1_000,
+        &i128::MAX,
+        &user,
+    );
+
+    assert_eq!(evil_client.reentered(), Some(false));
+
+    // Reserves reflect exactly one swap
+    let pool = amm_client.get_pool(&evil_token, &validator_token.address);
+    assert_eq!(pool.reserve_user_token, 100_000 - 1_000);
+    assert_eq!(pool.reserve_validator_token, 100_000 + amount_in);
+}
+
+#[test]
+fn test_reentrancy_lock_rejects_calls_while_held() {
+    let (env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &1_000);
+
+    // Hold the lock as an outer reserve-mutating call would
+    env.as_contract(&amm_client.address, || storage::enter_lock(&env).unwrap());
+
+    let result = amm_client.try_mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &1_000,
+        &user,
+        &0,
+    );
+    assert_eq!(result, Err(Ok(Error::Reentrancy)));
+
+    let result = amm_client.try_burn(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &user,
+        &0,
+        &0,
+    );
+    assert_eq!(result, Err(Ok(Error::Reentrancy)));
+
+    let result = amm_client.try_rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &i128::MAX,
+        &user,
+    );
+    assert_eq!(result, Err(Ok(Error::Reentrancy)));
+
+    let result = amm_client.try_swap_exact_in(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &0,
+        &user,
+    );
+    assert_eq!(result, Err(Ok(Error::Reentrancy)));
+
+    let result =
+        amm_client.try_execute_pending_fee_swaps(&user_token.address, &validator_token.address);
+    assert_eq!(result, Err(Ok(Error::Reentrancy)));
+
+    let result = amm_client.try_sweep_reserve_dust(&admin, &user_token.address, &validator_token.address);
+    assert_eq!(result, Err(Ok(Error::Reentrancy)));
+
+    // Nothing moved while locked, and calls go through once it is released
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(pool.reserve_user_token, 100_000);
+    assert_eq!(pool.reserve_validator_token, 100_000);
+
+    env.as_contract(&amm_client.address, || storage::exit_lock(&env));
+    amm_client.rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &i128::MAX,
+        &user,
+    );
+}
+
+#[test]
+fn test_reserve_liquidity_capped() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &10_000);
+    validator_token_admin.mint(&user, &10_000);
+
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &0,
+    );
+
+    // 1000 of the 10000 validator reserve back the locked MIN_LIQUIDITY, and
+    // 9028 * 0.997 = 9000 is the most the rest can back
+    let reserved = amm_client.reserve_liquidity_capped(
+        &user_token.address,
+        &validator_token.address,
+        &50_000,
+    );
+    assert_eq!(reserved, 9_028);
+    assert_eq!(
+        amm_client.get_pending_fee_swap(&user_token.address, &validator_token.address),
+        9_028
+    );
+
+    // The uncapped variant now rejects any further reservation
+    let result = amm_client.try_reserve_liquidity(&user_token.address, &validator_token.address, &1);
+    assert_eq!(result, Err(Ok(Error::InsufficientLiquidity)));
+
+    // The capped variant reserves nothing rather than erroring
+    let reserved = amm_client.reserve_liquidity_capped(
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+    );
+    assert_eq!(reserved, 0);
+}
+
+#[test]
+fn test_get_pool_info_matches_getters() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &50_000,
+        &100_000,
+        &user,
+        &0,
+    );
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &10_000);
+
+    let info = amm_client.get_pool_info(&user_token.address, &validator_token.address);
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    let pending = amm_client.get_pending_fee_swap(&user_token.address, &validator_token.address);
+
+    assert_eq!(info.reserve_user_token, pool.reserve_user_token);
+    assert_eq!(info.reserve_validator_token, pool.reserve_validator_token);
+    assert_eq!(
+        info.total_supply,
+        amm_client.get_total_supply(&user_token.address, &validator_token.address)
+    );
+    assert_eq!(info.pending_fee_swap, pending);
+    assert_eq!(info.effective_reserve_user, pool.reserve_user_token + pending);
+    assert_eq!(
+        info.effective_reserve_validator,
+        pool.reserve_validator_token - amm_client.calculate_fee_swap_output(&pending)
+    );
+    // 100_000 validator per 50_000 user = 2.0
+    assert_eq!(info.spot_price, 2 * crate::PRICE_SCALE);
+}
+
+#[test]
+fn test_get_effective_price_includes_fee() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &10_000_000);
+    validator_token_admin.mint(&user, &10_000_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1_000_000,
+        &1_000_000,
+        &user,
+        &0,
+    );
+
+    // Balanced pool: the mid is exactly 1.0
+    let mid = amm_client
+        .get_pool_info(&user_token.address, &validator_token.address)
+        .spot_price;
+    assert_eq!(mid, crate::PRICE_SCALE);
+
+    // Buying validator tokens with 10_000 user tokens yields 9_970
+    let price = amm_client.get_effective_price(
+        &user_token.address,
+        &validator_token.address,
+        &true,
+        &10_000,
+    );
+    assert_eq!(price, 10_000 * crate::PRICE_SCALE / 9_970);
+    // Above the mid by the fee factor SCALE / M
+    assert!(price > mid);
+    assert_eq!(price, mid * crate::SCALE / crate::M);
+
+    // Rebalance direction is priced at what rebalance_swap_exact_in pays
+    let amount_in = amm_client.calculate_rebalance_input(&10_000);
+    let rebalance_price = amm_client.get_effective_price(
+        &user_token.address,
+        &validator_token.address,
+        &false,
+        &amount_in,
+    );
+    let amount_out = amm_client.calculate_rebalance_output(&amount_in);
+    assert_eq!(rebalance_price, amount_in * crate::PRICE_SCALE / amount_out);
+
+    // More than the pool can pay out is rejected
+    let result = amm_client.try_get_effective_price(
+        &user_token.address,
+        &validator_token.address,
+        &true,
+        &2_000_000,
+    );
+    assert_eq!(result, Err(Ok(Error::InsufficientLiquidity)));
+}
+
+#[test]
+fn test_get_imbalance() {
+    let (env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+
+    // Single-sided deposit: validator tokens only, so the validator side is heavy
+    amm_client.mint_with_validator_token(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &user,
+        &0,
+        &i128::MAX,
+    );
+    assert_eq!(
+        amm_client.get_imbalance(&user_token.address, &validator_token.address),
+        10_000
+    );
+
+    // A user-heavy pool reports a negative imbalance
+    let (other_user_token, other_user_token_admin) = create_token_contract(&env, &admin);
+    other_user_token_admin.mint(&user, &1_000_000);
+    amm_client.mint(
+        &user,
+        &other_user_token.address,
+        &validator_token.address,
+        &30_000,
+        &10_000,
+        &user,
+        &0,
+    );
+    assert_eq!(
+        amm_client.get_imbalance(&other_user_token.address, &validator_token.address),
+        -20_000
+    );
+}
+
+#[test]
+fn test_get_k() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    assert_eq!(amm_client.get_k(&user_token.address, &validator_token.address), 0);
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+    assert_eq!(amm_client.get_k(&user_token.address, &validator_token.address), 10_000_000_000);
+
+    // Rebalance swaps trade at a fixed rate, so k tracks the new reserves
+    // rather than staying constant
+    let amount_in = amm_client.rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &i128::MAX,
+        &user,
+    );
+    assert_eq!(
+        amm_client.get_k(&user_token.address, &validator_token.address),
+        90_000 * (100_000 + amount_in)
+    );
+}
+
+#[test]
+fn test_get_k_overflow() {
+    let (_env, amm_client, _admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    let huge = 20_000_000_000_000_000_000i128;
+    user_token_admin.mint(&user, &huge);
+    validator_token_admin.mint(&user, &huge);
+    amm_client.mint(&user, &user_token.address, &validator_token.address, &huge, &huge, &user, &0);
+
+    let result = amm_client.try_get_k(&user_token.address, &validator_token.address);
+    assert_eq!(result, Err(Ok(Error::Overflow)));
+}
+
+#[test]
+fn test_sweep_reserve_dust() {
+    let (_env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+    for _ in 0..3 {
+        amm_client.rebalance_swap(
+            &user,
+            &user_token.address,
+            &validator_token.address,
+            &333,
+            &i128::MAX,
+            &user,
+        );
+    }
+
+    // Stray tokens sent straight to the contract are the only unattributable surplus
+    user_token.transfer(&user, &amm_client.address, &37);
+    validator_token.transfer(&user, &amm_client.address, &5);
+
+    let pool_before = amm_client.get_pool(&user_token.address, &validator_token.address);
+    let swept = amm_client.sweep_reserve_dust(&admin, &user_token.address, &validator_token.address);
+    assert_eq!(swept, (37, 5));
+    assert_eq!(user_token.balance(&admin), 37);
+    assert_eq!(validator_token.balance(&admin), 5);
+
+    // Reserves are untouched and fully backed
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(pool.reserve_user_token, pool_before.reserve_user_token);
+    assert_eq!(pool.reserve_validator_token, pool_before.reserve_validator_token);
+    assert_eq!(user_token.balance(&amm_client.address), pool.reserve_user_token);
+    assert_eq!(validator_token.balance(&amm_client.address), pool.reserve_validator_token);
+
+    // Nothing left to sweep, and only the admin may sweep
+    assert_eq!(
+        amm_client.sweep_reserve_dust(&admin, &user_token.address, &validator_token.address),
+        (0, 0)
+    );
+    let result = amm_client.try_sweep_reserve_dust(&user, &user_token.address, &validator_token.address);
+    assert_eq!(result, Err(Ok(Error::Unauthorized)));
+}
+
+#[test]
+fn test_snapshot_pool() {
+    let (env, amm_client, admin, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &0,
+    );
+    env.ledger().set_timestamp(1_000);
+    let first = amm_client.snapshot_pool(&admin, &user_token.address, &validator_token.address);
+
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &5_000,
+        &5_000,
+        &user,
+        &0,
+    );
+    env.ledger().set_timestamp(2_000);
+    let second = amm_client.snapshot_pool(&admin, &user_token.address, &validator_token.address);
+
+    assert_eq!((first, second), (0, 1));
+
+    let snapshot = amm_client.get_snapshot(&user_token.address, &validator_token.address, &first);
+    assert_eq!(snapshot.timestamp, 1_000);
+    assert_eq!(snapshot.reserve_user_token, 10_000);
+    assert_eq!(snapshot.reserve_validator_token, 10_000);
+    assert_eq!(snapshot.total_supply, 10_000);
+
+    let snapshot = amm_client.get_snapshot(&user_token.address, &validator_token.address, &second);
+    assert_eq!(snapshot.timestamp, 2_000);
+    assert_eq!(snapshot.reserve_user_token, 15_000);
+    assert_eq!(snapshot.reserve_validator_token, 15_000);
+    assert_eq!(snapshot.total_supply, 15_000);
+
+    let result = amm_client.try_get_snapshot(&user_token.address, &validator_token.address, &2);
+    assert_eq!(result, Err(Ok(Error::SnapshotNotFound)));
+}
+
+#[test]
+fn test_snapshot_ring_buffer_evicts_oldest() {
+    let (_env, amm_client, admin, _user, user_token, validator_token, _, _) = setup_test_env();
+
+    for _ in 0..(crate::storage::MAX_SNAPSHOTS + 1) {
+        amm_client.snapshot_pool(&admin, &user_token.address, &validator_token.address);
+    }
+
+    let result = amm_client.try_get_snapshot(&user_token.address, &validator_token.address, &0);
+    assert_eq!(result, Err(Ok(Error::SnapshotNotFound)));
+
+    let latest = amm_client.get_snapshot(
+        &user_token.address,
+        &validator_token.address,
+        &crate::storage::MAX_SNAPSHOTS,
+    );
+    assert_eq!(latest.index, crate::storage::MAX_SNAPSHOTS);
+}
+
+#[test]
+fn test_get_utilization() {
+    let (
+        _env,
+        amm_client,
+        _admin,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    assert_eq!(
+        amm_client.get_utilization(&user_token.address, &validator_token.address),
+        0
+    );
+
+    user_token_admin.mint(&user, &1_000_000);
+    validator_token_admin.mint(&user, &1_000_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+    assert_eq!(
+        amm_client.get_utilization(&user_token.address, &validator_token.address),
+        0
+    );
+
+    // 20_000 pending pays out 20_000 * 0.997 = 19_940 of 100_000 validator tokens
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &20_000);
+    assert_eq!(
+        amm_client.get_utilization(&user_token.address, &validator_token.address),
+        1_994
+    );
+
+    // Settling the swaps frees the reserves again
+    amm_client.execute_pending_fee_swaps(&user_token.address, &validator_token.address);
+    assert_eq!(
+        amm_client.get_utilization(&user_token.address, &validator_token.address),
+        0
+    );
+}
+
+#[test]
+fn test_reserve_liquidity_keeps_min_liquidity_backed() {
+    let (
+        _env,
+        amm_client,
+        _admin,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+    assert_eq!(
+        amm_client.get_total_supply(&user_token.address, &validator_token.address),
+        100_000
+    );
+
+    // 1000 of the 100_000 validator reserve back the locked MIN_LIQUIDITY, so
+    // pending output may reach 99_000: 99_298 * 0.997 = 99_000
+    let result =
+        amm_client.try_reserve_liquidity(&user_token.address, &validator_token.address, &99_299);
+    assert_eq!(result, Err(Ok(Error::InsufficientLiquidity)));
+
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &99_298);
+    let result =
+        amm_client.try_reserve_liquidity(&user_token.address, &validator_token.address, &1);
+    assert_eq!(result, Err(Ok(Error::InsufficientLiquidity)));
+}
+
+#[test]
+fn test_rebalance_swap_keeps_min_reserve() {
+    let (
+        _env,
+        amm_client,
+        admin,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &1_000_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+    assert_eq!(amm_client.min_reserve(), 1_000);
+
+    // Draining all but 500 user tokens would cross the floor
+    let result = amm_client.try_rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &99_500,
+        &i128::MAX,
+        &user,
+    );
+    assert_eq!(result, Err(Ok(Error::InsufficientReserves)));
+
+    amm_client.rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &99_000,
+        &i128::MAX,
+        &user,
+    );
+    assert_eq!(
+        amm_client
+            .get_pool(&user_token.address, &validator_token.address)
+            .reserve_user_token,
+        1_000
+    );
+    let result = amm_client.try_rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1,
+        &i128::MAX,
+        &user,
+    );
+    assert_eq!(result, Err(Ok(Error::InsufficientReserves)));
+
+    // The floor is configurable
+    assert_eq!(
+        amm_client.try_set_min_reserve(&user, &0),
+        Err(Ok(Error::Unauthorized))
+    );
+    amm_client.set_min_reserve(&admin, &100);
+    amm_client.rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &900,
+        &i128::MAX,
+        &user,
+    );
+    assert_eq!(
+        amm_client
+            .get_pool(&user_token.address, &validator_token.address)
+            .reserve_user_token,
+        100
+    );
+}
+
+#[test]
+fn test_burn_min_outputs() {
+    let (
+        _env,
+        amm_client,
+        _admin,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &1_000_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    // Quoted at 10_000 of each token, but a rebalance swap pulls 20% of the
+    // user tokens out before the burn lands
+    amm_client.rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &20_000,
+        &i128::MAX,
+        &user,
+    );
+    let lp_before = amm_client.get_lp_balance(&user_token.address, &validator_token.address, &user);
+
+    let result = amm_client.try_burn(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &user,
+        &10_000,
+        &10_000,
+    );
+    assert_eq!(result, Err(Ok(Error::SlippageExceeded)));
+    assert_eq!(
+        amm_client.get_lp_balance(&user_token.address, &validator_token.address, &user),
+        lp_before
+    );
+
+    let (amount_user, amount_validator) = amm_client.burn(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &user,
+        &8_000,
+        &10_000,
+    );
+    assert_eq!(amount_user, 8_000);
+    assert!(amount_validator > 10_000);
+}
+
+#[test]
+fn test_burn_min_outputs_after_fee_swaps_settle() {
+    let (
+        env,
+        amm_client,
+        _admin,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    // The LP quotes a 10_000 share burn at 10_000 of each token...
+    assert_eq!(amm_client.get_total_supply(&user_token.address, &validator_token.address), 100_000);
+
+    // ...then pending fee swaps settle first, moving validator tokens out
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &20_000);
+    amm_client.execute_pending_fee_swaps(&user_token.address, &validator_token.address);
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(pool.reserve_user_token, 120_000);
+    assert_eq!(pool.reserve_validator_token, 80_060);
+
+    let lp_before = amm_client.get_lp_balance(&user_token.address, &validator_token.address, &user);
+    let result = amm_client.try_burn(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &user,
+        &10_000,
+        &10_000,
+    );
+    assert_eq!(result, Err(Ok(Error::SlippageExceeded)));
+    assert_eq!(
+        amm_client.get_lp_balance(&user_token.address, &validator_token.address, &user),
+        lp_before
+    );
+    assert_eq!(
+        amm_client.get_pool(&user_token.address, &validator_token.address).reserve_validator_token,
+        80_060
+    );
+
+    // Re-quoted floors pass, and the payout goes to the recipient
+    let recipient = Address::generate(&env);
+    let (amount_user, amount_validator) = amm_client.burn(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &recipient,
+        &12_000,
+        &8_006,
+    );
+    assert_eq!((amount_user, amount_validator), (12_000, 8_006));
+    assert_eq!(user_token.balance(&recipient), 12_000);
+    assert_eq!(validator_token.balance(&recipient), 8_006);
+    assert_eq!(validator_token.balance(&user), 0);
+}
+
+#[test]
+fn test_mixed_decimals_pool_normalizes_amounts() {
+    let env = Env::default();
+    env.mock_all_auths();
+
+    let admin = Address::generate(&env);
+    let amm_client = TempoFeeAMMClient::new(&env, &env.register(TempoFeeAMM, ()));
+    amm_client.initialize(&admin);
+
+    let user_token = ScaledTokenClient::new(&env, &env.register(ScaledToken, ()));
+    let validator_token = ScaledTokenClient::new(&env, &env.register(ScaledToken, ()));
+    user_token.set_decimals(&6);
+    validator_token.set_decimals(&18);
+
+    let user = Address::generate(&env);
+    let one_user: i128 = 1_000_000;
+    let one_validator: i128 = 1_000_000_000_000_000_000;
+    user_token.mint(&user, &(2_000 * one_user));
+    validator_token.mint(&user, &(2_000 * one_validator));
+
+    // 1000 of each is worth the same, so LP is issued as if both had 18 decimals
+    let liquidity = amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &(1_000 * one_user),
+        &(1_000 * one_validator),
+        &user,
+        &0,
+    );
+    assert_eq!(liquidity, 1_000 * one_validator - 1_000);
+
+    // A fee swap of one user token pays out 0.997 validator tokens
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &one_user);
+    assert_eq!(
+        amm_client.execute_pending_fee_swaps(&user_token.address, &validator_token.address),
+        one_validator * 9970 / 10000
+    );
+
+    // Buying one user token back costs 0.9985 validator tokens
+    assert_eq!(
+        amm_client.rebalance_swap(
+            &user,
+            &user_token.address,
+            &validator_token.address,
+            &one_user,
+            &i128::MAX,
+            &user
+        ),
+        one_validator * 9985 / 10000 + 1
+    );
+}
+
+#[test]
+fn test_get_amount_out_detailed_splits_fee() {
+    let (
+        _env,
+        amm_client,
+        _admin,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &10_000_000);
+    validator_token_admin.mint(&user, &10_000_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &1_000_000,
+        &1_000_000,
+        &user,
+        &0,
+    );
+
+    // Fee swaps keep (SCALE - M) of the input
+    let amount_in = 100_000;
+    let (amount_out, fee_amount) = amm_client.get_amount_out_detailed(
+        &user_token.address,
+        &validator_token.address,
+        &true,
+        &amount_in,
+    );
+    assert_eq!(
+        fee_amount,
+        amount_in * (crate::SCALE - crate::M) / crate::SCALE
+    );
+    assert_eq!(amount_out, amm_client.calculate_fee_swap_output(&amount_in));
+    assert_eq!(amount_out + fee_amount, amount_in);
+
+    // Rebalance swaps pay out more than they take in, so no input is kept
+    let (amount_out, fee_amount) = amm_client.get_amount_out_detailed(
+        &user_token.address,
+        &validator_token.address,
+        &false,
+        &10_000,
+    );
+    assert_eq!(fee_amount, 0);
+    assert!(amount_out > 10_000);
+    assert!(amm_client.calculate_rebalance_input(&amount_out) <= 10_000);
+
+    // The quote is exactly what rebalance_swap_exact_in pays for the same input
+    assert_eq!(amount_out, 10_015);
+    let paid = amm_client.rebalance_swap_exact_in(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &0,
+        &user,
+    );
+    assert_eq!(paid, amount_out);
+
+    // Buying that output exactly costs the whole input, none of it kept aside
+    let amount_in = amm_client.rebalance_swap(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &amount_out,
+        &i128::MAX,
+        &user,
+    );
+    assert_eq!(amount_in + fee_amount, 10_000);
+
+    assert_eq!(
+        amm_client.try_get_amount_out_detailed(
+            &user_token.address,
+            &validator_token.address,
+            &true,
+            &0
+        ),
+        Err(Ok(Error::InvalidAmount))
+    );
+}
+
+#[test]
+fn test_transfer_lp_moves_balance() {
+    let (
+        env,
+        amm_client,
+        _,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+    let liquidity = amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &0,
+    );
+
+    let recipient = Address::generate(&env);
+    amm_client.transfer_lp(
+        &user,
+        &recipient,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+    );
+    assert_eq!(
+        amm_client.get_lp_balance(&user_token.address, &validator_token.address, &user),
+        liquidity - 1_000
+    );
+    assert_eq!(
+        amm_client.get_lp_balance(&user_token.address, &validator_token.address, &recipient),
+        1_000
+    );
+
+    // The recipient can burn what it received
+    let (amount_user, amount_validator) = amm_client.burn(
+        &recipient,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &recipient,
+        &0,
+        &0,
+    );
+    assert_eq!((amount_user, amount_validator), (1_000, 1_000));
+}
+
+#[test]
+fn test_transfer_lp_over_balance_fails() {
+    let (
+        env,
+        amm_client,
+        _,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+    let liquidity = amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &0,
+    );
+
+    let recipient = Address::generate(&env);
+    assert_eq!(
+        amm_client.try_transfer_lp(
+            &user,
+            &recipient,
+            &user_token.address,
+            &validator_token.address,
+            &(liquidity + 1)
+        ),
+        Err(Ok(Error::InsufficientLiquidity))
+    );
+    assert_eq!(
+        amm_client.get_lp_balance(&user_token.address, &validator_token.address, &user),
+        liquidity
+    );
+}
+
+#[test]
+fn test_transfer_lp_from_spends_allowance() {
+    let (
+        env,
+        amm_client,
+        _,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+    let liquidity = amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &0,
+    );
+
+    let spender = Address::generate(&env);
+    let recipient = Address::generate(&env);
+    amm_client.approve_lp(
+        &user,
+        &spender,
+        &user_token.address,
+        &validator_token.address,
+        &2_000,
+        &1_000,
+    );
+    assert_eq!(
+        amm_client.lp_allowance(
+            &user,
+            &spender,
+            &user_token.address,
+            &validator_token.address
+        ),
+        2_000
+    );
+
+    amm_client.transfer_lp_from(
+        &spender,
+        &user,
+        &recipient,
+        &user_token.address,
+        &validator_token.address,
+        &1_500,
+    );
+    assert_eq!(
+        amm_client.lp_allowance(
+            &user,
+            &spender,
+            &user_token.address,
+            &validator_token.address
+        ),
+        500
+    );
+    assert_eq!(
+        amm_client.get_lp_balance(&user_token.address, &validator_token.address, &user),
+        liquidity - 1_500
+    );
+    assert_eq!(
+        amm_client.get_lp_balance(&user_token.address, &validator_token.address, &recipient),
+        1_500
+    );
+
+    // Spending past the allowance, or after it expires, is refused
+    assert_eq!(
+        amm_client.try_transfer_lp_from(
+            &spender,
+            &user,
+            &recipient,
+            &user_token.address,
+            &validator_token.address,
+            &501
+        ),
+        Err(Ok(Error::InsufficientAllowance))
+    );
+    env.ledger().set_sequence_number(1_001);
+    assert_eq!(
+        amm_client.lp_allowance(
+            &user,
+            &spender,
+            &user_token.address,
+            &validator_token.address
+        ),
+        0
+    );
+}
+
+#[test]
+fn test_swap_exact_in_both_directions() {
+    let (
+        env,
+        amm_client,
+        _,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &200_000);
+    validator_token_admin.mint(&user, &200_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    // User token in: 1_000 * 0.997 * 100_000 / (100_000 + 997) = 987
+    let trader = Address::generate(&env);
+    user_token_admin.mint(&trader, &1_000);
+    let out = amm_client.swap_exact_in(
+        &trader,
+        &user_token.address,
+        &validator_token.address,
+        &1_000,
+        &987,
+        &trader,
+    );
+    assert_eq!(out, 987);
+    assert_eq!(validator_token.balance(&trader), 987);
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(
+        (pool.reserve_user_token, pool.reserve_validator_token),
+        (101_000, 99_013)
+    );
+
+    // Validator token in: 987 * 0.997 * 101_000 / (99_013 + 984.039) = 993
+    let out = amm_client.swap_exact_in(
+        &trader,
+        &validator_token.address,
+        &user_token.address,
+        &987,
+        &0,
+        &trader,
+    );
+    assert_eq!(out, 993);
+    assert_eq!(user_token.balance(&trader), 993);
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(
+        (pool.reserve_user_token, pool.reserve_validator_token),
+        (100_007, 100_000)
+    );
+}
+
+#[test]
+fn test_swap_exact_in_slippage_rejected() {
+    let (
+        env,
+        amm_client,
+        _,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &200_000);
+    validator_token_admin.mint(&user, &200_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    let trader = Address::generate(&env);
+    user_token_admin.mint(&trader, &1_000);
+    assert_eq!(
+        amm_client.try_swap_exact_in(
+            &trader,
+            &user_token.address,
+            &validator_token.address,
+            &1_000,
+            &988,
+            &trader
+        ),
+        Err(Ok(Error::SlippageExceeded))
+    );
+    assert_eq!(user_token.balance(&trader), 1_000);
+
+    // Tokens with no pool between them are refused
+    let (other_token, _) = create_token_contract(&env, &user);
+    assert_eq!(
+        amm_client.try_swap_exact_in(
+            &trader,
+            &user_token.address,
+            &other_token.address,
+            &1_000,
+            &0,
+            &trader
+        ),
+        Err(Ok(Error::PoolNotInitialized))
+    );
+}
+
+#[test]
+fn test_flash_loan_repaid_with_fee() {
+    let (
+        env,
+        amm_client,
+        _,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    let receiver = env.register(FlashReceiver, ());
+    FlashReceiverClient::new(&env, &receiver).init(&amm_client.address, &0);
+    // Fee on 10_000 is ceil(10_000 * 30 / 10_000) = 30
+    user_token_admin.mint(&receiver, &30);
+
+    let borrower = Address::generate(&env);
+    let fee = amm_client.flash_loan(
+        &borrower,
+        &user_token.address,
+        &validator_token.address,
+        &user_token.address,
+        &10_000,
+        &receiver,
+        &Bytes::new(&env),
+    );
+    assert_eq!(fee, 30);
+    assert_eq!(user_token.balance(&receiver), 0);
+    assert_eq!(user_token.balance(&amm_client.address), 100_030);
+
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(pool.reserve_user_token, 100_030);
+    assert_eq!(pool.reserve_validator_token, 100_000);
+}
+
+#[test]
+fn test_flash_loan_short_repayment_reverts() {
+    let (
+        env,
+        amm_client,
+        _,
+        user,
+        user_token,
+        validator_token,
+        user_token_admin,
+        validator_token_admin,
+    ) = setup_test_env();
+
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    let receiver = env.register(FlashReceiver, ());
+    FlashReceiverClient::new(&env, &receiver).init(&amm_client.address, &1);
+    validator_token_admin.mint(&receiver, &30);
+
+    let borrower = Address::generate(&env);
+    assert_eq!(
+        amm_client.try_flash_loan(
+            &borrower,
+            &user_token.address,
+            &validator_token.address,
+            &validator_token.address,
+            &10_000,
+            &receiver,
+            &Bytes::new(&env),
+        ),
+        Err(Ok(Error::InsufficientReserves))
+    );
+    assert_eq!(validator_token.balance(&receiver), 30);
+    assert_eq!(validator_token.balance(&amm_client.address), 100_000);
+
+    // Only the pool's own reserve tokens can be borrowed
+    let (other_token, _) = create_token_contract(&env, &user);
+    assert_eq!(
+        amm_client.try_flash_loan(
+            &borrower,
+            &user_token.address,
+            &validator_token.address,
+            &other_token.address,
+            &10_000,
+            &receiver,
+            &Bytes::new(&env),
+        ),
+        Err(Ok(Error::InvalidToken))
+    );
+}
+
+#[test]
+fn test_mint_min_liquidity() {
+    let (_env, amm_client, _, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+
+    // First deposit yields (10_000 + 10_000) / 2 - 1000 = 9000
+    let result = amm_client.try_mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &9_001,
+    );
+    assert_eq!(result, Err(Ok(Error::SlippageExceeded)));
+    assert_eq!(user_token.balance(&user), 100_000);
+    assert_eq!(validator_token.balance(&user), 100_000);
+    assert_eq!(amm_client.get_total_supply(&user_token.address, &validator_token.address), 0);
+
+    let liquidity = amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &10_000,
+        &user,
+        &9_000,
+    );
+    assert_eq!(liquidity, 9_000);
+
+    // Subsequent deposits are held to the proportional share: 5000 * 10_000 / 10_000
+    let result = amm_client.try_mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &5_000,
+        &5_000,
+        &user,
+        &5_001,
+    );
+    assert_eq!(result, Err(Ok(Error::SlippageExceeded)));
+    assert_eq!(user_token.balance(&user), 90_000);
+    assert_eq!(validator_token.balance(&user), 90_000);
+}
+
+#[test]
+fn test_burn_to_validator_token_matches_rebalance_rate() {
+    // Two identical pools: one exits two-sided, the other single-sided
+    let (_env, amm_client, _, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+    let (amount_user, amount_validator) = amm_client.burn(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &user,
+        &0,
+        &0,
+    );
+    assert_eq!((amount_user, amount_validator), (10_000, 10_000));
+
+    let (_env, amm_client, _, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    // The user-token share is sold at the rebalance rate: 10_000 * 9985 / 10_000 = 9985,
+    // one less than rebalance_swap charges for the same 10_000 user tokens
+    let rebalance_input = amm_client.calculate_rebalance_input(&amount_user);
+    assert_eq!(rebalance_input, 9_986);
+    let payout = amm_client.burn_to_validator_token(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &19_985,
+        &user,
+    );
+    assert_eq!(payout, amount_validator + rebalance_input - 1);
+    assert_eq!(user_token.balance(&user), 0);
+    assert_eq!(validator_token.balance(&user), 19_985);
+
+    let pool = amm_client.get_pool(&user_token.address, &validator_token.address);
+    assert_eq!(pool.reserve_user_token, 100_000);
+    assert_eq!(pool.reserve_validator_token, 80_015);
+
+    // A floor above the payout reverts
+    let result = amm_client.try_burn_to_validator_token(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &19_986,
+        &user,
+    );
+    assert_eq!(result, Err(Ok(Error::SlippageExceeded)));
+}
+
+#[test]
+fn test_burn_to_validator_token_respects_pending_swaps() {
+    let (_env, amm_client, _, user, user_token, validator_token, user_token_admin, validator_token_admin) =
+        setup_test_env();
+    user_token_admin.mint(&user, &100_000);
+    validator_token_admin.mint(&user, &100_000);
+    amm_client.mint(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &100_000,
+        &100_000,
+        &user,
+        &0,
+    );
+
+    // 85_000 pending reserves 85_000 * 9970 / 10_000 = 84_745, leaving 15_255 free
+    amm_client.reserve_liquidity(&user_token.address, &validator_token.address, &85_000);
+
+    let result = amm_client.try_burn_to_validator_token(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &0,
+        &user,
+    );
+    assert_eq!(result, Err(Ok(Error::InsufficientReserves)));
+
+    // The same share fits when taken two-sided
+    amm_client.burn(
+        &user,
+        &user_token.address,
+        &validator_token.address,
+        &10_000,
+        &user,
+        &0,
+        &0,
+    );
 }
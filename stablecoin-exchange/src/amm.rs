@@ -0,0 +1,54 @@
+use soroban_sdk::{
+    auth::{ContractContext, InvokerContractAuthEntry, SubContractInvocation},
+    contractclient, token, vec, Address, Env, IntoVal, Symbol,
+};
+
+/// Swap interface of the fee AMM pool used as a fallback for unfilled input
+#[allow(dead_code)]
+#[contractclient(name = "AmmClient")]
+pub trait AmmInterface {
+    fn swap_exact_in(
+        env: Env,
+        sender: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_in: i128,
+        min_amount_out: i128,
+        to: Address,
+    ) -> i128;
+}
+
+/// Swap `amount_in` of the exchange's own `token_in` through the AMM at `amm`,
+/// returning the `token_out` received by the exchange
+///
+/// The output is the exchange's `token_out` balance gain across the call,
+/// not the AMM's reported amount.
+pub fn swap_exact_in(
+    env: &Env,
+    amm: &Address,
+    token_in: &Address,
+    token_out: &Address,
+    amount_in: i128,
+    min_amount_out: i128,
+) -> i128 {
+    let this = env.current_contract_address();
+    let out_client = token::Client::new(env, token_out);
+    let balance_before = out_client.balance(&this);
+
+    // The AMM pulls the input from the exchange, one call below our own
+    env.authorize_as_current_contract(vec![
+        env,
+        InvokerContractAuthEntry::Contract(SubContractInvocation {
+            context: ContractContext {
+                contract: token_in.clone(),
+                fn_name: Symbol::new(env, "transfer"),
+                args: (this.clone(), amm.clone(), amount_in).into_val(env),
+            },
+            sub_invocations: vec![env],
+        }),
+    ]);
+
+    AmmClient::new(env, amm).swap_exact_in(&this, token_in, token_out, &amount_in, &min_amount_out, &this);
+
+    out_client.balance(&this) - balance_before
+}
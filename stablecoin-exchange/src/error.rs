@@ -46,4 +46,34 @@ pub enum Error {
     SameToken = 20,
     /// Tick not aligned to spacing
     TickNotAligned = 21,
+    /// Migration target must be exactly one version ahead of the stored version
+    InvalidVersion = 22,
+    /// Swap consumed less of the input than the requested minimum fill ratio
+    FillRatioNotMet = 23,
+    /// Too many order IDs passed to execute_block
+    BatchTooLarge = 24,
+    /// Quote token is not on the allowlist
+    QuoteTokenNotAllowed = 25,
+    /// Pair exists with base and quote the other way round
+    WrongTokenOrder = 26,
+    /// Fee exceeds the maximum allowed
+    FeeTooHigh = 27,
+    /// Route path is shorter than two tokens or per-hop minimums do not match its hops
+    InvalidPath = 28,
+    /// Flip orders are switched off by the admin
+    FlipsDisabled = 29,
+    /// Contract holds less of a token than a withdrawal would pay out
+    InsufficientReserves = 30,
+    /// Trading is halted by the admin
+    Paused = 31,
+    /// Order expiry ledger is already in the past
+    InvalidExpiry = 32,
+    /// Post-only order would cross the spread at placement
+    WouldCross = 33,
+    /// Price observations do not reach back far enough for the requested window
+    InsufficientHistory = 34,
+    /// Reduce-only order is larger than the position it would close
+    ReduceOnlyViolated = 35,
+    /// AMM fallback is not the pool configured by the admin
+    AmmNotAllowed = 36,
 }
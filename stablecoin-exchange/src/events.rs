@@ -1,12 +1,18 @@
-use soroban_sdk::{symbol_short, Address, Env, Symbol};
+use soroban_sdk::{symbol_short, Address, Env, Symbol, Vec};
 
 // Event topics
 const ORDER_PLACED: Symbol = symbol_short!("placed");
 const ORDER_CANCELED: Symbol = symbol_short!("canceled");
+const ORDER_AMENDED: Symbol = symbol_short!("amended");
 const ORDER_FILLED: Symbol = symbol_short!("filled");
 const TRADE: Symbol = symbol_short!("trade");
 const WITHDRAW: Symbol = symbol_short!("withdraw");
 const PAIR_CREATED: Symbol = symbol_short!("pair");
+const MIGRATED: Symbol = symbol_short!("migrated");
+const BALANCE_CHANGED: Symbol = symbol_short!("balance");
+const BEST_TICK_CHANGED: Symbol = symbol_short!("best_tick");
+const BLOCK_EXECUTED: Symbol = symbol_short!("block");
+const FLIP_SKIPPED: Symbol = symbol_short!("flip_skip");
 
 pub fn emit_order_placed(
     env: &Env,
@@ -25,24 +31,66 @@ pub fn emit_order_placed(
     );
 }
 
-pub fn emit_order_canceled(env: &Env, order_id: u128, maker: &Address, refund_amount: i128) {
-    env.events()
-        .publish((ORDER_CANCELED, maker), (order_id, refund_amount));
+pub fn emit_order_canceled(
+    env: &Env,
+    order_id: u128,
+    maker: &Address,
+    base_token: &Address,
+    quote_token: &Address,
+    refund_amount: i128,
+) {
+    env.events().publish(
+        (ORDER_CANCELED, maker, base_token, quote_token),
+        (order_id, refund_amount),
+    );
+}
+
+pub fn emit_order_amended(
+    env: &Env,
+    order_id: u128,
+    maker: &Address,
+    base_token: &Address,
+    quote_token: &Address,
+    remaining: i128,
+    refund_amount: i128,
+) {
+    env.events().publish(
+        (ORDER_AMENDED, maker, base_token, quote_token),
+        (order_id, remaining, refund_amount),
+    );
 }
 
 pub fn emit_order_filled(
     env: &Env,
     order_id: u128,
     maker: &Address,
+    base_token: &Address,
+    quote_token: &Address,
     filled_amount: i128,
     remaining: i128,
 ) {
     env.events().publish(
-        (ORDER_FILLED, maker),
+        (ORDER_FILLED, maker, base_token, quote_token),
         (order_id, filled_amount, remaining),
     );
 }
 
+/// A filled flip order's flipped side was not placed because the maker's
+/// balance no longer held its escrow
+pub fn emit_flip_skipped(
+    env: &Env,
+    order_id: u128,
+    maker: &Address,
+    base_token: &Address,
+    quote_token: &Address,
+    required_escrow: i128,
+) {
+    env.events().publish(
+        (FLIP_SKIPPED, maker, base_token, quote_token),
+        (order_id, required_escrow),
+    );
+}
+
 pub fn emit_trade(
     env: &Env,
     base_token: &Address,
@@ -59,12 +107,55 @@ pub fn emit_trade(
     );
 }
 
+pub fn emit_best_tick_changed(
+    env: &Env,
+    base_token: &Address,
+    quote_token: &Address,
+    best_bid_tick: i32,
+    best_ask_tick: i32,
+) {
+    env.events().publish(
+        (BEST_TICK_CHANGED, base_token, quote_token),
+        (best_bid_tick, best_ask_tick),
+    );
+}
+
+pub fn emit_block_executed(
+    env: &Env,
+    base_token: &Address,
+    quote_token: &Address,
+    activated: Vec<u128>,
+    new_best_bid: i32,
+    new_best_ask: i32,
+) {
+    env.events().publish(
+        (BLOCK_EXECUTED, base_token, quote_token),
+        (activated, new_best_bid, new_best_ask),
+    );
+}
+
 pub fn emit_withdraw(env: &Env, user: &Address, token: &Address, amount: i128) {
     env.events()
         .publish((WITHDRAW, user, token), amount);
 }
 
+pub fn emit_balance_changed(
+    env: &Env,
+    user: &Address,
+    token: &Address,
+    delta: i128,
+    new_balance: i128,
+) {
+    env.events()
+        .publish((BALANCE_CHANGED, user, token), (delta, new_balance));
+}
+
 pub fn emit_pair_created(env: &Env, base_token: &Address, quote_token: &Address) {
     env.events()
         .publish((PAIR_CREATED,), (base_token, quote_token));
 }
+
+pub fn emit_migrated(env: &Env, from_version: u32, to_version: u32) {
+    env.events()
+        .publish((MIGRATED,), (from_version, to_version));
+}
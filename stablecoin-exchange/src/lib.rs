@@ -1,5 +1,7 @@
 #![no_std]
+#![allow(clippy::too_many_arguments)]
 
+mod amm;
 mod error;
 mod events;
 mod order;
@@ -7,15 +9,25 @@ mod orderbook;
 mod storage;
 
 use error::Error;
-use order::Order;
+use order::{MakerFill, Order, UserSummary, MAX_SUMMARY_ORDERS};
 use orderbook::{
-    calculate_base_amount, calculate_quote_amount, find_next_ask_tick,
+    calculate_base_amount, calculate_fee, calculate_quote_amount, calculate_quote_amount_ceil,
+    find_next_ask_tick,
     find_next_bid_tick, get_ask_tick_level, get_bid_tick_level, get_orderbook, has_orderbook,
-    save_ask_tick_level, save_bid_tick_level, save_orderbook, tick_to_price, update_best_ask_tick,
-    update_best_bid_tick, validate_tick, Orderbook, TickLevel, MAX_TICK, MIN_ORDER_SIZE, MIN_TICK,
-    PRICE_SCALE, TICK_SPACING,
+    next_level_head, price_to_limit_tick, save_ask_tick_level, save_bid_tick_level, save_orderbook, sell_fee_in_quote, tick_to_price, update_best_ask_tick,
+    update_best_bid_tick, validate_tick, BookHealth, Checkpoint, Orderbook, PriceObservation, SwapResult, TickLevel, Trade, BPS_SCALE, MAX_FEE_BPS, MAX_TICK,
+    MIN_ORDER_SIZE, MIN_TICK, PRICE_OBSERVATION_SLOTS, PRICE_SCALE, TICK_SPACING,
 };
-use soroban_sdk::{contract, contractimpl, token, Address, Env};
+use soroban_sdk::{contract, contractimpl, token, Address, BytesN, Env, Vec};
+
+/// Storage layout version written by `initialize` on fresh deployments
+const CONTRACT_VERSION: u32 = 1;
+
+/// Nominal ledger close time, used to turn block horizons into seconds
+const SECONDS_PER_LEDGER: u64 = 5;
+
+/// Most prices returned by one price_table call
+const MAX_PRICE_TABLE_ENTRIES: i64 = 100;
 
 #[contract]
 pub struct StablecoinExchange;
@@ -25,11 +37,15 @@ impl StablecoinExchange {
     // ============ Initialization ============
 
     /// Initialize the contract with an admin
-    pub fn initialize(env: Env, admin: Address) -> Result<(), Error> {
+    pub fn initialize(env: Env, admin: Address, sequencer: Option<Address>) -> Result<(), Error> {
         if storage::has_admin(&env) {
             return Err(Error::AlreadyInitialized);
         }
         storage::set_admin(&env, &admin);
+        if let Some(sequencer) = sequencer {
+            storage::set_sequencer(&env, &sequencer);
+        }
+        storage::set_version(&env, CONTRACT_VERSION);
         storage::extend_instance_ttl(&env);
         Ok(())
     }
@@ -40,100 +56,2266 @@ impl StablecoinExchange {
         storage::get_admin(&env)
     }
 
+    // ============ Upgrades ============
+
+    /// Get the stored storage layout version (0 for deployments that predate versioning)
+    pub fn get_contract_version(env: Env) -> u32 {
+        storage::extend_instance_ttl(&env);
+        storage::get_version(&env)
+    }
+
+    /// Run the one-time data migration for `new_version` (admin only)
+    ///
+    /// Migrations are applied strictly in sequence: `new_version` must be exactly
+    /// one ahead of the stored version, so each step can only ever run once.
+    pub fn migrate(env: Env, caller: Address, new_version: u32) -> Result<(), Error> {
+        Self::require_admin(&env, &caller)?;
+        storage::extend_instance_ttl(&env);
+
+        let current_version = storage::get_version(&env);
+        if current_version.checked_add(1) != Some(new_version) {
+            return Err(Error::InvalidVersion);
+        }
+
+        Self::run_migration(&env, new_version)?;
+        storage::set_version(&env, new_version);
+
+        events::emit_migrated(&env, current_version, new_version);
+
+        Ok(())
+    }
+
     // ============ Trading Pair Management ============
 
     /// Create a new trading pair (admin only)
+    ///
+    /// `min_order_size` overrides the pair's order size floor, which is
+    /// `MIN_ORDER_SIZE` when `None`.
     pub fn create_pair(
         env: Env,
         base_token: Address,
         quote_token: Address,
+        min_order_size: Option<i128>,
     ) -> Result<(), Error> {
         let admin = storage::get_admin(&env);
         admin.require_auth();
 
-        if base_token == quote_token {
-            return Err(Error::SameToken);
+        if base_token == quote_token {
+            return Err(Error::SameToken);
+        }
+        if min_order_size.is_some_and(|size| size <= 0) {
+            return Err(Error::InvalidAmount);
+        }
+
+        if has_orderbook(&env, &base_token, &quote_token) {
+            return Err(Error::PairAlreadyExists);
+        }
+
+        // An empty allowlist admits any quote token
+        let allowed_quote_tokens = storage::get_allowed_quote_tokens(&env);
+        if !allowed_quote_tokens.is_empty() && !allowed_quote_tokens.contains(&quote_token) {
+            return Err(Error::QuoteTokenNotAllowed);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let mut orderbook = Orderbook::new(base_token.clone(), quote_token.clone());
+        if let Some(min_order_size) = min_order_size {
+            orderbook.min_order_size = min_order_size;
+        }
+        save_orderbook(&env, &orderbook);
+
+        events::emit_pair_created(&env, &base_token, &quote_token);
+
+        Ok(())
+    }
+
+    /// Change a pair's order size floor (admin only); orders already placed
+    /// are unaffected
+    pub fn set_min_order_size(
+        env: Env,
+        admin: Address,
+        base_token: Address,
+        quote_token: Address,
+        value: i128,
+    ) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+
+        if value <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let mut orderbook =
+            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+        orderbook.min_order_size = value;
+        save_orderbook(&env, &orderbook);
+        Ok(())
+    }
+
+    /// Allow a token to be used as the quote asset of new pairs (admin only)
+    pub fn add_allowed_quote_token(env: Env, admin: Address, token: Address) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+        storage::extend_instance_ttl(&env);
+        storage::add_allowed_quote_token(&env, &token);
+        Ok(())
+    }
+
+    /// Remove a token from the quote allowlist; existing pairs are unaffected (admin only)
+    pub fn remove_allowed_quote_token(env: Env, admin: Address, token: Address) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+        storage::extend_instance_ttl(&env);
+        storage::remove_allowed_quote_token(&env, &token);
+        Ok(())
+    }
+
+    /// Tokens allowed as quote assets; empty means any token is allowed
+    pub fn allowed_quote_tokens(env: Env) -> Vec<Address> {
+        storage::extend_instance_ttl(&env);
+        storage::get_allowed_quote_tokens(&env)
+    }
+
+    /// Get orderbook state
+    pub fn get_orderbook(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+    ) -> Result<Orderbook, Error> {
+        storage::extend_instance_ttl(&env);
+        get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)
+    }
+
+    /// Liquidity flags and populated level counts per side for a pair's book
+    pub fn get_book_health(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+    ) -> Result<BookHealth, Error> {
+        storage::extend_instance_ttl(&env);
+        let orderbook = get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+        Ok(orderbook.health())
+    }
+
+    /// Set the address allowed to call `execute_block` (admin only)
+    pub fn set_sequencer(env: Env, admin: Address, new_sequencer: Address) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+        storage::extend_instance_ttl(&env);
+        storage::set_sequencer(&env, &new_sequencer);
+        Ok(())
+    }
+
+    /// Get the address allowed to call `execute_block` (the admin until one is set)
+    pub fn sequencer(env: Env) -> Address {
+        storage::extend_instance_ttl(&env);
+        storage::get_sequencer(&env)
+    }
+
+    /// Set the cancel fee: `fee_bps` of a canceled order's refund goes to the
+    /// treasury unless the order has rested at least `min_age` seconds
+    /// (admin only, at most `MAX_FEE_BPS`, default 0)
+    pub fn set_cancel_fee(env: Env, admin: Address, fee_bps: u32, min_age: u64) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+
+        if fee_bps > MAX_FEE_BPS {
+            return Err(Error::FeeTooHigh);
+        }
+
+        storage::extend_instance_ttl(&env);
+        storage::set_cancel_fee_bps(&env, fee_bps);
+        storage::set_cancel_fee_min_age(&env, min_age);
+        Ok(())
+    }
+
+    /// Set the pair's trading fee: `fee_bps` of every taker's output is
+    /// credited to the treasury's exchange balance while makers still receive
+    /// the full matched amount (admin only, at most `MAX_FEE_BPS`, default 0)
+    pub fn set_fee_bps(
+        env: Env,
+        admin: Address,
+        base_token: Address,
+        quote_token: Address,
+        fee_bps: u32,
+    ) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+
+        if fee_bps > MAX_FEE_BPS {
+            return Err(Error::FeeTooHigh);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let mut orderbook =
+            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+        orderbook.fee_bps = fee_bps;
+        save_orderbook(&env, &orderbook);
+        Ok(())
+    }
+
+    /// Collect every trading fee in the quote token (admin only, default off)
+    ///
+    /// With this set, a sell's fee is `fee_bps` of the base it sells, converted
+    /// to quote at each fill tick and taken from the quote output. The base fee
+    /// and its conversion both round down, in the taker's favor, so the fee can
+    /// fall short of `fee_bps` of the output by a unit per tick filled.
+    ///
+    /// A buy no longer gives up `fee_bps` of its base output; its fee is charged
+    /// in quote as `fee_bps` of the quote the fills cost, rounded down. The fee
+    /// is set aside from the swap's input, so the book is matched with
+    /// `amount_in * 10000 / (10000 + fee_bps)`.
+    pub fn set_fee_in_quote_only(env: Env, admin: Address, enabled: bool) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+        storage::extend_instance_ttl(&env);
+        storage::set_fee_in_quote_only(&env, enabled);
+        Ok(())
+    }
+
+    /// Whether all trading fees are collected in the quote token
+    pub fn fee_in_quote_only(env: Env) -> bool {
+        storage::extend_instance_ttl(&env);
+        storage::get_fee_in_quote_only(&env)
+    }
+
+    /// Set the address credited with trading fees (admin only)
+    pub fn set_treasury(env: Env, admin: Address, treasury: Address) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+        storage::extend_instance_ttl(&env);
+        storage::set_treasury(&env, &treasury);
+        Ok(())
+    }
+
+    /// Get the address credited with trading fees (the admin until one is set)
+    pub fn treasury(env: Env) -> Address {
+        storage::extend_instance_ttl(&env);
+        storage::get_treasury(&env)
+    }
+
+    /// Set the fee AMM pool `swap_exact_in` may route unfilled input through (admin only)
+    pub fn set_amm_pool(env: Env, admin: Address, amm: Address) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+        storage::extend_instance_ttl(&env);
+        storage::set_amm_pool(&env, &amm);
+        Ok(())
+    }
+
+    /// Get the fee AMM pool swaps may fall back to, if one is set
+    pub fn amm_pool(env: Env) -> Option<Address> {
+        storage::extend_instance_ttl(&env);
+        storage::get_amm_pool(&env)
+    }
+
+    /// Get the cancel fee as `(fee_bps, min_age)`
+    pub fn cancel_fee(env: Env) -> (u32, u64) {
+        storage::extend_instance_ttl(&env);
+        (storage::get_cancel_fee_bps(&env), storage::get_cancel_fee_min_age(&env))
+    }
+
+    // ============ Order Placement ============
+
+    /// Place a limit order
+    ///
+    /// A bid at or above the best ask, or an ask at or below the best bid, first
+    /// matches the opposite side up to its own tick, crediting the fills to the
+    /// maker's exchange balance, and only the unfilled remainder is queued.
+    /// Returns 0 when nothing is left to queue (a remainder below the pair's
+    /// minimum order size stays in the balance as well).
+    pub fn place(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        expiry: u32,
+    ) -> Result<u128, Error> {
+        Self::place_order(&env, maker, base_token, quote_token, is_bid, tick, amount, None, None, expiry, false, false)
+    }
+
+    /// Place a limit order that must not cross the live book: bids at or above
+    /// the best ask and asks at or below the best bid fail with `WouldCross`
+    ///
+    /// The check runs at placement; the order still waits in the pending queue.
+    pub fn place_post_only(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        expiry: u32,
+    ) -> Result<u128, Error> {
+        let orderbook = get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+        if orderbook.crosses(is_bid, tick) {
+            return Err(Error::WouldCross);
+        }
+        Self::place_order(&env, maker, base_token, quote_token, is_bid, tick, amount, None, None, expiry, false, false)
+    }
+
+    /// Place a limit order at the tick nearest `price` (scaled by `PRICE_SCALE`)
+    /// without crossing it: rounded down for bids and up for asks.
+    /// Returns `(order_id, tick)`.
+    pub fn place_at_price(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        price: i128,
+        amount: i128,
+    ) -> Result<(u128, i32), Error> {
+        let tick = price_to_limit_tick(price, is_bid)?;
+        let order_id =
+            Self::place_order(&env, maker, base_token, quote_token, is_bid, tick, amount, None, None, 0, false, false)?;
+        Ok((order_id, tick))
+    }
+
+    /// Place a limit order whose fills are reported to `callback` through
+    /// `on_order_filled`; a failing callback does not revert the fill
+    pub fn place_with_callback(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        callback: Address,
+    ) -> Result<u128, Error> {
+        Self::place_order(&env, maker, base_token, quote_token, is_bid, tick, amount, Some(callback), None, 0, false, false)
+    }
+
+    /// Place a limit order whose fill proceeds are transferred straight to the
+    /// maker instead of accumulating in their exchange balance
+    pub fn place_with_auto_settle(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        expiry: u32,
+    ) -> Result<u128, Error> {
+        Self::place_order(&env, maker, base_token, quote_token, is_bid, tick, amount, None, None, expiry, true, false)
+    }
+
+    /// Place a limit order that may only shrink the maker's position in the
+    /// pair: an ask must not exceed the base they are long, a bid must not
+    /// exceed the base they are short, or it fails with `ReduceOnlyViolated`
+    ///
+    /// The check runs at placement against fills so far, less the unfilled
+    /// amount of the maker's pending and resting reduce-only orders on the same
+    /// side, so together they can at most close the position. Other orders are
+    /// not counted.
+    pub fn place_reduce_only(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        expiry: u32,
+    ) -> Result<u128, Error> {
+        Self::require_reducible(&env, &maker, &base_token, &quote_token, is_bid, amount)?;
+        Self::place_order(&env, maker, base_token, quote_token, is_bid, tick, amount, None, None, expiry, false, true)
+    }
+
+    /// Place a limit order that only fills at `min_fill_tick` or better: asks
+    /// at or above it, bids at or below it. Orders fill at the tick they rest
+    /// at, so a `min_fill_tick` beyond `tick` (above it for an ask, below it for
+    /// a bid) could never fill while still showing as liquidity and is rejected
+    pub fn place_with_min_fill(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        min_fill_tick: i32,
+    ) -> Result<u128, Error> {
+        validate_tick(min_fill_tick)?;
+        let unfillable = if is_bid { min_fill_tick < tick } else { min_fill_tick > tick };
+        if unfillable {
+            return Err(Error::InvalidTick);
+        }
+        Self::place_order(&env, maker, base_token, quote_token, is_bid, tick, amount, None, Some(min_fill_tick), 0, false, false)
+    }
+
+    /// Place a flip order (auto-creates opposite side when filled)
+    ///
+    /// A crossing flip order matches like `place` first; only the unfilled
+    /// remainder rests, and it flips for that remainder. Returns 0 when
+    /// nothing is left to rest.
+    pub fn place_flip(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        flip_tick: i32,
+        expiry: u32,
+    ) -> Result<u128, Error> {
+        maker.require_auth();
+        Self::require_not_paused(&env)?;
+        Self::require_flips_enabled(&env)?;
+        validate_tick(tick)?;
+        validate_tick(flip_tick)?;
+        Self::validate_expiry(&env, expiry)?;
+
+        storage::extend_instance_ttl(&env);
+
+        // Create pending flip order
+        let order_id = storage::get_next_pending_order_id(&env);
+        let mut new_order = if is_bid {
+            Order::new_flip_bid(order_id, maker.clone(), base_token.clone(), quote_token.clone(), tick, amount, flip_tick)?
+        } else {
+            Order::new_flip_ask(order_id, maker.clone(), base_token.clone(), quote_token.clone(), tick, amount, flip_tick)?
+        };
+        new_order.placed_at = env.ledger().timestamp();
+        new_order.expiry_ledger = expiry;
+
+        let escrow = Self::escrow_deposit(&env, &maker, &base_token, &quote_token, is_bid, tick, amount)?;
+
+        // A marketable flip takes what it can; only the remainder rests and flips
+        let amount = Self::match_crossing(&env, &maker, &base_token, &quote_token, is_bid, tick, amount, escrow)?;
+        if amount == 0 {
+            return Ok(0);
+        }
+        new_order.amount = amount;
+        new_order.remaining = amount;
+
+        order::save_pending_order(&env, &new_order);
+
+        events::emit_order_placed(
+            &env,
+            order_id,
+            &maker,
+            &base_token,
+            &quote_token,
+            is_bid,
+            tick,
+            amount,
+            true,
+        );
+
+        Ok(order_id)
+    }
+
+    /// Place a flip order whose flipped side is placed `spread_ticks` away from the fill tick
+    /// (above it for bids, below it for asks) instead of at a fixed flip_tick.
+    /// Crossing orders match first as in `place_flip`.
+    pub fn place_flip_relative(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        spread_ticks: i32,
+    ) -> Result<u128, Error> {
+        maker.require_auth();
+        Self::require_not_paused(&env)?;
+        Self::require_flips_enabled(&env)?;
+        validate_tick(tick)?;
+
+        storage::extend_instance_ttl(&env);
+
+        let order_id = storage::get_next_pending_order_id(&env);
+        let mut new_order = Order::new_relative_flip(
+            order_id,
+            maker.clone(),
+            base_token.clone(),
+            quote_token.clone(),
+            is_bid,
+            tick,
+            amount,
+            spread_ticks,
+        )?;
+        new_order.placed_at = env.ledger().timestamp();
+
+        let escrow = Self::escrow_deposit(&env, &maker, &base_token, &quote_token, is_bid, tick, amount)?;
+
+        // A marketable flip takes what it can; only the remainder rests and flips
+        let amount = Self::match_crossing(&env, &maker, &base_token, &quote_token, is_bid, tick, amount, escrow)?;
+        if amount == 0 {
+            return Ok(0);
+        }
+        new_order.amount = amount;
+        new_order.remaining = amount;
+
+        order::save_pending_order(&env, &new_order);
+
+        events::emit_order_placed(
+            &env,
+            order_id,
+            &maker,
+            &base_token,
+            &quote_token,
+            is_bid,
+            tick,
+            amount,
+            true,
+        );
+
+        Ok(order_id)
+    }
+
+    /// Keep each order's pending ID through activation instead of minting a new
+    /// active ID (admin only); only affects orders activated afterwards
+    pub fn set_stable_order_ids(env: Env, admin: Address, enabled: bool) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+
+        storage::extend_instance_ttl(&env);
+        storage::set_stable_order_ids(&env, enabled);
+        Ok(())
+    }
+
+    /// Whether activation keeps the pending order ID
+    pub fn stable_order_ids(env: Env) -> bool {
+        storage::extend_instance_ttl(&env);
+        storage::get_stable_order_ids(&env)
+    }
+
+    /// Development mode: make `place` link orders straight into the book
+    /// instead of queueing them for `execute_block` (admin only, default off).
+    /// Never enable this in production, where activation order is the
+    /// sequencer's job.
+    pub fn set_auto_activate(env: Env, admin: Address, enabled: bool) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+
+        storage::extend_instance_ttl(&env);
+        storage::set_auto_activate(&env, enabled);
+        Ok(())
+    }
+
+    /// Whether `place` activates orders immediately (development mode)
+    pub fn auto_activate(env: Env) -> bool {
+        storage::extend_instance_ttl(&env);
+        storage::get_auto_activate(&env)
+    }
+
+    /// Safe mode for flip orders (admin only, default on). While off, placing a
+    /// flip order fails with `FlipsDisabled` and resting flip orders fill like
+    /// plain orders without spawning their flipped side.
+    pub fn set_flips_enabled(env: Env, admin: Address, enabled: bool) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+
+        storage::extend_instance_ttl(&env);
+        storage::set_flips_enabled(&env, enabled);
+        Ok(())
+    }
+
+    /// Whether flip orders can be placed and spawn their flipped side
+    pub fn flips_enabled(env: Env) -> bool {
+        storage::extend_instance_ttl(&env);
+        storage::get_flips_enabled(&env)
+    }
+
+    /// Halt trading (admin only). Placing, replacing, activating and swapping
+    /// fail with `Paused`; cancel and withdraw stay open so users can exit.
+    pub fn pause(env: Env, admin: Address) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+        storage::extend_instance_ttl(&env);
+        storage::set_paused(&env, true);
+        Ok(())
+    }
+
+    /// Resume trading (admin only)
+    pub fn unpause(env: Env, admin: Address) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+        storage::extend_instance_ttl(&env);
+        storage::set_paused(&env, false);
+        Ok(())
+    }
+
+    /// Whether trading is halted
+    pub fn is_paused(env: Env) -> bool {
+        storage::extend_instance_ttl(&env);
+        storage::get_paused(&env)
+    }
+
+    /// Atomically cancel an order and place a replacement on the same pair and
+    /// side at `new_tick` for `new_amount`
+    ///
+    /// The replacement keeps the old order's callback, expiry, auto-settle,
+    /// reduce-only, fill protection and flip settings; those that depend on the
+    /// tick or size are re-checked and the call fails if they no longer hold.
+    /// The old order's escrow, less the cancel fee if it is still fresh, is
+    /// credited to the maker's exchange balance and the new escrow is drawn from
+    /// that balance first, with any shortfall transferred in. The replacement is
+    /// a new pending order at the back of its queue; if it crosses the book it
+    /// matches like `place` first, returning 0 when nothing is left to rest.
+    pub fn replace(
+        env: Env,
+        maker: Address,
+        order_id: u128,
+        new_tick: i32,
+        new_amount: i128,
+    ) -> Result<u128, Error> {
+        maker.require_auth();
+        Self::require_not_paused(&env)?;
+        validate_tick(new_tick)?;
+
+        storage::extend_instance_ttl(&env);
+
+        // Take the old order off the book (or out of the pending queue)
+        let old_order = if let Some(pending_order) = order::get_pending_order(&env, order_id) {
+            if pending_order.maker != maker {
+                return Err(Error::NotOrderOwner);
+            }
+            order::delete_pending_order(&env, &pending_order);
+            pending_order
+        } else if let Some(active_order) = order::get_order(&env, order_id) {
+            if active_order.maker != maker {
+                return Err(Error::NotOrderOwner);
+            }
+            let mut orderbook =
+                get_orderbook(&env, &active_order.base_token, &active_order.quote_token)
+                    .ok_or(Error::PairNotFound)?;
+            Self::remove_order_from_book(&env, &mut orderbook, &active_order)?;
+            save_orderbook(&env, &orderbook);
+            order::delete_order(&env, &active_order);
+            active_order
+        } else {
+            return Err(Error::OrderNotFound);
+        };
+
+        let (refund_token, escrow) = old_order.remaining_escrow();
+        let refund = Self::charge_cancel_fee(&env, &old_order, escrow);
+        storage::add_balance(&env, &maker, &refund_token, refund);
+        events::emit_order_canceled(
+            &env,
+            order_id,
+            &maker,
+            &old_order.base_token,
+            &old_order.quote_token,
+            refund,
+        );
+
+        // The replacement keeps the old order's settings, checked against its new tick and size
+        let base_token = old_order.base_token.clone();
+        let quote_token = old_order.quote_token.clone();
+        let is_bid = old_order.is_bid;
+        let mut new_order = if old_order.is_flip {
+            Self::require_flips_enabled(&env)?;
+            if old_order.spread_ticks != 0 {
+                Order::new_relative_flip(0, maker.clone(), base_token.clone(), quote_token.clone(), is_bid, new_tick, new_amount, old_order.spread_ticks)?
+            } else if is_bid {
+                Order::new_flip_bid(0, maker.clone(), base_token.clone(), quote_token.clone(), new_tick, new_amount, old_order.flip_tick)?
+            } else {
+                Order::new_flip_ask(0, maker.clone(), base_token.clone(), quote_token.clone(), new_tick, new_amount, old_order.flip_tick)?
+            }
+        } else if is_bid {
+            Order::new_bid(0, maker.clone(), base_token.clone(), quote_token.clone(), new_tick, new_amount)
+        } else {
+            Order::new_ask(0, maker.clone(), base_token.clone(), quote_token.clone(), new_tick, new_amount)
+        };
+        new_order.callback = old_order.callback.clone();
+        new_order.auto_settle = old_order.auto_settle;
+        new_order.reduce_only = old_order.reduce_only;
+        Self::validate_expiry(&env, old_order.expiry_ledger)?;
+        new_order.expiry_ledger = old_order.expiry_ledger;
+        if old_order.min_fill_tick != old_order.tick {
+            let unfillable = if is_bid {
+                old_order.min_fill_tick < new_tick
+            } else {
+                old_order.min_fill_tick > new_tick
+            };
+            if unfillable {
+                return Err(Error::InvalidTick);
+            }
+            new_order.min_fill_tick = old_order.min_fill_tick;
+        }
+        if new_order.reduce_only {
+            Self::require_reducible(&env, &maker, &base_token, &quote_token, is_bid, new_amount)?;
+        }
+
+        // Fund the new order from the refunded balance, topping up by transfer
+        let (deposit_token, deposit_amount) =
+            Self::required_escrow(&env, &base_token, &quote_token, is_bid, new_tick, new_amount)?;
+        Self::fund_from_balance(&env, &maker, &deposit_token, deposit_amount);
+
+        // A marketable replacement takes what it can before resting the remainder
+        let new_amount =
+            Self::match_crossing(&env, &maker, &base_token, &quote_token, is_bid, new_tick, new_amount, deposit_amount)?;
+        if new_amount == 0 {
+            return Ok(0);
+        }
+
+        let new_order_id = storage::get_next_pending_order_id(&env);
+        new_order.order_id = new_order_id;
+        new_order.amount = new_amount;
+        new_order.remaining = new_amount;
+        new_order.placed_at = env.ledger().timestamp();
+        order::save_pending_order(&env, &new_order);
+
+        events::emit_order_placed(
+            &env,
+            new_order_id,
+            &maker,
+            &base_token,
+            &quote_token,
+            is_bid,
+            new_tick,
+            new_amount,
+            new_order.is_flip,
+        );
+
+        Ok(new_order_id)
+    }
+
+    /// Place several pending orders on one pair, funding each escrow from the
+    /// maker's exchange balance first and transferring only the shortfall
+    ///
+    /// `new_orders` holds `(is_bid, tick, amount)` entries, all validated before
+    /// any is placed. Crossing entries match like `place` first. Returns the
+    /// new order IDs in the same order, 0 for entries with nothing left to rest.
+    pub fn rebalance_orders(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        new_orders: Vec<(bool, i32, i128)>,
+    ) -> Result<Vec<u128>, Error> {
+        maker.require_auth();
+        Self::require_not_paused(&env)?;
+
+        let mut escrows = Vec::new(&env);
+        for (is_bid, tick, amount) in new_orders.iter() {
+            validate_tick(tick)?;
+            escrows.push_back(Self::required_escrow(&env, &base_token, &quote_token, is_bid, tick, amount)?);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let mut order_ids = Vec::new(&env);
+        for (i, (is_bid, tick, amount)) in new_orders.iter().enumerate() {
+            let (deposit_token, deposit_amount) = escrows.get_unchecked(i as u32);
+            Self::fund_from_balance(&env, &maker, &deposit_token, deposit_amount);
+            order_ids.push_back(Self::save_new_pending_order(
+                &env,
+                &maker,
+                &base_token,
+                &quote_token,
+                is_bid,
+                tick,
+                amount,
+                deposit_amount,
+            )?);
+        }
+
+        Ok(order_ids)
+    }
+
+    /// Place several pending orders on one pair with a single escrow transfer
+    /// per token
+    ///
+    /// `orders` holds `(is_bid, tick, amount)` entries, capped by
+    /// `max_activations_per_block`. Every entry is validated before anything is
+    /// transferred, so one bad entry rejects the whole batch. Crossing entries
+    /// match like `place` first. Returns the new order IDs in the same order,
+    /// 0 for entries with nothing left to rest.
+    pub fn place_batch(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        orders: Vec<(bool, i32, i128)>,
+    ) -> Result<Vec<u128>, Error> {
+        maker.require_auth();
+        Self::require_not_paused(&env)?;
+
+        if orders.len() > storage::get_max_activations_per_block(&env) {
+            return Err(Error::BatchTooLarge);
+        }
+
+        let mut base_deposit: i128 = 0;
+        let mut quote_deposit: i128 = 0;
+        let mut escrows = Vec::new(&env);
+        for (is_bid, tick, amount) in orders.iter() {
+            validate_tick(tick)?;
+            let (_, deposit_amount) =
+                Self::required_escrow(&env, &base_token, &quote_token, is_bid, tick, amount)?;
+            escrows.push_back(deposit_amount);
+            if is_bid {
+                quote_deposit = quote_deposit.checked_add(deposit_amount).ok_or(Error::Overflow)?;
+            } else {
+                base_deposit = base_deposit.checked_add(deposit_amount).ok_or(Error::Overflow)?;
+            }
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        for (token, deposit) in [(&base_token, base_deposit), (&quote_token, quote_deposit)] {
+            if deposit > 0 {
+                token::Client::new(&env, token).transfer(&maker, &env.current_contract_address(), &deposit);
+            }
+        }
+
+        let mut order_ids = Vec::new(&env);
+        for (i, (is_bid, tick, amount)) in orders.iter().enumerate() {
+            order_ids.push_back(Self::save_new_pending_order(
+                &env,
+                &maker,
+                &base_token,
+                &quote_token,
+                is_bid,
+                tick,
+                amount,
+                escrows.get_unchecked(i as u32),
+            )?);
+        }
+
+        Ok(order_ids)
+    }
+
+    /// Set the maximum number of order IDs a single execute_block call accepts (admin only)
+    pub fn set_max_activations_per_block(env: Env, admin: Address, max: u32) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+
+        if max == 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        storage::extend_instance_ttl(&env);
+        storage::set_max_activations_per_block(&env, max);
+        Ok(())
+    }
+
+    /// Get the maximum number of order IDs a single execute_block call accepts
+    pub fn max_activations_per_block(env: Env) -> u32 {
+        storage::extend_instance_ttl(&env);
+        storage::get_max_activations_per_block(&env)
+    }
+
+    /// Execute pending orders (activate them into the orderbook)
+    ///
+    /// Batches larger than `max_activations_per_block` are rejected outright with
+    /// `BatchTooLarge` rather than truncated, so no order is silently left behind.
+    /// Publishes a `block` event with the newly assigned active order IDs and the
+    /// resulting best ticks, so indexers can follow the queue from events alone.
+    ///
+    /// In the original Tempo implementation only the protocol (Address::ZERO) can call
+    /// this during block finalization. This port restricts it to the configured
+    /// sequencer (the admin until one is set) so users cannot selectively activate
+    /// orders to front-run.
+    pub fn execute_block(
+        env: Env,
+        caller: Address,
+        base_token: Address,
+        quote_token: Address,
+        order_ids: Vec<u128>,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        if caller != storage::get_sequencer(&env) {
+            return Err(Error::Unauthorized);
+        }
+        Self::require_not_paused(&env)?;
+        if order_ids.len() > storage::get_max_activations_per_block(&env) {
+            return Err(Error::BatchTooLarge);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let mut orderbook =
+            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+        let (prev_best_bid, prev_best_ask) = (orderbook.best_bid_tick, orderbook.best_ask_tick);
+
+        let mut activated = Vec::new(&env);
+        for order_id in order_ids.iter() {
+            if let Some(pending_order) = order::get_pending_order(&env, order_id) {
+                // Move to active and link into orderbook
+                order::delete_pending_order(&env, &pending_order);
+                activated.push_back(Self::activate_order(&env, &mut orderbook, pending_order)?);
+            }
+        }
+
+        if orderbook.best_bid_tick != prev_best_bid || orderbook.best_ask_tick != prev_best_ask {
+            Self::record_mid_price(&env, &mut orderbook);
+        }
+        save_orderbook(&env, &orderbook);
+        events::emit_block_executed(
+            &env,
+            &base_token,
+            &quote_token,
+            activated,
+            orderbook.best_bid_tick,
+            orderbook.best_ask_tick,
+        );
+        Ok(())
+    }
+
+    /// Pre-create empty tick levels so the first order placed at each tick does
+    /// not pay for creating the entry (admin only). Ticks that already have a
+    /// level are left alone. Empty levels hold no liquidity and are skipped by
+    /// matching; a warmed level is deleted as usual once its last order leaves.
+    /// Returns the number of levels created.
+    pub fn warm_ticks(
+        env: Env,
+        caller: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        ticks: Vec<i32>,
+    ) -> Result<u32, Error> {
+        Self::require_admin(&env, &caller)?;
+
+        if ticks.len() > storage::get_max_activations_per_block(&env) {
+            return Err(Error::BatchTooLarge);
+        }
+        if !has_orderbook(&env, &base_token, &quote_token) {
+            return Err(Error::PairNotFound);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let mut warmed = 0;
+        for tick in ticks.iter() {
+            validate_tick(tick)?;
+            if orderbook::has_tick_level(&env, &base_token, &quote_token, is_bid, tick) {
+                continue;
+            }
+            if is_bid {
+                save_bid_tick_level(&env, &base_token, &quote_token, tick, &TickLevel::default());
+            } else {
+                save_ask_tick_level(&env, &base_token, &quote_token, tick, &TickLevel::default());
+            }
+            warmed += 1;
+        }
+
+        Ok(warmed)
+    }
+
+    /// Seed a pair with protocol-owned liquidity (admin only)
+    ///
+    /// Places `bid_ticks` bids at one, two, ... tick spacings below tick 0 and
+    /// `ask_ticks` asks at the same distances above it, each for
+    /// `amount_per_level` base. Orders are active immediately, owned by the
+    /// admin and escrowed from its exchange balance. Each level is checked like
+    /// any placement: the pair must not be paused, the level must clear the
+    /// pair's minimum size, and one that would cross the resting book fails
+    /// with `WouldCross`. Returns the order IDs, bids first.
+    pub fn seed_pair(
+        env: Env,
+        caller: Address,
+        base_token: Address,
+        quote_token: Address,
+        bid_ticks: u32,
+        ask_ticks: u32,
+        amount_per_level: i128,
+    ) -> Result<Vec<u128>, Error> {
+        Self::require_admin(&env, &caller)?;
+        Self::require_not_paused(&env)?;
+
+        if bid_ticks.saturating_add(ask_ticks) > storage::get_max_activations_per_block(&env) {
+            return Err(Error::BatchTooLarge);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let mut orderbook =
+            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+
+        let mut order_ids = Vec::new(&env);
+        for level in 1..=bid_ticks as i32 {
+            let tick = -level * TICK_SPACING;
+            order_ids.push_back(Self::place_from_balance(
+                &env,
+                &mut orderbook,
+                &caller,
+                true,
+                tick,
+                amount_per_level,
+            )?);
+        }
+        for level in 1..=ask_ticks as i32 {
+            let tick = level * TICK_SPACING;
+            order_ids.push_back(Self::place_from_balance(
+                &env,
+                &mut orderbook,
+                &caller,
+                false,
+                tick,
+                amount_per_level,
+            )?);
+        }
+
+        save_orderbook(&env, &orderbook);
+        Ok(order_ids)
+    }
+
+    /// Cancel an order, crediting its remaining escrow (quote for bids, base for
+    /// asks) less any cancel fee to the maker's exchange balance
+    pub fn cancel(env: Env, maker: Address, order_id: u128) -> Result<i128, Error> {
+        maker.require_auth();
+        storage::extend_instance_ttl(&env);
+
+        // Try pending order first
+        if let Some(pending_order) = order::get_pending_order(&env, order_id) {
+            if pending_order.maker != maker {
+                return Err(Error::NotOrderOwner);
+            }
+            return Ok(Self::cancel_pending_order(&env, &pending_order));
+        }
+
+        // Try active order
+        if let Some(active_order) = order::get_order(&env, order_id) {
+            if active_order.maker != maker {
+                return Err(Error::NotOrderOwner);
+            }
+            return Self::cancel_active_order(&env, &active_order);
+        }
+
+        Err(Error::OrderNotFound)
+    }
+
+    /// Cancel every pending and active order `maker` has in a pair, crediting
+    /// each refund as `cancel` does
+    ///
+    /// Returns the total refunded, quote from bids plus base from asks, and 0
+    /// when the maker has nothing to cancel.
+    pub fn cancel_all(
+        env: Env,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+    ) -> Result<i128, Error> {
+        maker.require_auth();
+        storage::extend_instance_ttl(&env);
+
+        let in_pair =
+            |order: &Order| order.base_token == base_token && order.quote_token == quote_token;
+        let mut refunded: i128 = 0;
+
+        for order_id in storage::get_maker_order_ids(&env, &maker, true).iter() {
+            if let Some(pending_order) = order::get_pending_order(&env, order_id) {
+                if in_pair(&pending_order) {
+                    refunded += Self::cancel_pending_order(&env, &pending_order);
+                }
+            }
+        }
+
+        for order_id in storage::get_maker_order_ids(&env, &maker, false).iter() {
+            if let Some(active_order) = order::get_order(&env, order_id) {
+                if in_pair(&active_order) {
+                    refunded += Self::cancel_active_order(&env, &active_order)?;
+                }
+            }
+        }
+
+        Ok(refunded)
+    }
+
+    /// Shrink an active order to `new_amount` remaining without losing its
+    /// queue position, crediting the freed escrow (less any cancel fee) to the
+    /// maker's exchange balance. Reducing to zero cancels the order.
+    ///
+    /// A reduction is a partial cancel, so a fresh order pays the cancel fee on
+    /// the freed escrow; otherwise amending down to the minimum size and then
+    /// canceling would dodge most of the fee.
+    pub fn amend(env: Env, maker: Address, order_id: u128, new_amount: i128) -> Result<(), Error> {
+        maker.require_auth();
+        storage::extend_instance_ttl(&env);
+
+        let mut active_order = order::get_order(&env, order_id).ok_or(Error::OrderNotFound)?;
+        if active_order.maker != maker {
+            return Err(Error::NotOrderOwner);
+        }
+        if new_amount < 0 || new_amount >= active_order.remaining {
+            return Err(Error::InvalidAmount);
+        }
+        if new_amount == 0 {
+            Self::cancel_active_order(&env, &active_order)?;
+            return Ok(());
+        }
+
+        let base_token = active_order.base_token.clone();
+        let quote_token = active_order.quote_token.clone();
+        let orderbook = get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+        if new_amount < orderbook.min_order_size {
+            return Err(Error::OrderTooSmall);
+        }
+        let tick = active_order.tick;
+        let reduction = active_order.remaining - new_amount;
+
+        let mut level = if active_order.is_bid {
+            get_bid_tick_level(&env, &base_token, &quote_token, tick)
+        } else {
+            get_ask_tick_level(&env, &base_token, &quote_token, tick)
+        };
+        level.total_liquidity -= reduction;
+        if active_order.is_bid {
+            save_bid_tick_level(&env, &base_token, &quote_token, tick, &level);
+        } else {
+            save_ask_tick_level(&env, &base_token, &quote_token, tick, &level);
+        }
+
+        // Shrink the original amount too, so a flip order's flipped side stays
+        // backed by what actually filled
+        let (refund_token, escrow_before) = active_order.remaining_escrow();
+        active_order.remaining = new_amount;
+        active_order.amount -= reduction;
+        let (_, escrow_after) = active_order.remaining_escrow();
+        let freed = escrow_before - escrow_after;
+        storage::add_pair_escrow(&env, &base_token, &quote_token, active_order.is_bid, -freed);
+        order::save_order(&env, &active_order);
+
+        let refund = Self::charge_cancel_fee(&env, &active_order, freed);
+        storage::add_balance(&env, &maker, &refund_token, refund);
+
+        events::emit_order_amended(&env, order_id, &maker, &base_token, &quote_token, new_amount, refund);
+        Ok(())
+    }
+
+    /// Match the best bid against the best ask until the book is no longer
+    /// crossed, returning the base amount matched (admin only, recovery tool)
+    ///
+    /// Each match trades at the bid's tick, so the ask maker is credited
+    /// exactly the quote the bid escrowed for it. Fill limits are overridden.
+    pub fn force_uncross(env: Env, caller: Address, base_token: Address, quote_token: Address) -> Result<i128, Error> {
+        Self::require_admin(&env, &caller)?;
+        storage::extend_instance_ttl(&env);
+
+        let mut orderbook =
+            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+
+        let mut total_matched: i128 = 0;
+        while orderbook.has_bids() && orderbook.has_asks() && orderbook.best_bid_tick >= orderbook.best_ask_tick {
+            let bid_level = get_bid_tick_level(&env, &base_token, &quote_token, orderbook.best_bid_tick);
+            if bid_level.is_empty() {
+                update_best_bid_tick(&env, &mut orderbook);
+                continue;
+            }
+            let ask_level = get_ask_tick_level(&env, &base_token, &quote_token, orderbook.best_ask_tick);
+            if ask_level.is_empty() {
+                update_best_ask_tick(&env, &mut orderbook);
+                continue;
+            }
+
+            let bid = order::get_order(&env, bid_level.head).ok_or(Error::OrderNotFound)?;
+            let ask = order::get_order(&env, ask_level.head).ok_or(Error::OrderNotFound)?;
+            let fill_amount = bid.remaining.min(ask.remaining);
+            let quote_amount = calculate_quote_amount(fill_amount, bid.tick);
+
+            Self::force_fill(&env, &mut orderbook, bid, fill_amount, &base_token, fill_amount)?;
+            Self::force_fill(&env, &mut orderbook, ask, fill_amount, &quote_token, quote_amount)?;
+            total_matched += fill_amount;
+        }
+
+        save_orderbook(&env, &orderbook);
+
+        Ok(total_matched)
+    }
+
+    // ============ Swap Execution ============
+
+    /// Swap exact amount in (taker sells exact amount)
+    ///
+    /// `min_fill_ratio_bps` is the minimum share of `amount_in` (in basis points)
+    /// that must be consumed by the book; a thinner fill reverts the whole swap.
+    /// The output is delivered to `to`, while any unused input is refunded to `taker`.
+    ///
+    /// With `amm_fallback` set, input the book leaves unused is instead swapped
+    /// through that AMM pool and its output combined with the book's;
+    /// `min_amount_out` applies to the total. The pool must be the one set with
+    /// `set_amm_pool`, and its output is measured as the exchange's balance gain.
+    ///
+    /// With `stp` (self-trade prevention) set, the taker's own resting orders
+    /// reached by the swap are canceled and refunded to their balance instead
+    /// of being filled.
+    pub fn swap_exact_in(
+        env: Env,
+        taker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_buy: bool, // true = buy base with quote, false = sell base for quote
+        amount_in: i128,
+        min_amount_out: i128,
+        min_fill_ratio_bps: u32,
+        to: Address,
+        amm_fallback: Option<Address>,
+        stp: bool,
+    ) -> Result<i128, Error> {
+        taker.require_auth();
+
+        if min_fill_ratio_bps as i128 > BPS_SCALE {
+            return Err(Error::InvalidAmount);
+        }
+        if let Some(amm) = &amm_fallback {
+            if storage::get_amm_pool(&env).as_ref() != Some(amm) {
+                return Err(Error::AmmNotAllowed);
+            }
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let orderbook = Self::swap_orderbook(&env, &base_token, &quote_token)?;
+
+        // Transfer input tokens from taker
+        let (input_token, output_token) = if is_buy {
+            (&quote_token, &base_token)
+        } else {
+            (&base_token, &quote_token)
+        };
+        let token_client = token::Client::new(&env, input_token);
+        token_client.transfer(&taker, &env.current_contract_address(), &amount_in);
+
+        let limit_tick = if is_buy { MAX_TICK } else { MIN_TICK };
+        let result = Self::execute_swap(&env, &taker, orderbook, is_buy, amount_in, limit_tick, stp, i128::MAX)?;
+        let (mut amount_out, mut refund) = (result.amount_out, result.refund);
+
+        // Check the book absorbed enough of the input
+        let amount_consumed = amount_in - refund;
+        if amount_consumed * BPS_SCALE < amount_in * min_fill_ratio_bps as i128 {
+            return Err(Error::FillRatioNotMet);
+        }
+
+        // Route whatever the book left over through the AMM instead of refunding it
+        if let Some(amm) = &amm_fallback {
+            if refund > 0 {
+                let min_amm_out = (min_amount_out - amount_out).max(0);
+                amount_out += amm::swap_exact_in(&env, amm, input_token, output_token, refund, min_amm_out);
+                refund = 0;
+            }
+        }
+
+        // Check slippage
+        if amount_out < min_amount_out {
+            return Err(Error::SlippageExceeded);
+        }
+
+        // Refund unused input
+        if refund > 0 {
+            token_client.transfer(&env.current_contract_address(), &taker, &refund);
+        }
+
+        // Transfer output to recipient
+        let out_token_client = token::Client::new(&env, output_token);
+        out_token_client.transfer(&env.current_contract_address(), &to, &amount_out);
+
+        Ok(amount_out)
+    }
+
+    /// Swap against the book without filling past `limit_tick`: buys stop
+    /// above it, sells below it, and the unmatched input is refunded
+    ///
+    /// Returns the detailed `SwapResult`, including the price improvement of
+    /// the fill's VWAP over the limit price. `stp` is as in `swap_exact_in`.
+    pub fn swap_with_limit(
+        env: Env,
+        taker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_buy: bool,
+        amount_in: i128,
+        limit_tick: i32,
+        min_amount_out: i128,
+        stp: bool,
+    ) -> Result<SwapResult, Error> {
+        taker.require_auth();
+        validate_tick(limit_tick)?;
+
+        if amount_in <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let orderbook = Self::swap_orderbook(&env, &base_token, &quote_token)?;
+
+        let (input_token, output_token) = if is_buy {
+            (&quote_token, &base_token)
+        } else {
+            (&base_token, &quote_token)
+        };
+        let token_client = token::Client::new(&env, input_token);
+        token_client.transfer(&taker, &env.current_contract_address(), &amount_in);
+
+        let result = Self::execute_swap(&env, &taker, orderbook, is_buy, amount_in, limit_tick, stp, i128::MAX)?;
+
+        if result.amount_out < min_amount_out {
+            return Err(Error::SlippageExceeded);
+        }
+
+        if result.refund > 0 {
+            token_client.transfer(&env.current_contract_address(), &taker, &result.refund);
+        }
+        if result.amount_out > 0 {
+            token::Client::new(&env, output_token).transfer(
+                &env.current_contract_address(),
+                &taker,
+                &result.amount_out,
+            );
+        }
+
+        Ok(result)
+    }
+
+    /// Immediate-or-cancel limit order: match up to `amount` base against the
+    /// opposite side at `tick` or better and refund the rest instead of
+    /// resting it. With `fill_or_kill` the whole call reverts with
+    /// `SlippageExceeded` unless all of `amount` fills.
+    ///
+    /// Bids pay in up to `amount` at the limit price and get back whatever
+    /// better prices saved.
+    pub fn place_immediate(
+        env: Env,
+        taker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        fill_or_kill: bool,
+    ) -> Result<SwapResult, Error> {
+        taker.require_auth();
+        validate_tick(tick)?;
+
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let orderbook = Self::swap_orderbook(&env, &base_token, &quote_token)?;
+
+        let (input_token, output_token, amount_in) = if is_bid {
+            (&quote_token, &base_token, calculate_quote_amount_ceil(amount, tick))
+        } else {
+            (&base_token, &quote_token, amount)
+        };
+        let token_client = token::Client::new(&env, input_token);
+        token_client.transfer(&taker, &env.current_contract_address(), &amount_in);
+
+        let result = Self::execute_swap(&env, &taker, orderbook, is_bid, amount_in, tick, false, amount)?;
+
+        if fill_or_kill && result.base_amount < amount {
+            return Err(Error::SlippageExceeded);
+        }
+
+        if result.refund > 0 {
+            token_client.transfer(&env.current_contract_address(), &taker, &result.refund);
+        }
+        if result.amount_out > 0 {
+            token::Client::new(&env, output_token).transfer(
+                &env.current_contract_address(),
+                &taker,
+                &result.amount_out,
+            );
+        }
+
+        Ok(result)
+    }
+
+    /// Swap `amount_in` of `path[0]` through each consecutive pair in `path`,
+    /// delivering the last token to the taker
+    ///
+    /// Each hop sells into whichever of the two pair orientations exists.
+    /// `per_hop_min` is empty or holds the minimum output of every hop; a hop
+    /// falling short aborts the whole route with `SlippageExceeded`, as does a
+    /// final output below `min_amount_out`. Intermediate tokens stay in the
+    /// contract: input a hop cannot fill is credited to the taker's exchange
+    /// balance, except on the first hop where it is refunded. `stp` is as in
+    /// `swap_exact_in` and applies to every hop.
+    pub fn swap_route(
+        env: Env,
+        taker: Address,
+        path: Vec<Address>,
+        amount_in: i128,
+        min_amount_out: i128,
+        per_hop_min: Vec<i128>,
+        stp: bool,
+    ) -> Result<i128, Error> {
+        taker.require_auth();
+
+        let hops = path.len().saturating_sub(1);
+        if hops == 0 || !(per_hop_min.is_empty() || per_hop_min.len() == hops) {
+            return Err(Error::InvalidPath);
+        }
+        if amount_in <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        storage::extend_instance_ttl(&env);
+
+        let first_token = path.get_unchecked(0);
+        token::Client::new(&env, &first_token).transfer(&taker, &env.current_contract_address(), &amount_in);
+
+        let mut amount = amount_in;
+        for hop in 0..hops {
+            let token_in = path.get_unchecked(hop);
+            let token_out = path.get_unchecked(hop + 1);
+
+            // Buying base when the output token is the pair's base, selling it otherwise
+            let (orderbook, is_buy) = if let Some(orderbook) = get_orderbook(&env, &token_out, &token_in) {
+                (orderbook, true)
+            } else {
+                (get_orderbook(&env, &token_in, &token_out).ok_or(Error::PairNotFound)?, false)
+            };
+
+            let limit_tick = if is_buy { MAX_TICK } else { MIN_TICK };
+            let SwapResult { amount_out, refund, .. } =
+                Self::execute_swap(&env, &taker, orderbook, is_buy, amount, limit_tick, stp, i128::MAX)?;
+            if !per_hop_min.is_empty() && amount_out < per_hop_min.get_unchecked(hop) {
+                return Err(Error::SlippageExceeded);
+            }
+
+            if refund > 0 {
+                if hop == 0 {
+                    token::Client::new(&env, &token_in).transfer(&env.current_contract_address(), &taker, &refund);
+                } else {
+                    storage::add_balance(&env, &taker, &token_in, refund);
+                }
+            }
+            amount = amount_out;
+        }
+
+        if amount < min_amount_out {
+            return Err(Error::SlippageExceeded);
+        }
+
+        let last_token = path.get_unchecked(hops);
+        token::Client::new(&env, &last_token).transfer(&env.current_contract_address(), &taker, &amount);
+
+        Ok(amount)
+    }
+
+    /// Set how many trades are kept per pair in the trade history (admin only)
+    pub fn set_trade_buffer_size(env: Env, admin: Address, size: u32) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+
+        if size == 0 || size > storage::MAX_TRADE_BUFFER_SIZE {
+            return Err(Error::InvalidAmount);
+        }
+
+        storage::extend_instance_ttl(&env);
+        storage::set_trade_buffer_size(&env, size);
+        Ok(())
+    }
+
+    /// Number of trades kept per pair in the trade history
+    pub fn trade_buffer_size(env: Env) -> u32 {
+        storage::extend_instance_ttl(&env);
+        storage::get_trade_buffer_size(&env)
+    }
+
+    /// Up to `count` of the pair's most recent swaps, oldest first
+    pub fn get_recent_trades(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+        count: u32,
+    ) -> Vec<Trade> {
+        storage::extend_instance_ttl(&env);
+
+        let total = orderbook::get_trade_count(&env, &base_token, &quote_token);
+        let buffer_size = storage::get_trade_buffer_size(&env);
+        let first = total.saturating_sub(count.min(buffer_size));
+
+        let mut trades = Vec::new(&env);
+        for index in first..total {
+            if let Some(trade) =
+                orderbook::get_trade(&env, &base_token, &quote_token, index, buffer_size)
+            {
+                trades.push_back(trade);
+            }
+        }
+        trades
+    }
+
+    /// Set how many fills are kept per maker in the fill history (admin only)
+    pub fn set_maker_fill_buffer_size(env: Env, admin: Address, size: u32) -> Result<(), Error> {
+        Self::require_admin(&env, &admin)?;
+
+        if size == 0 || size > storage::MAX_MAKER_FILL_BUFFER_SIZE {
+            return Err(Error::InvalidAmount);
+        }
+
+        storage::extend_instance_ttl(&env);
+        storage::set_maker_fill_buffer_size(&env, size);
+        Ok(())
+    }
+
+    /// Number of fills kept per maker in the fill history
+    pub fn maker_fill_buffer_size(env: Env) -> u32 {
+        storage::extend_instance_ttl(&env);
+        storage::get_maker_fill_buffer_size(&env)
+    }
+
+    /// Up to `count` of the maker's most recent fills across all pairs, oldest first
+    pub fn get_maker_fills(env: Env, maker: Address, count: u32) -> Vec<MakerFill> {
+        storage::extend_instance_ttl(&env);
+
+        let total = order::get_maker_fill_count(&env, &maker);
+        let buffer_size = storage::get_maker_fill_buffer_size(&env);
+        let first = total.saturating_sub(count.min(buffer_size));
+
+        let mut fills = Vec::new(&env);
+        for index in first..total {
+            if let Some(fill) = order::get_maker_fill(&env, &maker, index, buffer_size) {
+                fills.push_back(fill);
+            }
+        }
+        fills
+    }
+
+    /// Heuristic chance, in basis points, that an active order fills within
+    /// `horizon_blocks` ledgers
+    ///
+    /// `velocity` is the base volume of recorded trades on the side that consumes
+    /// the order (buys for asks, sells for bids), divided by the seconds from the
+    /// oldest such trade to now (at least one ledger). With `ahead` the liquidity
+    /// at better ticks plus the orders queued before it at its own tick:
+    ///
+    /// `min(10000, velocity * horizon_blocks * 5s * 10000 / (ahead + remaining))`
+    pub fn estimate_fill_probability(
+        env: Env,
+        order_id: u128,
+        horizon_blocks: u32,
+    ) -> Result<u32, Error> {
+        storage::extend_instance_ttl(&env);
+
+        let order = order::get_order(&env, order_id).ok_or(Error::OrderNotFound)?;
+        let needed = Self::liquidity_ahead(&env, &order)? + order.remaining;
+
+        let total = orderbook::get_trade_count(&env, &order.base_token, &order.quote_token);
+        let buffer_size = storage::get_trade_buffer_size(&env);
+        let now = env.ledger().timestamp();
+
+        let mut volume: i128 = 0;
+        let mut oldest = now;
+        for index in total.saturating_sub(buffer_size)..total {
+            let Some(trade) =
+                orderbook::get_trade(&env, &order.base_token, &order.quote_token, index, buffer_size)
+            else {
+                continue;
+            };
+            // A trade consumes asks when the taker buys and bids when it sells
+            if trade.is_buy == order.is_bid {
+                continue;
+            }
+            volume += trade.base_amount;
+            oldest = oldest.min(trade.timestamp);
+        }
+
+        if volume == 0 || needed <= 0 {
+            return Ok(0);
+        }
+
+        let window = now.saturating_sub(oldest).max(SECONDS_PER_LEDGER);
+        let horizon = horizon_blocks as u64 * SECONDS_PER_LEDGER;
+        let expected = volume * horizon as i128 / window as i128;
+
+        Ok((expected * BPS_SCALE / needed).min(BPS_SCALE) as u32)
+    }
+
+    /// Quote swap exact amount in, net of the taker fee
+    pub fn quote_swap_in(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+        is_buy: bool,
+        amount_in: i128,
+    ) -> Result<i128, Error> {
+        let (net_out, _fee) =
+            Self::quote_swap_in_with_fee(env, base_token, quote_token, is_buy, amount_in)?;
+        Ok(net_out)
+    }
+
+    /// Quote swap exact amount in, returning `(net_out, fee)` where `net_out` is
+    /// what the taker receives and `fee` is the pair's trading fee taken from
+    /// the output, or charged in quote at the fill ticks under `fee_in_quote_only`.
+    pub fn quote_swap_in_with_fee(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+        is_buy: bool,
+        amount_in: i128,
+    ) -> Result<(i128, i128), Error> {
+        storage::extend_instance_ttl(&env);
+
+        let orderbook = Self::swap_orderbook(&env, &base_token, &quote_token)?;
+
+        let fee_in_quote = storage::get_fee_in_quote_only(&env);
+        if is_buy && fee_in_quote {
+            let budget = Self::quote_fee_budget(amount_in, orderbook.fee_bps);
+            let (used_in, total_out, _) = Self::quote_gross_out(&env, &orderbook, is_buy, budget);
+            return Ok((total_out, calculate_fee(used_in, orderbook.fee_bps)));
+        }
+
+        let (_, total_out, sell_quote_fee) = Self::quote_gross_out(&env, &orderbook, is_buy, amount_in);
+        let fee = if fee_in_quote {
+            sell_quote_fee
+        } else {
+            calculate_fee(total_out, orderbook.fee_bps)
+        };
+
+        Ok((total_out - fee, fee))
+    }
+
+    /// Price at the last tick a swap of `amount_in` would touch (the post-trade
+    /// marginal price, not the average execution price)
+    pub fn marginal_price_after(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+        is_buy: bool,
+        amount_in: i128,
+    ) -> Result<i128, Error> {
+        storage::extend_instance_ttl(&env);
+
+        let orderbook = Self::swap_orderbook(&env, &base_token, &quote_token)?;
+
+        let mut remaining_in = amount_in;
+        let mut last_tick: Option<i32> = None;
+
+        if is_buy {
+            let mut tick = orderbook.best_ask_tick;
+            while remaining_in > 0 && tick <= MAX_TICK {
+                let level = get_ask_tick_level(&env, &base_token, &quote_token, tick);
+                if !level.is_empty() {
+                    let (used_in, filled_base) =
+                        Self::quote_level_fill(&env, &level, tick, true, remaining_in);
+
+                    if filled_base > 0 {
+                        remaining_in -= used_in;
+                        last_tick = Some(tick);
+                    }
+                }
+                tick += TICK_SPACING;
+            }
+        } else {
+            let mut tick = orderbook.best_bid_tick;
+            while remaining_in > 0 && tick >= MIN_TICK {
+                let level = get_bid_tick_level(&env, &base_token, &quote_token, tick);
+                if !level.is_empty() {
+                    let (used_in, _) =
+                        Self::quote_level_fill(&env, &level, tick, false, remaining_in);
+
+                    if used_in > 0 {
+                        remaining_in -= used_in;
+                        last_tick = Some(tick);
+                    }
+                }
+                tick -= TICK_SPACING;
+            }
+        }
+
+        last_tick.map(tick_to_price).ok_or(Error::NoLiquidity)
+    }
+
+    // ============ Balance Management ============
+
+    /// Get user's exchange balance for a token
+    pub fn balance_of(env: Env, user: Address, token: Address) -> i128 {
+        storage::extend_instance_ttl(&env);
+        storage::get_balance(&env, &user, &token)
+    }
+
+    /// Net base `user` has bought (positive) or sold (negative) through fills
+    /// in a pair, as maker or taker
+    pub fn position(env: Env, user: Address, base_token: Address, quote_token: Address) -> i128 {
+        storage::extend_instance_ttl(&env);
+        storage::get_position(&env, &user, &base_token, &quote_token)
+    }
+
+    /// A user's active and pending order IDs on a pair plus their internal balances
+    /// of both tokens; each order list is capped at MAX_SUMMARY_ORDERS
+    pub fn get_user_summary(
+        env: Env,
+        user: Address,
+        base_token: Address,
+        quote_token: Address,
+    ) -> UserSummary {
+        storage::extend_instance_ttl(&env);
+
+        let mut active_order_ids = Vec::new(&env);
+        for order_id in storage::get_maker_order_ids(&env, &user, false).iter() {
+            if active_order_ids.len() >= MAX_SUMMARY_ORDERS {
+                break;
+            }
+            if let Some(order) = order::get_order(&env, order_id) {
+                if order.base_token == base_token && order.quote_token == quote_token {
+                    active_order_ids.push_back(order_id);
+                }
+            }
+        }
+
+        let mut pending_order_ids = Vec::new(&env);
+        for order_id in storage::get_maker_order_ids(&env, &user, true).iter() {
+            if pending_order_ids.len() >= MAX_SUMMARY_ORDERS {
+                break;
+            }
+            if let Some(order) = order::get_pending_order(&env, order_id) {
+                if order.base_token == base_token && order.quote_token == quote_token {
+                    pending_order_ids.push_back(order_id);
+                }
+            }
+        }
+
+        UserSummary {
+            active_order_ids,
+            pending_order_ids,
+            base_balance: storage::get_balance(&env, &user, &base_token),
+            quote_balance: storage::get_balance(&env, &user, &quote_token),
+        }
+    }
+
+    /// Withdraw tokens from exchange balance
+    pub fn withdraw(env: Env, user: Address, token: Address, amount: i128) -> Result<(), Error> {
+        user.require_auth();
+        storage::extend_instance_ttl(&env);
+
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        if !storage::sub_balance(&env, &user, &token, amount) {
+            return Err(Error::InsufficientBalance);
+        }
+
+        // Accounting should make this unreachable; fail with a typed error
+        // rather than an opaque token transfer failure if it ever breaks
+        let token_client = token::Client::new(&env, &token);
+        if token_client.balance(&env.current_contract_address()) < amount {
+            return Err(Error::InsufficientReserves);
+        }
+
+        token_client.transfer(&env.current_contract_address(), &user, &amount);
+
+        events::emit_withdraw(&env, &user, &token, amount);
+
+        Ok(())
+    }
+
+    /// Credit tokens to the caller's exchange balance, e.g. to fund the admin
+    /// before seeding a pair
+    pub fn deposit(env: Env, user: Address, token: Address, amount: i128) -> Result<(), Error> {
+        user.require_auth();
+        storage::extend_instance_ttl(&env);
+
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        let token_client = token::Client::new(&env, &token);
+        token_client.transfer(&user, &env.current_contract_address(), &amount);
+        storage::add_balance(&env, &user, &token, amount);
+
+        Ok(())
+    }
+
+    // ============ View Functions ============
+
+    /// Get order by ID
+    pub fn get_order(env: Env, order_id: u128) -> Option<Order> {
+        storage::extend_instance_ttl(&env);
+        order::get_order(&env, order_id)
+    }
+
+    /// Escrow locked by a pair's resting orders as `(base from asks, quote from bids)`,
+    /// valuing each bid's unfilled remainder at its tick
+    pub fn get_pair_escrow(env: Env, base_token: Address, quote_token: Address) -> (i128, i128) {
+        storage::extend_instance_ttl(&env);
+        storage::get_pair_escrow(&env, &base_token, &quote_token)
+    }
+
+    /// Escrow locked by a pair's pending orders as `(base from asks, quote from bids)`
+    pub fn get_pending_escrow(env: Env, base_token: Address, quote_token: Address) -> (i128, i128) {
+        storage::extend_instance_ttl(&env);
+        storage::get_pending_escrow(&env, &base_token, &quote_token)
+    }
+
+    /// Get pending order by ID
+    pub fn get_pending_order(env: Env, order_id: u128) -> Option<Order> {
+        storage::extend_instance_ttl(&env);
+        order::get_pending_order(&env, order_id)
+    }
+
+    /// Up to `limit` pending order IDs starting at position `start` of the
+    /// pending queue, oldest first, for sequencers paging through activations.
+    /// The queue is read page by page from the oldest non-empty page, stopping
+    /// once `limit` IDs are collected.
+    pub fn get_pending(env: Env, start: u32, limit: u32) -> Vec<u128> {
+        storage::extend_instance_ttl(&env);
+        let mut result = Vec::new(&env);
+        let mut skip = start;
+        let last = storage::get_pending_last_page(&env);
+        let mut page = storage::get_pending_first_page(&env);
+        while page <= last && result.len() < limit {
+            let ids = storage::get_pending_page(&env, page);
+            if skip >= ids.len() {
+                skip -= ids.len();
+            } else {
+                let end = ids.len().min(skip.saturating_add(limit - result.len()));
+                result.append(&ids.slice(skip..end));
+                skip = 0;
+            }
+            page += 1;
+        }
+        result
+    }
+
+    /// Hash of the pair's book state (best ticks, order ID counters and populated
+    /// levels) so light clients can detect any change with one call. The counters
+    /// are shared by all pairs, so placing on another pair changes it too.
+    pub fn orderbook_state_hash(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+    ) -> Result<BytesN<32>, Error> {
+        storage::extend_instance_ttl(&env);
+
+        let orderbook =
+            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+
+        Ok(orderbook::state_hash(
+            &env,
+            &orderbook,
+            storage::get_current_active_order_id(&env),
+            storage::get_current_pending_order_id(&env),
+        ))
+    }
+
+    /// Summary of the pair's best ticks, level counts, order ID counters and
+    /// active escrow. The counters are shared by all pairs, so activity on
+    /// another pair also moves the checkpoint.
+    pub fn checkpoint(env: Env, base_token: Address, quote_token: Address) -> Result<Checkpoint, Error> {
+        storage::extend_instance_ttl(&env);
+
+        let orderbook =
+            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+        let (escrow_base, escrow_quote) = storage::get_pair_escrow(&env, &base_token, &quote_token);
+
+        Ok(Checkpoint {
+            best_bid_tick: orderbook.best_bid_tick,
+            best_ask_tick: orderbook.best_ask_tick,
+            bid_levels: orderbook.bid_levels,
+            ask_levels: orderbook.ask_levels,
+            next_active_id: storage::get_current_active_order_id(&env),
+            next_pending_id: storage::get_current_pending_order_id(&env),
+            escrow_base,
+            escrow_quote,
+            base_token,
+            quote_token,
+        })
+    }
+
+    /// Whether `checkpoint` still matches the pair's current state
+    pub fn verify_checkpoint(env: Env, checkpoint: Checkpoint) -> Result<bool, Error> {
+        let current = Self::checkpoint(
+            env,
+            checkpoint.base_token.clone(),
+            checkpoint.quote_token.clone(),
+        )?;
+        Ok(current == checkpoint)
+    }
+
+    /// Page through a pair's active orders, ordered by tick (ascending, bids before
+    /// asks at the same tick) and then by queue position
+    ///
+    /// Pass `cursor = 0` for the first page, then the returned cursor for each
+    /// following page. A returned cursor of 0 means the book is exhausted.
+    pub fn get_active_orders_paginated(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+        cursor: u128,
+        limit: u32,
+    ) -> Result<(Vec<Order>, u128), Error> {
+        storage::extend_instance_ttl(&env);
+
+        let orderbook =
+            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+
+        // Start just before the first slot, or at the cursor order's queue position
+        let (mut tick, mut is_ask, mut current_id) = if cursor == 0 {
+            (MIN_TICK - TICK_SPACING, true, 0)
+        } else {
+            let start = order::get_order(&env, cursor).ok_or(Error::OrderNotFound)?;
+            if start.base_token != base_token || start.quote_token != quote_token {
+                return Err(Error::OrderNotFound);
+            }
+            (start.tick, !start.is_bid, cursor)
+        };
+
+        let mut orders = Vec::new(&env);
+
+        loop {
+            if current_id == 0 {
+                match next_level_head(&env, &orderbook, tick, is_ask) {
+                    Some((next_tick, next_is_ask, head)) => {
+                        tick = next_tick;
+                        is_ask = next_is_ask;
+                        current_id = head;
+                    }
+                    None => break,
+                }
+            }
+
+            if orders.len() >= limit {
+                break;
+            }
+
+            let current = order::get_order(&env, current_id).ok_or(Error::OrderNotFound)?;
+            current_id = current.next;
+            orders.push_back(current);
+        }
+
+        Ok((orders, current_id))
+    }
+
+    /// Get tick level
+    pub fn get_tick_level(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+    ) -> TickLevel {
+        storage::extend_instance_ttl(&env);
+        if is_bid {
+            get_bid_tick_level(&env, &base_token, &quote_token, tick)
+        } else {
+            get_ask_tick_level(&env, &base_token, &quote_token, tick)
+        }
+    }
+
+    /// Up to `max_levels` `(tick, total_liquidity)` pairs for one side of the
+    /// book, walking outward from the best tick (descending for bids,
+    /// ascending for asks). Empty for an unknown pair or an empty side.
+    pub fn get_depth(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        max_levels: u32,
+    ) -> Vec<(i32, i128)> {
+        storage::extend_instance_ttl(&env);
+        let mut depth = Vec::new(&env);
+        let Some(orderbook) = get_orderbook(&env, &base_token, &quote_token) else {
+            return depth;
+        };
+
+        let mut next = if is_bid {
+            orderbook.has_bids().then_some(orderbook.best_bid_tick)
+        } else {
+            orderbook.has_asks().then_some(orderbook.best_ask_tick)
+        };
+        while depth.len() < max_levels {
+            let Some(tick) = next else {
+                break;
+            };
+            let level = if is_bid {
+                get_bid_tick_level(&env, &base_token, &quote_token, tick)
+            } else {
+                get_ask_tick_level(&env, &base_token, &quote_token, tick)
+            };
+            if level.total_liquidity > 0 {
+                depth.push_back((tick, level.total_liquidity));
+            }
+            next = if is_bid {
+                find_next_bid_tick(&env, &base_token, &quote_token, tick - TICK_SPACING)
+            } else {
+                find_next_ask_tick(&env, &base_token, &quote_token, tick + TICK_SPACING)
+            };
+        }
+        depth
+    }
+
+    /// Resting base liquidity on one side of the book summed over the
+    /// non-empty levels from `tick_low` to `tick_high` inclusive
+    pub fn liquidity_in_range(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick_low: i32,
+        tick_high: i32,
+    ) -> Result<i128, Error> {
+        validate_tick(tick_low)?;
+        validate_tick(tick_high)?;
+        if tick_low > tick_high {
+            return Err(Error::InvalidTick);
+        }
+
+        storage::extend_instance_ttl(&env);
+        if !has_orderbook(&env, &base_token, &quote_token) {
+            return Err(Error::PairNotFound);
+        }
+
+        let mut liquidity: i128 = 0;
+        let mut tick = tick_low;
+        while tick <= tick_high {
+            let level = if is_bid {
+                get_bid_tick_level(&env, &base_token, &quote_token, tick)
+            } else {
+                get_ask_tick_level(&env, &base_token, &quote_token, tick)
+            };
+            if !level.is_empty() {
+                liquidity += level.total_liquidity;
+            }
+            tick += TICK_SPACING;
+        }
+        Ok(liquidity)
+    }
+
+    /// Best bid tick and its total liquidity, or None for an unknown pair or
+    /// an empty bid side
+    pub fn best_bid(env: Env, base_token: Address, quote_token: Address) -> Option<(i32, i128)> {
+        storage::extend_instance_ttl(&env);
+        let orderbook = get_orderbook(&env, &base_token, &quote_token)?;
+        if !orderbook.has_bids() {
+            return None;
+        }
+        let level = get_bid_tick_level(&env, &base_token, &quote_token, orderbook.best_bid_tick);
+        Some((orderbook.best_bid_tick, level.total_liquidity))
+    }
+
+    /// Best ask tick and its total liquidity, or None for an unknown pair or
+    /// an empty ask side
+    pub fn best_ask(env: Env, base_token: Address, quote_token: Address) -> Option<(i32, i128)> {
+        storage::extend_instance_ttl(&env);
+        let orderbook = get_orderbook(&env, &base_token, &quote_token)?;
+        if !orderbook.has_asks() {
+            return None;
+        }
+        let level = get_ask_tick_level(&env, &base_token, &quote_token, orderbook.best_ask_tick);
+        Some((orderbook.best_ask_tick, level.total_liquidity))
+    }
+
+    /// Best ask tick minus best bid tick, or None unless both sides have liquidity
+    pub fn spread_ticks(env: Env, base_token: Address, quote_token: Address) -> Option<i32> {
+        storage::extend_instance_ttl(&env);
+        let orderbook = get_orderbook(&env, &base_token, &quote_token)?;
+        if !orderbook.has_bids() || !orderbook.has_asks() {
+            return None;
+        }
+        Some(orderbook.best_ask_tick - orderbook.best_bid_tick)
+    }
+
+    /// Time-weighted average mid price over the last `window_ledgers` ledgers,
+    /// scaled by PRICE_SCALE
+    ///
+    /// The mid price is sampled after every swap and every `execute_block` that
+    /// moves a best tick. Fails with `InsufficientHistory` when the window starts
+    /// before the pair's oldest retained observation.
+    pub fn get_twap(
+        env: Env,
+        base_token: Address,
+        quote_token: Address,
+        window_ledgers: u32,
+    ) -> Result<i128, Error> {
+        storage::extend_instance_ttl(&env);
+        if window_ledgers == 0 {
+            return Err(Error::InvalidAmount);
+        }
+        let orderbook = get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+
+        let now = env.ledger().sequence();
+        let start = now.checked_sub(window_ledgers).ok_or(Error::InsufficientHistory)?;
+        let start_cumulative = Self::price_cumulative_at(&env, &orderbook, start)?;
+        Ok((orderbook.price_cumulative_at(now) - start_cumulative) / window_ledgers as i128)
+    }
+
+    /// Get constants
+    pub fn min_tick() -> i32 {
+        MIN_TICK
+    }
+
+    pub fn max_tick() -> i32 {
+        MAX_TICK
+    }
+
+    pub fn tick_spacing() -> i32 {
+        TICK_SPACING
+    }
+
+    pub fn price_scale() -> i128 {
+        PRICE_SCALE
+    }
+
+    /// Order size floor given to new pairs unless `create_pair` overrides it
+    pub fn min_order_size() -> i128 {
+        MIN_ORDER_SIZE
+    }
+
+    pub fn tick_to_price(tick: i32) -> i128 {
+        tick_to_price(tick)
+    }
+
+    /// Ticks from `levels` steps below `center_tick` to `levels` steps above it,
+    /// ascending, each `step * TICK_SPACING` apart and paired with its price
+    pub fn price_ladder(
+        env: Env,
+        center_tick: i32,
+        levels: u32,
+        step: u32,
+    ) -> Result<Vec<(i32, i128)>, Error> {
+        validate_tick(center_tick)?;
+        if step == 0 {
+            return Err(Error::InvalidAmount);
         }
 
-        if has_orderbook(&env, &base_token, &quote_token) {
-            return Err(Error::PairAlreadyExists);
+        // Both ends of the ladder must be valid ticks
+        let stride = step as i64 * TICK_SPACING as i64;
+        let span = levels as i64 * stride;
+        let (low, high) = (center_tick as i64 - span, center_tick as i64 + span);
+        if low < MIN_TICK as i64 || high > MAX_TICK as i64 {
+            return Err(Error::InvalidTick);
         }
 
-        storage::extend_instance_ttl(&env);
+        let mut ladder = Vec::new(&env);
+        let mut tick = low;
+        while tick <= high {
+            ladder.push_back((tick as i32, tick_to_price(tick as i32)));
+            tick += stride;
+        }
+        Ok(ladder)
+    }
 
-        let orderbook = Orderbook::new(base_token.clone(), quote_token.clone());
-        save_orderbook(&env, &orderbook);
+    /// Prices at `from_tick`, `from_tick + step`, ... up to `to_tick`, so
+    /// clients can cache the tick-to-price curve in one call. `step` is in
+    /// ticks and must be a positive multiple of `TICK_SPACING`; at most 100
+    /// prices are returned per call.
+    pub fn price_table(env: Env, from_tick: i32, to_tick: i32, step: i32) -> Result<Vec<i128>, Error> {
+        validate_tick(from_tick)?;
+        validate_tick(to_tick)?;
+        if from_tick > to_tick {
+            return Err(Error::InvalidTick);
+        }
+        if step <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        if step % TICK_SPACING != 0 {
+            return Err(Error::TickNotAligned);
+        }
+        if (to_tick - from_tick) as i64 / step as i64 >= MAX_PRICE_TABLE_ENTRIES {
+            return Err(Error::InvalidAmount);
+        }
 
-        events::emit_pair_created(&env, &base_token, &quote_token);
+        let mut table = Vec::new(&env);
+        let mut tick = from_tick;
+        while tick <= to_tick {
+            table.push_back(tick_to_price(tick));
+            tick += step;
+        }
+        Ok(table)
+    }
+
+    // ============ Internal Functions ============
 
+    /// Unfilled base of the maker's pending and resting reduce-only orders on
+    /// one side of a pair
+    /// Fail with `ReduceOnlyViolated` unless a reduce-only order of `amount`
+    /// fits in the maker's position net of their open reduce-only orders
+    fn require_reducible(
+        env: &Env,
+        maker: &Address,
+        base_token: &Address,
+        quote_token: &Address,
+        is_bid: bool,
+        amount: i128,
+    ) -> Result<(), Error> {
+        let position = storage::get_position(env, maker, base_token, quote_token);
+        let reducible = if is_bid { -position } else { position }
+            - Self::open_reduce_only(env, maker, base_token, quote_token, is_bid);
+        if amount > reducible {
+            return Err(Error::ReduceOnlyViolated);
+        }
         Ok(())
     }
 
-    /// Get orderbook state
-    pub fn get_orderbook(
-        env: Env,
-        base_token: Address,
-        quote_token: Address,
-    ) -> Result<Orderbook, Error> {
-        storage::extend_instance_ttl(&env);
-        get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)
+    fn open_reduce_only(
+        env: &Env,
+        maker: &Address,
+        base_token: &Address,
+        quote_token: &Address,
+        is_bid: bool,
+    ) -> i128 {
+        let counts = |order: &Order| {
+            order.reduce_only
+                && order.is_bid == is_bid
+                && order.base_token == *base_token
+                && order.quote_token == *quote_token
+        };
+        let mut open: i128 = 0;
+        for order_id in storage::get_maker_order_ids(env, maker, true).iter() {
+            if let Some(pending_order) = order::get_pending_order(env, order_id) {
+                if counts(&pending_order) {
+                    open += pending_order.remaining;
+                }
+            }
+        }
+        for order_id in storage::get_maker_order_ids(env, maker, false).iter() {
+            if let Some(active_order) = order::get_order(env, order_id) {
+                if counts(&active_order) {
+                    open += active_order.remaining;
+                }
+            }
+        }
+        open
     }
 
-    // ============ Order Placement ============
-
-    /// Place a limit order
-    pub fn place(
-        env: Env,
+    /// Validate, escrow and store a new pending limit order
+    fn place_order(
+        env: &Env,
         maker: Address,
         base_token: Address,
         quote_token: Address,
         is_bid: bool,
         tick: i32,
         amount: i128,
+        callback: Option<Address>,
+        min_fill_tick: Option<i32>,
+        expiry: u32,
+        auto_settle: bool,
+        reduce_only: bool,
     ) -> Result<u128, Error> {
         maker.require_auth();
+        Self::require_not_paused(env)?;
         validate_tick(tick)?;
+        Self::validate_expiry(env, expiry)?;
 
-        if amount < MIN_ORDER_SIZE {
-            return Err(Error::OrderTooSmall);
-        }
-
-        storage::extend_instance_ttl(&env);
-
-        // Verify pair exists
-        let _orderbook =
-            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+        storage::extend_instance_ttl(env);
 
-        // Calculate and transfer deposit
-        let deposit_token;
-        let deposit_amount;
+        let escrow = Self::escrow_deposit(env, &maker, &base_token, &quote_token, is_bid, tick, amount)?;
 
-        if is_bid {
-            // Buying base with quote: deposit quote tokens
-            deposit_token = quote_token.clone();
-            deposit_amount = calculate_quote_amount(amount, tick);
-        } else {
-            // Selling base for quote: deposit base tokens
-            deposit_token = base_token.clone();
-            deposit_amount = amount;
+        // A marketable order takes what it can before resting the remainder
+        let amount = Self::match_crossing(env, &maker, &base_token, &quote_token, is_bid, tick, amount, escrow)?;
+        if amount == 0 {
+            return Ok(0);
         }
 
-        // Transfer tokens to contract
-        let token_client = token::Client::new(&env, &deposit_token);
-        token_client.transfer(&maker, &env.current_contract_address(), &deposit_amount);
-
         // Create pending order
-        let order_id = storage::get_next_pending_order_id(&env);
-        let new_order = if is_bid {
+        let order_id = storage::get_next_pending_order_id(env);
+        let mut new_order = if is_bid {
             Order::new_bid(order_id, maker.clone(), base_token.clone(), quote_token.clone(), tick, amount)
         } else {
             Order::new_ask(order_id, maker.clone(), base_token.clone(), quote_token.clone(), tick, amount)
         };
-
-        order::save_pending_order(&env, &new_order);
+        new_order.callback = callback;
+        new_order.min_fill_tick = min_fill_tick.unwrap_or(tick);
+        new_order.placed_at = env.ledger().timestamp();
+        new_order.expiry_ledger = expiry;
+        new_order.auto_settle = auto_settle;
+        new_order.reduce_only = reduce_only;
+
+        // Development mode skips the pending queue; the returned ID is the active one
+        let auto_activate = storage::get_auto_activate(env);
+        if !auto_activate {
+            order::save_pending_order(env, &new_order);
+        }
 
         events::emit_order_placed(
-            &env,
+            env,
             order_id,
             &maker,
             &base_token,
@@ -144,190 +2326,144 @@ impl StablecoinExchange {
             false,
         );
 
-        Ok(order_id)
-    }
-
-    /// Place a flip order (auto-creates opposite side when filled)
-    pub fn place_flip(
-        env: Env,
-        maker: Address,
-        base_token: Address,
-        quote_token: Address,
-        is_bid: bool,
-        tick: i32,
-        amount: i128,
-        flip_tick: i32,
-    ) -> Result<u128, Error> {
-        maker.require_auth();
-        validate_tick(tick)?;
-        validate_tick(flip_tick)?;
-
-        if amount < MIN_ORDER_SIZE {
-            return Err(Error::OrderTooSmall);
-        }
-
-        storage::extend_instance_ttl(&env);
-
-        // Verify pair exists
-        let _orderbook =
-            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
-
-        // Calculate and transfer deposit
-        let deposit_token;
-        let deposit_amount;
-
-        if is_bid {
-            deposit_token = quote_token.clone();
-            deposit_amount = calculate_quote_amount(amount, tick);
-        } else {
-            deposit_token = base_token.clone();
-            deposit_amount = amount;
+        if auto_activate {
+            let mut orderbook =
+                get_orderbook(env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+            let active_id = Self::activate_order(env, &mut orderbook, new_order)?;
+            save_orderbook(env, &orderbook);
+            return Ok(active_id);
         }
 
-        // Transfer tokens to contract
-        let token_client = token::Client::new(&env, &deposit_token);
-        token_client.transfer(&maker, &env.current_contract_address(), &deposit_amount);
-
-        // Create pending flip order
-        let order_id = storage::get_next_pending_order_id(&env);
-        let new_order = if is_bid {
-            Order::new_flip_bid(order_id, maker.clone(), base_token.clone(), quote_token.clone(), tick, amount, flip_tick)?
-        } else {
-            Order::new_flip_ask(order_id, maker.clone(), base_token.clone(), quote_token.clone(), tick, amount, flip_tick)?
-        };
-
-        order::save_pending_order(&env, &new_order);
-
-        events::emit_order_placed(
-            &env,
-            order_id,
-            &maker,
-            &base_token,
-            &quote_token,
-            is_bid,
-            tick,
-            amount,
-            true,
-        );
-
         Ok(order_id)
     }
 
-    /// Execute pending orders (activate them into the orderbook)
-    ///
-    /// WARNING: In the original Tempo implementation, this function is privileged
-    /// and can only be called by the protocol (Address::ZERO) during block finalization.
-    /// This prevents front-running and selective order activation.
-    /// In this Soroban port, the function is permissionless - any user can call it.
-    /// Consider adding admin-only restriction for production use.
-    pub fn execute_block(
-        env: Env,
-        base_token: Address,
-        quote_token: Address,
-        order_ids: soroban_sdk::Vec<u128>,
-    ) -> Result<(), Error> {
-        // TODO: Add access control - original Tempo requires sender == Address::ZERO
-        storage::extend_instance_ttl(&env);
+    /// Drop a pending order and credit its escrow, less any cancel fee, to the
+    /// maker's balance
+    fn cancel_pending_order(env: &Env, pending_order: &Order) -> i128 {
+        // Bids escrowed quote at their tick, asks escrowed base
+        let (refund_token, escrow) = pending_order.remaining_escrow();
+        let refund = Self::charge_cancel_fee(env, pending_order, escrow);
+        order::delete_pending_order(env, pending_order);
+
+        // Refund is handled by the caller through withdraw
+        storage::add_balance(env, &pending_order.maker, &refund_token, refund);
+
+        events::emit_order_canceled(
+            env,
+            pending_order.order_id,
+            &pending_order.maker,
+            &pending_order.base_token,
+            &pending_order.quote_token,
+            refund,
+        );
+        refund
+    }
 
+    /// Take an active order off the book and credit its remaining escrow, less
+    /// any cancel fee, to the maker's balance
+    fn cancel_active_order(env: &Env, active_order: &Order) -> Result<i128, Error> {
+        // Remove from orderbook linked list
         let mut orderbook =
-            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+            get_orderbook(env, &active_order.base_token, &active_order.quote_token)
+                .ok_or(Error::PairNotFound)?;
+        Self::remove_order_from_book(env, &mut orderbook, active_order)?;
+        save_orderbook(env, &orderbook);
+
+        let (refund_token, escrow) = active_order.remaining_escrow();
+        let refund = Self::charge_cancel_fee(env, active_order, escrow);
+        order::delete_order(env, active_order);
+
+        // Add to balance for withdrawal
+        storage::add_balance(env, &active_order.maker, &refund_token, refund);
+
+        events::emit_order_canceled(
+            env,
+            active_order.order_id,
+            &active_order.maker,
+            &active_order.base_token,
+            &active_order.quote_token,
+            refund,
+        );
+        Ok(refund)
+    }
 
-        for order_id in order_ids.iter() {
-            if let Some(pending_order) = order::get_pending_order(&env, order_id) {
-                // Move to active and link into orderbook
-                Self::activate_order(&env, &mut orderbook, pending_order)?;
-                order::delete_pending_order(&env, order_id);
-            }
+    /// Credit the treasury with the cancel fee on `refund` if `order` has not
+    /// rested long enough to be exempt, returning what is left for the maker
+    fn charge_cancel_fee(env: &Env, order: &Order, refund: i128) -> i128 {
+        let fee_bps = storage::get_cancel_fee_bps(env);
+        let age = env.ledger().timestamp().saturating_sub(order.placed_at);
+        if fee_bps == 0 || age >= storage::get_cancel_fee_min_age(env) {
+            return refund;
         }
 
-        save_orderbook(&env, &orderbook);
-        Ok(())
+        let fee = calculate_fee(refund, fee_bps);
+        if fee > 0 {
+            let (fee_token, _) = order.remaining_escrow();
+            storage::add_balance(env, &storage::get_treasury(env), &fee_token, fee);
+        }
+        refund - fee
     }
 
-    /// Cancel an order
-    pub fn cancel(env: Env, maker: Address, order_id: u128) -> Result<i128, Error> {
-        maker.require_auth();
-        storage::extend_instance_ttl(&env);
-
-        // Try pending order first
-        if let Some(pending_order) = order::get_pending_order(&env, order_id) {
-            if pending_order.maker != maker {
-                return Err(Error::NotOrderOwner);
-            }
-
-            let refund = pending_order.remaining;
-            order::delete_pending_order(&env, order_id);
-
-            // Refund is handled by the caller through withdraw
-            storage::add_balance(&env, &maker, &pending_order.maker, refund);
-
-            events::emit_order_canceled(&env, order_id, &maker, refund);
-            return Ok(refund);
+    /// Load a pair's orderbook for swapping, telling a caller who passed the
+    /// tokens reversed apart from one asking for a pair that does not exist
+    fn swap_orderbook(env: &Env, base_token: &Address, quote_token: &Address) -> Result<Orderbook, Error> {
+        if let Some(orderbook) = get_orderbook(env, base_token, quote_token) {
+            return Ok(orderbook);
         }
-
-        // Try active order
-        if let Some(active_order) = order::get_order(&env, order_id) {
-            if active_order.maker != maker {
-                return Err(Error::NotOrderOwner);
-            }
-
-            // Remove from orderbook linked list
-            Self::remove_order_from_book(&env, &active_order)?;
-
-            let refund = active_order.remaining;
-            order::delete_order(&env, order_id);
-
-            // Add to balance for withdrawal
-            storage::add_balance(&env, &maker, &active_order.maker, refund);
-
-            events::emit_order_canceled(&env, order_id, &maker, refund);
-            return Ok(refund);
+        if has_orderbook(env, quote_token, base_token) {
+            return Err(Error::WrongTokenOrder);
         }
-
-        Err(Error::OrderNotFound)
+        Err(Error::PairNotFound)
     }
 
-    // ============ Swap Execution ============
-
-    /// Swap exact amount in (taker sells exact amount)
-    pub fn swap_exact_in(
-        env: Env,
-        taker: Address,
-        base_token: Address,
-        quote_token: Address,
-        is_buy: bool, // true = buy base with quote, false = sell base for quote
+    /// Match `amount_in`, already held by the contract, against a pair's book
+    /// up to `limit_tick` (inclusive). Buys stop once `max_base_out` base is
+    /// matched. The trading fee is credited to the treasury here; the caller
+    /// delivers the net `amount_out` and returns or reroutes the `refund`.
+    fn execute_swap(
+        env: &Env,
+        taker: &Address,
+        mut orderbook: Orderbook,
+        is_buy: bool,
         amount_in: i128,
-        min_amount_out: i128,
-    ) -> Result<i128, Error> {
-        taker.require_auth();
-        storage::extend_instance_ttl(&env);
-
-        let mut orderbook =
-            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
-
-        // Transfer input tokens from taker
-        let input_token = if is_buy {
-            &quote_token
+        limit_tick: i32,
+        stp: bool,
+        max_base_out: i128,
+    ) -> Result<SwapResult, Error> {
+        Self::require_not_paused(env)?;
+        let base_token = orderbook.base_token.clone();
+        let quote_token = orderbook.quote_token.clone();
+        let (prev_best_bid, prev_best_ask) = (orderbook.best_bid_tick, orderbook.best_ask_tick);
+
+        // A buy paying its fee in quote sets the fee aside from the input first
+        let fee_in_quote = storage::get_fee_in_quote_only(env);
+        let quote_fee = is_buy && fee_in_quote;
+        let budget = if quote_fee {
+            Self::quote_fee_budget(amount_in, orderbook.fee_bps)
         } else {
-            &base_token
+            amount_in
         };
-        let token_client = token::Client::new(&env, input_token);
-        token_client.transfer(&taker, &env.current_contract_address(), &amount_in);
 
-        let mut remaining_in = amount_in;
+        let mut remaining_in = budget;
         let mut total_out: i128 = 0;
+        let mut last_fill_tick = 0;
+        // Sell fee in quote, converted at each fill tick
+        let mut sell_quote_fee: i128 = 0;
 
         if is_buy {
             // Buy base with quote: match against asks
-            while remaining_in > 0 && orderbook.has_asks() {
-                let tick = orderbook.best_ask_tick;
-                let mut level = get_ask_tick_level(&env, &base_token, &quote_token, tick);
+            let mut tick = orderbook.best_ask_tick;
+            while remaining_in > 0 && tick <= MAX_TICK.min(limit_tick) {
+                let mut level = get_ask_tick_level(env, &base_token, &quote_token, tick);
 
                 if level.is_empty() {
                     // Find next ask tick
-                    if let Some(next_tick) = find_next_ask_tick(&env, &base_token, &quote_token, tick + TICK_SPACING)
+                    if let Some(next_tick) = find_next_ask_tick(env, &base_token, &quote_token, tick + TICK_SPACING)
                     {
-                        orderbook.best_ask_tick = next_tick;
+                        if tick == orderbook.best_ask_tick {
+                            orderbook.best_ask_tick = next_tick;
+                        }
+                        tick = next_tick;
                         continue;
                     } else {
                         break;
@@ -335,39 +2471,75 @@ impl StablecoinExchange {
                 }
 
                 // Calculate how much base we can buy with remaining quote
-                let base_available = calculate_base_amount(remaining_in, tick);
+                let base_available = calculate_base_amount(remaining_in, tick).min(max_base_out - total_out);
                 let fill_amount = base_available.min(level.total_liquidity);
 
+                // Dust threshold: quote worth less than one base unit at this tick
+                // cannot buy anything here or at any pricier tick, so it is refunded
                 if fill_amount == 0 {
                     break;
                 }
-
-                // Fill orders at this tick
-                let (filled_base, filled_quote) =
-                    Self::fill_tick_level(&env, &mut level, &base_token, &quote_token, tick, fill_amount, false)?;
-
+                let start_tick = tick;
+
+                // Fill orders at this tick, spending at most the remaining quote
+                let (filled_base, filled_quote) = Self::fill_tick_level(
+                    env,
+                    &mut level,
+                    &base_token,
+                    &quote_token,
+                    tick,
+                    fill_amount,
+                    false,
+                    remaining_in,
+                    stp.then_some(taker),
+                )?;
+
+                // Each fill's cost is rounded up, so the round trip through
+                // calculate_base_amount can never overspend the input
+                if filled_quote > remaining_in {
+                    return Err(Error::Overflow);
+                }
                 remaining_in -= filled_quote;
                 total_out += filled_base;
+                last_fill_tick = tick;
 
                 // Save updated level
                 if level.is_empty() {
-                    orderbook::delete_ask_tick_level(&env, &base_token, &quote_token, tick);
-                    update_best_ask_tick(&env, &mut orderbook);
+                    orderbook::delete_ask_tick_level(env, &base_token, &quote_token, tick);
+                    orderbook.level_removed(false);
+                    update_best_ask_tick(env, &mut orderbook);
                 } else {
-                    save_ask_tick_level(&env, &base_token, &quote_token, tick, &level);
+                    save_ask_tick_level(env, &base_token, &quote_token, tick, &level);
+                }
+
+                // Whatever is left here is held back by fill limits, or the
+                // leftover quote cannot buy another unit; try the next level
+                if filled_base < fill_amount {
+                    match find_next_ask_tick(env, &base_token, &quote_token, tick + TICK_SPACING) {
+                        Some(next_tick) => tick = next_tick,
+                        None => break,
+                    }
+                }
+
+                // Never spin on a level that neither filled nor moved the cursor
+                if filled_base == 0 && filled_quote == 0 && tick == start_tick {
+                    break;
                 }
             }
         } else {
             // Sell base for quote: match against bids
-            while remaining_in > 0 && orderbook.has_bids() {
-                let tick = orderbook.best_bid_tick;
-                let mut level = get_bid_tick_level(&env, &base_token, &quote_token, tick);
+            let mut tick = orderbook.best_bid_tick;
+            while remaining_in > 0 && tick >= MIN_TICK.max(limit_tick) {
+                let mut level = get_bid_tick_level(env, &base_token, &quote_token, tick);
 
                 if level.is_empty() {
                     // Find next bid tick
-                    if let Some(next_tick) = find_next_bid_tick(&env, &base_token, &quote_token, tick - TICK_SPACING)
+                    if let Some(next_tick) = find_next_bid_tick(env, &base_token, &quote_token, tick - TICK_SPACING)
                     {
-                        orderbook.best_bid_tick = next_tick;
+                        if tick == orderbook.best_bid_tick {
+                            orderbook.best_bid_tick = next_tick;
+                        }
+                        tick = next_tick;
                         continue;
                     } else {
                         break;
@@ -381,210 +2553,508 @@ impl StablecoinExchange {
                 }
 
                 // Fill orders at this tick
-                let (filled_base, filled_quote) =
-                    Self::fill_tick_level(&env, &mut level, &base_token, &quote_token, tick, fill_amount, true)?;
+                let (filled_base, filled_quote) = Self::fill_tick_level(
+                    env,
+                    &mut level,
+                    &base_token,
+                    &quote_token,
+                    tick,
+                    fill_amount,
+                    true,
+                    i128::MAX,
+                    stp.then_some(taker),
+                )?;
 
                 remaining_in -= filled_base;
                 total_out += filled_quote;
+                sell_quote_fee += sell_fee_in_quote(filled_base, tick, orderbook.fee_bps);
+                last_fill_tick = tick;
 
                 // Save updated level
                 if level.is_empty() {
-                    orderbook::delete_bid_tick_level(&env, &base_token, &quote_token, tick);
-                    update_best_bid_tick(&env, &mut orderbook);
+                    orderbook::delete_bid_tick_level(env, &base_token, &quote_token, tick);
+                    orderbook.level_removed(true);
+                    update_best_bid_tick(env, &mut orderbook);
                 } else {
-                    save_bid_tick_level(&env, &base_token, &quote_token, tick, &level);
+                    save_bid_tick_level(env, &base_token, &quote_token, tick, &level);
                 }
-            }
-        }
 
-        // Check slippage
-        if total_out < min_amount_out {
-            return Err(Error::SlippageExceeded);
+                // The rest of this level is held back by fill limits
+                if filled_base < fill_amount {
+                    match find_next_bid_tick(env, &base_token, &quote_token, tick - TICK_SPACING) {
+                        Some(next_tick) => tick = next_tick,
+                        None => break,
+                    }
+                }
+            }
         }
 
-        // Refund unused input
-        if remaining_in > 0 {
-            token_client.transfer(&env.current_contract_address(), &taker, &remaining_in);
+        Self::record_mid_price(env, &mut orderbook);
+        save_orderbook(env, &orderbook);
+        Self::emit_best_tick_if_changed(env, &orderbook, prev_best_bid, prev_best_ask);
+
+        let amount_matched = budget - remaining_in;
+        let base_amount = if is_buy { total_out } else { amount_matched };
+        let quote_amount = if is_buy { amount_matched } else { total_out };
+
+        if total_out > 0 {
+            let trade = Trade {
+                index: orderbook::get_trade_count(env, &base_token, &quote_token),
+                tick: last_fill_tick,
+                base_amount,
+                quote_amount,
+                is_buy,
+                timestamp: env.ledger().timestamp(),
+            };
+            let buffer_size = storage::get_trade_buffer_size(env);
+            orderbook::push_trade(env, &base_token, &quote_token, &trade, buffer_size);
         }
 
-        // Transfer output to taker
-        let output_token = if is_buy {
-            &base_token
-        } else {
-            &quote_token
-        };
-        let out_token_client = token::Client::new(&env, output_token);
-        out_token_client.transfer(&env.current_contract_address(), &taker, &total_out);
-
-        save_orderbook(&env, &orderbook);
-
         events::emit_trade(
-            &env,
+            env,
             &base_token,
             &quote_token,
-            &taker,
+            taker,
             is_buy,
-            if is_buy { total_out } else { amount_in - remaining_in },
-            if is_buy { amount_in - remaining_in } else { total_out },
+            base_amount,
+            quote_amount,
             orderbook.best_bid_tick,
         );
 
-        Ok(total_out)
+        // Makers were paid in full; the fee comes out of the taker's output, or
+        // out of the quote set aside from the input for a buy paying in quote
+        let (out_fee, quote_in_fee) = if quote_fee {
+            (0, calculate_fee(quote_amount, orderbook.fee_bps))
+        } else if fee_in_quote {
+            (sell_quote_fee, 0)
+        } else {
+            (calculate_fee(total_out, orderbook.fee_bps), 0)
+        };
+        let treasury = storage::get_treasury(env);
+        if out_fee > 0 {
+            let output_token = if is_buy { &base_token } else { &quote_token };
+            storage::add_balance(env, &treasury, output_token, out_fee);
+        }
+        if quote_in_fee > 0 {
+            storage::add_balance(env, &treasury, &quote_token, quote_in_fee);
+        }
+        if base_amount > 0 {
+            let position_delta = if is_buy { total_out - out_fee } else { -base_amount };
+            storage::add_position(env, taker, &base_token, &quote_token, position_delta);
+        }
+
+        let (vwap, price_improvement) = if base_amount > 0 {
+            let vwap = quote_amount * PRICE_SCALE / base_amount;
+            let limit_price = tick_to_price(limit_tick);
+            (vwap, if is_buy { limit_price - vwap } else { vwap - limit_price })
+        } else {
+            (0, 0)
+        };
+
+        Ok(SwapResult {
+            amount_out: total_out - out_fee,
+            refund: amount_in - amount_matched - quote_in_fee,
+            base_amount,
+            quote_amount,
+            vwap,
+            price_improvement,
+        })
     }
 
-    /// Quote swap exact amount in
-    pub fn quote_swap_in(
-        env: Env,
-        base_token: Address,
-        quote_token: Address,
-        is_buy: bool,
-        amount_in: i128,
-    ) -> Result<i128, Error> {
-        storage::extend_instance_ttl(&env);
+    /// Largest part of `amount_in` whose quote fee still fits in the rest:
+    /// `amount_in * 10000 / (10000 + fee_bps)`
+    fn quote_fee_budget(amount_in: i128, fee_bps: u32) -> i128 {
+        amount_in * BPS_SCALE / (BPS_SCALE + fee_bps as i128)
+    }
 
-        let orderbook =
-            get_orderbook(&env, &base_token, &quote_token).ok_or(Error::PairNotFound)?;
+    /// Walk the book to compute the gross output of a swap without mutating
+    /// state, returning `(input_used, output, sell_quote_fee)` where the last is
+    /// a sell's fee converted at each fill tick (zero for buys)
+    fn quote_gross_out(env: &Env, orderbook: &Orderbook, is_buy: bool, amount_in: i128) -> (i128, i128, i128) {
+        let base_token = &orderbook.base_token;
+        let quote_token = &orderbook.quote_token;
 
         let mut remaining_in = amount_in;
         let mut total_out: i128 = 0;
+        let mut sell_quote_fee: i128 = 0;
 
         if is_buy {
             let mut tick = orderbook.best_ask_tick;
             while remaining_in > 0 && tick <= MAX_TICK {
-                let level = get_ask_tick_level(&env, &base_token, &quote_token, tick);
+                let level = get_ask_tick_level(env, base_token, quote_token, tick);
                 if level.is_empty() {
                     tick += TICK_SPACING;
                     continue;
                 }
 
-                let base_available = calculate_base_amount(remaining_in, tick);
-                let fill_amount = base_available.min(level.total_liquidity);
-
-                if fill_amount > 0 {
-                    let quote_cost = calculate_quote_amount(fill_amount, tick);
-                    remaining_in -= quote_cost;
-                    total_out += fill_amount;
-                }
+                let (used_in, filled_base) =
+                    Self::quote_level_fill(env, &level, tick, true, remaining_in);
+                remaining_in -= used_in;
+                total_out += filled_base;
 
                 tick += TICK_SPACING;
             }
         } else {
             let mut tick = orderbook.best_bid_tick;
             while remaining_in > 0 && tick >= MIN_TICK {
-                let level = get_bid_tick_level(&env, &base_token, &quote_token, tick);
+                let level = get_bid_tick_level(env, base_token, quote_token, tick);
                 if level.is_empty() {
                     tick -= TICK_SPACING;
                     continue;
                 }
 
-                let fill_amount = remaining_in.min(level.total_liquidity);
+                let (used_in, quote_received) =
+                    Self::quote_level_fill(env, &level, tick, false, remaining_in);
+                remaining_in -= used_in;
+                total_out += quote_received;
+                sell_quote_fee += sell_fee_in_quote(used_in, tick, orderbook.fee_bps);
+
+                tick -= TICK_SPACING;
+            }
+        }
+
+        (amount_in - remaining_in, total_out, sell_quote_fee)
+    }
+
+    /// Simulate a taker trading `amount_in` into a level, returning
+    /// `(input_used, output)` with the same per-order rounding and fill limits
+    /// as a swap (quote in for base out on buys, base in for quote out on sells)
+    fn quote_level_fill(
+        env: &Env,
+        level: &TickLevel,
+        tick: i32,
+        is_buy: bool,
+        amount_in: i128,
+    ) -> (i128, i128) {
+        let mut used_in: i128 = 0;
+        let mut out: i128 = 0;
+
+        let mut order_id = level.head;
+        while order_id != 0 {
+            let Some(order) = order::get_order(env, order_id) else {
+                break;
+            };
+            order_id = order.next;
+            if !order.accepts_fill_at(tick) || order.is_expired(env.ledger().sequence()) {
+                continue;
+            }
+
+            if is_buy {
+                let fill_amount = order
+                    .remaining
+                    .min(calculate_base_amount(amount_in - used_in, tick));
+                if fill_amount == 0 {
+                    break;
+                }
+                used_in += calculate_quote_amount_ceil(fill_amount, tick);
+                out += fill_amount;
+            } else {
+                let fill_amount = order.remaining.min(amount_in - used_in);
+                if fill_amount == 0 {
+                    break;
+                }
+                used_in += fill_amount;
+                out += calculate_quote_amount(fill_amount, tick);
+            }
+        }
+
+        (used_in, out)
+    }
 
-                if fill_amount > 0 {
-                    let quote_received = calculate_quote_amount(fill_amount, tick);
-                    remaining_in -= fill_amount;
-                    total_out += quote_received;
-                }
+    /// Base liquidity that must fill before `order`: every level priced better
+    /// than its tick plus the orders queued ahead of it at that tick
+    fn liquidity_ahead(env: &Env, order: &Order) -> Result<i128, Error> {
+        let base_token = &order.base_token;
+        let quote_token = &order.quote_token;
+        let orderbook = get_orderbook(env, base_token, quote_token).ok_or(Error::PairNotFound)?;
 
+        let mut ahead: i128 = 0;
+        if order.is_bid {
+            let mut tick = orderbook.best_bid_tick;
+            while tick > order.tick {
+                ahead += get_bid_tick_level(env, base_token, quote_token, tick).total_liquidity;
                 tick -= TICK_SPACING;
             }
+        } else {
+            let mut tick = orderbook.best_ask_tick;
+            while tick < order.tick {
+                ahead += get_ask_tick_level(env, base_token, quote_token, tick).total_liquidity;
+                tick += TICK_SPACING;
+            }
         }
 
-        Ok(total_out)
-    }
-
-    // ============ Balance Management ============
+        let mut prev_id = order.prev;
+        while prev_id != 0 {
+            let prev = order::get_order(env, prev_id).ok_or(Error::OrderNotFound)?;
+            ahead += prev.remaining;
+            prev_id = prev.prev;
+        }
 
-    /// Get user's exchange balance for a token
-    pub fn balance_of(env: Env, user: Address, token: Address) -> i128 {
-        storage::extend_instance_ttl(&env);
-        storage::get_balance(&env, &user, &token)
+        Ok(ahead)
     }
 
-    /// Withdraw tokens from exchange balance
-    pub fn withdraw(env: Env, user: Address, token: Address, amount: i128) -> Result<(), Error> {
-        user.require_auth();
-        storage::extend_instance_ttl(&env);
-
-        if amount <= 0 {
-            return Err(Error::InvalidAmount);
+    /// Verify the pair exists and the order clears its size floor, and work
+    /// out the escrow the order needs (quote for bids, base for asks)
+    fn required_escrow(
+        env: &Env,
+        base_token: &Address,
+        quote_token: &Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+    ) -> Result<(Address, i128), Error> {
+        let orderbook = get_orderbook(env, base_token, quote_token).ok_or(Error::PairNotFound)?;
+        if amount < orderbook.min_order_size {
+            return Err(Error::OrderTooSmall);
         }
 
-        if !storage::sub_balance(&env, &user, &token, amount) {
-            return Err(Error::InsufficientBalance);
+        let (deposit_token, deposit_amount) = if is_bid {
+            // Buying base with quote: deposit quote tokens
+            (quote_token, calculate_quote_amount(amount, tick))
+        } else {
+            // Selling base for quote: deposit base tokens
+            (base_token, amount)
+        };
+
+        // Bid escrow is in quote and must clear the minimum on its own
+        if deposit_amount < orderbook.min_order_size {
+            return Err(Error::OrderTooSmall);
         }
 
-        let token_client = token::Client::new(&env, &token);
-        token_client.transfer(&env.current_contract_address(), &user, &amount);
+        Ok((deposit_token.clone(), deposit_amount))
+    }
 
-        events::emit_withdraw(&env, &user, &token, amount);
+    /// Verify the pair exists and pull the order's escrow from the maker,
+    /// returning the amount taken
+    fn escrow_deposit(
+        env: &Env,
+        maker: &Address,
+        base_token: &Address,
+        quote_token: &Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+    ) -> Result<i128, Error> {
+        let (deposit_token, deposit_amount) =
+            Self::required_escrow(env, base_token, quote_token, is_bid, tick, amount)?;
 
-        Ok(())
+        let token_client = token::Client::new(env, &deposit_token);
+        token_client.transfer(maker, &env.current_contract_address(), &deposit_amount);
+        Ok(deposit_amount)
     }
 
-    // ============ View Functions ============
+    /// Match a crossing limit order's `escrow`, already held by the contract,
+    /// against the opposite side up to its tick. The fill proceeds and the
+    /// unspent escrow are credited to the maker's balance; returns the base
+    /// amount left unfilled.
+    fn match_on_placement(
+        env: &Env,
+        maker: &Address,
+        orderbook: Orderbook,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        escrow: i128,
+    ) -> Result<i128, Error> {
+        let (input_token, output_token) = if is_bid {
+            (orderbook.quote_token.clone(), orderbook.base_token.clone())
+        } else {
+            (orderbook.base_token.clone(), orderbook.quote_token.clone())
+        };
 
-    /// Get order by ID
-    pub fn get_order(env: Env, order_id: u128) -> Option<Order> {
-        storage::extend_instance_ttl(&env);
-        order::get_order(&env, order_id)
+        let result = Self::execute_swap(env, maker, orderbook, is_bid, escrow, tick, false, amount)?;
+
+        if result.amount_out > 0 {
+            storage::add_balance(env, maker, &output_token, result.amount_out);
+        }
+        if result.refund > 0 {
+            storage::add_balance(env, maker, &input_token, result.refund);
+        }
+        Ok(amount - result.base_amount)
     }
 
-    /// Get pending order by ID
-    pub fn get_pending_order(env: Env, order_id: u128) -> Option<Order> {
-        storage::extend_instance_ttl(&env);
-        order::get_pending_order(&env, order_id)
+    /// Match a newly placed order holding `escrow` against the book if it
+    /// crosses, then escrow the unfilled remainder from the maker's balance.
+    /// Returns the amount left to rest: all of `amount` if it does not cross,
+    /// and 0 when the remainder is below the pair's floor, in which case it
+    /// stays in the maker's balance.
+    fn match_crossing(
+        env: &Env,
+        maker: &Address,
+        base_token: &Address,
+        quote_token: &Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        escrow: i128,
+    ) -> Result<i128, Error> {
+        let orderbook = get_orderbook(env, base_token, quote_token).ok_or(Error::PairNotFound)?;
+        if !orderbook.crosses(is_bid, tick) {
+            return Ok(amount);
+        }
+
+        let amount = Self::match_on_placement(env, maker, orderbook, is_bid, tick, amount, escrow)?;
+        match Self::required_escrow(env, base_token, quote_token, is_bid, tick, amount) {
+            Ok((deposit_token, deposit_amount)) => {
+                Self::fund_from_balance(env, maker, &deposit_token, deposit_amount);
+                Ok(amount)
+            }
+            Err(Error::OrderTooSmall) => Ok(0),
+            Err(err) => Err(err),
+        }
     }
 
-    /// Get tick level
-    pub fn get_tick_level(
-        env: Env,
-        base_token: Address,
-        quote_token: Address,
+    /// Queue an already-escrowed plain limit order, matching it first if it
+    /// crosses the book. Returns its pending ID, or 0 if nothing was left to rest.
+    fn save_new_pending_order(
+        env: &Env,
+        maker: &Address,
+        base_token: &Address,
+        quote_token: &Address,
         is_bid: bool,
         tick: i32,
-    ) -> TickLevel {
-        storage::extend_instance_ttl(&env);
-        if is_bid {
-            get_bid_tick_level(&env, &base_token, &quote_token, tick)
+        amount: i128,
+        escrow: i128,
+    ) -> Result<u128, Error> {
+        let amount = Self::match_crossing(env, maker, base_token, quote_token, is_bid, tick, amount, escrow)?;
+        if amount == 0 {
+            return Ok(0);
+        }
+
+        let order_id = storage::get_next_pending_order_id(env);
+        let mut new_order = if is_bid {
+            Order::new_bid(order_id, maker.clone(), base_token.clone(), quote_token.clone(), tick, amount)
         } else {
-            get_ask_tick_level(&env, &base_token, &quote_token, tick)
+            Order::new_ask(order_id, maker.clone(), base_token.clone(), quote_token.clone(), tick, amount)
+        };
+        new_order.placed_at = env.ledger().timestamp();
+        order::save_pending_order(env, &new_order);
+
+        events::emit_order_placed(env, order_id, maker, base_token, quote_token, is_bid, tick, amount, false);
+        Ok(order_id)
+    }
+
+    /// Take `amount` of `token` from the maker's exchange balance, transferring
+    /// whatever the balance does not cover from their wallet
+    fn fund_from_balance(env: &Env, maker: &Address, token: &Address, amount: i128) {
+        let from_balance = storage::get_balance(env, maker, token).min(amount);
+        if from_balance > 0 {
+            storage::sub_balance(env, maker, token, from_balance);
+        }
+        let top_up = amount - from_balance;
+        if top_up > 0 {
+            token::Client::new(env, token).transfer(maker, &env.current_contract_address(), &top_up);
         }
     }
 
-    /// Get constants
-    pub fn min_tick() -> i32 {
-        MIN_TICK
+    /// Sample the pair's mid price into its TWAP accumulator
+    fn record_mid_price(env: &Env, orderbook: &mut Orderbook) {
+        if let Some(observation) = orderbook.record_mid_price(env.ledger().sequence()) {
+            orderbook::push_price_observation(
+                env,
+                &orderbook.base_token,
+                &orderbook.quote_token,
+                &observation,
+            );
+        }
     }
 
-    pub fn max_tick() -> i32 {
-        MAX_TICK
+    /// TWAP accumulator value at a past `ledger`, interpolated between the
+    /// retained observations around it (the mid price is constant in between)
+    fn price_cumulative_at(env: &Env, orderbook: &Orderbook, ledger: u32) -> Result<i128, Error> {
+        if orderbook.observation_count == 0 {
+            return Err(Error::InsufficientHistory);
+        }
+        if ledger >= orderbook.price_ledger {
+            return Ok(orderbook.price_cumulative_at(ledger));
+        }
+
+        let (base_token, quote_token) = (&orderbook.base_token, &orderbook.quote_token);
+        let oldest = orderbook.observation_count.saturating_sub(PRICE_OBSERVATION_SLOTS);
+        let mut next: Option<PriceObservation> = None;
+        for index in (oldest..orderbook.observation_count).rev() {
+            let observation = orderbook::get_price_observation(env, base_token, quote_token, index)
+                .ok_or(Error::InsufficientHistory)?;
+            if observation.ledger <= ledger {
+                // The newest observation is at price_ledger, past `ledger`, so a
+                // later one was always seen first
+                let next = next.ok_or(Error::InsufficientHistory)?;
+                let span = (next.ledger - observation.ledger) as i128;
+                let elapsed = (ledger - observation.ledger) as i128;
+                return Ok(observation.price_cumulative
+                    + (next.price_cumulative - observation.price_cumulative) * elapsed / span);
+            }
+            next = Some(observation);
+        }
+        Err(Error::InsufficientHistory)
     }
 
-    pub fn tick_spacing() -> i32 {
-        TICK_SPACING
+    /// Publish the pair's top of book if either best tick moved from the given values
+    fn emit_best_tick_if_changed(
+        env: &Env,
+        orderbook: &Orderbook,
+        prev_best_bid: i32,
+        prev_best_ask: i32,
+    ) {
+        if orderbook.best_bid_tick != prev_best_bid || orderbook.best_ask_tick != prev_best_ask {
+            events::emit_best_tick_changed(
+                env,
+                &orderbook.base_token,
+                &orderbook.quote_token,
+                orderbook.best_bid_tick,
+                orderbook.best_ask_tick,
+            );
+        }
     }
 
-    pub fn price_scale() -> i128 {
-        PRICE_SCALE
+    fn require_admin(env: &Env, caller: &Address) -> Result<(), Error> {
+        caller.require_auth();
+        if *caller != storage::get_admin(env) {
+            return Err(Error::Unauthorized);
+        }
+        Ok(())
     }
 
-    pub fn min_order_size() -> i128 {
-        MIN_ORDER_SIZE
+    fn require_not_paused(env: &Env) -> Result<(), Error> {
+        if storage::get_paused(env) {
+            return Err(Error::Paused);
+        }
+        Ok(())
     }
 
-    pub fn tick_to_price(tick: i32) -> i128 {
-        tick_to_price(tick)
+    /// Reject an expiry ledger that has already passed (0 never expires)
+    fn validate_expiry(env: &Env, expiry: u32) -> Result<(), Error> {
+        if expiry != 0 && expiry < env.ledger().sequence() {
+            return Err(Error::InvalidExpiry);
+        }
+        Ok(())
     }
 
-    // ============ Internal Functions ============
+    fn require_flips_enabled(env: &Env) -> Result<(), Error> {
+        if !storage::get_flips_enabled(env) {
+            return Err(Error::FlipsDisabled);
+        }
+        Ok(())
+    }
+
+    /// Data migration for a single version step. No step rewrites stored data
+    /// yet; new `Order` or `Orderbook` fields should be backfilled here.
+    fn run_migration(_env: &Env, _version: u32) -> Result<(), Error> {
+        Ok(())
+    }
 
     fn activate_order(
         env: &Env,
         orderbook: &mut Orderbook,
         mut pending_order: Order,
-    ) -> Result<(), Error> {
-        // Assign new active order ID
-        let active_id = storage::get_next_active_order_id(env);
+    ) -> Result<u128, Error> {
+        // Assign new active order ID unless stable IDs are on, in which case the
+        // pending ID (high bit set) carries over and cannot clash with minted ones
+        let active_id = if storage::get_stable_order_ids(env) {
+            pending_order.order_id
+        } else {
+            storage::get_next_active_order_id(env)
+        };
         pending_order.order_id = active_id;
 
         let base_token = &pending_order.base_token;
@@ -600,6 +3070,7 @@ impl StablecoinExchange {
         // Add to end of linked list at this tick
         if level.tail == 0 {
             // First order at this tick
+            orderbook.level_added(pending_order.is_bid);
             level.head = active_id;
             level.tail = active_id;
         } else {
@@ -615,8 +3086,16 @@ impl StablecoinExchange {
         level.total_liquidity += pending_order.remaining;
 
         // Save order and level
-        order::save_order(env, &pending_order);
+        order::insert_order(env, &pending_order);
+        storage::add_pair_escrow(
+            env,
+            base_token,
+            quote_token,
+            pending_order.is_bid,
+            pending_order.remaining_escrow().1,
+        );
 
+        let (prev_best_bid, prev_best_ask) = (orderbook.best_bid_tick, orderbook.best_ask_tick);
         if pending_order.is_bid {
             save_bid_tick_level(env, base_token, quote_token, pending_order.tick, &level);
             if pending_order.tick > orderbook.best_bid_tick {
@@ -628,11 +3107,217 @@ impl StablecoinExchange {
                 orderbook.best_ask_tick = pending_order.tick;
             }
         }
+        Self::emit_best_tick_if_changed(env, orderbook, prev_best_bid, prev_best_ask);
+
+        Ok(active_id)
+    }
+
+    /// Place an order funded from the maker's exchange balance and link it
+    /// straight into the book, skipping the pending stage. Nothing is matched,
+    /// so an order that would cross the book fails with `WouldCross`.
+    fn place_from_balance(
+        env: &Env,
+        orderbook: &mut Orderbook,
+        maker: &Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+    ) -> Result<u128, Error> {
+        validate_tick(tick)?;
+        if orderbook.crosses(is_bid, tick) {
+            return Err(Error::WouldCross);
+        }
+        let base_token = orderbook.base_token.clone();
+        let quote_token = orderbook.quote_token.clone();
+
+        let (escrow_token, escrow_amount) =
+            Self::required_escrow(env, &base_token, &quote_token, is_bid, tick, amount)?;
+        if !storage::sub_balance(env, maker, &escrow_token, escrow_amount) {
+            return Err(Error::InsufficientBalance);
+        }
+
+        let order_id = storage::get_next_pending_order_id(env);
+        let mut new_order = if is_bid {
+            Order::new_bid(order_id, maker.clone(), base_token.clone(), quote_token.clone(), tick, amount)
+        } else {
+            Order::new_ask(order_id, maker.clone(), base_token.clone(), quote_token.clone(), tick, amount)
+        };
+        new_order.placed_at = env.ledger().timestamp();
+        events::emit_order_placed(env, order_id, maker, &base_token, &quote_token, is_bid, tick, amount, false);
+
+        Self::activate_order(env, orderbook, new_order)
+    }
+
+    /// Fill the head order of the best level on its side outside of a swap,
+    /// crediting its maker with `credit_amount` of `credit_token` and popping it
+    /// off the level once fully filled
+    fn force_fill(
+        env: &Env,
+        orderbook: &mut Orderbook,
+        mut filled_order: Order,
+        fill_amount: i128,
+        credit_token: &Address,
+        credit_amount: i128,
+    ) -> Result<(), Error> {
+        let (base_token, quote_token, tick) =
+            (orderbook.base_token.clone(), orderbook.quote_token.clone(), filled_order.tick);
+        let mut level = if filled_order.is_bid {
+            get_bid_tick_level(env, &base_token, &quote_token, tick)
+        } else {
+            get_ask_tick_level(env, &base_token, &quote_token, tick)
+        };
+        level.total_liquidity -= fill_amount;
+
+        Self::fill_resting_order(env, &mut filled_order, fill_amount)?;
+        Self::credit_maker(env, &filled_order, fill_amount, credit_token, credit_amount)?;
+        Self::record_maker_position(env, &filled_order, fill_amount);
+        events::emit_order_filled(
+            env,
+            filled_order.order_id,
+            &filled_order.maker,
+            &base_token,
+            &quote_token,
+            fill_amount,
+            filled_order.remaining,
+        );
+        order::record_maker_fill(env, &filled_order, fill_amount);
+        order::notify_fill(env, &filled_order, fill_amount);
+
+        if filled_order.is_fully_filled() {
+            if filled_order.is_flip && storage::get_flips_enabled(env) {
+                Self::spawn_flipped_order(env, &filled_order)?;
+            }
+
+            level.head = filled_order.next;
+            if let Some(mut next_order) = order::get_order(env, filled_order.next) {
+                next_order.prev = 0;
+                order::save_order(env, &next_order);
+            } else {
+                level.tail = 0;
+            }
+            order::delete_order(env, &filled_order);
+        } else {
+            order::save_order(env, &filled_order);
+        }
+
+        // Save or delete level, moving the best tick on past a level that emptied
+        let (prev_best_bid, prev_best_ask) = (orderbook.best_bid_tick, orderbook.best_ask_tick);
+        if filled_order.is_bid {
+            if level.is_empty() {
+                orderbook::delete_bid_tick_level(env, &base_token, &quote_token, tick);
+                orderbook.level_removed(true);
+                orderbook.best_bid_tick = find_next_bid_tick(env, &base_token, &quote_token, tick - TICK_SPACING)
+                    .unwrap_or(MIN_TICK - 1);
+            } else {
+                save_bid_tick_level(env, &base_token, &quote_token, tick, &level);
+            }
+        } else if level.is_empty() {
+            orderbook::delete_ask_tick_level(env, &base_token, &quote_token, tick);
+            orderbook.level_removed(false);
+            orderbook.best_ask_tick = find_next_ask_tick(env, &base_token, &quote_token, tick + TICK_SPACING)
+                .unwrap_or(MAX_TICK + 1);
+        } else {
+            save_ask_tick_level(env, &base_token, &quote_token, tick, &level);
+        }
+        Self::emit_best_tick_if_changed(env, orderbook, prev_best_bid, prev_best_ask);
+
+        Ok(())
+    }
+
+    /// Queue the flipped side of a fully filled flip order, escrowing it from
+    /// the fill proceeds credited to the maker's balance. If the balance no
+    /// longer covers it the flip is skipped with a `flip_skip` event.
+    fn spawn_flipped_order(env: &Env, filled_order: &Order) -> Result<(), Error> {
+        let mut flipped = filled_order.create_flipped_order(0)?;
+        let (escrow_token, escrow) = flipped.remaining_escrow();
+        if !storage::sub_balance(env, &filled_order.maker, &escrow_token, escrow) {
+            events::emit_flip_skipped(
+                env,
+                filled_order.order_id,
+                &filled_order.maker,
+                &filled_order.base_token,
+                &filled_order.quote_token,
+                escrow,
+            );
+            return Ok(());
+        }
 
+        flipped.order_id = storage::get_next_pending_order_id(env);
+        flipped.placed_at = env.ledger().timestamp();
+        order::save_pending_order(env, &flipped);
+        Ok(())
+    }
+
+    /// Fill an active order, releasing the escrow backing the filled part from
+    /// its pair's running total
+    fn fill_resting_order(env: &Env, resting_order: &mut Order, fill_amount: i128) -> Result<(), Error> {
+        let (_, escrow_before) = resting_order.remaining_escrow();
+        resting_order.fill(fill_amount)?;
+        let (_, escrow_after) = resting_order.remaining_escrow();
+        storage::add_pair_escrow(
+            env,
+            &resting_order.base_token,
+            &resting_order.quote_token,
+            resting_order.is_bid,
+            escrow_after - escrow_before,
+        );
+        Ok(())
+    }
+
+    /// Pay a maker the proceeds of a fill of `fill_amount`, straight to their
+    /// wallet for auto-settle orders and into their exchange balance otherwise
+    ///
+    /// An auto-settle flip order keeps this fill's share of the flipped order's
+    /// escrow in the balance, so the flip is funded once the order fills; only
+    /// the rest goes to the wallet.
+    fn credit_maker(
+        env: &Env,
+        filled_order: &Order,
+        fill_amount: i128,
+        token: &Address,
+        amount: i128,
+    ) -> Result<(), Error> {
+        if !filled_order.auto_settle {
+            storage::add_balance(env, &filled_order.maker, token, amount);
+            return Ok(());
+        }
+
+        let held = if filled_order.is_flip && storage::get_flips_enabled(env) {
+            filled_order.flip_escrow_share(fill_amount)?.min(amount)
+        } else {
+            0
+        };
+        if held > 0 {
+            storage::add_balance(env, &filled_order.maker, token, held);
+        }
+        if amount > held {
+            token::Client::new(env, token).transfer(
+                &env.current_contract_address(),
+                &filled_order.maker,
+                &(amount - held),
+            );
+        }
         Ok(())
     }
 
-    fn remove_order_from_book(env: &Env, order_to_remove: &Order) -> Result<(), Error> {
+    /// Move a maker's position by a fill of their order: bids gain base,
+    /// asks give it up
+    fn record_maker_position(env: &Env, filled_order: &Order, fill_amount: i128) {
+        let delta = if filled_order.is_bid { fill_amount } else { -fill_amount };
+        storage::add_position(
+            env,
+            &filled_order.maker,
+            &filled_order.base_token,
+            &filled_order.quote_token,
+            delta,
+        );
+    }
+
+    fn remove_order_from_book(
+        env: &Env,
+        orderbook: &mut Orderbook,
+        order_to_remove: &Order,
+    ) -> Result<(), Error> {
         let base_token = &order_to_remove.base_token;
         let quote_token = &order_to_remove.quote_token;
         let tick = order_to_remove.tick;
@@ -663,14 +3348,32 @@ impl StablecoinExchange {
         }
 
         level.total_liquidity -= order_to_remove.remaining;
+        storage::add_pair_escrow(
+            env,
+            base_token,
+            quote_token,
+            order_to_remove.is_bid,
+            -order_to_remove.remaining_escrow().1,
+        );
 
-        // Save or delete level
+        // Save or delete level, moving the best tick off a level that emptied
         if level.is_empty() {
+            let (prev_best_bid, prev_best_ask) =
+                (orderbook.best_bid_tick, orderbook.best_ask_tick);
             if order_to_remove.is_bid {
                 orderbook::delete_bid_tick_level(env, base_token, quote_token, tick);
+                orderbook.level_removed(true);
+                if tick == orderbook.best_bid_tick {
+                    update_best_bid_tick(env, orderbook);
+                }
             } else {
                 orderbook::delete_ask_tick_level(env, base_token, quote_token, tick);
+                orderbook.level_removed(false);
+                if tick == orderbook.best_ask_tick {
+                    update_best_ask_tick(env, orderbook);
+                }
             }
+            Self::emit_best_tick_if_changed(env, orderbook, prev_best_bid, prev_best_ask);
         } else if order_to_remove.is_bid {
             save_bid_tick_level(env, base_token, quote_token, tick, &level);
         } else {
@@ -688,6 +3391,8 @@ impl StablecoinExchange {
         tick: i32,
         mut amount_to_fill: i128,
         is_bid: bool,
+        max_quote: i128,
+        stp_taker: Option<&Address>,
     ) -> Result<(i128, i128), Error> {
         let mut total_base_filled: i128 = 0;
         let mut total_quote_filled: i128 = 0;
@@ -698,16 +3403,50 @@ impl StablecoinExchange {
             let mut current_order = order::get_order(env, current_order_id)
                 .ok_or(Error::OrderNotFound)?;
 
-            let fill_amount = amount_to_fill.min(current_order.remaining);
-            current_order.fill(fill_amount)?;
+            // Expired orders, and with self-trade prevention the taker's own orders,
+            // leave the book and their escrow goes back to the maker
+            if current_order.is_expired(env.ledger().sequence())
+                || stp_taker == Some(&current_order.maker)
+            {
+                let next_order_id = current_order.next;
+                Self::evict_resting_order(env, level, &current_order)?;
+                current_order_id = next_order_id;
+                continue;
+            }
+
+            // Orders whose fill limit excludes this tick keep their place in the queue
+            if !current_order.accepts_fill_at(tick) {
+                current_order_id = current_order.next;
+                continue;
+            }
+
+            let mut fill_amount = amount_to_fill.min(current_order.remaining);
+
+            // Taker buys pay for each fill rounded up, capped by the quote left
+            let quote_amount = if is_bid {
+                calculate_quote_amount(fill_amount, tick)
+            } else {
+                fill_amount = fill_amount
+                    .min(calculate_base_amount(max_quote - total_quote_filled, tick));
+                if fill_amount == 0 {
+                    break;
+                }
+                calculate_quote_amount_ceil(fill_amount, tick)
+            };
 
+            Self::fill_resting_order(env, &mut current_order, fill_amount)?;
             let base_amount = fill_amount;
-            let quote_amount = calculate_quote_amount(fill_amount, tick);
 
             total_base_filled += base_amount;
             total_quote_filled += quote_amount;
             amount_to_fill -= fill_amount;
-            level.total_liquidity -= fill_amount;
+
+            // Level liquidity must always cover the orders queued on it
+            level.total_liquidity = level
+                .total_liquidity
+                .checked_sub(fill_amount)
+                .filter(|liquidity| *liquidity >= 0)
+                .ok_or(Error::Overflow)?;
 
             // Credit maker with the appropriate token
             let credit_token = if is_bid {
@@ -720,36 +3459,32 @@ impl StablecoinExchange {
             } else {
                 quote_amount
             };
-            storage::add_balance(env, &current_order.maker, credit_token, credit_amount);
+            Self::credit_maker(env, &current_order, fill_amount, credit_token, credit_amount)?;
+            Self::record_maker_position(env, &current_order, fill_amount);
 
             events::emit_order_filled(
                 env,
                 current_order_id,
                 &current_order.maker,
+                base_token,
+                quote_token,
                 fill_amount,
                 current_order.remaining,
             );
+            order::record_maker_fill(env, &current_order, fill_amount);
+            order::notify_fill(env, &current_order, fill_amount);
 
             let next_order_id = current_order.next;
 
             if current_order.is_fully_filled() {
                 // Handle flip order
-                if current_order.is_flip {
-                    let flipped = current_order
-                        .create_flipped_order(storage::get_next_pending_order_id(env))?;
-                    order::save_pending_order(env, &flipped);
-                }
-
-                // Remove from list
-                level.head = next_order_id;
-                if next_order_id == 0 {
-                    level.tail = 0;
-                } else if let Some(mut next_order) = order::get_order(env, next_order_id) {
-                    next_order.prev = 0;
-                    order::save_order(env, &next_order);
+                if current_order.is_flip && storage::get_flips_enabled(env) {
+                    Self::spawn_flipped_order(env, &current_order)?;
                 }
 
-                order::delete_order(env, current_order_id);
+                // Unlink from the list; skipped orders may still be queued ahead
+                Self::unlink_from_level(env, level, &current_order);
+                order::delete_order(env, &current_order);
             } else {
                 order::save_order(env, &current_order);
             }
@@ -759,6 +3494,49 @@ impl StablecoinExchange {
 
         Ok((total_base_filled, total_quote_filled))
     }
+
+    /// Take an evicted or self-trading order off a level mid-swap, crediting
+    /// its remaining escrow to the maker's balance
+    fn evict_resting_order(env: &Env, level: &mut TickLevel, evicted: &Order) -> Result<(), Error> {
+        level.total_liquidity = level
+            .total_liquidity
+            .checked_sub(evicted.remaining)
+            .filter(|liquidity| *liquidity >= 0)
+            .ok_or(Error::Overflow)?;
+
+        let (refund_token, refund) = evicted.remaining_escrow();
+        storage::add_pair_escrow(env, &evicted.base_token, &evicted.quote_token, evicted.is_bid, -refund);
+        storage::add_balance(env, &evicted.maker, &refund_token, refund);
+
+        Self::unlink_from_level(env, level, evicted);
+        order::delete_order(env, evicted);
+
+        events::emit_order_canceled(
+            env,
+            evicted.order_id,
+            &evicted.maker,
+            &evicted.base_token,
+            &evicted.quote_token,
+            refund,
+        );
+        Ok(())
+    }
+
+    /// Splice an order out of its level's linked list
+    fn unlink_from_level(env: &Env, level: &mut TickLevel, removed: &Order) {
+        if removed.prev == 0 {
+            level.head = removed.next;
+        } else if let Some(mut prev_order) = order::get_order(env, removed.prev) {
+            prev_order.next = removed.next;
+            order::save_order(env, &prev_order);
+        }
+        if removed.next == 0 {
+            level.tail = removed.prev;
+        } else if let Some(mut next_order) = order::get_order(env, removed.next) {
+            next_order.prev = removed.prev;
+            order::save_order(env, &next_order);
+        }
+    }
 }
 
 #[cfg(test)]
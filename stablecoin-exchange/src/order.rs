@@ -1,7 +1,8 @@
-use soroban_sdk::{contracttype, Address, Env};
+use soroban_sdk::{contracttype, Address, Env, IntoVal, InvokeError, Symbol, Val, Vec};
 
 use crate::error::Error;
-use crate::storage::{extend_persistent_ttl, DataKey};
+use crate::orderbook::{calculate_quote_amount, validate_tick};
+use crate::storage::{self, extend_persistent_ttl, DataKey};
 
 /// Represents a limit order in the orderbook
 #[contracttype]
@@ -31,6 +32,55 @@ pub struct Order {
     pub is_flip: bool,
     /// Target tick for the flipped order (only used if is_flip)
     pub flip_tick: i32,
+    /// Offset from the fill tick for the flipped order (0 means use flip_tick)
+    pub spread_ticks: i32,
+    /// Maker contract notified through `on_order_filled` on every fill
+    pub callback: Option<Address>,
+    /// Worst tick the maker accepts a fill at: asks fill only at or above it,
+    /// bids only at or below it (normally equal to `tick`)
+    pub min_fill_tick: i32,
+    /// Ledger timestamp at which the order (or the order it flipped from) was placed
+    pub placed_at: u64,
+    /// Last ledger sequence the order can fill in (0 = good-till-cancel);
+    /// flipped orders inherit it
+    pub expiry_ledger: u32,
+    /// Transfer fill proceeds straight to the maker instead of crediting
+    /// their exchange balance
+    pub auto_settle: bool,
+    /// Placed with `place_reduce_only`; its unfilled amount is held against
+    /// the maker's position when further reduce-only orders are placed
+    pub reduce_only: bool,
+}
+
+/// A user's orders and internal balances for one trading pair
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct UserSummary {
+    /// Active order IDs on this pair (at most MAX_SUMMARY_ORDERS)
+    pub active_order_ids: Vec<u128>,
+    /// Pending order IDs on this pair (at most MAX_SUMMARY_ORDERS)
+    pub pending_order_ids: Vec<u128>,
+    /// Internal balance of the base token
+    pub base_balance: i128,
+    /// Internal balance of the quote token
+    pub quote_balance: i128,
+}
+
+/// Maximum number of order IDs returned per list in a UserSummary
+pub const MAX_SUMMARY_ORDERS: u32 = 100;
+
+/// A fill against one of a maker's orders, kept after the order is deleted
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MakerFill {
+    /// Sequential fill index for the maker
+    pub index: u32,
+    pub order_id: u128,
+    /// Base amount filled
+    pub fill_amount: i128,
+    pub tick: i32,
+    /// Ledger timestamp of the fill
+    pub timestamp: u64,
 }
 
 impl Order {
@@ -56,6 +106,13 @@ impl Order {
             next: 0,
             is_flip: false,
             flip_tick: 0,
+            spread_ticks: 0,
+            callback: None,
+            min_fill_tick: tick,
+            placed_at: 0,
+            expiry_ledger: 0,
+            auto_settle: false,
+            reduce_only: false,
         }
     }
 
@@ -81,6 +138,13 @@ impl Order {
             next: 0,
             is_flip: false,
             flip_tick: 0,
+            spread_ticks: 0,
+            callback: None,
+            min_fill_tick: tick,
+            placed_at: 0,
+            expiry_ledger: 0,
+            auto_settle: false,
+            reduce_only: false,
         }
     }
 
@@ -111,6 +175,13 @@ impl Order {
             next: 0,
             is_flip: true,
             flip_tick,
+            spread_ticks: 0,
+            callback: None,
+            min_fill_tick: tick,
+            placed_at: 0,
+            expiry_ledger: 0,
+            auto_settle: false,
+            reduce_only: false,
         })
     }
 
@@ -141,9 +212,75 @@ impl Order {
             next: 0,
             is_flip: true,
             flip_tick,
+            spread_ticks: 0,
+            callback: None,
+            min_fill_tick: tick,
+            placed_at: 0,
+            expiry_ledger: 0,
+            auto_settle: false,
+            reduce_only: false,
         })
     }
 
+    /// Create a new flip order whose flipped side lands `spread_ticks` away from the fill tick
+    pub fn new_relative_flip(
+        order_id: u128,
+        maker: Address,
+        base_token: Address,
+        quote_token: Address,
+        is_bid: bool,
+        tick: i32,
+        amount: i128,
+        spread_ticks: i32,
+    ) -> Result<Self, Error> {
+        if spread_ticks <= 0 {
+            return Err(if is_bid {
+                Error::InvalidBidFlipTick
+            } else {
+                Error::InvalidAskFlipTick
+            });
+        }
+        let order = Self {
+            order_id,
+            maker,
+            base_token,
+            quote_token,
+            is_bid,
+            tick,
+            amount,
+            remaining: amount,
+            prev: 0,
+            next: 0,
+            is_flip: true,
+            flip_tick: 0,
+            spread_ticks,
+            callback: None,
+            min_fill_tick: tick,
+            placed_at: 0,
+            expiry_ledger: 0,
+            auto_settle: false,
+            reduce_only: false,
+        };
+        // Reject up front if the flipped order could never be placed
+        validate_tick(order.flip_target_tick()?)?;
+        Ok(order)
+    }
+
+    /// Tick the flipped order is placed at: fill tick ± spread for relative flips,
+    /// otherwise the fixed flip_tick
+    pub fn flip_target_tick(&self) -> Result<i32, Error> {
+        if self.spread_ticks == 0 {
+            return Ok(self.flip_tick);
+        }
+        // Orders only fill at their own tick, so that is the fill tick
+        if self.is_bid {
+            self.tick.checked_add(self.spread_ticks)
+        } else {
+            self.tick.checked_sub(self.spread_ticks)
+        }
+        .ok_or(Error::Overflow)
+    }
+
     /// Fill a portion of the order
     pub fn fill(&mut self, amount: i128) -> Result<(), Error> {
         if amount > self.remaining {
@@ -158,6 +295,52 @@ impl Order {
         self.remaining == 0
     }
 
+    /// Whether the maker's `min_fill_tick` allows a fill at `tick`
+    pub fn accepts_fill_at(&self, tick: i32) -> bool {
+        if self.is_bid {
+            tick <= self.min_fill_tick
+        } else {
+            tick >= self.min_fill_tick
+        }
+    }
+
+    /// Whether the order's expiry ledger has passed at `ledger`
+    pub fn is_expired(&self, ledger: u32) -> bool {
+        self.expiry_ledger != 0 && ledger > self.expiry_ledger
+    }
+
+    /// Token and amount still held in escrow for the unfilled remainder
+    /// (quote at the order's tick for bids, base for asks)
+    pub fn remaining_escrow(&self) -> (Address, i128) {
+        if self.is_bid {
+            (
+                self.quote_token.clone(),
+                calculate_quote_amount(self.remaining, self.tick),
+            )
+        } else {
+            (self.base_token.clone(), self.remaining)
+        }
+    }
+
+    /// Part of the flipped order's escrow (base for a filled bid, quote at the
+    /// flip tick for a filled ask) attributable to a fill of `fill_amount`
+    /// that has just been applied. Shares are rounded so that all fills of the
+    /// order add up to exactly the flipped order's escrow.
+    pub fn flip_escrow_share(&self, fill_amount: i128) -> Result<i128, Error> {
+        let escrow = if self.is_bid {
+            self.amount
+        } else {
+            calculate_quote_amount(self.amount, self.flip_target_tick()?)
+        };
+        let filled_after = self.amount - self.remaining;
+        let filled_before = filled_after - fill_amount;
+        let share_of = |filled: i128| -> Result<i128, Error> {
+            let product = escrow.checked_mul(filled).ok_or(Error::Overflow)?;
+            Ok((product + self.amount - 1) / self.amount)
+        };
+        Ok(share_of(filled_after)? - share_of(filled_before)?)
+    }
+
     /// Create the flipped order after this order is fully filled
     pub fn create_flipped_order(&self, new_order_id: u128) -> Result<Order, Error> {
         if !self.is_flip {
@@ -168,23 +351,98 @@ impl Order {
         }
 
         // Flip the side: bid becomes ask, ask becomes bid
+        let tick = self.flip_target_tick()?;
         Ok(Order {
             order_id: new_order_id,
             maker: self.maker.clone(),
             base_token: self.base_token.clone(),
             quote_token: self.quote_token.clone(),
             is_bid: !self.is_bid,
-            tick: self.flip_tick,
+            tick,
             amount: self.amount,
             remaining: self.amount,
             prev: 0,
             next: 0,
             is_flip: false, // Flipped orders are not recursive
             flip_tick: 0,
+            spread_ticks: 0,
+            callback: self.callback.clone(),
+            min_fill_tick: tick,
+            placed_at: 0,
+            expiry_ledger: self.expiry_ledger,
+            auto_settle: self.auto_settle,
+            reduce_only: false,
         })
     }
 }
 
+/// Tell the order's callback contract, if any, about a fill
+///
+/// Calls `on_order_filled(order_id, base_token, quote_token, is_bid, tick,
+/// fill_amount, remaining)`. Errors raised by the callback are ignored so a
+/// misbehaving maker contract cannot block takers.
+pub fn notify_fill(env: &Env, order: &Order, fill_amount: i128) {
+    let Some(callback) = &order.callback else {
+        return;
+    };
+    let args: Vec<Val> = (
+        order.order_id,
+        order.base_token.clone(),
+        order.quote_token.clone(),
+        order.is_bid,
+        order.tick,
+        fill_amount,
+        order.remaining,
+    )
+        .into_val(env);
+    let _ = env.try_invoke_contract::<(), InvokeError>(
+        callback,
+        &Symbol::new(env, "on_order_filled"),
+        args,
+    );
+}
+
+// ============ Maker Fill History Storage ============
+
+pub fn get_maker_fill_count(env: &Env, maker: &Address) -> u32 {
+    let key = DataKey::MakerFillCount(maker.clone());
+    let count = env.storage().persistent().get(&key).unwrap_or(0);
+    if env.storage().persistent().has(&key) {
+        extend_persistent_ttl(env, &key);
+    }
+    count
+}
+
+/// Append a fill of `order` to its maker's ring buffer, overwriting the oldest once full
+pub fn record_maker_fill(env: &Env, order: &Order, fill_amount: i128) {
+    let index = get_maker_fill_count(env, &order.maker);
+    let fill = MakerFill {
+        index,
+        order_id: order.order_id,
+        fill_amount,
+        tick: order.tick,
+        timestamp: env.ledger().timestamp(),
+    };
+    let slot = index % storage::get_maker_fill_buffer_size(env);
+    let slot_key = DataKey::MakerFill(order.maker.clone(), slot);
+    env.storage().persistent().set(&slot_key, &fill);
+    extend_persistent_ttl(env, &slot_key);
+
+    let count_key = DataKey::MakerFillCount(order.maker.clone());
+    env.storage().persistent().set(&count_key, &(index + 1));
+    extend_persistent_ttl(env, &count_key);
+}
+
+pub fn get_maker_fill(env: &Env, maker: &Address, index: u32, buffer_size: u32) -> Option<MakerFill> {
+    let key = DataKey::MakerFill(maker.clone(), index % buffer_size);
+    let fill: Option<MakerFill> = env.storage().persistent().get(&key);
+    if fill.is_some() {
+        extend_persistent_ttl(env, &key);
+    }
+    // The slot may hold a newer fill, or a stale one from a different buffer size
+    fill.filter(|f| f.index == index)
+}
+
 // ============ Order Storage Functions ============
 
 pub fn save_order(env: &Env, order: &Order) {
@@ -202,15 +460,26 @@ pub fn get_order(env: &Env, order_id: u128) -> Option<Order> {
     order
 }
 
-pub fn delete_order(env: &Env, order_id: u128) {
-    let key = DataKey::Order(order_id);
+/// Store a newly activated order and index it under its maker
+pub fn insert_order(env: &Env, order: &Order) {
+    save_order(env, order);
+    storage::add_maker_order_id(env, &order.maker, false, order.order_id);
+}
+
+pub fn delete_order(env: &Env, order: &Order) {
+    let key = DataKey::Order(order.order_id);
     env.storage().persistent().remove(&key);
+    storage::remove_maker_order_id(env, &order.maker, false, order.order_id);
 }
 
 pub fn save_pending_order(env: &Env, order: &Order) {
     let key = DataKey::PendingOrder(order.order_id);
     env.storage().persistent().set(&key, order);
     extend_persistent_ttl(env, &key);
+    storage::add_maker_order_id(env, &order.maker, true, order.order_id);
+    storage::add_pending_list_id(env, order.order_id);
+    let (_, escrow) = order.remaining_escrow();
+    storage::add_pending_escrow(env, &order.base_token, &order.quote_token, order.is_bid, escrow);
 }
 
 pub fn get_pending_order(env: &Env, order_id: u128) -> Option<Order> {
@@ -222,7 +491,11 @@ pub fn get_pending_order(env: &Env, order_id: u128) -> Option<Order> {
     order
 }
 
-pub fn delete_pending_order(env: &Env, order_id: u128) {
-    let key = DataKey::PendingOrder(order_id);
+pub fn delete_pending_order(env: &Env, order: &Order) {
+    let key = DataKey::PendingOrder(order.order_id);
     env.storage().persistent().remove(&key);
+    storage::remove_maker_order_id(env, &order.maker, true, order.order_id);
+    storage::remove_pending_list_id(env, order.order_id);
+    let (_, escrow) = order.remaining_escrow();
+    storage::add_pending_escrow(env, &order.base_token, &order.quote_token, order.is_bid, -escrow);
 }
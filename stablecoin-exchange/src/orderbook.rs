@@ -1,4 +1,4 @@
-use soroban_sdk::{contracttype, Address, Env};
+use soroban_sdk::{contracttype, Address, Bytes, BytesN, Env};
 
 use crate::error::Error;
 use crate::storage::{extend_persistent_ttl, DataKey};
@@ -9,6 +9,10 @@ pub const MAX_TICK: i32 = 2000;
 pub const TICK_SPACING: i32 = 10;
 pub const PRICE_SCALE: i128 = 100_000;
 pub const MIN_ORDER_SIZE: i128 = 10_000_000; // $10 with 6 decimals
+pub const BPS_SCALE: i128 = 10_000;
+pub const MAX_FEE_BPS: u32 = 100; // 1%
+/// Price observations kept per pair for TWAP queries
+pub const PRICE_OBSERVATION_SLOTS: u32 = 64;
 
 /// Represents liquidity at a specific price tick
 #[contracttype]
@@ -23,8 +27,10 @@ pub struct TickLevel {
 }
 
 impl TickLevel {
+    /// A level is empty when it has no head order or no liquidity left, so a
+    /// head pointer lingering on a drained level is never matched against
     pub fn is_empty(&self) -> bool {
-        self.head == 0
+        self.head == 0 || self.total_liquidity <= 0
     }
 }
 
@@ -40,6 +46,109 @@ pub struct Orderbook {
     pub best_bid_tick: i32,
     /// Best (lowest) ask tick
     pub best_ask_tick: i32,
+    /// Number of populated bid tick levels
+    pub bid_levels: u32,
+    /// Number of populated ask tick levels
+    pub ask_levels: u32,
+    /// Sum of the mid price times the ledgers it was in force, for TWAPs
+    pub price_cumulative: i128,
+    /// Mid price in force since `price_ledger` (0 until the book first had
+    /// both sides)
+    pub mid_price: i128,
+    /// Ledger of the latest price observation
+    pub price_ledger: u32,
+    /// Number of price observations ever recorded
+    pub observation_count: u32,
+    /// Smallest base amount an order may have, and smallest quote escrow a
+    /// bid may have (`MIN_ORDER_SIZE` unless overridden)
+    pub min_order_size: i128,
+    /// Trading fee in basis points skimmed from taker output for the treasury
+    pub fee_bps: u32,
+}
+
+/// Liquidity flags and level counts for a pair's book
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct BookHealth {
+    pub has_bids: bool,
+    pub has_asks: bool,
+    /// Liquidity on exactly one side
+    pub is_one_sided: bool,
+    /// Best bid at or above best ask
+    pub is_crossed: bool,
+    pub bid_levels: u32,
+    pub ask_levels: u32,
+}
+
+/// Compact summary of a pair's on-chain state for hybrid off-chain matching:
+/// parties sign its hash and later confirm it with `verify_checkpoint`
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub base_token: Address,
+    pub quote_token: Address,
+    pub best_bid_tick: i32,
+    pub best_ask_tick: i32,
+    pub bid_levels: u32,
+    pub ask_levels: u32,
+    /// Global active order ID counter
+    pub next_active_id: u128,
+    /// Global pending order ID counter
+    pub next_pending_id: u128,
+    /// Base held by the pair's active asks
+    pub escrow_base: i128,
+    /// Quote held by the pair's active bids
+    pub escrow_quote: i128,
+}
+
+/// Detailed outcome of a swap against a pair's book
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwapResult {
+    /// Output owed to the recipient, net of the taker fee
+    pub amount_out: i128,
+    /// Input left unmatched
+    pub refund: i128,
+    /// Base matched against makers
+    pub base_amount: i128,
+    /// Quote matched against makers
+    pub quote_amount: i128,
+    /// Volume-weighted fill price in quote per base, scaled by PRICE_SCALE
+    /// (0 if nothing filled)
+    pub vwap: i128,
+    /// How far the VWAP beat the limit tick's price, in the same scale:
+    /// limit minus VWAP for buys, VWAP minus limit for sells (0 if nothing
+    /// filled). Reporting only; makers keep their resting price.
+    pub price_improvement: i128,
+}
+
+/// TWAP accumulator value recorded the first time the mid price was updated
+/// in a ledger
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PriceObservation {
+    /// Sequential observation index for the pair
+    pub index: u32,
+    pub ledger: u32,
+    pub price_cumulative: i128,
+}
+
+/// A completed swap as recorded in a pair's trade history
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trade {
+    /// Sequential trade index for the pair
+    pub index: u32,
+    /// Last tick the swap filled at
+    pub tick: i32,
+    /// Base amount matched
+    pub base_amount: i128,
+    /// Quote amount matched
+    pub quote_amount: i128,
+    /// True if the taker bought base
+    pub is_buy: bool,
+    /// Ledger timestamp of the swap
+    pub timestamp: u64,
 }
 
 impl Orderbook {
@@ -49,6 +158,44 @@ impl Orderbook {
             quote_token,
             best_bid_tick: MIN_TICK - 1, // No bids initially
             best_ask_tick: MAX_TICK + 1, // No asks initially
+            bid_levels: 0,
+            ask_levels: 0,
+            price_cumulative: 0,
+            mid_price: 0,
+            price_ledger: 0,
+            observation_count: 0,
+            min_order_size: MIN_ORDER_SIZE,
+            fee_bps: 0,
+        }
+    }
+
+    /// Count a tick level that just received its first order
+    pub fn level_added(&mut self, is_bid: bool) {
+        if is_bid {
+            self.bid_levels += 1;
+        } else {
+            self.ask_levels += 1;
+        }
+    }
+
+    /// Count a tick level that was just deleted
+    pub fn level_removed(&mut self, is_bid: bool) {
+        if is_bid {
+            self.bid_levels = self.bid_levels.saturating_sub(1);
+        } else {
+            self.ask_levels = self.ask_levels.saturating_sub(1);
+        }
+    }
+
+    pub fn health(&self) -> BookHealth {
+        let (has_bids, has_asks) = (self.has_bids(), self.has_asks());
+        BookHealth {
+            has_bids,
+            has_asks,
+            is_one_sided: has_bids != has_asks,
+            is_crossed: has_bids && has_asks && self.best_bid_tick >= self.best_ask_tick,
+            bid_levels: self.bid_levels,
+            ask_levels: self.ask_levels,
         }
     }
 
@@ -59,6 +206,53 @@ impl Orderbook {
     pub fn has_asks(&self) -> bool {
         self.best_ask_tick <= MAX_TICK
     }
+
+    /// Whether a new order at `tick` would trade against the opposite side:
+    /// bids at or above the best ask, asks at or below the best bid
+    pub fn crosses(&self, is_bid: bool, tick: i32) -> bool {
+        if is_bid {
+            self.has_asks() && tick >= self.best_ask_tick
+        } else {
+            self.has_bids() && tick <= self.best_bid_tick
+        }
+    }
+
+    /// Average of the best bid and ask prices, if both sides have liquidity
+    pub fn current_mid_price(&self) -> Option<i128> {
+        if !self.has_bids() || !self.has_asks() {
+            return None;
+        }
+        Some((tick_to_price(self.best_bid_tick) + tick_to_price(self.best_ask_tick)) / 2)
+    }
+
+    /// Accumulator extrapolated to `ledger` at the mid price in force
+    pub fn price_cumulative_at(&self, ledger: u32) -> i128 {
+        self.price_cumulative + self.mid_price * ledger.saturating_sub(self.price_ledger) as i128
+    }
+
+    /// Fold the mid price in force into the accumulator and adopt the current
+    /// one, which is left unchanged while either side is empty
+    ///
+    /// Returns the observation to store on the first update in a ledger; later
+    /// updates in the same ledger only replace the mid price going forward.
+    pub fn record_mid_price(&mut self, ledger: u32) -> Option<PriceObservation> {
+        let mid_price = self.current_mid_price()?;
+        if self.observation_count > 0 && ledger == self.price_ledger {
+            self.mid_price = mid_price;
+            return None;
+        }
+
+        self.price_cumulative = self.price_cumulative_at(ledger);
+        self.price_ledger = ledger;
+        self.mid_price = mid_price;
+        let observation = PriceObservation {
+            index: self.observation_count,
+            ledger,
+            price_cumulative: self.price_cumulative,
+        };
+        self.observation_count += 1;
+        Some(observation)
+    }
 }
 
 
@@ -87,6 +281,84 @@ pub fn has_orderbook(env: &Env, base_token: &Address, quote_token: &Address) ->
     env.storage().persistent().has(&key)
 }
 
+// ============ Trade History Storage ============
+
+pub fn get_trade_count(env: &Env, base_token: &Address, quote_token: &Address) -> u32 {
+    let key = DataKey::TradeCount(base_token.clone(), quote_token.clone());
+    let count = env.storage().persistent().get(&key).unwrap_or(0);
+    if env.storage().persistent().has(&key) {
+        extend_persistent_ttl(env, &key);
+    }
+    count
+}
+
+/// Append a trade to the pair's ring buffer of `buffer_size` slots,
+/// overwriting the oldest once full
+pub fn push_trade(
+    env: &Env,
+    base_token: &Address,
+    quote_token: &Address,
+    trade: &Trade,
+    buffer_size: u32,
+) {
+    let slot_key = DataKey::Trade(base_token.clone(), quote_token.clone(), trade.index % buffer_size);
+    env.storage().persistent().set(&slot_key, trade);
+    extend_persistent_ttl(env, &slot_key);
+
+    let count_key = DataKey::TradeCount(base_token.clone(), quote_token.clone());
+    env.storage().persistent().set(&count_key, &(trade.index + 1));
+    extend_persistent_ttl(env, &count_key);
+}
+
+pub fn get_trade(
+    env: &Env,
+    base_token: &Address,
+    quote_token: &Address,
+    index: u32,
+    buffer_size: u32,
+) -> Option<Trade> {
+    let key = DataKey::Trade(base_token.clone(), quote_token.clone(), index % buffer_size);
+    let trade: Option<Trade> = env.storage().persistent().get(&key);
+    if trade.is_some() {
+        extend_persistent_ttl(env, &key);
+    }
+    // The slot may hold a newer trade, or a stale one from a different buffer size
+    trade.filter(|t| t.index == index)
+}
+
+// ============ Price Observation Storage ============
+
+/// Store a TWAP observation in the pair's ring buffer, overwriting the oldest
+/// once all `PRICE_OBSERVATION_SLOTS` are used
+pub fn push_price_observation(
+    env: &Env,
+    base_token: &Address,
+    quote_token: &Address,
+    observation: &PriceObservation,
+) {
+    let key = DataKey::PriceObservation(
+        base_token.clone(),
+        quote_token.clone(),
+        observation.index % PRICE_OBSERVATION_SLOTS,
+    );
+    env.storage().persistent().set(&key, observation);
+    extend_persistent_ttl(env, &key);
+}
+
+pub fn get_price_observation(
+    env: &Env,
+    base_token: &Address,
+    quote_token: &Address,
+    index: u32,
+) -> Option<PriceObservation> {
+    let key = DataKey::PriceObservation(base_token.clone(), quote_token.clone(), index % PRICE_OBSERVATION_SLOTS);
+    let observation: Option<PriceObservation> = env.storage().persistent().get(&key);
+    if observation.is_some() {
+        extend_persistent_ttl(env, &key);
+    }
+    observation.filter(|o| o.index == index)
+}
+
 // ============ Tick Level Storage ============
 
 pub fn get_bid_tick_level(env: &Env, base_token: &Address, quote_token: &Address, tick: i32) -> TickLevel {
@@ -129,10 +401,20 @@ pub fn delete_ask_tick_level(env: &Env, base_token: &Address, quote_token: &Addr
     env.storage().persistent().remove(&key);
 }
 
+/// Whether a bid or ask level entry is stored at `tick`, even an empty (warmed) one
+pub fn has_tick_level(env: &Env, base_token: &Address, quote_token: &Address, is_bid: bool, tick: i32) -> bool {
+    let key = if is_bid {
+        DataKey::BidTickLevel(base_token.clone(), quote_token.clone(), tick)
+    } else {
+        DataKey::AskTickLevel(base_token.clone(), quote_token.clone(), tick)
+    };
+    env.storage().persistent().has(&key)
+}
+
 // ============ Tick Validation ============
 
 pub fn validate_tick(tick: i32) -> Result<(), Error> {
-    if tick < MIN_TICK || tick > MAX_TICK {
+    if !(MIN_TICK..=MAX_TICK).contains(&tick) {
         return Err(Error::InvalidTick);
     }
     if tick % TICK_SPACING != 0 {
@@ -156,47 +438,115 @@ pub fn align_tick_up(tick: i32) -> i32 {
 
 // ============ Price/Tick Conversion ============
 
+/// Fixed-point scale of `TICK_POWERS`
+const POW_SCALE: i128 = 1_000_000_000_000_000_000;
+
+/// 1.0001^(2^i) scaled by POW_SCALE, enough bits to cover |tick| <= MAX_TICK
+const TICK_POWERS: [i128; 11] = [
+    1_000_100_000_000_000_000,
+    1_000_200_010_000_000_000,
+    1_000_400_060_004_000_100,
+    1_000_800_280_056_007_001,
+    1_001_601_200_560_182_044,
+    1_003_204_964_963_598_015,
+    1_006_420_201_727_613_920,
+    1_012_881_622_445_451_097,
+    1_025_929_181_087_729_344,
+    1_052_530_684_607_338_948,
+    1_107_820_842_039_993_614,
+];
+
 /// Convert tick to price
-/// Price = PRICE_SCALE * (1.0001 ^ tick)
-/// Approximation using integer math
+/// Price = PRICE_SCALE * (1.0001 ^ tick), rounded down
+///
+/// Multiplies together the powers of 1.0001 for the set bits of |tick| and
+/// inverts the result for negative ticks. Ticks outside the valid range are
+/// clamped to it.
 pub fn tick_to_price(tick: i32) -> i128 {
-    // Base price at tick 0 is PRICE_SCALE (100,000)
-    // Each tick multiplies by 1.0001
-    // We use a simplified linear approximation for small tick ranges
-    // price = PRICE_SCALE * (1 + tick * 0.0001)
-    // price = PRICE_SCALE + tick * 10
-
-    // For a more accurate exponential, we'd need more complex math
-    // But for stablecoins with small tick range, linear is reasonable
-    let adjustment = (tick as i128) * 10;
-    let price = PRICE_SCALE + adjustment;
-
-    // Ensure price is always positive
-    if price < 1 {
-        1
-    } else {
-        price
+    let tick = tick.clamp(MIN_TICK, MAX_TICK);
+
+    let mut ratio = POW_SCALE;
+    let mut bits = tick.unsigned_abs();
+    for power in TICK_POWERS {
+        if bits & 1 == 1 {
+            ratio = ratio * power / POW_SCALE;
+        }
+        bits >>= 1;
+    }
+    if tick < 0 {
+        ratio = POW_SCALE * POW_SCALE / ratio;
     }
+
+    ratio * PRICE_SCALE / POW_SCALE
 }
 
 /// Convert price to tick (inverse of tick_to_price)
+///
+/// Rounds down: returns the highest aligned tick whose price does not exceed
+/// `price`. If several adjacent ticks map to that same integer price (possible
+/// once the curve rounds), the lowest of them is returned, so the result is
+/// deterministic whatever the curve. Prices below MIN_TICK clamp to MIN_TICK.
+#[allow(dead_code)]
 pub fn price_to_tick(price: i128) -> i32 {
-    if price <= 0 {
+    price_to_tick_on_curve(price, tick_to_price)
+}
+
+/// `price_to_tick` against an arbitrary non-decreasing tick-to-price curve
+pub(crate) fn price_to_tick_on_curve(price: i128, curve: impl Fn(i32) -> i128) -> i32 {
+    // Binary search over aligned tick indices
+    let steps = (MAX_TICK - MIN_TICK) / TICK_SPACING;
+    let tick_at = |index: i32| MIN_TICK + index * TICK_SPACING;
+
+    if price < curve(MIN_TICK) {
         return MIN_TICK;
     }
 
-    // Inverse of: price = PRICE_SCALE + tick * 10
-    // tick = (price - PRICE_SCALE) / 10
-    let tick = ((price - PRICE_SCALE) / 10) as i32;
+    // Highest index whose price is <= price
+    let (mut lo, mut hi) = (0, steps);
+    while lo < hi {
+        let mid = lo + (hi - lo + 1) / 2;
+        if curve(tick_at(mid)) <= price {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    let floor_price = curve(tick_at(lo));
+
+    // Tie-break: lowest index sharing that price
+    let mut first = 0;
+    let mut last = lo;
+    while first < last {
+        let mid = first + (last - first) / 2;
+        if curve(tick_at(mid)) < floor_price {
+            first = mid + 1;
+        } else {
+            last = mid;
+        }
+    }
+    tick_at(first)
+}
 
-    // Clamp to valid range
-    if tick < MIN_TICK {
-        MIN_TICK
-    } else if tick > MAX_TICK {
-        MAX_TICK
-    } else {
-        align_tick_down(tick)
+/// Aligned tick for a limit order at `price`, rounded in the maker's favor:
+/// the highest tick priced at or below it for bids, the lowest at or above it
+/// for asks. Fails with `InvalidTick` if no tick in range qualifies.
+pub fn price_to_limit_tick(price: i128, is_bid: bool) -> Result<i32, Error> {
+    let tick = price_to_tick(price);
+    let tick_price = tick_to_price(tick);
+    if is_bid {
+        if tick_price > price {
+            return Err(Error::InvalidTick);
+        }
+        return Ok(tick);
+    }
+    if tick_price >= price {
+        return Ok(tick);
+    }
+    let next = tick + TICK_SPACING;
+    if next > MAX_TICK {
+        return Err(Error::InvalidTick);
     }
+    Ok(next)
 }
 
 /// Calculate quote amount from base amount and tick (for bids: buying base with quote)
@@ -206,6 +556,22 @@ pub fn calculate_quote_amount(base_amount: i128, tick: i32) -> i128 {
     (base_amount * price) / PRICE_SCALE
 }
 
+/// Calculate quote amount from base amount and tick, rounding up
+///
+/// Used to charge takers buying base so that a fill always costs at least one
+/// unit of quote; with floor rounding, a fill of a few base units below parity
+/// would otherwise cost nothing.
+pub fn calculate_quote_amount_ceil(base_amount: i128, tick: i32) -> i128 {
+    let price = tick_to_price(tick);
+    let product = base_amount * price;
+    let quote = product / PRICE_SCALE;
+    if product % PRICE_SCALE > 0 {
+        quote + 1
+    } else {
+        quote
+    }
+}
+
 /// Calculate base amount from quote amount and tick (for asks: selling base for quote)
 pub fn calculate_base_amount(quote_amount: i128, tick: i32) -> i128 {
     let price = tick_to_price(tick);
@@ -216,6 +582,17 @@ pub fn calculate_base_amount(quote_amount: i128, tick: i32) -> i128 {
     (quote_amount * PRICE_SCALE) / price
 }
 
+/// Fee of `fee_bps` on `amount` (rounded down in the payer's favor)
+pub fn calculate_fee(amount: i128, fee_bps: u32) -> i128 {
+    (amount * fee_bps as i128) / BPS_SCALE
+}
+
+/// Fee of `fee_bps` on `base_amount` sold at `tick`, charged in quote: the base
+/// fee and its conversion at the tick both round down in the payer's favor
+pub fn sell_fee_in_quote(base_amount: i128, tick: i32, fee_bps: u32) -> i128 {
+    calculate_quote_amount(calculate_fee(base_amount, fee_bps), tick)
+}
+
 // ============ Best Tick Discovery ============
 
 /// Find the next initialized bid tick at or below the given tick
@@ -275,3 +652,80 @@ pub fn update_best_ask_tick(env: &Env, orderbook: &mut Orderbook) {
         orderbook.best_ask_tick = MAX_TICK + 1;
     }
 }
+
+// ============ Book Traversal ============
+
+/// Find the first populated level strictly after the `(tick, is_ask)` slot, walking
+/// ticks in ascending order with the bid level visited before the ask level at
+/// each tick. Returns the slot and the head order ID of its queue.
+pub fn next_level_head(
+    env: &Env,
+    orderbook: &Orderbook,
+    from_tick: i32,
+    from_is_ask: bool,
+) -> Option<(i32, bool, u128)> {
+    let mut tick = from_tick;
+    let mut is_ask = from_is_ask;
+
+    loop {
+        if is_ask {
+            tick += TICK_SPACING;
+            is_ask = false;
+            if tick > MAX_TICK {
+                return None;
+            }
+        } else {
+            is_ask = true;
+        }
+
+        // Bids only rest at or below the best bid, asks at or above the best ask
+        let level = if is_ask {
+            if tick < orderbook.best_ask_tick {
+                continue;
+            }
+            get_ask_tick_level(env, &orderbook.base_token, &orderbook.quote_token, tick)
+        } else {
+            if tick > orderbook.best_bid_tick {
+                continue;
+            }
+            get_bid_tick_level(env, &orderbook.base_token, &orderbook.quote_token, tick)
+        };
+
+        if !level.is_empty() {
+            return Some((tick, is_ask, level.head));
+        }
+    }
+}
+
+/// SHA-256 over the book's best ticks, the global order ID counters and every
+/// populated level (tick, side, head, tail, liquidity) in traversal order
+pub fn state_hash(
+    env: &Env,
+    orderbook: &Orderbook,
+    next_active_id: u128,
+    next_pending_id: u128,
+) -> BytesN<32> {
+    let mut data = Bytes::new(env);
+    data.extend_from_array(&orderbook.best_bid_tick.to_be_bytes());
+    data.extend_from_array(&orderbook.best_ask_tick.to_be_bytes());
+    data.extend_from_array(&next_active_id.to_be_bytes());
+    data.extend_from_array(&next_pending_id.to_be_bytes());
+
+    let mut slot = next_level_head(env, orderbook, MIN_TICK - TICK_SPACING, true);
+    while let Some((tick, is_ask, _head)) = slot {
+        let level = if is_ask {
+            get_ask_tick_level(env, &orderbook.base_token, &orderbook.quote_token, tick)
+        } else {
+            get_bid_tick_level(env, &orderbook.base_token, &orderbook.quote_token, tick)
+        };
+        data.extend_from_array(&tick.to_be_bytes());
+        data.push_back(is_ask as u8);
+        data.extend_from_array(&level.head.to_be_bytes());
+        data.extend_from_array(&level.tail.to_be_bytes());
+        data.extend_from_array(&level.total_liquidity.to_be_bytes());
+
+        slot = next_level_head(env, orderbook, tick, is_ask);
+    }
+
+    env.crypto().sha256(&data).to_bytes()
+}
@@ -1,4 +1,6 @@
-use soroban_sdk::{contracttype, Address, Env};
+use soroban_sdk::{contracttype, Address, Env, Vec};
+
+use crate::events;
 
 /// Storage keys for the contract
 #[contracttype]
@@ -22,6 +24,60 @@ pub enum DataKey {
     AskTickLevel(Address, Address, i32),
     /// User balance (user, token)
     Balance(Address, Address),
+    /// Contract storage layout version
+    Version,
+    /// Maximum number of order IDs accepted by a single execute_block call
+    MaxActivationsPerBlock,
+    /// Active order IDs owned by a maker
+    MakerOrders(Address),
+    /// Pending order IDs owned by a maker
+    MakerPendingOrders(Address),
+    /// Whether activation keeps the pending order ID instead of minting a new one
+    StableOrderIds,
+    /// Development mode: `place` activates orders immediately instead of queueing them
+    AutoActivate,
+    /// Whether flip orders can be placed and spawn their flipped side when filled
+    FlipsEnabled,
+    /// Number of trades ever recorded for a pair (base_token, quote_token)
+    TradeCount(Address, Address),
+    /// Trade history ring buffer slot (base_token, quote_token, slot)
+    Trade(Address, Address, u32),
+    /// Number of trade history slots kept per pair
+    TradeBufferSize,
+    /// Number of fills ever recorded for a maker
+    MakerFillCount(Address),
+    /// Maker fill history ring buffer slot (maker, slot)
+    MakerFill(Address, u32),
+    /// Number of fill history slots kept per maker
+    MakerFillBufferSize,
+    /// Tokens allowed as the quote asset of new pairs (empty allows any)
+    AllowedQuoteTokens,
+    /// Fee in basis points taken from the refund of a canceled order
+    CancelFeeBps,
+    /// Seconds an order must rest before it can be canceled without the fee
+    CancelFeeMinAge,
+    /// Escrow held by a pair's resting orders as (base from asks, quote from bids)
+    PairEscrow(Address, Address),
+    /// Escrow held by a pair's pending orders as (base from asks, quote from bids)
+    PendingEscrow(Address, Address),
+    /// Address allowed to call execute_block
+    Sequencer,
+    /// Whether trading is halted
+    Paused,
+    /// TWAP price observation ring buffer slot (base_token, quote_token, slot)
+    PriceObservation(Address, Address, u32),
+    /// Address credited with the trading fee
+    Treasury,
+    /// Fee AMM pool that swaps may route unfilled input through
+    AmmPool,
+    /// Whether buys pay the trading fee in quote instead of base
+    FeeInQuoteOnly,
+    /// IDs of pending orders in one page of the pending queue, oldest first
+    PendingPage(u32),
+    /// Lowest pending queue page that may still hold IDs
+    PendingFirstPage,
+    /// Net base a user gained from fills in a pair (user, base_token, quote_token)
+    Position(Address, Address, Address),
 }
 
 // TTL constants
@@ -57,8 +113,21 @@ pub fn has_admin(env: &Env) -> bool {
     env.storage().instance().has(&DataKey::Admin)
 }
 
+// ============ Version Storage ============
+
+pub fn get_version(env: &Env) -> u32 {
+    env.storage().instance().get(&DataKey::Version).unwrap_or(0)
+}
+
+pub fn set_version(env: &Env, version: u32) {
+    env.storage().instance().set(&DataKey::Version, &version);
+}
+
 // ============ Order ID Counters ============
 
+/// Set on every pending order ID so pending and minted active IDs never collide
+pub const PENDING_ORDER_ID_BIT: u128 = 1 << 127;
+
 pub fn get_next_active_order_id(env: &Env) -> u128 {
     let key = DataKey::ActiveOrderId;
     let id: u128 = env.storage().instance().get(&key).unwrap_or(1);
@@ -70,7 +139,7 @@ pub fn get_next_pending_order_id(env: &Env) -> u128 {
     let key = DataKey::PendingOrderId;
     let id: u128 = env.storage().instance().get(&key).unwrap_or(1);
     env.storage().instance().set(&key, &(id + 1));
-    id
+    id | PENDING_ORDER_ID_BIT
 }
 
 pub fn get_current_active_order_id(env: &Env) -> u128 {
@@ -81,10 +150,412 @@ pub fn get_current_active_order_id(env: &Env) -> u128 {
 }
 
 pub fn get_current_pending_order_id(env: &Env) -> u128 {
-    env.storage()
+    let id: u128 = env
+        .storage()
         .instance()
         .get(&DataKey::PendingOrderId)
-        .unwrap_or(1)
+        .unwrap_or(1);
+    id | PENDING_ORDER_ID_BIT
+}
+
+// ============ Stable Order IDs ============
+
+pub fn get_stable_order_ids(env: &Env) -> bool {
+    env.storage()
+        .instance()
+        .get(&DataKey::StableOrderIds)
+        .unwrap_or(false)
+}
+
+pub fn set_stable_order_ids(env: &Env, enabled: bool) {
+    env.storage()
+        .instance()
+        .set(&DataKey::StableOrderIds, &enabled);
+}
+
+// ============ Auto Activation (development mode) ============
+
+pub fn get_auto_activate(env: &Env) -> bool {
+    env.storage()
+        .instance()
+        .get(&DataKey::AutoActivate)
+        .unwrap_or(false)
+}
+
+pub fn set_auto_activate(env: &Env, enabled: bool) {
+    env.storage().instance().set(&DataKey::AutoActivate, &enabled);
+}
+
+// ============ Pause ============
+
+pub fn get_paused(env: &Env) -> bool {
+    env.storage()
+        .instance()
+        .get(&DataKey::Paused)
+        .unwrap_or(false)
+}
+
+pub fn set_paused(env: &Env, paused: bool) {
+    env.storage().instance().set(&DataKey::Paused, &paused);
+}
+
+// ============ Flip Orders ============
+
+pub fn get_flips_enabled(env: &Env) -> bool {
+    env.storage()
+        .instance()
+        .get(&DataKey::FlipsEnabled)
+        .unwrap_or(true)
+}
+
+pub fn set_flips_enabled(env: &Env, enabled: bool) {
+    env.storage().instance().set(&DataKey::FlipsEnabled, &enabled);
+}
+
+// ============ Fee Storage ============
+
+/// Sequencer address, defaulting to the admin until one is configured
+pub fn get_sequencer(env: &Env) -> Address {
+    env.storage()
+        .instance()
+        .get(&DataKey::Sequencer)
+        .unwrap_or_else(|| get_admin(env))
+}
+
+pub fn set_sequencer(env: &Env, sequencer: &Address) {
+    env.storage().instance().set(&DataKey::Sequencer, sequencer);
+}
+
+/// Treasury address, defaulting to the admin until one is configured
+pub fn get_treasury(env: &Env) -> Address {
+    env.storage()
+        .instance()
+        .get(&DataKey::Treasury)
+        .unwrap_or_else(|| get_admin(env))
+}
+
+pub fn set_treasury(env: &Env, treasury: &Address) {
+    env.storage().instance().set(&DataKey::Treasury, treasury);
+}
+
+pub fn get_fee_in_quote_only(env: &Env) -> bool {
+    env.storage()
+        .instance()
+        .get(&DataKey::FeeInQuoteOnly)
+        .unwrap_or(false)
+}
+
+pub fn set_fee_in_quote_only(env: &Env, enabled: bool) {
+    env.storage().instance().set(&DataKey::FeeInQuoteOnly, &enabled);
+}
+
+pub fn get_amm_pool(env: &Env) -> Option<Address> {
+    env.storage().instance().get(&DataKey::AmmPool)
+}
+
+pub fn set_amm_pool(env: &Env, amm: &Address) {
+    env.storage().instance().set(&DataKey::AmmPool, amm);
+}
+
+pub fn get_cancel_fee_bps(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::CancelFeeBps)
+        .unwrap_or(0)
+}
+
+pub fn set_cancel_fee_bps(env: &Env, fee_bps: u32) {
+    env.storage().instance().set(&DataKey::CancelFeeBps, &fee_bps);
+}
+
+pub fn get_cancel_fee_min_age(env: &Env) -> u64 {
+    env.storage()
+        .instance()
+        .get(&DataKey::CancelFeeMinAge)
+        .unwrap_or(0)
+}
+
+pub fn set_cancel_fee_min_age(env: &Env, min_age: u64) {
+    env.storage()
+        .instance()
+        .set(&DataKey::CancelFeeMinAge, &min_age);
+}
+
+// ============ Quote Token Allowlist ============
+
+pub fn get_allowed_quote_tokens(env: &Env) -> Vec<Address> {
+    env.storage()
+        .instance()
+        .get(&DataKey::AllowedQuoteTokens)
+        .unwrap_or(Vec::new(env))
+}
+
+pub fn add_allowed_quote_token(env: &Env, token: &Address) {
+    let mut tokens = get_allowed_quote_tokens(env);
+    if tokens.contains(token) {
+        return;
+    }
+    tokens.push_back(token.clone());
+    env.storage()
+        .instance()
+        .set(&DataKey::AllowedQuoteTokens, &tokens);
+}
+
+pub fn remove_allowed_quote_token(env: &Env, token: &Address) {
+    let mut tokens = get_allowed_quote_tokens(env);
+    let Some(index) = tokens.first_index_of(token) else {
+        return;
+    };
+    tokens.remove(index);
+    env.storage()
+        .instance()
+        .set(&DataKey::AllowedQuoteTokens, &tokens);
+}
+
+// ============ Block Execution Config ============
+
+/// Default cap on activations per execute_block, keeping calls within Soroban's ledger access limits
+pub const DEFAULT_MAX_ACTIVATIONS_PER_BLOCK: u32 = 50;
+
+pub fn get_max_activations_per_block(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::MaxActivationsPerBlock)
+        .unwrap_or(DEFAULT_MAX_ACTIVATIONS_PER_BLOCK)
+}
+
+pub fn set_max_activations_per_block(env: &Env, max: u32) {
+    env.storage()
+        .instance()
+        .set(&DataKey::MaxActivationsPerBlock, &max);
+}
+
+// ============ Maker Order Index ============
+
+fn maker_orders_key(maker: &Address, pending: bool) -> DataKey {
+    if pending {
+        DataKey::MakerPendingOrders(maker.clone())
+    } else {
+        DataKey::MakerOrders(maker.clone())
+    }
+}
+
+/// Order IDs owned by a maker, in placement order
+pub fn get_maker_order_ids(env: &Env, maker: &Address, pending: bool) -> Vec<u128> {
+    let key = maker_orders_key(maker, pending);
+    let ids = env.storage().persistent().get(&key);
+    if ids.is_some() {
+        extend_persistent_ttl(env, &key);
+    }
+    ids.unwrap_or(Vec::new(env))
+}
+
+pub fn add_maker_order_id(env: &Env, maker: &Address, pending: bool, order_id: u128) {
+    let mut ids = get_maker_order_ids(env, maker, pending);
+    if ids.contains(order_id) {
+        return;
+    }
+    ids.push_back(order_id);
+    let key = maker_orders_key(maker, pending);
+    env.storage().persistent().set(&key, &ids);
+    extend_persistent_ttl(env, &key);
+}
+
+pub fn remove_maker_order_id(env: &Env, maker: &Address, pending: bool, order_id: u128) {
+    let mut ids = get_maker_order_ids(env, maker, pending);
+    let Some(index) = ids.first_index_of(order_id) else {
+        return;
+    };
+    ids.remove(index);
+    let key = maker_orders_key(maker, pending);
+    if ids.is_empty() {
+        env.storage().persistent().remove(&key);
+    } else {
+        env.storage().persistent().set(&key, &ids);
+        extend_persistent_ttl(env, &key);
+    }
+}
+
+// ============ Pending Order List ============
+
+/// Pending order IDs per page of the pending queue. IDs are handed out in
+/// increasing order, so page `n` holds the IDs `n * PENDING_PAGE_SIZE` up to
+/// the next page and every entry stays small however many orders are queued.
+pub const PENDING_PAGE_SIZE: u128 = 64;
+/// Empty pages a removal steps the first page past, bounding its cost
+const MAX_PENDING_PAGE_SKIP: u32 = 16;
+
+fn pending_page(order_id: u128) -> u32 {
+    ((order_id & !PENDING_ORDER_ID_BIT) / PENDING_PAGE_SIZE) as u32
+}
+
+pub fn get_pending_first_page(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::PendingFirstPage)
+        .unwrap_or(0)
+}
+
+/// Page holding the most recently assigned pending order ID
+pub fn get_pending_last_page(env: &Env) -> u32 {
+    pending_page(get_current_pending_order_id(env))
+}
+
+pub fn get_pending_page(env: &Env, page: u32) -> Vec<u128> {
+    let key = DataKey::PendingPage(page);
+    let ids = env.storage().persistent().get(&key);
+    if ids.is_some() {
+        extend_persistent_ttl(env, &key);
+    }
+    ids.unwrap_or(Vec::new(env))
+}
+
+pub fn add_pending_list_id(env: &Env, order_id: u128) {
+    let page = pending_page(order_id);
+    let mut ids = get_pending_page(env, page);
+    if ids.contains(order_id) {
+        return;
+    }
+    ids.push_back(order_id);
+    let key = DataKey::PendingPage(page);
+    env.storage().persistent().set(&key, &ids);
+    extend_persistent_ttl(env, &key);
+}
+
+pub fn remove_pending_list_id(env: &Env, order_id: u128) {
+    let page = pending_page(order_id);
+    let mut ids = get_pending_page(env, page);
+    let Some(index) = ids.first_index_of(order_id) else {
+        return;
+    };
+    ids.remove(index);
+    let key = DataKey::PendingPage(page);
+    if !ids.is_empty() {
+        env.storage().persistent().set(&key, &ids);
+        extend_persistent_ttl(env, &key);
+        return;
+    }
+    env.storage().persistent().remove(&key);
+
+    // Move the first page past emptied pages so readers skip them
+    let mut first = get_pending_first_page(env);
+    let last = get_pending_last_page(env);
+    let mut skipped = 0;
+    while first < last
+        && skipped < MAX_PENDING_PAGE_SKIP
+        && !env.storage().persistent().has(&DataKey::PendingPage(first))
+    {
+        first += 1;
+        skipped += 1;
+    }
+    env.storage()
+        .instance()
+        .set(&DataKey::PendingFirstPage, &first);
+}
+
+// ============ Trade History Config ============
+
+pub const DEFAULT_TRADE_BUFFER_SIZE: u32 = 32;
+pub const MAX_TRADE_BUFFER_SIZE: u32 = 256;
+
+pub fn get_trade_buffer_size(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::TradeBufferSize)
+        .unwrap_or(DEFAULT_TRADE_BUFFER_SIZE)
+}
+
+pub fn set_trade_buffer_size(env: &Env, size: u32) {
+    env.storage()
+        .instance()
+        .set(&DataKey::TradeBufferSize, &size);
+}
+
+// ============ Maker Fill History Config ============
+
+pub const DEFAULT_MAKER_FILL_BUFFER_SIZE: u32 = 32;
+pub const MAX_MAKER_FILL_BUFFER_SIZE: u32 = 256;
+
+pub fn get_maker_fill_buffer_size(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::MakerFillBufferSize)
+        .unwrap_or(DEFAULT_MAKER_FILL_BUFFER_SIZE)
+}
+
+pub fn set_maker_fill_buffer_size(env: &Env, size: u32) {
+    env.storage()
+        .instance()
+        .set(&DataKey::MakerFillBufferSize, &size);
+}
+
+// ============ Pair Escrow Totals ============
+
+pub fn get_pair_escrow(env: &Env, base_token: &Address, quote_token: &Address) -> (i128, i128) {
+    let key = DataKey::PairEscrow(base_token.clone(), quote_token.clone());
+    let escrow = env.storage().persistent().get(&key);
+    if escrow.is_some() {
+        extend_persistent_ttl(env, &key);
+    }
+    escrow.unwrap_or((0, 0))
+}
+
+/// Adjust the quote escrow total for bids or the base escrow total for asks
+pub fn add_pair_escrow(env: &Env, base_token: &Address, quote_token: &Address, is_bid: bool, delta: i128) {
+    let (mut base_escrow, mut quote_escrow) = get_pair_escrow(env, base_token, quote_token);
+    if is_bid {
+        quote_escrow += delta;
+    } else {
+        base_escrow += delta;
+    }
+    let key = DataKey::PairEscrow(base_token.clone(), quote_token.clone());
+    env.storage().persistent().set(&key, &(base_escrow, quote_escrow));
+    extend_persistent_ttl(env, &key);
+}
+
+pub fn get_pending_escrow(env: &Env, base_token: &Address, quote_token: &Address) -> (i128, i128) {
+    let key = DataKey::PendingEscrow(base_token.clone(), quote_token.clone());
+    let escrow = env.storage().persistent().get(&key);
+    if escrow.is_some() {
+        extend_persistent_ttl(env, &key);
+    }
+    escrow.unwrap_or((0, 0))
+}
+
+/// Adjust the pending quote escrow total for bids or the pending base escrow total for asks
+pub fn add_pending_escrow(env: &Env, base_token: &Address, quote_token: &Address, is_bid: bool, delta: i128) {
+    let (mut base_escrow, mut quote_escrow) = get_pending_escrow(env, base_token, quote_token);
+    if is_bid {
+        quote_escrow += delta;
+    } else {
+        base_escrow += delta;
+    }
+    let key = DataKey::PendingEscrow(base_token.clone(), quote_token.clone());
+    env.storage().persistent().set(&key, &(base_escrow, quote_escrow));
+    extend_persistent_ttl(env, &key);
+}
+
+// ============ Positions ============
+
+/// Net base `user` has bought (positive) or sold (negative) through fills in a pair
+pub fn get_position(env: &Env, user: &Address, base_token: &Address, quote_token: &Address) -> i128 {
+    let key = DataKey::Position(user.clone(), base_token.clone(), quote_token.clone());
+    let position = env.storage().persistent().get(&key);
+    if position.is_some() {
+        extend_persistent_ttl(env, &key);
+    }
+    position.unwrap_or(0)
+}
+
+pub fn add_position(env: &Env, user: &Address, base_token: &Address, quote_token: &Address, delta: i128) {
+    let position = get_position(env, user, base_token, quote_token) + delta;
+    let key = DataKey::Position(user.clone(), base_token.clone(), quote_token.clone());
+    if position == 0 {
+        env.storage().persistent().remove(&key);
+    } else {
+        env.storage().persistent().set(&key, &position);
+        extend_persistent_ttl(env, &key);
+    }
 }
 
 // ============ Balance Storage ============
@@ -105,8 +576,9 @@ pub fn set_balance(env: &Env, user: &Address, token: &Address, amount: i128) {
 }
 
 pub fn add_balance(env: &Env, user: &Address, token: &Address, amount: i128) {
-    let current = get_balance(env, user, token);
-    set_balance(env, user, token, current + amount);
+    let new_balance = get_balance(env, user, token) + amount;
+    set_balance(env, user, token, new_balance);
+    events::emit_balance_changed(env, user, token, amount, new_balance);
 }
 
 pub fn sub_balance(env: &Env, user: &Address, token: &Address, amount: i128) -> bool {
@@ -115,5 +587,6 @@ pub fn sub_balance(env: &Env, user: &Address, token: &Address, amount: i128) ->
         return false;
     }
     set_balance(env, user, token, current - amount);
+    events::emit_balance_changed(env, user, token, -amount, current - amount);
     true
 }
@@ -1,12 +1,51 @@
 use crate::{
-    orderbook::{tick_to_price, PRICE_SCALE, MIN_TICK, MAX_TICK, TICK_SPACING, MIN_ORDER_SIZE},
+    orderbook::{
+        calculate_base_amount, calculate_quote_amount, get_orderbook, price_to_tick, price_to_tick_on_curve,
+        save_ask_tick_level, save_orderbook, tick_to_price, TickLevel, PRICE_SCALE, MIN_TICK, MAX_TICK, TICK_SPACING, MIN_ORDER_SIZE,
+    },
+    order::MakerFill,
     Error, StablecoinExchange, StablecoinExchangeClient,
 };
 use soroban_sdk::{
-    testutils::Address as _,
+    contract, contractimpl, symbol_short,
+    testutils::{Address as _, Events, Ledger, MockAuth, MockAuthInvoke},
     token::{StellarAssetClient, TokenClient},
-    vec, Address, Env,
+    vec, Address, Env, IntoVal,
 };
+use tempo_fee_amm::{TempoFeeAMM, TempoFeeAMMClient};
+
+/// Maker contract that records fill callbacks, or rejects them once told to
+#[contract]
+pub struct RecordingMaker;
+
+#[contractimpl]
+impl RecordingMaker {
+    pub fn set_failing(env: Env, failing: bool) {
+        env.storage().instance().set(&symbol_short!("failing"), &failing);
+    }
+
+    pub fn on_order_filled(
+        env: Env,
+        order_id: u128,
+        _base_token: Address,
+        _quote_token: Address,
+        _is_bid: bool,
+        _tick: i32,
+        fill_amount: i128,
+        remaining: i128,
+    ) {
+        if env.storage().instance().get(&symbol_short!("failing")).unwrap_or(false) {
+            panic!("callback rejected");
+        }
+        env.storage()
+            .instance()
+            .set(&symbol_short!("last"), &(order_id, fill_amount, remaining));
+    }
+
+    pub fn last_fill(env: Env) -> Option<(u128, i128, i128)> {
+        env.storage().instance().get(&symbol_short!("last"))
+    }
+}
 
 fn create_token<'a>(env: &Env, admin: &Address) -> (TokenClient<'a>, StellarAssetClient<'a>) {
     let contract_address = env.register_stellar_asset_contract_v2(admin.clone());
@@ -34,7 +73,7 @@ fn setup_test_env() -> (
     // Deploy exchange
     let exchange_address = env.register(StablecoinExchange, ());
     let exchange = StablecoinExchangeClient::new(&env, &exchange_address);
-    exchange.initialize(&admin);
+    exchange.initialize(&admin, &None);
 
     // Create tokens
     let (base_token, base_admin) = create_token(&env, &admin);
@@ -54,6 +93,20 @@ fn setup_test_env() -> (
     )
 }
 
+fn place_active(
+    env: &Env,
+    exchange: &StablecoinExchangeClient,
+    maker: &Address,
+    base_token: &Address,
+    quote_token: &Address,
+    is_bid: bool,
+    tick: i32,
+    amount: i128,
+) {
+    let order_id = exchange.place(maker, base_token, quote_token, &is_bid, &tick, &amount, &0);
+    exchange.execute_block(&exchange.admin(), base_token, quote_token, &vec![env, order_id]);
+}
+
 #[test]
 fn test_initialize() {
     let env = Env::default();
@@ -63,15 +116,15 @@ fn test_initialize() {
     let exchange_address = env.register(StablecoinExchange, ());
     let exchange = StablecoinExchangeClient::new(&env, &exchange_address);
 
-    exchange.initialize(&admin);
+    exchange.initialize(&admin, &None);
     assert_eq!(exchange.admin(), admin);
 }
 
 #[test]
 fn test_create_pair() {
-    let (_env, exchange, admin, _user, base_token, quote_token, _, _) = setup_test_env();
+    let (_env, exchange, _admin, _user, base_token, quote_token, _, _) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
 
     // Verify orderbook exists
     let orderbook = exchange.get_orderbook(&base_token.address, &quote_token.address);
@@ -81,28 +134,49 @@ fn test_create_pair() {
 
 #[test]
 fn test_create_pair_same_token_fails() {
-    let (_env, exchange, admin, _user, base_token, _quote_token, _, _) = setup_test_env();
+    let (_env, exchange, _admin, _user, base_token, _quote_token, _, _) = setup_test_env();
 
-    let result = exchange.try_create_pair(&base_token.address, &base_token.address);
+    let result = exchange.try_create_pair(&base_token.address, &base_token.address, &None);
     assert_eq!(result, Err(Ok(Error::SameToken)));
 }
 
 #[test]
 fn test_create_pair_duplicate_fails() {
-    let (_env, exchange, admin, _user, base_token, quote_token, _, _) = setup_test_env();
+    let (_env, exchange, _admin, _user, base_token, quote_token, _, _) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
 
-    let result = exchange.try_create_pair(&base_token.address, &quote_token.address);
+    let result = exchange.try_create_pair(&base_token.address, &quote_token.address, &None);
     assert_eq!(result, Err(Ok(Error::PairAlreadyExists)));
 }
 
+#[test]
+fn test_create_pair_quote_allowlist() {
+    let (env, exchange, admin, _user, base_token, quote_token, _, _) = setup_test_env();
+
+    let (other_token, _) = create_token(&env, &admin);
+    exchange.add_allowed_quote_token(&admin, &quote_token.address);
+    assert_eq!(exchange.allowed_quote_tokens(), vec![&env, quote_token.address.clone()]);
+
+    // Quote token on the list is accepted
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    // Anything else is rejected as quote
+    let result = exchange.try_create_pair(&base_token.address, &other_token.address, &None);
+    assert_eq!(result, Err(Ok(Error::QuoteTokenNotAllowed)));
+
+    // An emptied allowlist admits any quote token again
+    exchange.remove_allowed_quote_token(&admin, &quote_token.address);
+    assert!(exchange.allowed_quote_tokens().is_empty());
+    exchange.create_pair(&base_token.address, &other_token.address, &None);
+}
+
 #[test]
 fn test_place_bid_order() {
-    let (_env, exchange, admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+    let (_env, exchange, _admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
 
     // Create pair
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
 
     // Mint quote tokens to user
     quote_admin.mint(&user, &1_000_000_000);
@@ -117,6 +191,7 @@ fn test_place_bid_order() {
         &true,
         &tick,
         &amount,
+        &0,
     );
 
     assert!(order_id > 0);
@@ -133,10 +208,10 @@ fn test_place_bid_order() {
 
 #[test]
 fn test_place_ask_order() {
-    let (_env, exchange, admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
+    let (_env, exchange, _admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
 
     // Create pair
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
 
     // Mint base tokens to user
     base_admin.mint(&user, &1_000_000_000);
@@ -151,6 +226,7 @@ fn test_place_ask_order() {
         &false,
         &tick,
         &amount,
+        &0,
     );
 
     assert!(order_id > 0);
@@ -163,9 +239,9 @@ fn test_place_ask_order() {
 
 #[test]
 fn test_order_too_small_fails() {
-    let (_env, exchange, admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+    let (_env, exchange, _admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
     quote_admin.mint(&user, &1_000_000_000);
 
     // Try to place order below minimum
@@ -176,6 +252,7 @@ fn test_order_too_small_fails() {
         &true,
         &0,
         &(MIN_ORDER_SIZE - 1),
+        &0,
     );
 
     assert_eq!(result, Err(Ok(Error::OrderTooSmall)));
@@ -183,9 +260,9 @@ fn test_order_too_small_fails() {
 
 #[test]
 fn test_invalid_tick_fails() {
-    let (_env, exchange, admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+    let (_env, exchange, _admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
     quote_admin.mint(&user, &1_000_000_000);
 
     // Try tick outside range
@@ -196,6 +273,7 @@ fn test_invalid_tick_fails() {
         &true,
         &(MAX_TICK + 1),
         &MIN_ORDER_SIZE,
+        &0,
     );
 
     assert_eq!(result, Err(Ok(Error::InvalidTick)));
@@ -205,7 +283,7 @@ fn test_invalid_tick_fails() {
 fn test_execute_block() {
     let (env, exchange, admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
     quote_admin.mint(&user, &1_000_000_000);
 
     let order_id = exchange.place(
@@ -215,10 +293,12 @@ fn test_execute_block() {
         &true,
         &0,
         &MIN_ORDER_SIZE,
+        &0,
     );
 
     // Execute block to activate order
     exchange.execute_block(
+        &admin,
         &base_token.address,
         &quote_token.address,
         &vec![&env, order_id],
@@ -233,11 +313,106 @@ fn test_execute_block() {
     assert!(orderbook.has_bids());
 }
 
+#[test]
+fn test_execute_block_requires_sequencer() {
+    let (env, exchange, admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    quote_admin.mint(&user, &1_000_000_000);
+    assert_eq!(exchange.sequencer(), admin);
+
+    let order_id = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &0,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+    let ids = vec![&env, order_id];
+
+    // Users cannot activate their own orders
+    let result = exchange.try_execute_block(&user, &base_token.address, &quote_token.address, &ids);
+    assert_eq!(result, Err(Ok(Error::Unauthorized)));
+
+    // Once rotated, only the new sequencer can activate, not the admin
+    let sequencer = Address::generate(&env);
+    exchange.set_sequencer(&admin, &sequencer);
+    assert_eq!(exchange.sequencer(), sequencer);
+    let result =
+        exchange.try_execute_block(&admin, &base_token.address, &quote_token.address, &ids);
+    assert_eq!(result, Err(Ok(Error::Unauthorized)));
+    assert!(exchange.get_pending_order(&order_id).is_some());
+
+    exchange.execute_block(&sequencer, &base_token.address, &quote_token.address, &ids);
+    assert!(exchange.get_pending_order(&order_id).is_none());
+    assert!(exchange
+        .get_orderbook(&base_token.address, &quote_token.address)
+        .has_bids());
+}
+
+#[test]
+fn test_stable_order_ids_survive_activation() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    base_admin.mint(&user, &1_000_000_000);
+
+    // Default mode: activation mints a new, disjoint active ID
+    let pending_id = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, pending_id],
+    );
+    assert!(exchange.get_order(&pending_id).is_none());
+    assert!(exchange.get_order(&1).is_some());
+
+    exchange.set_stable_order_ids(&admin, &true);
+    assert!(exchange.stable_order_ids());
+
+    let order_id = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &20,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+    assert!(exchange.get_pending_order(&order_id).is_some());
+
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+    assert!(exchange.get_pending_order(&order_id).is_none());
+    let active = exchange.get_order(&order_id).unwrap();
+    assert_eq!(active.order_id, order_id);
+    assert_eq!(active.tick, 20);
+
+    // The same ID still addresses the order for cancellation
+    assert_eq!(exchange.cancel(&user, &order_id), MIN_ORDER_SIZE);
+    assert!(exchange.get_order(&order_id).is_none());
+}
+
 #[test]
 fn test_cancel_pending_order() {
-    let (_env, exchange, admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+    let (_env, exchange, _admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
     quote_admin.mint(&user, &1_000_000_000);
 
     let order_id = exchange.place(
@@ -247,6 +422,7 @@ fn test_cancel_pending_order() {
         &true,
         &0,
         &MIN_ORDER_SIZE,
+        &0,
     );
 
     // Cancel the order
@@ -257,11 +433,59 @@ fn test_cancel_pending_order() {
     assert!(exchange.get_pending_order(&order_id).is_none());
 }
 
+#[test]
+fn test_replace_order() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    base_admin.mint(&user, &100_000_000);
+
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        20,
+        20_000_000,
+    );
+
+    // Grow the ask and move it: 20M comes back from escrow, 10M is topped up
+    let new_id = exchange.replace(&user, &1, &30, &30_000_000);
+    assert!(exchange.get_order(&1).is_none());
+    let level = exchange.get_tick_level(&base_token.address, &quote_token.address, &false, &20);
+    assert!(level.is_empty());
+
+    let new_order = exchange.get_pending_order(&new_id).unwrap();
+    assert!(!new_order.is_bid);
+    assert_eq!(new_order.tick, 30);
+    assert_eq!(new_order.amount, 30_000_000);
+    assert_eq!(base_token.balance(&user), 70_000_000);
+    assert_eq!(base_token.balance(&exchange.address), 30_000_000);
+    assert_eq!(exchange.balance_of(&user, &base_token.address), 0);
+
+    // Shrinking leaves the excess escrow in the exchange balance
+    let smaller_id = exchange.replace(&user, &new_id, &30, &10_000_000);
+    assert!(exchange.get_pending_order(&new_id).is_none());
+    assert_eq!(
+        exchange.get_pending_order(&smaller_id).unwrap().amount,
+        10_000_000
+    );
+    assert_eq!(base_token.balance(&user), 70_000_000);
+    assert_eq!(exchange.balance_of(&user, &base_token.address), 20_000_000);
+
+    // Only the maker may replace
+    let other = Address::generate(&env);
+    let result = exchange.try_replace(&other, &smaller_id, &30, &10_000_000);
+    assert_eq!(result, Err(Ok(Error::NotOrderOwner)));
+}
+
 #[test]
 fn test_place_flip_order() {
-    let (_env, exchange, admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+    let (_env, exchange, _admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
     quote_admin.mint(&user, &1_000_000_000);
 
     // Place flip bid: buy at tick 0, flip to sell at tick 100
@@ -273,6 +497,7 @@ fn test_place_flip_order() {
         &0,
         &MIN_ORDER_SIZE,
         &100, // flip_tick must be > tick for bids
+        &0,
     );
 
     let pending = exchange.get_pending_order(&order_id);
@@ -282,11 +507,73 @@ fn test_place_flip_order() {
     assert_eq!(order.flip_tick, 100);
 }
 
+#[test]
+fn test_relative_flip_lands_at_fill_tick_plus_spread() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+
+    let order_id = exchange.place_flip_relative(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &20,
+        &MIN_ORDER_SIZE,
+        &30,
+    );
+    let order = exchange.get_pending_order(&order_id).unwrap();
+    assert!(order.is_flip);
+    assert_eq!(order.spread_ticks, 30);
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+
+    // Sell into the bid to fill it completely
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &MIN_ORDER_SIZE,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+
+    let flipped = exchange.get_pending_order(&(order_id + 1)).unwrap();
+    assert!(!flipped.is_bid);
+    assert!(!flipped.is_flip);
+    assert_eq!(flipped.tick, 20 + 30);
+    assert_eq!(flipped.amount, MIN_ORDER_SIZE);
+
+    // A non-positive spread would flip onto or through the filled tick
+    let result = exchange.try_place_flip_relative(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &20,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+    assert_eq!(result, Err(Ok(Error::InvalidBidFlipTick)));
+}
+
 #[test]
 fn test_invalid_flip_tick_bid() {
-    let (_env, exchange, admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+    let (_env, exchange, _admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
     quote_admin.mint(&user, &1_000_000_000);
 
     // Flip tick must be > tick for bids
@@ -298,6 +585,7 @@ fn test_invalid_flip_tick_bid() {
         &100,
         &MIN_ORDER_SIZE,
         &0, // Invalid: flip_tick <= tick
+        &0,
     );
 
     assert_eq!(result, Err(Ok(Error::InvalidBidFlipTick)));
@@ -305,9 +593,9 @@ fn test_invalid_flip_tick_bid() {
 
 #[test]
 fn test_invalid_flip_tick_ask() {
-    let (_env, exchange, admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
+    let (_env, exchange, _admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
     base_admin.mint(&user, &1_000_000_000);
 
     // Flip tick must be < tick for asks
@@ -319,6 +607,7 @@ fn test_invalid_flip_tick_ask() {
         &0,
         &MIN_ORDER_SIZE,
         &100, // Invalid: flip_tick >= tick
+        &0,
     );
 
     assert_eq!(result, Err(Ok(Error::InvalidAskFlipTick)));
@@ -336,12 +625,69 @@ fn test_tick_to_price() {
     assert!(tick_to_price(-100) < tick_to_price(0));
 }
 
+#[test]
+fn test_price_to_tick_rounds_down_to_lowest_tick() {
+    // Exact prices round-trip
+    assert_eq!(price_to_tick(tick_to_price(0)), 0);
+    assert_eq!(price_to_tick(tick_to_price(-150)), -150);
+    assert_eq!(price_to_tick(tick_to_price(MAX_TICK)), MAX_TICK);
+
+    // Between two ticks the lower one wins, on both sides of tick 0
+    assert_eq!(price_to_tick(tick_to_price(20) + 1), 20);
+    assert_eq!(price_to_tick(tick_to_price(-20) - 1), -30);
+    assert_eq!(price_to_tick(0), MIN_TICK);
+
+    // A coarse curve where each pair of aligned ticks shares one integer price
+    let coarse = |tick: i32| PRICE_SCALE + (tick.div_euclid(20) as i128) * 100;
+    assert_eq!(coarse(0), coarse(10));
+    assert_eq!(price_to_tick_on_curve(coarse(0), coarse), 0);
+    assert_eq!(price_to_tick_on_curve(coarse(0) + 50, coarse), 0);
+    assert_eq!(price_to_tick_on_curve(coarse(-20), coarse), -20);
+    assert_eq!(price_to_tick_on_curve(coarse(-10) - 1, coarse), -40);
+}
+
+#[test]
+fn test_price_ladder() {
+    let (env, exchange, _admin, _user, _, _, _, _) = setup_test_env();
+
+    // 5 levels either side of tick 100, two tick spacings apart
+    let ladder = exchange.price_ladder(&100, &5, &2);
+    assert_eq!(ladder.len(), 11);
+    for (i, (tick, price)) in ladder.iter().enumerate() {
+        let expected_tick = 100 + (i as i32 - 5) * 2 * TICK_SPACING;
+        assert_eq!(tick, expected_tick);
+        assert_eq!(price, tick_to_price(expected_tick));
+    }
+    assert_eq!(ladder.get(0), Some((0, PRICE_SCALE)));
+    assert_eq!(ladder.get(10), Some((200, tick_to_price(200))));
+
+    // A single level is just the center
+    assert_eq!(
+        exchange.price_ladder(&0, &0, &1),
+        vec![&env, (0, PRICE_SCALE)]
+    );
+
+    // Ladders must stay in range and start from an aligned tick
+    assert_eq!(
+        exchange.try_price_ladder(&(MAX_TICK - 20), &3, &1),
+        Err(Ok(Error::InvalidTick))
+    );
+    assert_eq!(
+        exchange.try_price_ladder(&5, &1, &1),
+        Err(Ok(Error::TickNotAligned))
+    );
+    assert_eq!(
+        exchange.try_price_ladder(&0, &1, &0),
+        Err(Ok(Error::InvalidAmount))
+    );
+}
+
 #[test]
 fn test_swap_exact_in_buy() {
     let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
         setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
 
     // Maker provides liquidity (ask order - selling base)
     let maker = Address::generate(&env);
@@ -351,12 +697,14 @@ fn test_swap_exact_in_buy() {
         &maker,
         &base_token.address,
         &quote_token.address,
-        &false,   // ask
-        &0,       // tick
+        &false,       // ask
+        &0,           // tick
         &100_000_000, // 100 base
+        &0,
     );
 
     exchange.execute_block(
+        &admin,
         &base_token.address,
         &quote_token.address,
         &vec![&env, ask_order_id],
@@ -373,6 +721,10 @@ fn test_swap_exact_in_buy() {
         &true, // is_buy
         &quote_in,
         &0, // min_amount_out
+        &0, // min_fill_ratio_bps
+        &user,
+        &None,
+        &false,
     );
 
     // Should receive base tokens
@@ -384,7 +736,7 @@ fn test_swap_exact_in_sell() {
     let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
         setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
 
     // Maker provides liquidity (bid order - buying base)
     let maker = Address::generate(&env);
@@ -394,12 +746,14 @@ fn test_swap_exact_in_sell() {
         &maker,
         &base_token.address,
         &quote_token.address,
-        &true,    // bid
-        &0,       // tick
+        &true,        // bid
+        &0,           // tick
         &100_000_000, // 100 base worth
+        &0,
     );
 
     exchange.execute_block(
+        &admin,
         &base_token.address,
         &quote_token.address,
         &vec![&env, bid_order_id],
@@ -416,16 +770,60 @@ fn test_swap_exact_in_sell() {
         &false, // is_buy = false means selling base
         &base_in,
         &0,
+        &0,
+        &user,
+        &None,
+        &false,
     );
 
     assert!(quote_out > 0);
 }
 
+#[test]
+fn test_swap_reversed_tokens_wrong_token_order() {
+    let (env, exchange, admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    quote_admin.mint(&user, &10_000_000);
+
+    // The pair exists, just not in this order
+    let result = exchange.try_swap_exact_in(
+        &user,
+        &quote_token.address,
+        &base_token.address,
+        &false,
+        &10_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(result, Err(Ok(Error::WrongTokenOrder)));
+    let result = exchange.try_quote_swap_in(
+        &quote_token.address,
+        &base_token.address,
+        &false,
+        &10_000_000,
+    );
+    assert_eq!(result, Err(Ok(Error::WrongTokenOrder)));
+
+    // Unknown in either order is still PairNotFound
+    let (other_token, _) = create_token(&env, &admin);
+    let result = exchange.try_quote_swap_in(
+        &other_token.address,
+        &base_token.address,
+        &false,
+        &10_000_000,
+    );
+    assert_eq!(result, Err(Ok(Error::PairNotFound)));
+}
+
 #[test]
 fn test_quote_swap() {
     let (env, exchange, admin, _user, base_token, quote_token, base_admin, _) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
 
     // Add some liquidity
     let maker = Address::generate(&env);
@@ -438,9 +836,11 @@ fn test_quote_swap() {
         &false,
         &0,
         &100_000_000,
+        &0,
     );
 
     exchange.execute_block(
+        &admin,
         &base_token.address,
         &quote_token.address,
         &vec![&env, ask_order_id],
@@ -448,21 +848,17 @@ fn test_quote_swap() {
 
     // Quote the swap
     let quote_in = 50_000_000i128;
-    let expected_out = exchange.quote_swap_in(
-        &base_token.address,
-        &quote_token.address,
-        &true,
-        &quote_in,
-    );
+    let expected_out =
+        exchange.quote_swap_in(&base_token.address, &quote_token.address, &true, &quote_in);
 
     assert!(expected_out > 0);
 }
 
 #[test]
 fn test_withdraw() {
-    let (_env, exchange, admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
+    let (_env, exchange, _admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
 
-    exchange.create_pair(&base_token.address, &quote_token.address);
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
 
     // Give user some balance (simulating filled order credit)
     // We'll do this by placing and canceling an order
@@ -475,14 +871,96 @@ fn test_withdraw() {
         &false,
         &0,
         &MIN_ORDER_SIZE,
+        &0,
     );
 
     // Cancel to get balance credit
     exchange.cancel(&user, &order_id);
 
-    // Check balance
-    let balance = exchange.balance_of(&user, &user); // Note: balance key uses maker address
+    // Check balance, credited in the ask's base token
+    let balance = exchange.balance_of(&user, &base_token.address);
     assert_eq!(balance, MIN_ORDER_SIZE);
+
+    exchange.withdraw(&user, &base_token.address, &MIN_ORDER_SIZE);
+    assert_eq!(exchange.balance_of(&user, &base_token.address), 0);
+    assert_eq!(base_token.balance(&user), 1_000_000_000);
+}
+
+#[test]
+fn test_get_user_summary() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    base_admin.mint(&user, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    // Two active orders (one gets fully filled below) and one pending
+    let ask_id = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+    let bid_id = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &0,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, ask_id, bid_id],
+    );
+    let pending_id = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &0,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+
+    // A taker lifts the ask, crediting the user quote
+    let taker = Address::generate(&env);
+    quote_admin.mint(&taker, &1_000_000_000);
+    exchange.swap_exact_in(
+        &taker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &20_000_000,
+        &0,
+        &0,
+        &taker,
+        &None,
+        &false,
+    );
+
+    let summary = exchange.get_user_summary(&user, &base_token.address, &quote_token.address);
+    // Active IDs are reassigned on activation: ask = 1, bid = 2; the ask is gone
+    assert_eq!(summary.active_order_ids, vec![&env, 2]);
+    assert_eq!(summary.pending_order_ids, vec![&env, pending_id]);
+    assert_eq!(summary.base_balance, 0);
+    assert_eq!(
+        summary.quote_balance,
+        calculate_quote_amount(MIN_ORDER_SIZE, 10)
+    );
+
+    // Other pairs are filtered out
+    let (other_quote, _) = create_token(&env, &admin);
+    let other = exchange.get_user_summary(&user, &base_token.address, &other_quote.address);
+    assert!(other.active_order_ids.is_empty());
+    assert!(other.pending_order_ids.is_empty());
 }
 
 #[test]
@@ -493,3 +971,4916 @@ fn test_constants() {
     assert_eq!(StablecoinExchange::price_scale(), PRICE_SCALE);
     assert_eq!(StablecoinExchange::min_order_size(), MIN_ORDER_SIZE);
 }
+
+#[test]
+fn test_migrate_advances_version_once() {
+    let (_env, exchange, admin, _user, _base_token, _quote_token, _, _) = setup_test_env();
+
+    assert_eq!(exchange.get_contract_version(), 1);
+
+    exchange.migrate(&admin, &2);
+    assert_eq!(exchange.get_contract_version(), 2);
+
+    // The same step cannot run twice, and versions cannot be skipped
+    assert_eq!(
+        exchange.try_migrate(&admin, &2),
+        Err(Ok(Error::InvalidVersion))
+    );
+    assert_eq!(
+        exchange.try_migrate(&admin, &4),
+        Err(Ok(Error::InvalidVersion))
+    );
+    assert_eq!(exchange.get_contract_version(), 2);
+}
+
+#[test]
+fn test_migrate_requires_admin() {
+    let (_env, exchange, _admin, user, _base_token, _quote_token, _, _) = setup_test_env();
+
+    assert_eq!(
+        exchange.try_migrate(&user, &2),
+        Err(Ok(Error::Unauthorized))
+    );
+}
+
+#[test]
+fn test_swap_exact_in_min_fill_ratio_reverts_on_thin_book() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    // Only 100 base resting on the ask side
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    let ask_order_id = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &100_000_000,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, ask_order_id],
+    );
+
+    // Taker offers 500 quote but insists on at least 50% being filled
+    quote_admin.mint(&user, &1_000_000_000);
+    let result = exchange.try_swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &500_000_000,
+        &0,
+        &5_000,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(result, Err(Ok(Error::FillRatioNotMet)));
+
+    // Nothing moved: the taker keeps all input and the ask is untouched
+    assert_eq!(quote_token.balance(&user), 1_000_000_000);
+    assert_eq!(base_token.balance(&user), 0);
+    let level = exchange.get_tick_level(&base_token.address, &quote_token.address, &false, &0);
+    assert_eq!(level.total_liquidity, 100_000_000);
+
+    // A 20% floor is satisfied by the same book
+    let base_out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &500_000_000,
+        &0,
+        &2_000,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(base_out, 100_000_000);
+}
+
+#[test]
+fn test_marginal_price_after_reports_last_touched_tick() {
+    let (env, exchange, _admin, _user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    for tick in [0, 10, 20] {
+        place_active(
+            &env,
+            &exchange,
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            false,
+            tick,
+            100_000_000,
+        );
+    }
+
+    // 250 quote clears the first two levels and part of the third
+    let price = exchange.marginal_price_after(
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &250_000_000,
+    );
+    assert_eq!(price, tick_to_price(20));
+
+    // A small buy only touches the best level
+    let price = exchange.marginal_price_after(
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &50_000_000,
+    );
+    assert_eq!(price, tick_to_price(0));
+
+    // No bids rest on the book
+    let result = exchange.try_marginal_price_after(
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &50_000_000,
+    );
+    assert_eq!(result, Err(Ok(Error::NoLiquidity)));
+}
+
+#[test]
+fn test_get_active_orders_paginated() {
+    let (env, exchange, _admin, maker, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&maker, &1_000_000_000);
+
+    // 25 orders spread over five bid and five ask ticks
+    for i in 0..25i32 {
+        let is_bid = i % 2 == 0;
+        let offset = (i % 5 + 1) * 10;
+        let tick = if is_bid { -offset } else { offset };
+        place_active(
+            &env,
+            &exchange,
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            is_bid,
+            tick,
+            2 * MIN_ORDER_SIZE,
+        );
+    }
+
+    let mut all = soroban_sdk::Vec::new(&env);
+    let mut cursor = 0u128;
+    let mut page_sizes = soroban_sdk::Vec::new(&env);
+    loop {
+        let (page, next) = exchange.get_active_orders_paginated(
+            &base_token.address,
+            &quote_token.address,
+            &cursor,
+            &10,
+        );
+        page_sizes.push_back(page.len());
+        all.append(&page);
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+
+    assert_eq!(page_sizes, vec![&env, 10, 10, 5]);
+    assert_eq!(all.len(), 25);
+
+    // Ascending by tick, then by queue (activation) order within a tick
+    for i in 1..all.len() {
+        let prev = all.get(i - 1).unwrap();
+        let cur = all.get(i).unwrap();
+        assert!(prev.tick < cur.tick || (prev.tick == cur.tick && prev.order_id < cur.order_id));
+    }
+}
+
+#[test]
+fn test_quote_swap_in_with_fee_matches_swap() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        -10,
+        100_000_000,
+    );
+
+    let base_in = 50_000_000i128;
+    let (net_out, fee) = exchange.quote_swap_in_with_fee(
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &base_in,
+    );
+    assert_eq!(fee, 0);
+    assert_eq!(
+        exchange.quote_swap_in(&base_token.address, &quote_token.address, &false, &base_in),
+        net_out
+    );
+
+    // Setting min_amount_out to the quoted net succeeds and pays exactly that
+    base_admin.mint(&user, &base_in);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &base_in,
+        &net_out,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, net_out);
+    assert_eq!(quote_token.balance(&user), net_out);
+}
+
+#[test]
+fn test_balance_changed_events_on_fill_and_withdraw() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        0,
+        100_000_000,
+    );
+
+    // Sell side fill credits the maker's quote balance
+    let quote_admin = StellarAssetClient::new(&env, &quote_token.address);
+    quote_admin.mint(&user, &50_000_000);
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &50_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    let credit = (
+        exchange.address.clone(),
+        (
+            symbol_short!("balance"),
+            maker.clone(),
+            quote_token.address.clone(),
+        )
+            .into_val(&env),
+        (50_000_000i128, 50_000_000i128).into_val(&env),
+    );
+    assert!(env.events().all().first_index_of(credit).is_some());
+
+    exchange.withdraw(&maker, &quote_token.address, &20_000_000);
+    let debit = (
+        exchange.address.clone(),
+        (
+            symbol_short!("balance"),
+            maker.clone(),
+            quote_token.address.clone(),
+        )
+            .into_val(&env),
+        (-20_000_000i128, 30_000_000i128).into_val(&env),
+    );
+    assert!(env.events().all().first_index_of(debit).is_some());
+}
+
+#[test]
+fn test_best_tick_changed_event_only_on_change() {
+    let (env, exchange, _admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    let top_of_book = |best_bid: i32| {
+        (
+            exchange.address.clone(),
+            (
+                symbol_short!("best_tick"),
+                base_token.address.clone(),
+                quote_token.address.clone(),
+            )
+                .into_val(&env),
+            (best_bid, MAX_TICK + 1).into_val(&env),
+        )
+    };
+
+    // First bid establishes the best bid
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        MIN_ORDER_SIZE,
+    );
+    assert!(env.events().all().first_index_of(top_of_book(0)).is_some());
+
+    // A deeper bid leaves the top of book alone
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        -10,
+        2 * MIN_ORDER_SIZE,
+    );
+    assert!(env.events().all().first_index_of(top_of_book(0)).is_none());
+
+    // Cancelling the only order at the best bid moves it down
+    exchange.cancel(&user, &1);
+    assert!(env
+        .events()
+        .all()
+        .first_index_of(top_of_book(-10))
+        .is_some());
+    assert_eq!(
+        exchange
+            .get_orderbook(&base_token.address, &quote_token.address)
+            .best_bid_tick,
+        -10
+    );
+}
+
+#[test]
+fn test_recent_trades_ring_buffer() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    exchange.set_trade_buffer_size(&admin, &3);
+    assert_eq!(exchange.trade_buffer_size(), 3);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&maker, &1_000_000_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        10,
+        500_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        500_000_000,
+    );
+    base_admin.mint(&user, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    // Four swaps alternating sides, each at a later timestamp
+    for i in 0..4u64 {
+        env.ledger().set_timestamp(1_000 + i);
+        exchange.swap_exact_in(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &i.is_multiple_of(2),
+            &(10_000_000 * (i as i128 + 1)),
+            &0,
+            &0,
+            &user,
+            &None,
+            &false,
+        );
+    }
+
+    // Only the last three are kept, oldest first
+    let trades = exchange.get_recent_trades(&base_token.address, &quote_token.address, &10);
+    assert_eq!(trades.len(), 3);
+    for (offset, trade) in trades.iter().enumerate() {
+        let i = offset as u64 + 1;
+        assert_eq!(trade.index, i as u32);
+        assert_eq!(trade.timestamp, 1_000 + i);
+        assert_eq!(trade.is_buy, i.is_multiple_of(2));
+        assert_eq!(trade.tick, if trade.is_buy { 10 } else { 0 });
+    }
+    // Second swap sold 20M base into the bid at tick 0
+    let sell = trades.get(0).unwrap();
+    assert_eq!(sell.base_amount, 20_000_000);
+    assert_eq!(sell.quote_amount, calculate_quote_amount(20_000_000, 0));
+
+    let latest = exchange.get_recent_trades(&base_token.address, &quote_token.address, &1);
+    assert_eq!(latest, vec![&env, trades.get(2).unwrap()]);
+}
+
+#[test]
+fn test_orderbook_state_hash() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    base_admin.mint(&user, &1_000_000_000);
+
+    let empty = exchange.orderbook_state_hash(&base_token.address, &quote_token.address);
+    assert_eq!(
+        exchange.orderbook_state_hash(&base_token.address, &quote_token.address),
+        empty
+    );
+
+    // Placing bumps the pending counter; activating changes the levels
+    let order_id = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+    let pending = exchange.orderbook_state_hash(&base_token.address, &quote_token.address);
+    assert_ne!(pending, empty);
+
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+    let active = exchange.orderbook_state_hash(&base_token.address, &quote_token.address);
+    assert_ne!(active, pending);
+
+    // Reads and failed calls leave it unchanged
+    exchange.get_orderbook(&base_token.address, &quote_token.address);
+    let _ = exchange.try_cancel(&Address::generate(&env), &1);
+    assert_eq!(
+        exchange.orderbook_state_hash(&base_token.address, &quote_token.address),
+        active
+    );
+}
+
+#[test]
+fn test_execute_block_rejects_oversized_batch() {
+    let (env, exchange, admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    exchange.set_max_activations_per_block(&admin, &2);
+    assert_eq!(exchange.max_activations_per_block(), 2);
+
+    let mut order_ids = vec![&env];
+    for _ in 0..3 {
+        order_ids.push_back(exchange.place(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &0,
+            &MIN_ORDER_SIZE,
+            &0,
+        ));
+    }
+
+    // Oversized batches error instead of truncating, leaving every order pending
+    let result = exchange.try_execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &order_ids,
+    );
+    assert_eq!(result, Err(Ok(Error::BatchTooLarge)));
+    for order_id in order_ids.iter() {
+        assert!(exchange.get_pending_order(&order_id).is_some());
+    }
+
+    // A batch within the limit goes through
+    order_ids.pop_back();
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &order_ids,
+    );
+    let level = exchange.get_tick_level(&base_token.address, &quote_token.address, &true, &0);
+    assert_eq!(level.total_liquidity, 2 * MIN_ORDER_SIZE);
+}
+
+#[test]
+fn test_bid_with_dust_quote_escrow_fails() {
+    let (_env, exchange, _admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    // At tick -1000 a minimum-size bid only escrows 90% of MIN_ORDER_SIZE in quote
+    let result = exchange.try_place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &-1000,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+    assert_eq!(result, Err(Ok(Error::OrderTooSmall)));
+
+    let result = exchange.try_place_flip(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &-1000,
+        &MIN_ORDER_SIZE,
+        &0,
+        &0,
+    );
+    assert_eq!(result, Err(Ok(Error::OrderTooSmall)));
+
+    // Sizing up so the quote escrow clears the minimum is accepted
+    exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &-1000,
+        &(2 * MIN_ORDER_SIZE),
+        &0,
+    );
+}
+
+#[test]
+fn test_swap_exact_in_to_recipient() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        0,
+        100_000_000,
+    );
+
+    let recipient = Address::generate(&env);
+    quote_admin.mint(&user, &80_000_000);
+
+    // 80 quote against 100 base of depth: fully consumed, nothing refunded
+    let base_out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &80_000_000,
+        &0,
+        &0,
+        &recipient,
+        &None,
+        &false,
+    );
+
+    assert_eq!(base_out, 80_000_000);
+    assert_eq!(base_token.balance(&recipient), 80_000_000);
+    assert_eq!(base_token.balance(&user), 0);
+    assert_eq!(quote_token.balance(&user), 0);
+}
+
+#[test]
+fn test_drained_level_with_lingering_head_is_empty() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        10,
+        MIN_ORDER_SIZE,
+    );
+
+    // Fully fill the level: it is removed and reads back as empty
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &20_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    let level = exchange.get_tick_level(&base_token.address, &quote_token.address, &false, &10);
+    assert!(level.is_empty());
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        20,
+        MIN_ORDER_SIZE,
+    );
+
+    // Simulate a drained best level whose head pointer was never unlinked
+    let stale = TickLevel {
+        head: 999,
+        tail: 999,
+        total_liquidity: 0,
+    };
+    assert!(stale.is_empty());
+    env.as_contract(&exchange.address, || {
+        save_ask_tick_level(&env, &base_token.address, &quote_token.address, 10, &stale);
+        let mut book = get_orderbook(&env, &base_token.address, &quote_token.address).unwrap();
+        book.best_ask_tick = 10;
+        save_orderbook(&env, &book);
+    });
+
+    // Matching skips the stale level and fills at the next real one
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &5_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, calculate_base_amount(5_000_000, 20));
+}
+
+#[test]
+fn test_buy_rounding_never_undercharges() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    // Below parity one unit of base is worth less than one unit of quote, so
+    // floor rounding in calculate_quote_amount would price a 1-unit fill at 0
+    let tick = -10;
+    assert_eq!(calculate_quote_amount(1, tick), 0);
+    assert_eq!(calculate_base_amount(1, tick), 1);
+
+    let maker_a = Address::generate(&env);
+    let maker_b = Address::generate(&env);
+    base_admin.mint(&maker_a, &MIN_ORDER_SIZE);
+    base_admin.mint(&maker_b, &MIN_ORDER_SIZE);
+    place_active(
+        &env,
+        &exchange,
+        &maker_a,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        tick,
+        MIN_ORDER_SIZE,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker_b,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        tick,
+        MIN_ORDER_SIZE,
+    );
+
+    // A single unit of quote buys exactly one unit of base and pays for it
+    quote_admin.mint(&user, &1);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &1,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, 1);
+    assert_eq!(quote_token.balance(&user), 0);
+    assert_eq!(exchange.balance_of(&maker_a, &quote_token.address), 1);
+    assert_eq!(
+        exchange
+            .get_tick_level(&base_token.address, &quote_token.address, &false, &tick)
+            .total_liquidity,
+        2 * MIN_ORDER_SIZE - 1
+    );
+
+    // A buy spanning both makers: quoted and executed output agree, the input is
+    // never overspent, and every unit of quote taken ends up with a maker
+    let amount_in = 10_000_001;
+    let quoted =
+        exchange.quote_swap_in(&base_token.address, &quote_token.address, &true, &amount_in);
+    quote_admin.mint(&user, &amount_in);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &amount_in,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, quoted);
+    assert!(out <= calculate_base_amount(amount_in, tick));
+
+    let spent = amount_in - quote_token.balance(&user);
+    assert!(spent <= amount_in);
+    let maker_quote = exchange.balance_of(&maker_a, &quote_token.address)
+        + exchange.balance_of(&maker_b, &quote_token.address);
+    assert_eq!(maker_quote, spent + 1);
+    assert_eq!(quote_token.balance(&exchange.address), maker_quote);
+    assert!(calculate_quote_amount(out, tick) <= spent);
+}
+
+#[test]
+fn test_estimate_fill_probability() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &200_000_000);
+    quote_admin.mint(&maker, &100_000_000);
+    let front = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &100_000_000,
+        &0,
+    );
+    let deep = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &20,
+        &100_000_000,
+        &0,
+    );
+    let bid = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &-10,
+        &50_000_000,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, front, deep, bid],
+    );
+    // Active IDs are minted in activation order
+    let (front, deep, bid) = (1u128, 2u128, 3u128);
+
+    // No recorded volume yet
+    assert_eq!(exchange.estimate_fill_probability(&front, &10), 0);
+
+    // Three 20M buys, one ledger apart, all taken from the front ask
+    quote_admin.mint(&user, &60_000_000);
+    for i in 0..3 {
+        env.ledger().set_timestamp(1_000 + 5 * i);
+        exchange.swap_exact_in(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &20_000_000,
+            &0,
+            &0,
+            &user,
+            &None,
+            &false,
+        );
+    }
+
+    // 60M over 10s is 30M per ledger against the front ask's 40M remaining
+    assert_eq!(exchange.estimate_fill_probability(&front, &1), 7_500);
+    assert_eq!(exchange.estimate_fill_probability(&front, &10), 10_000);
+
+    // The deeper ask waits behind the front ask's 40M as well
+    assert_eq!(exchange.estimate_fill_probability(&deep, &1), 2_142);
+
+    // Nobody has sold into the bids
+    assert_eq!(exchange.estimate_fill_probability(&bid, &10), 0);
+
+    let result = exchange.try_estimate_fill_probability(&999, &1);
+    assert_eq!(result, Err(Ok(Error::OrderNotFound)));
+}
+
+#[test]
+fn test_swap_amm_fallback_completes_fill() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    // Thin book: a single 10M ask at parity
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &10_000_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        0,
+        10_000_000,
+    );
+
+    // Fee AMM pool of base (user token) against quote (validator token)
+    let amm = env.register(TempoFeeAMM, ());
+    let amm_client = TempoFeeAMMClient::new(&env, &amm);
+    amm_client.initialize(&admin);
+    let lp = Address::generate(&env);
+    base_admin.mint(&lp, &1_000_000_000);
+    quote_admin.mint(&lp, &1_000_000_000);
+    amm_client.mint(
+        &lp,
+        &base_token.address,
+        &quote_token.address,
+        &1_000_000_000,
+        &1_000_000_000,
+        &lp,
+        &0,
+    );
+
+    // Only the admin-configured pool may be routed through
+    quote_admin.mint(&user, &30_000_000);
+    let rogue = env.register(TempoFeeAMM, ());
+    exchange.set_amm_pool(&admin, &amm);
+    assert_eq!(exchange.amm_pool(), Some(amm.clone()));
+    let result = exchange.try_swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &30_000_000,
+        &0,
+        &0,
+        &user,
+        &Some(rogue),
+        &false,
+    );
+    assert_eq!(result, Err(Ok(Error::AmmNotAllowed)));
+
+    // The book fills 10M; the AMM turns the other 20M of quote into
+    // 20M * 9970 * 1e9 / (1e9 * 10000 + 20M * 9970) base
+    let expected_out = 10_000_000 + 19_550_169;
+
+    // Asking for more than book plus AMM can deliver reverts everything
+    let result = exchange.try_swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &30_000_000,
+        &(expected_out + 1),
+        &0,
+        &user,
+        &Some(amm.clone()),
+        &false,
+    );
+    assert!(result.is_err());
+    assert_eq!(quote_token.balance(&user), 30_000_000);
+
+    // Only the taker signs; the exchange authorizes the AMM's pull itself
+    let args = (
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        30_000_000i128,
+        expected_out,
+        0u32,
+        &user,
+        Some(amm.clone()),
+        false,
+    );
+    env.mock_auths(&[MockAuth {
+        address: &user,
+        invoke: &MockAuthInvoke {
+            contract: &exchange.address,
+            fn_name: "swap_exact_in",
+            args: args.into_val(&env),
+            sub_invokes: &[MockAuthInvoke {
+                contract: &quote_token.address,
+                fn_name: "transfer",
+                args: (&user, &exchange.address, 30_000_000i128).into_val(&env),
+                sub_invokes: &[],
+            }],
+        },
+    }]);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &30_000_000,
+        &expected_out,
+        &0,
+        &user,
+        &Some(amm.clone()),
+        &false,
+    );
+    env.mock_all_auths();
+
+    assert_eq!(out, expected_out);
+    assert_eq!(base_token.balance(&user), expected_out);
+    assert_eq!(quote_token.balance(&user), 0);
+    assert_eq!(quote_token.balance(&amm), 1_020_000_000);
+    assert_eq!(
+        exchange.balance_of(&maker, &quote_token.address),
+        10_000_000
+    );
+    assert_eq!(quote_token.balance(&exchange.address), 10_000_000);
+    assert_eq!(base_token.balance(&exchange.address), 0);
+    assert!(!exchange
+        .get_orderbook(&base_token.address, &quote_token.address)
+        .has_asks());
+}
+
+#[test]
+fn test_fill_callback_notifies_maker_contract() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let maker = env.register(RecordingMaker, ());
+    let maker_client = RecordingMakerClient::new(&env, &maker);
+    base_admin.mint(&maker, &100_000_000);
+    let order_id = exchange.place_with_callback(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &100_000_000,
+        &maker,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+    assert_eq!(maker_client.last_fill(), None);
+
+    // A fill reports the active order ID, fill size and what is left
+    quote_admin.mint(&user, &60_000_000);
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &30_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(maker_client.last_fill(), Some((1, 30_000_000, 70_000_000)));
+
+    // A failing callback does not stop the taker's swap
+    maker_client.set_failing(&true);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &30_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, 30_000_000);
+    assert_eq!(exchange.get_order(&1).unwrap().remaining, 40_000_000);
+    assert_eq!(maker_client.last_fill(), Some((1, 30_000_000, 70_000_000)));
+}
+
+#[test]
+fn test_seed_pair_places_symmetric_depth() {
+    let (_env, exchange, admin, _user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    // Fund the admin inside the exchange
+    let per_level = 50_000_000;
+    base_admin.mint(&admin, &(3 * per_level));
+    quote_admin.mint(&admin, &(3 * per_level));
+    exchange.deposit(&admin, &base_token.address, &(3 * per_level));
+    exchange.deposit(&admin, &quote_token.address, &(3 * per_level));
+
+    let order_ids = exchange.seed_pair(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &3,
+        &3,
+        &per_level,
+    );
+    assert_eq!(order_ids.len(), 6);
+
+    // Same depth at matching distances on both sides, live without execute_block
+    for level in 1..=3 {
+        let bid = exchange.get_tick_level(
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &(-level * TICK_SPACING),
+        );
+        let ask = exchange.get_tick_level(
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &(level * TICK_SPACING),
+        );
+        assert_eq!(bid.total_liquidity, per_level);
+        assert_eq!(ask.total_liquidity, per_level);
+    }
+    let orderbook = exchange.get_orderbook(&base_token.address, &quote_token.address);
+    assert_eq!(orderbook.best_bid_tick, -TICK_SPACING);
+    assert_eq!(orderbook.best_ask_tick, TICK_SPACING);
+    assert_eq!(
+        exchange
+            .get_order(&order_ids.get(0).unwrap())
+            .unwrap()
+            .maker,
+        admin
+    );
+
+    // Escrow came out of the admin's balance: all the base, and the bids' quote
+    assert_eq!(exchange.balance_of(&admin, &base_token.address), 0);
+    let bid_quote: i128 = (1..=3)
+        .map(|level| calculate_quote_amount(per_level, -level * TICK_SPACING))
+        .sum();
+    assert_eq!(
+        exchange.balance_of(&admin, &quote_token.address),
+        3 * per_level - bid_quote
+    );
+
+    // Seeding past the admin's remaining balance fails
+    let result = exchange.try_seed_pair(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &0,
+        &1,
+        &per_level,
+    );
+    assert_eq!(result, Err(Ok(Error::InsufficientBalance)));
+}
+
+#[test]
+fn test_seed_pair_respects_existing_book() {
+    let (env, exchange, admin, _user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let per_level = 50_000_000;
+    base_admin.mint(&admin, &(3 * per_level));
+    quote_admin.mint(&admin, &(3 * per_level));
+    exchange.deposit(&admin, &base_token.address, &(3 * per_level));
+    exchange.deposit(&admin, &quote_token.address, &(3 * per_level));
+
+    // A resting ask below where the seeded bids would go
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &per_level);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        -2 * TICK_SPACING,
+        per_level,
+    );
+
+    // Seeding a bid at or above the best ask would cross the book
+    let result = exchange.try_seed_pair(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &2,
+        &0,
+        &per_level,
+    );
+    assert_eq!(result, Err(Ok(Error::WouldCross)));
+
+    // Asks can still be seeded beside the resting liquidity, and the book stays uncrossed
+    let order_ids = exchange.seed_pair(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &0,
+        &2,
+        &per_level,
+    );
+    assert_eq!(order_ids.len(), 2);
+    let orderbook = exchange.get_orderbook(&base_token.address, &quote_token.address);
+    assert_eq!(orderbook.best_ask_tick, -2 * TICK_SPACING);
+    assert!(!exchange
+        .get_book_health(&base_token.address, &quote_token.address)
+        .is_crossed);
+
+    // Too small a level is rejected as for any order
+    let result = exchange.try_seed_pair(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &0,
+        &1,
+        &(MIN_ORDER_SIZE - 1),
+    );
+    assert_eq!(result, Err(Ok(Error::OrderTooSmall)));
+
+    // Nothing can be seeded while trading is paused
+    exchange.pause(&admin);
+    let result = exchange.try_seed_pair(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &0,
+        &1,
+        &per_level,
+    );
+    assert_eq!(result, Err(Ok(Error::Paused)));
+}
+
+#[test]
+fn test_min_fill_tick_rejects_unfillable_protection() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let guarded_maker = Address::generate(&env);
+    base_admin.mint(&guarded_maker, &100_000_000);
+
+    // An ask displayed at tick 0 that only sells at tick 10 could never fill
+    let result = exchange.try_place_with_min_fill(
+        &guarded_maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &100_000_000,
+        &10,
+    );
+    assert_eq!(result, Err(Ok(Error::InvalidTick)));
+
+    // Nor could a bid at tick 10 that only buys at tick 0
+    let result = exchange.try_place_with_min_fill(
+        &guarded_maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10,
+        &100_000_000,
+        &0,
+    );
+    assert_eq!(result, Err(Ok(Error::InvalidTick)));
+    assert_eq!(exchange.get_pending(&0, &10), vec![&env]);
+    assert_eq!(base_token.balance(&guarded_maker), 100_000_000);
+
+    // A protection on the fillable side rests and fills like a plain order
+    let guarded = exchange.place_with_min_fill(
+        &guarded_maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &100_000_000,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, guarded],
+    );
+
+    let quoted = exchange.quote_swap_in(
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10_010_000,
+    );
+    quote_admin.mint(&user, &10_010_000);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10_010_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, 10_000_000);
+    assert_eq!(quoted, out);
+    assert_eq!(exchange.get_order(&1).unwrap().remaining, 90_000_000);
+
+    // A protection outside the tick range is rejected
+    let result = exchange.try_place_with_min_fill(
+        &guarded_maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &10_000_000,
+        &(MAX_TICK + TICK_SPACING),
+    );
+    assert_eq!(result, Err(Ok(Error::InvalidTick)));
+}
+
+#[test]
+fn test_cancel_fee_charged_only_on_fresh_orders() {
+    let (env, exchange, admin, _user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    assert_eq!(exchange.cancel_fee(), (0, 0));
+    assert_eq!(
+        exchange.try_set_cancel_fee(&admin, &101, &60),
+        Err(Ok(Error::FeeTooHigh))
+    );
+    exchange.set_cancel_fee(&admin, &50, &60);
+    assert_eq!(exchange.cancel_fee(), (50, 60));
+    let treasury = Address::generate(&env);
+    exchange.set_treasury(&admin, &treasury);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &200_000_000);
+    env.ledger().set_timestamp(1_000);
+    let fresh = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &100_000_000,
+        &0,
+    );
+    let aged = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &100_000_000,
+        &0,
+    );
+
+    // Canceled 10 seconds after placing: 0.5% goes to the treasury
+    env.ledger().set_timestamp(1_010);
+    assert_eq!(exchange.cancel(&maker, &fresh), 99_500_000);
+    assert_eq!(exchange.balance_of(&treasury, &base_token.address), 500_000);
+    assert_eq!(exchange.balance_of(&admin, &base_token.address), 0);
+
+    // Rested a full minute: refunded in full
+    env.ledger().set_timestamp(1_060);
+    assert_eq!(exchange.cancel(&maker, &aged), 100_000_000);
+    assert_eq!(exchange.balance_of(&treasury, &base_token.address), 500_000);
+}
+
+#[test]
+fn test_replace_charges_cancel_fee_on_fresh_orders() {
+    let (env, exchange, admin, _user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    exchange.set_cancel_fee(&admin, &50, &60);
+    let treasury = Address::generate(&env);
+    exchange.set_treasury(&admin, &treasury);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &200_000_000);
+    env.ledger().set_timestamp(1_000);
+    let fresh = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &100_000_000,
+        &0,
+    );
+
+    // Replacing 10 seconds in costs the same 0.5% as canceling would
+    env.ledger().set_timestamp(1_010);
+    let replacement = exchange.replace(&maker, &fresh, &10, &100_000_000);
+    assert_eq!(exchange.balance_of(&treasury, &base_token.address), 500_000);
+    assert_eq!(exchange.balance_of(&maker, &base_token.address), 0);
+    assert_eq!(base_token.balance(&maker), 99_500_000);
+
+    // The replacement rested a full minute: replacing it again is free
+    env.ledger().set_timestamp(1_070);
+    exchange.replace(&maker, &replacement, &20, &100_000_000);
+    assert_eq!(exchange.balance_of(&treasury, &base_token.address), 500_000);
+    assert_eq!(exchange.balance_of(&maker, &base_token.address), 0);
+    assert_eq!(base_token.balance(&maker), 99_500_000);
+}
+
+#[test]
+fn test_amend_charges_cancel_fee_on_fresh_orders() {
+    let (env, exchange, admin, _user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    exchange.set_cancel_fee(&admin, &50, &60);
+    let treasury = Address::generate(&env);
+    exchange.set_treasury(&admin, &treasury);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &100_000_000);
+    env.ledger().set_timestamp(1_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        0,
+        100_000_000,
+    );
+    let order_id = 1;
+
+    // Shrinking a fresh order is a partial cancel: 0.5% of the freed escrow
+    env.ledger().set_timestamp(1_010);
+    exchange.amend(&maker, &order_id, &60_000_000);
+    assert_eq!(exchange.balance_of(&maker, &base_token.address), 39_800_000);
+    assert_eq!(exchange.balance_of(&treasury, &base_token.address), 200_000);
+
+    // Once it has rested a full minute the reduction is free
+    env.ledger().set_timestamp(1_060);
+    exchange.amend(&maker, &order_id, &20_000_000);
+    assert_eq!(exchange.balance_of(&maker, &base_token.address), 79_800_000);
+    assert_eq!(exchange.balance_of(&treasury, &base_token.address), 200_000);
+}
+
+#[test]
+fn test_replace_keeps_order_settings() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    base_admin.mint(&user, &100_000_000);
+    let expiry = env.ledger().sequence() + 100;
+
+    // No entry point sets both, so give a callback order an expiry
+    let callback = Address::generate(&env);
+    let order_id = exchange.place_with_callback(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &10_000_000,
+        &callback,
+    );
+    env.as_contract(&exchange.address, || {
+        let mut pending = crate::order::get_pending_order(&env, order_id).unwrap();
+        pending.expiry_ledger = expiry;
+        env.storage()
+            .persistent()
+            .set(&crate::storage::DataKey::PendingOrder(order_id), &pending);
+    });
+
+    let new_id = exchange.replace(&user, &order_id, &20, &20_000_000);
+    let new_order = exchange.get_pending_order(&new_id).unwrap();
+    assert_eq!(new_order.tick, 20);
+    assert_eq!(new_order.amount, 20_000_000);
+    assert_eq!(new_order.callback, Some(callback));
+    assert_eq!(new_order.expiry_ledger, expiry);
+    assert!(!new_order.is_flip);
+
+    // A flip order stays a flip with the same flip tick
+    let flip_id = exchange.place_flip(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &10_000_000,
+        &0,
+        &expiry,
+    );
+    let new_flip_id = exchange.replace(&user, &flip_id, &20, &10_000_000);
+    let new_flip = exchange.get_pending_order(&new_flip_id).unwrap();
+    assert!(new_flip.is_flip);
+    assert_eq!(new_flip.flip_tick, 0);
+    assert_eq!(new_flip.expiry_ledger, expiry);
+
+    // ...and the flip tick must still sit on the right side of the new tick
+    let result = exchange.try_replace(&user, &new_flip_id, &-10, &10_000_000);
+    assert_eq!(result, Err(Ok(Error::InvalidAskFlipTick)));
+
+    // Fill protection is kept, and rejected if the new tick could never fill it
+    let protected_id = exchange.place_with_min_fill(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &10_000_000,
+        &0,
+    );
+    let new_protected_id = exchange.replace(&user, &protected_id, &20, &10_000_000);
+    assert_eq!(
+        exchange.get_pending_order(&new_protected_id).unwrap().min_fill_tick,
+        0
+    );
+    let result = exchange.try_replace(&user, &new_protected_id, &-10, &10_000_000);
+    assert_eq!(result, Err(Ok(Error::InvalidTick)));
+}
+
+#[test]
+fn test_force_uncross_clears_crossed_book() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let bidder = Address::generate(&env);
+    let asker = Address::generate(&env);
+    quote_admin.mint(&bidder, &200_000_000);
+    base_admin.mint(&asker, &200_000_000);
+
+    // Activation inserts orders without matching, so these leave the book crossed
+    let bid = exchange.place(
+        &bidder,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10,
+        &100_000_000,
+        &0,
+    );
+    let low_ask = exchange.place(
+        &asker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &60_000_000,
+        &0,
+    );
+    let high_ask = exchange.place(
+        &asker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &60_000_000,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, bid, low_ask, high_ask],
+    );
+    let book = exchange.get_orderbook(&base_token.address, &quote_token.address);
+    assert!(book.best_bid_tick >= book.best_ask_tick);
+
+    assert_eq!(
+        exchange.try_force_uncross(&user, &base_token.address, &quote_token.address),
+        Err(Ok(Error::Unauthorized))
+    );
+
+    // 60M against the tick 0 ask, then 40M against the tick 10 ask, all at the bid's tick 10
+    assert_eq!(
+        exchange.force_uncross(&admin, &base_token.address, &quote_token.address),
+        100_000_000
+    );
+    assert_eq!(
+        exchange.balance_of(&bidder, &base_token.address),
+        100_000_000
+    );
+    assert_eq!(
+        exchange.balance_of(&asker, &quote_token.address),
+        60_060_000 + 40_040_000
+    );
+
+    assert!(exchange.get_order(&1).is_none());
+    assert!(exchange.get_order(&2).is_none());
+    assert_eq!(exchange.get_order(&3).unwrap().remaining, 20_000_000);
+
+    let book = exchange.get_orderbook(&base_token.address, &quote_token.address);
+    assert!(!book.has_bids());
+    assert_eq!(book.best_ask_tick, 10);
+    assert_eq!(
+        exchange
+            .get_tick_level(&base_token.address, &quote_token.address, &false, &10)
+            .total_liquidity,
+        20_000_000
+    );
+}
+
+#[test]
+fn test_pair_escrow_tracks_resting_orders() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&maker, &1_000_000_000);
+
+    let ids = vec![
+        &env,
+        exchange.place(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &10,
+            &50_000_000,
+            &0,
+        ),
+        exchange.place(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &-10,
+            &20_000_000,
+            &0,
+        ),
+        exchange.place(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &20,
+            &30_000_000,
+            &0,
+        ),
+        exchange.place(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &30,
+            &40_000_000,
+            &0,
+        ),
+    ];
+
+    // Pending orders are not on the book yet
+    assert_eq!(
+        exchange.get_pair_escrow(&base_token.address, &quote_token.address),
+        (0, 0)
+    );
+    exchange.execute_block(&admin, &base_token.address, &quote_token.address, &ids);
+
+    // Bids escrow 50_050_000 + 19_980_000 quote, asks 30M + 40M base
+    assert_eq!(
+        exchange.get_pair_escrow(&base_token.address, &quote_token.address),
+        (70_000_000, 70_030_000)
+    );
+
+    // Selling 10M into the tick 10 bid releases 10_010_000 quote
+    base_admin.mint(&user, &10_000_000);
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(
+        exchange.get_pair_escrow(&base_token.address, &quote_token.address),
+        (70_000_000, 60_020_000)
+    );
+
+    // Canceling the tick 20 ask releases its 30M base
+    exchange.cancel(&maker, &3);
+    assert_eq!(
+        exchange.get_pair_escrow(&base_token.address, &quote_token.address),
+        (40_000_000, 60_020_000)
+    );
+}
+
+#[test]
+fn test_pending_escrow_moves_to_active_on_execute() {
+    let (env, exchange, admin, _user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&maker, &1_000_000_000);
+
+    let bid = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10,
+        &50_000_000,
+        &0,
+    );
+    let ask = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &20,
+        &30_000_000,
+        &0,
+    );
+    let canceled = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &30,
+        &40_000_000,
+        &0,
+    );
+
+    // Bid escrows 50_050_000 quote, asks 30M + 40M base
+    assert_eq!(
+        exchange.get_pending_escrow(&base_token.address, &quote_token.address),
+        (70_000_000, 50_050_000)
+    );
+
+    // Canceling a pending ask releases its base from the pending total
+    exchange.cancel(&maker, &canceled);
+    assert_eq!(
+        exchange.get_pending_escrow(&base_token.address, &quote_token.address),
+        (30_000_000, 50_050_000)
+    );
+
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, bid, ask],
+    );
+    assert_eq!(
+        exchange.get_pending_escrow(&base_token.address, &quote_token.address),
+        (0, 0)
+    );
+    assert_eq!(
+        exchange.get_pair_escrow(&base_token.address, &quote_token.address),
+        (30_000_000, 50_050_000)
+    );
+}
+
+#[test]
+fn test_auto_activate_places_directly_into_book() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    assert!(!exchange.auto_activate());
+    assert_eq!(
+        exchange.try_set_auto_activate(&user, &true),
+        Err(Ok(Error::Unauthorized))
+    );
+    exchange.set_auto_activate(&admin, &true);
+    assert!(exchange.auto_activate());
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &100_000_000);
+    let order_id = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &100_000_000,
+        &0,
+    );
+
+    // Resting on the book without an execute_block call
+    let order = exchange.get_order(&order_id).unwrap();
+    assert_eq!(order.remaining, 100_000_000);
+    assert!(exchange.get_pending_order(&order_id).is_none());
+    assert_eq!(
+        exchange
+            .get_orderbook(&base_token.address, &quote_token.address)
+            .best_ask_tick,
+        10
+    );
+    assert_eq!(
+        exchange
+            .get_tick_level(&base_token.address, &quote_token.address, &false, &10)
+            .total_liquidity,
+        100_000_000
+    );
+}
+
+#[test]
+fn test_warm_ticks_adds_no_liquidity() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let ticks = vec![&env, 0, 10, 20];
+    assert_eq!(
+        exchange.try_warm_ticks(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &ticks
+        ),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(
+        exchange.warm_ticks(
+            &admin,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &ticks
+        ),
+        3
+    );
+    assert_eq!(
+        exchange.warm_ticks(
+            &admin,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &vec![&env, 20, 30]
+        ),
+        1
+    );
+    assert_eq!(
+        exchange.try_warm_ticks(
+            &admin,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &vec![&env, 5]
+        ),
+        Err(Ok(Error::TickNotAligned))
+    );
+
+    // Warmed levels are empty and the book still has no asks
+    let level = exchange.get_tick_level(&base_token.address, &quote_token.address, &false, &0);
+    assert_eq!((level.head, level.total_liquidity), (0, 0));
+    assert!(!exchange
+        .get_orderbook(&base_token.address, &quote_token.address)
+        .has_asks());
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &100_000_000);
+    let order_id = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &20,
+        &100_000_000,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+    assert_eq!(
+        exchange
+            .get_orderbook(&base_token.address, &quote_token.address)
+            .best_ask_tick,
+        20
+    );
+
+    // A buy passes over the warmed ticks and fills at tick 20
+    quote_admin.mint(&user, &10_020_000);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10_020_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, 10_000_000);
+    assert_eq!(exchange.get_order(&1).unwrap().remaining, 90_000_000);
+}
+
+#[test]
+fn test_swap_route_per_hop_minimum_aborts_early() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+    let (third_token, third_admin) = create_token(&env, &admin);
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    exchange.create_pair(&third_token.address, &quote_token.address, &None);
+
+    // base -> quote sells into a bid at parity, quote -> third buys asks at
+    // parity and then at tick 100
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &100_000_000);
+    third_admin.mint(&maker, &100_000_000);
+    let ids = vec![
+        &env,
+        exchange.place(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &0,
+            &100_000_000,
+            &0,
+        ),
+    ];
+    exchange.execute_block(&admin, &base_token.address, &quote_token.address, &ids);
+    let ids = vec![
+        &env,
+        exchange.place(
+            &maker,
+            &third_token.address,
+            &quote_token.address,
+            &false,
+            &0,
+            &50_000_000,
+            &0,
+        ),
+        exchange.place(
+            &maker,
+            &third_token.address,
+            &quote_token.address,
+            &false,
+            &100,
+            &50_000_000,
+            &0,
+        ),
+    ];
+    exchange.execute_block(&admin, &third_token.address, &quote_token.address, &ids);
+
+    base_admin.mint(&user, &60_000_000);
+    let path = vec![
+        &env,
+        base_token.address.clone(),
+        quote_token.address.clone(),
+        third_token.address.clone(),
+    ];
+
+    assert_eq!(
+        exchange.try_swap_route(&user, &path, &60_000_000, &0, &vec![&env, 60_000_000], &false),
+        Err(Ok(Error::InvalidPath))
+    );
+
+    // The first hop yields 60M quote, but the second only 50M + 9_900_597
+    let result = exchange.try_swap_route(
+        &user,
+        &path,
+        &60_000_000,
+        &0,
+        &vec![&env, 60_000_000, 60_000_000],
+        &false,
+    );
+    assert_eq!(result, Err(Ok(Error::SlippageExceeded)));
+    assert_eq!(base_token.balance(&user), 60_000_000);
+    assert_eq!(exchange.get_order(&1).unwrap().remaining, 100_000_000);
+
+    let out = exchange.swap_route(
+        &user,
+        &path,
+        &60_000_000,
+        &0,
+        &vec![&env, 60_000_000, 59_000_000],
+        &false,
+    );
+    assert_eq!(out, 59_900_597);
+    assert_eq!(third_token.balance(&user), 59_900_597);
+    assert_eq!(base_token.balance(&user), 0);
+    assert_eq!(quote_token.balance(&user), 0);
+}
+
+#[test]
+fn test_swap_route_matches_manual_hops() {
+    // Builds the same USDC/USDT and DAI/USDT books in a fresh environment and
+    // trades 80M USDC into DAI, either routed or as two separate swaps
+    fn usdc_to_dai(routed: bool, min_amount_out: i128) -> Result<(i128, i128), Error> {
+        let (env, exchange, admin, user, usdc, usdt, usdc_admin, usdt_admin) = setup_test_env();
+        let (dai, dai_admin) = create_token(&env, &admin);
+
+        exchange.create_pair(&usdc.address, &usdt.address, &None);
+        exchange.create_pair(&dai.address, &usdt.address, &None);
+
+        let maker = Address::generate(&env);
+        usdt_admin.mint(&maker, &200_000_000);
+        dai_admin.mint(&maker, &200_000_000);
+        place_active(&env, &exchange, &maker, &usdc.address, &usdt.address, true, 0, 40_000_000);
+        place_active(&env, &exchange, &maker, &usdc.address, &usdt.address, true, -10, 60_000_000);
+        place_active(&env, &exchange, &maker, &dai.address, &usdt.address, false, 0, 50_000_000);
+        place_active(&env, &exchange, &maker, &dai.address, &usdt.address, false, 10, 100_000_000);
+
+        usdc_admin.mint(&user, &80_000_000);
+        let out = if routed {
+            let path = vec![&env, usdc.address.clone(), usdt.address.clone(), dai.address.clone()];
+            match exchange.try_swap_route(&user, &path, &80_000_000, &min_amount_out, &vec![&env], &false) {
+                Ok(Ok(out)) => out,
+                Err(Ok(err)) => return Err(err),
+                _ => panic!("unexpected swap_route failure"),
+            }
+        } else {
+            let usdt_out = exchange.swap_exact_in(
+                &user,
+                &usdc.address,
+                &usdt.address,
+                &false,
+                &80_000_000,
+                &0,
+                &0,
+                &user,
+                &None,
+                &false,
+            );
+            exchange.swap_exact_in(
+                &user,
+                &dai.address,
+                &usdt.address,
+                &true,
+                &usdt_out,
+                &min_amount_out,
+                &0,
+                &user,
+                &None,
+                &false,
+            )
+        };
+
+        assert_eq!(dai.balance(&user), out);
+        assert_eq!(usdc.balance(&user), 0);
+        Ok((out, usdt.balance(&user) + exchange.balance_of(&user, &usdt.address)))
+    }
+
+    let (routed_out, routed_usdt) = usdc_to_dai(true, 0).unwrap();
+    assert_eq!((routed_out, routed_usdt), usdc_to_dai(false, 0).unwrap());
+    assert_eq!(routed_usdt, 0);
+
+    // Only the final output is held to the minimum
+    assert_eq!(usdc_to_dai(true, routed_out), Ok((routed_out, 0)));
+    assert_eq!(
+        usdc_to_dai(true, routed_out + 1),
+        Err(Error::SlippageExceeded)
+    );
+}
+
+#[test]
+fn test_price_table_matches_tick_to_price() {
+    let (_env, exchange, _admin, _user, _, _, _, _) = setup_test_env();
+
+    let table = exchange.price_table(&-500, &500, &50);
+    assert_eq!(table.len(), 21);
+    for (i, price) in table.iter().enumerate() {
+        assert_eq!(price, exchange.tick_to_price(&(-500 + i as i32 * 50)));
+    }
+
+    // A step that overshoots stops before to_tick
+    let table = exchange.price_table(&0, &240, &100);
+    assert_eq!(table.len(), 3);
+    assert_eq!(table.get(2), Some(tick_to_price(200)));
+
+    // The full range at one tick spacing needs several calls
+    assert_eq!(
+        exchange.try_price_table(&MIN_TICK, &MAX_TICK, &TICK_SPACING),
+        Err(Ok(Error::InvalidAmount))
+    );
+    assert_eq!(exchange.price_table(&0, &990, &TICK_SPACING).len(), 100);
+    assert_eq!(
+        exchange.try_price_table(&0, &1000, &TICK_SPACING),
+        Err(Ok(Error::InvalidAmount))
+    );
+
+    assert_eq!(
+        exchange.try_price_table(&10, &0, &TICK_SPACING),
+        Err(Ok(Error::InvalidTick))
+    );
+    assert_eq!(
+        exchange.try_price_table(&0, &100, &15),
+        Err(Ok(Error::TickNotAligned))
+    );
+    assert_eq!(
+        exchange.try_price_table(&0, &100, &0),
+        Err(Ok(Error::InvalidAmount))
+    );
+}
+
+#[test]
+fn test_fill_and_cancel_events_carry_pair() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &100_000_000);
+    let order_id = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &100_000_000,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+
+    quote_admin.mint(&user, &10_000_000);
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    let filled = (
+        exchange.address.clone(),
+        (
+            symbol_short!("filled"),
+            maker.clone(),
+            base_token.address.clone(),
+            quote_token.address.clone(),
+        )
+            .into_val(&env),
+        (1u128, 10_000_000i128, 90_000_000i128).into_val(&env),
+    );
+    assert!(env.events().all().first_index_of(filled).is_some());
+
+    exchange.cancel(&maker, &1);
+    let canceled = (
+        exchange.address.clone(),
+        (
+            symbol_short!("canceled"),
+            maker.clone(),
+            base_token.address.clone(),
+            quote_token.address.clone(),
+        )
+            .into_val(&env),
+        (1u128, 90_000_000i128).into_val(&env),
+    );
+    assert!(env.events().all().first_index_of(canceled).is_some());
+}
+
+#[test]
+fn test_book_health_reports_one_sided_book() {
+    let (env, exchange, _admin, _user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let empty = exchange.get_book_health(&base_token.address, &quote_token.address);
+    assert!(!empty.has_bids && !empty.has_asks && !empty.is_one_sided);
+
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    for tick in [10, 10, 20] {
+        place_active(
+            &env,
+            &exchange,
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            false,
+            tick,
+            10_000_000,
+        );
+    }
+
+    let health = exchange.get_book_health(&base_token.address, &quote_token.address);
+    assert!(!health.has_bids);
+    assert!(health.has_asks);
+    assert!(health.is_one_sided);
+    assert!(!health.is_crossed);
+    assert_eq!((health.bid_levels, health.ask_levels), (0, 2));
+
+    // Emptying the tick 20 level drops it from the count
+    exchange.cancel(&maker, &3);
+    let health = exchange.get_book_health(&base_token.address, &quote_token.address);
+    assert_eq!(health.ask_levels, 1);
+    assert!(health.is_one_sided);
+
+    let other = Address::generate(&env);
+    assert_eq!(
+        exchange.try_get_book_health(&other, &quote_token.address),
+        Err(Ok(Error::PairNotFound))
+    );
+}
+
+#[test]
+fn test_maker_fill_history_outlives_orders() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    assert_eq!(exchange.maker_fill_buffer_size(), 32);
+    assert_eq!(
+        exchange.try_set_maker_fill_buffer_size(&user, &8),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(
+        exchange.try_set_maker_fill_buffer_size(&admin, &0),
+        Err(Ok(Error::InvalidAmount))
+    );
+
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        10,
+        10_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        30_000_000,
+    );
+
+    // The first sell empties order 1 and dips into order 2, the second takes more of order 2
+    base_admin.mint(&user, &20_000_000);
+    for (timestamp, amount) in [(1_000u64, 15_000_000i128), (1_001, 5_000_000)] {
+        env.ledger().set_timestamp(timestamp);
+        exchange.swap_exact_in(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &amount,
+            &0,
+            &0,
+            &user,
+            &None,
+            &false,
+        );
+    }
+    assert!(exchange.get_order(&1).is_none());
+
+    let fills = exchange.get_maker_fills(&maker, &10);
+    let expected = [
+        (1u128, 10_000_000i128, 10, 1_000u64),
+        (2, 5_000_000, 0, 1_000),
+        (2, 5_000_000, 0, 1_001),
+    ];
+    assert_eq!(fills.len(), 3);
+    for (index, (fill, (order_id, fill_amount, tick, timestamp))) in
+        fills.iter().zip(expected).enumerate()
+    {
+        assert_eq!(
+            fill,
+            MakerFill {
+                index: index as u32,
+                order_id,
+                fill_amount,
+                tick,
+                timestamp,
+            }
+        );
+    }
+
+    let latest = exchange.get_maker_fills(&maker, &1);
+    assert_eq!(latest, vec![&env, fills.get(2).unwrap()]);
+    assert!(exchange.get_maker_fills(&user, &10).is_empty());
+}
+
+#[test]
+fn test_flips_disabled_fills_without_flipping() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+
+    let order_id = exchange.place_flip(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &0,
+        &MIN_ORDER_SIZE,
+        &10,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+
+    assert!(exchange.flips_enabled());
+    assert_eq!(
+        exchange.try_set_flips_enabled(&user, &false),
+        Err(Ok(Error::Unauthorized))
+    );
+    exchange.set_flips_enabled(&admin, &false);
+    assert!(!exchange.flips_enabled());
+
+    // New flip orders are refused, plain orders are not
+    assert_eq!(
+        exchange.try_place_flip(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &0,
+            &MIN_ORDER_SIZE,
+            &10,
+            &0,
+        ),
+        Err(Ok(Error::FlipsDisabled))
+    );
+    assert_eq!(
+        exchange.try_place_flip_relative(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &0,
+            &MIN_ORDER_SIZE,
+            &10
+        ),
+        Err(Ok(Error::FlipsDisabled))
+    );
+    exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &0,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+
+    // The resting flip order fills and credits the maker but spawns nothing
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &MIN_ORDER_SIZE,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert!(exchange.get_pending_order(&(order_id + 2)).is_none());
+    assert_eq!(
+        exchange.balance_of(&maker, &base_token.address),
+        MIN_ORDER_SIZE
+    );
+}
+
+#[test]
+fn test_place_at_price_rounds_toward_maker() {
+    let (env, exchange, _admin, _user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&maker, &1_000_000_000);
+
+    // Halfway between ticks 10 and 20: bids round down, asks round up
+    let price = (tick_to_price(10) + tick_to_price(20)) / 2;
+    let (bid_id, bid_tick) = exchange.place_at_price(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &price,
+        &MIN_ORDER_SIZE,
+    );
+    assert_eq!(bid_tick, 10);
+    assert_eq!(exchange.get_pending_order(&bid_id).unwrap().tick, 10);
+
+    let (ask_id, ask_tick) = exchange.place_at_price(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &price,
+        &MIN_ORDER_SIZE,
+    );
+    assert_eq!(ask_tick, 20);
+    assert_eq!(exchange.get_pending_order(&ask_id).unwrap().tick, 20);
+
+    // A price exactly on a tick uses that tick on both sides
+    let (_, tick) = exchange.place_at_price(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &tick_to_price(-30),
+        &MIN_ORDER_SIZE,
+    );
+    assert_eq!(tick, -30);
+
+    assert_eq!(
+        exchange.try_place_at_price(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &(tick_to_price(MAX_TICK) + 1),
+            &MIN_ORDER_SIZE,
+        ),
+        Err(Ok(Error::InvalidTick))
+    );
+}
+
+#[test]
+fn test_withdraw_fails_typed_when_underfunded() {
+    let (env, exchange, _admin, user, base_token, _quote_token, base_admin, _) = setup_test_env();
+
+    base_admin.mint(&user, &1_000_000);
+    exchange.deposit(&user, &base_token.address, &1_000_000);
+
+    // Simulate an accounting bug crediting more than the contract holds
+    env.as_contract(&exchange.address, || {
+        crate::storage::add_balance(&env, &user, &base_token.address, 500_000);
+    });
+    assert_eq!(
+        exchange.try_withdraw(&user, &base_token.address, &1_500_000),
+        Err(Ok(Error::InsufficientReserves))
+    );
+    assert_eq!(exchange.balance_of(&user, &base_token.address), 1_500_000);
+
+    // Anything the contract can cover still pays out
+    exchange.withdraw(&user, &base_token.address, &1_000_000);
+    assert_eq!(base_token.balance(&user), 1_000_000);
+}
+
+#[test]
+fn test_checkpoint_detects_state_change() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        10,
+        50_000_000,
+    );
+
+    let checkpoint = exchange.checkpoint(&base_token.address, &quote_token.address);
+    assert_eq!(checkpoint.best_ask_tick, 10);
+    assert_eq!(checkpoint.ask_levels, 1);
+    assert_eq!(
+        (checkpoint.escrow_base, checkpoint.escrow_quote),
+        (50_000_000, 0)
+    );
+    assert!(exchange.verify_checkpoint(&checkpoint));
+
+    // A new pending order moves the shared counter
+    exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &20,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+    assert!(!exchange.verify_checkpoint(&checkpoint));
+
+    // A tampered copy of a fresh checkpoint is rejected too
+    let mut fresh = exchange.checkpoint(&base_token.address, &quote_token.address);
+    assert!(exchange.verify_checkpoint(&fresh));
+    fresh.escrow_base += 1;
+    assert!(!exchange.verify_checkpoint(&fresh));
+
+    let mut unknown = checkpoint.clone();
+    unknown.base_token = user;
+    assert_eq!(
+        exchange.try_verify_checkpoint(&unknown),
+        Err(Ok(Error::PairNotFound))
+    );
+}
+
+#[test]
+fn test_swap_with_limit_reports_price_improvement() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        10,
+        20_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        30,
+        20_000_000,
+    );
+
+    // Limited to tick 20, the buy takes only the tick 10 ask and refunds the rest
+    quote_admin.mint(&user, &100_000_000);
+    let result = exchange.swap_with_limit(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &100_000_000,
+        &20,
+        &0,
+        &false,
+    );
+    let cost = calculate_quote_amount(20_000_000, 10);
+    assert_eq!(result.amount_out, 20_000_000);
+    assert_eq!(result.base_amount, 20_000_000);
+    assert_eq!(result.quote_amount, cost);
+    assert_eq!(result.refund, 100_000_000 - cost);
+    assert_eq!(result.vwap, tick_to_price(10));
+    assert_eq!(
+        result.price_improvement,
+        tick_to_price(20) - tick_to_price(10)
+    );
+    assert!(result.price_improvement > 0);
+
+    assert_eq!(base_token.balance(&user), 20_000_000);
+    assert_eq!(quote_token.balance(&user), 100_000_000 - cost);
+    assert_eq!(
+        exchange
+            .get_orderbook(&base_token.address, &quote_token.address)
+            .best_ask_tick,
+        30
+    );
+}
+
+#[test]
+fn test_relative_flip_ask_lands_at_fill_tick_minus_spread() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    let order_id = exchange.place_flip_relative(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &20,
+        &MIN_ORDER_SIZE,
+        &30,
+    );
+    let order = exchange.get_pending_order(&order_id).unwrap();
+    assert!(!order.is_bid);
+    assert_eq!(order.spread_ticks, 30);
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+
+    // Buy the whole ask
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &calculate_quote_amount(MIN_ORDER_SIZE, 20),
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+
+    let flipped = exchange.get_pending_order(&(order_id + 1)).unwrap();
+    assert!(flipped.is_bid);
+    assert!(!flipped.is_flip);
+    assert_eq!(flipped.tick, 20 - 30);
+    assert_eq!(flipped.amount, MIN_ORDER_SIZE);
+
+    // The spread must be positive and keep the flipped bid at or above MIN_TICK
+    for spread in [0, 20 - MIN_TICK + TICK_SPACING] {
+        assert_eq!(
+            exchange.try_place_flip_relative(
+                &maker,
+                &base_token.address,
+                &quote_token.address,
+                &false,
+                &20,
+                &MIN_ORDER_SIZE,
+                &spread,
+            ),
+            Err(Ok(if spread == 0 {
+                Error::InvalidAskFlipTick
+            } else {
+                Error::InvalidTick
+            }))
+        );
+    }
+}
+
+#[test]
+fn test_cancel_bid_refunds_quote_deposit() {
+    let (env, exchange, _admin, user, base_token, quote_token, _, quote_admin) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    quote_admin.mint(&user, &1_000_000_000);
+    let deposit = calculate_quote_amount(50_000_000, 20);
+
+    // Pending bid: the quote deposit comes back, keyed by the quote token
+    let pending = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &20,
+        &50_000_000,
+        &0,
+    );
+    assert_eq!(quote_token.balance(&user), 1_000_000_000 - deposit);
+    assert_eq!(exchange.cancel(&user, &pending), deposit);
+    assert_eq!(exchange.balance_of(&user, &quote_token.address), deposit);
+    assert_eq!(exchange.balance_of(&user, &base_token.address), 0);
+    exchange.withdraw(&user, &quote_token.address, &deposit);
+    assert_eq!(quote_token.balance(&user), 1_000_000_000);
+
+    // Active bid: same once it is on the book
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        20,
+        50_000_000,
+    );
+    assert_eq!(exchange.cancel(&user, &1), deposit);
+    exchange.withdraw(&user, &quote_token.address, &deposit);
+    assert_eq!(quote_token.balance(&user), 1_000_000_000);
+}
+
+#[test]
+fn test_rebalance_orders_redeploys_fill_credit() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &50_000_000);
+    quote_admin.mint(&maker, &100_000_000);
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        0,
+        50_000_000,
+    );
+
+    // Harvest: the ask fills and credits the maker 50M quote
+    quote_admin.mint(&user, &50_000_000);
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &50_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(
+        exchange.balance_of(&maker, &quote_token.address),
+        50_000_000
+    );
+
+    // An invalid entry rejects the whole batch
+    assert_eq!(
+        exchange.try_rebalance_orders(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &vec![&env, (true, 0, 30_000_000), (true, 5, 30_000_000)],
+        ),
+        Err(Ok(Error::TickNotAligned))
+    );
+
+    // Redeploy: the credit covers the first bid and most of the second
+    let second_cost = calculate_quote_amount(30_000_000, -10);
+    let ids = exchange.rebalance_orders(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, (true, 0, 30_000_000), (true, -10, 30_000_000)],
+    );
+    assert_eq!(ids.len(), 2);
+    let second = exchange.get_pending_order(&ids.get(1).unwrap()).unwrap();
+    assert_eq!(
+        (second.is_bid, second.tick, second.amount),
+        (true, -10, 30_000_000)
+    );
+
+    assert_eq!(exchange.balance_of(&maker, &quote_token.address), 0);
+    assert_eq!(
+        quote_token.balance(&maker),
+        100_000_000 - (30_000_000 + second_cost - 50_000_000)
+    );
+}
+
+#[test]
+fn test_tick_to_price_follows_exponential_curve() {
+    // Strictly increasing over the whole range
+    let mut previous = tick_to_price(MIN_TICK);
+    for tick in MIN_TICK + 1..=MAX_TICK {
+        let price = tick_to_price(tick);
+        assert!(price > previous);
+        previous = price;
+    }
+
+    // Aligned ticks round-trip through price_to_tick
+    for tick in (MIN_TICK..=MAX_TICK).step_by(TICK_SPACING as usize) {
+        assert_eq!(price_to_tick(tick_to_price(tick)), tick);
+    }
+
+    // 1.0001^2000 = 1.221390545..., 1.0001^-2000 = 0.818738939...
+    assert_eq!(tick_to_price(MAX_TICK), 122_139);
+    assert_eq!(tick_to_price(MIN_TICK), 81_873);
+    assert_eq!(tick_to_price(10), 100_100);
+    assert_eq!(tick_to_price(-10), 99_900);
+    assert_eq!(tick_to_price(100), 101_004);
+    assert_eq!(tick_to_price(-100), 99_005);
+
+    // Out-of-range ticks clamp
+    assert_eq!(tick_to_price(MAX_TICK + 500), tick_to_price(MAX_TICK));
+}
+
+#[test]
+fn test_pause_blocks_trading_but_not_exit() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        MIN_ORDER_SIZE,
+    );
+    let pending_id = exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &0,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+
+    assert_eq!(exchange.try_pause(&user), Err(Ok(Error::Unauthorized)));
+    exchange.pause(&admin);
+    assert!(exchange.is_paused());
+
+    assert_eq!(
+        exchange.try_place(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &0,
+            &MIN_ORDER_SIZE,
+            &0,
+        ),
+        Err(Ok(Error::Paused))
+    );
+    assert_eq!(
+        exchange.try_place_flip(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &0,
+            &MIN_ORDER_SIZE,
+            &10,
+            &0,
+        ),
+        Err(Ok(Error::Paused))
+    );
+    assert_eq!(
+        exchange.try_swap_exact_in(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &MIN_ORDER_SIZE,
+            &0,
+            &0,
+            &user,
+            &None,
+            &false,
+        ),
+        Err(Ok(Error::Paused))
+    );
+    assert_eq!(
+        exchange.try_execute_block(
+            &admin,
+            &base_token.address,
+            &quote_token.address,
+            &vec![&env, pending_id]
+        ),
+        Err(Ok(Error::Paused))
+    );
+
+    // Makers can still pull both orders and withdraw the refunds
+    let refund = exchange.cancel(&maker, &1) + exchange.cancel(&maker, &pending_id);
+    assert_eq!(exchange.balance_of(&maker, &quote_token.address), refund);
+    exchange.withdraw(&maker, &quote_token.address, &refund);
+    assert_eq!(quote_token.balance(&maker), 1_000_000_000);
+
+    exchange.unpause(&admin);
+    assert!(!exchange.is_paused());
+    exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &0,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+}
+
+#[test]
+fn test_expired_order_skipped_and_refunded() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let expiring_maker = Address::generate(&env);
+    let maker = Address::generate(&env);
+    base_admin.mint(&expiring_maker, &1_000_000_000);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+    env.ledger().set_sequence_number(100);
+
+    // An expiry that has already passed is rejected
+    assert_eq!(
+        exchange.try_place(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &0,
+            &MIN_ORDER_SIZE,
+            &99
+        ),
+        Err(Ok(Error::InvalidExpiry))
+    );
+
+    // The expiring ask is queued ahead of a good-till-cancel ask at the same tick
+    let ids = vec![
+        &env,
+        exchange.place(
+            &expiring_maker,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &0,
+            &20_000_000,
+            &110,
+        ),
+        exchange.place(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &0,
+            &20_000_000,
+            &0,
+        ),
+    ];
+    exchange.execute_block(&admin, &base_token.address, &quote_token.address, &ids);
+
+    env.ledger().set_sequence_number(111);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, 10_000_000);
+
+    // The expired ask was removed unfilled and its base credited back
+    assert_eq!(
+        exchange.balance_of(&expiring_maker, &base_token.address),
+        20_000_000
+    );
+    assert_eq!(
+        exchange.balance_of(&maker, &quote_token.address),
+        10_000_000
+    );
+    let level = exchange.get_tick_level(&base_token.address, &quote_token.address, &false, &0);
+    assert_eq!(level.total_liquidity, 10_000_000);
+    assert_eq!(
+        exchange.get_pair_escrow(&base_token.address, &quote_token.address),
+        (10_000_000, 0)
+    );
+}
+
+#[test]
+fn test_amend_reduces_order_in_place() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    let other = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&other, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        50_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &other,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        30_000_000,
+    );
+
+    assert_eq!(
+        exchange.try_amend(&other, &1, &20_000_000),
+        Err(Ok(Error::NotOrderOwner))
+    );
+    assert_eq!(
+        exchange.try_amend(&maker, &1, &60_000_000),
+        Err(Ok(Error::InvalidAmount))
+    );
+    assert_eq!(
+        exchange.try_amend(&maker, &1, &(MIN_ORDER_SIZE - 1)),
+        Err(Ok(Error::OrderTooSmall))
+    );
+
+    exchange.amend(&maker, &1, &20_000_000);
+    assert_eq!(exchange.get_order(&1).unwrap().remaining, 20_000_000);
+    assert_eq!(
+        exchange.balance_of(&maker, &quote_token.address),
+        30_000_000
+    );
+    let level = exchange.get_tick_level(&base_token.address, &quote_token.address, &true, &0);
+    assert_eq!(level.total_liquidity, 50_000_000);
+    assert_eq!(level.head, 1);
+    assert_eq!(
+        exchange.get_pair_escrow(&base_token.address, &quote_token.address),
+        (0, 50_000_000)
+    );
+
+    // The amended order keeps its place at the front of the queue
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &20_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert!(exchange.get_order(&1).is_none());
+    assert_eq!(exchange.get_order(&2).unwrap().remaining, 30_000_000);
+
+    // Reducing to zero cancels the order
+    exchange.amend(&other, &2, &0);
+    assert!(exchange.get_order(&2).is_none());
+    assert_eq!(
+        exchange.balance_of(&other, &quote_token.address),
+        30_000_000
+    );
+    assert!(!exchange
+        .get_orderbook(&base_token.address, &quote_token.address)
+        .has_bids());
+}
+
+#[test]
+fn test_place_batch_mixed_sides() {
+    let (env, exchange, _admin, _user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&maker, &1_000_000_000);
+
+    let orders = vec![
+        &env,
+        (true, 0, 20_000_000),
+        (false, 10, 30_000_000),
+        (true, -10, 20_000_000),
+        (false, 20, 30_000_000),
+    ];
+    let ids = exchange.place_batch(&maker, &base_token.address, &quote_token.address, &orders);
+    assert_eq!(ids.len(), 4);
+    for (i, (is_bid, tick, amount)) in orders.iter().enumerate() {
+        let order = exchange
+            .get_pending_order(&ids.get(i as u32).unwrap())
+            .unwrap();
+        assert_eq!(
+            (order.is_bid, order.tick, order.amount),
+            (is_bid, tick, amount)
+        );
+    }
+
+    let quote_deposit = 20_000_000 + calculate_quote_amount(20_000_000, -10);
+    assert_eq!(base_token.balance(&maker), 1_000_000_000 - 60_000_000);
+    assert_eq!(quote_token.balance(&maker), 1_000_000_000 - quote_deposit);
+    assert_eq!(
+        exchange.get_pending_escrow(&base_token.address, &quote_token.address),
+        (60_000_000, quote_deposit)
+    );
+}
+
+#[test]
+fn test_place_batch_rejects_misaligned_tick() {
+    let (env, exchange, _admin, _user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&maker, &1_000_000_000);
+
+    let orders = vec![
+        &env,
+        (true, 0, 20_000_000),
+        (false, TICK_SPACING / 2 + 10, 30_000_000),
+    ];
+    assert_eq!(
+        exchange.try_place_batch(&maker, &base_token.address, &quote_token.address, &orders),
+        Err(Ok(Error::TickNotAligned))
+    );
+
+    // Nothing was escrowed or queued
+    assert_eq!(base_token.balance(&maker), 1_000_000_000);
+    assert_eq!(quote_token.balance(&maker), 1_000_000_000);
+    assert_eq!(
+        exchange.get_pending_escrow(&base_token.address, &quote_token.address),
+        (0, 0)
+    );
+}
+
+#[test]
+fn test_get_depth_walks_ask_levels() {
+    let (env, exchange, _admin, _user, base_token, quote_token, base_admin, _) = setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+
+    for (tick, amount) in [
+        (30, 20_000_000),
+        (10, 30_000_000),
+        (50, 40_000_000),
+        (10, 10_000_000),
+    ] {
+        place_active(
+            &env,
+            &exchange,
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            false,
+            tick,
+            amount,
+        );
+    }
+
+    let depth = exchange.get_depth(&base_token.address, &quote_token.address, &false, &10);
+    assert_eq!(
+        depth,
+        vec![&env, (10, 40_000_000), (30, 20_000_000), (50, 40_000_000)]
+    );
+
+    let depth = exchange.get_depth(&base_token.address, &quote_token.address, &false, &2);
+    assert_eq!(depth, vec![&env, (10, 40_000_000), (30, 20_000_000)]);
+
+    // The bid side is empty
+    assert!(exchange
+        .get_depth(&base_token.address, &quote_token.address, &true, &10)
+        .is_empty());
+}
+
+#[test]
+fn test_self_trade_prevention_cancels_own_order() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&user, &1_000_000_000);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    // The taker's own ask is the best ask, another maker's sits behind it
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        0,
+        20_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        10,
+        20_000_000,
+    );
+
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10_010_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &true,
+    );
+    assert_eq!(out, 10_000_000);
+
+    // The own ask was canceled and refunded unfilled; the fill came from tick 10
+    assert!(exchange.get_order(&1).is_none());
+    assert_eq!(exchange.balance_of(&user, &base_token.address), 20_000_000);
+    assert_eq!(
+        exchange.balance_of(&maker, &quote_token.address),
+        10_010_000
+    );
+    assert_eq!(exchange.get_order(&2).unwrap().remaining, 10_000_000);
+    assert_eq!(
+        exchange
+            .get_orderbook(&base_token.address, &quote_token.address)
+            .best_ask_tick,
+        10
+    );
+}
+
+#[test]
+fn test_place_immediate_fok_full_fill() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        0,
+        20_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        10,
+        20_000_000,
+    );
+
+    // Buys 20M at tick 0 and 10M at tick 10, paying only for what it matched
+    let result = exchange.place_immediate(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10,
+        &30_000_000,
+        &true,
+    );
+    let cost = 20_000_000 + calculate_quote_amount(10_000_000, 10);
+    assert_eq!(result.base_amount, 30_000_000);
+    assert_eq!(result.quote_amount, cost);
+    assert_eq!(base_token.balance(&user), 30_000_000);
+    assert_eq!(quote_token.balance(&user), 1_000_000_000 - cost);
+    assert_eq!(exchange.get_order(&2).unwrap().remaining, 10_000_000);
+}
+
+#[test]
+fn test_place_immediate_ioc_refunds_remainder() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        20_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        -10,
+        20_000_000,
+    );
+
+    // The tick -10 bid is past the limit, so only 20M fills and 10M comes back
+    let result = exchange.place_immediate(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &30_000_000,
+        &false,
+    );
+    assert_eq!(result.base_amount, 20_000_000);
+    assert_eq!(result.refund, 10_000_000);
+    assert_eq!(base_token.balance(&user), 1_000_000_000 - 20_000_000);
+    assert_eq!(quote_token.balance(&user), 20_000_000);
+
+    // Nothing rests for the taker
+    let orderbook = exchange.get_orderbook(&base_token.address, &quote_token.address);
+    assert!(!orderbook.has_asks());
+    assert_eq!(orderbook.best_bid_tick, -10);
+}
+
+#[test]
+fn test_place_immediate_fok_reverts_when_short() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        20_000_000,
+    );
+
+    assert_eq!(
+        exchange.try_place_immediate(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &0,
+            &30_000_000,
+            &true
+        ),
+        Err(Ok(Error::SlippageExceeded))
+    );
+    assert_eq!(base_token.balance(&user), 1_000_000_000);
+    assert_eq!(exchange.get_order(&1).unwrap().remaining, 20_000_000);
+}
+
+#[test]
+fn test_place_post_only_rejects_crossing() {
+    let (env, exchange, _admin, _user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&maker, &1_000_000_000);
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        -10,
+        20_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        10,
+        20_000_000,
+    );
+
+    for (is_bid, tick) in [(true, 10), (true, 20), (false, -10), (false, -20)] {
+        assert_eq!(
+            exchange.try_place_post_only(
+                &maker,
+                &base_token.address,
+                &quote_token.address,
+                &is_bid,
+                &tick,
+                &20_000_000,
+                &0
+            ),
+            Err(Ok(Error::WouldCross))
+        );
+    }
+
+    // Inside the spread both sides are accepted
+    let bid = exchange.place_post_only(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &0,
+        &20_000_000,
+        &0,
+    );
+    let ask = exchange.place_post_only(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &20_000_000,
+        &0,
+    );
+    assert!(exchange.get_pending_order(&bid).unwrap().is_bid);
+    assert!(!exchange.get_pending_order(&ask).unwrap().is_bid);
+}
+
+#[test]
+fn test_swap_dust_input_refunds_cleanly() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        10,
+        50_000_000,
+    );
+
+    // One quote unit is below the price of one base unit at tick 10
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &1,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, 0);
+    assert_eq!(quote_token.balance(&user), 1_000_000_000);
+
+    // Dust left over after a fill is refunded as well
+    let cost = calculate_quote_amount(10_000_000, 10);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &(cost + 1),
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, 10_000_000);
+    assert_eq!(quote_token.balance(&user), 1_000_000_000 - cost);
+    assert_eq!(exchange.get_order(&1).unwrap().remaining, 40_000_000);
+}
+
+#[test]
+fn test_twap_moves_toward_recent_mid() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+    env.ledger().set_sequence_number(1_000);
+
+    // No price until both sides are quoted
+    assert_eq!(
+        exchange.try_get_twap(&base_token.address, &quote_token.address, &1),
+        Err(Ok(Error::InsufficientHistory))
+    );
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        100_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        100,
+        MIN_ORDER_SIZE,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        200,
+        100_000_000,
+    );
+
+    let first_mid = (exchange.tick_to_price(&0) + exchange.tick_to_price(&100)) / 2;
+    env.ledger().set_sequence_number(1_100);
+    assert_eq!(
+        exchange.get_twap(&base_token.address, &quote_token.address, &100),
+        first_mid
+    );
+
+    // Clearing the ask at tick 100 moves the best ask, and the mid, up to 200
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &11_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    let second_mid = (exchange.tick_to_price(&0) + exchange.tick_to_price(&200)) / 2;
+    assert!(second_mid > first_mid);
+
+    env.ledger().set_sequence_number(1_200);
+    let recent = exchange.get_twap(&base_token.address, &quote_token.address, &100);
+    let full = exchange.get_twap(&base_token.address, &quote_token.address, &200);
+    let partial = exchange.get_twap(&base_token.address, &quote_token.address, &150);
+    assert_eq!(recent, second_mid);
+    assert_eq!(full, (first_mid + second_mid) / 2);
+    assert_eq!(partial, (first_mid * 50 + second_mid * 100) / 150);
+    assert!(recent > partial && partial > full);
+
+    // The window cannot reach back before the first observation
+    assert_eq!(
+        exchange.try_get_twap(&base_token.address, &quote_token.address, &201),
+        Err(Ok(Error::InsufficientHistory))
+    );
+}
+
+#[test]
+fn test_execute_block_emits_activated_ids() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    base_admin.mint(&user, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    let ids = vec![
+        &env,
+        exchange.place(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &-10,
+            &20_000_000,
+            &0,
+        ),
+        exchange.place(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &20,
+            &20_000_000,
+            &0,
+        ),
+    ];
+    exchange.execute_block(&admin, &base_token.address, &quote_token.address, &ids);
+
+    let block_executed = (
+        exchange.address.clone(),
+        (
+            symbol_short!("block"),
+            base_token.address.clone(),
+            quote_token.address.clone(),
+        )
+            .into_val(&env),
+        (vec![&env, 1u128, 2u128], -10i32, 20i32).into_val(&env),
+    );
+    assert!(env.events().all().first_index_of(block_executed).is_some());
+}
+
+#[test]
+fn test_best_bid_ask_and_spread() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    // Unknown pair and empty book
+    assert_eq!(
+        exchange.best_bid(&base_token.address, &quote_token.address),
+        None
+    );
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    assert_eq!(
+        exchange.best_bid(&base_token.address, &quote_token.address),
+        None
+    );
+    assert_eq!(
+        exchange.best_ask(&base_token.address, &quote_token.address),
+        None
+    );
+    assert_eq!(
+        exchange.spread_ticks(&base_token.address, &quote_token.address),
+        None
+    );
+
+    base_admin.mint(&user, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    // One-sided: two bids at the best tick
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        10,
+        20_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        10,
+        30_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        40_000_000,
+    );
+    assert_eq!(
+        exchange.best_bid(&base_token.address, &quote_token.address),
+        Some((10, 50_000_000))
+    );
+    assert_eq!(
+        exchange.best_ask(&base_token.address, &quote_token.address),
+        None
+    );
+    assert_eq!(
+        exchange.spread_ticks(&base_token.address, &quote_token.address),
+        None
+    );
+
+    // Two-sided
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        40,
+        25_000_000,
+    );
+    assert_eq!(
+        exchange.best_ask(&base_token.address, &quote_token.address),
+        Some((40, 25_000_000))
+    );
+    assert_eq!(
+        exchange.spread_ticks(&base_token.address, &quote_token.address),
+        Some(30)
+    );
+}
+
+#[test]
+fn test_cancel_all_clears_maker_orders_in_pair() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    // Two active and two pending orders, plus another maker's bid that stays put
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        20_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        100,
+        20_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        10,
+        40_000_000,
+    );
+    exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &-10,
+        &20_000_000,
+        &0,
+    );
+    exchange.place(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &50,
+        &30_000_000,
+        &0,
+    );
+
+    let escrowed_base = 1_000_000_000 - base_token.balance(&maker);
+    let escrowed_quote = 1_000_000_000 - quote_token.balance(&maker);
+    assert_eq!(escrowed_base, 50_000_000);
+
+    let refunded = exchange.cancel_all(&maker, &base_token.address, &quote_token.address);
+    assert_eq!(refunded, escrowed_base + escrowed_quote);
+    assert_eq!(
+        exchange.balance_of(&maker, &base_token.address),
+        escrowed_base
+    );
+    assert_eq!(
+        exchange.balance_of(&maker, &quote_token.address),
+        escrowed_quote
+    );
+    assert_eq!(
+        exchange
+            .get_pair_escrow(&base_token.address, &quote_token.address)
+            .0,
+        0
+    );
+    assert_eq!(
+        exchange.get_pending_escrow(&base_token.address, &quote_token.address),
+        (0, 0)
+    );
+
+    // Only the other maker's bid is left
+    assert_eq!(
+        exchange.best_ask(&base_token.address, &quote_token.address),
+        None
+    );
+    assert_eq!(
+        exchange.best_bid(&base_token.address, &quote_token.address),
+        Some((10, 40_000_000))
+    );
+
+    // Nothing left to cancel
+    assert_eq!(
+        exchange.cancel_all(&maker, &base_token.address, &quote_token.address),
+        0
+    );
+}
+
+#[test]
+fn test_place_crossing_bid_fills_then_rests_remainder() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        0,
+        20_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        10,
+        20_000_000,
+    );
+
+    // Takes both ask levels, then queues the last 20M at its own tick
+    let order_id = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10,
+        &60_000_000,
+        &0,
+    );
+    let pending = exchange.get_pending_order(&order_id).unwrap();
+    assert_eq!(pending.remaining, 20_000_000);
+    assert_eq!(pending.tick, 10);
+
+    // The full escrow came in; the tick 0 fill saved 20K quote over the limit
+    assert_eq!(quote_token.balance(&user), 1_000_000_000 - 60_060_000);
+    assert_eq!(exchange.balance_of(&user, &base_token.address), 40_000_000);
+    assert_eq!(exchange.balance_of(&user, &quote_token.address), 20_000);
+    assert_eq!(
+        exchange.balance_of(&maker, &quote_token.address),
+        20_000_000 + 20_020_000
+    );
+    assert_eq!(
+        exchange.get_pending_escrow(&base_token.address, &quote_token.address),
+        (0, 20_020_000)
+    );
+
+    let book = exchange.get_orderbook(&base_token.address, &quote_token.address);
+    assert!(!book.has_asks());
+
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+    assert_eq!(
+        exchange.best_bid(&base_token.address, &quote_token.address),
+        Some((10, 20_000_000))
+    );
+}
+
+#[test]
+fn test_place_crossing_ask_fully_filled_rests_nothing() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        50_000_000,
+    );
+
+    let order_id = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &-10,
+        &30_000_000,
+        &0,
+    );
+    assert_eq!(order_id, 0);
+    assert_eq!(exchange.balance_of(&user, &quote_token.address), 30_000_000);
+    assert_eq!(exchange.balance_of(&user, &base_token.address), 0);
+    assert_eq!(
+        exchange.best_bid(&base_token.address, &quote_token.address),
+        Some((0, 20_000_000))
+    );
+}
+
+#[test]
+fn test_place_batch_and_flip_match_crossing_orders() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &20_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    for tick in [0, 10] {
+        place_active(
+            &env,
+            &exchange,
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            false,
+            tick,
+            20_000_000,
+        );
+    }
+
+    // The crossing bid takes the tick 0 ask and rests the rest; the ask does not cross
+    let order_ids = exchange.place_batch(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, (true, 0, 30_000_000), (false, 50, 20_000_000)],
+    );
+    assert_eq!(
+        exchange
+            .get_pending_order(&order_ids.get(0).unwrap())
+            .unwrap()
+            .remaining,
+        10_000_000
+    );
+    assert_eq!(
+        exchange
+            .get_pending_order(&order_ids.get(1).unwrap())
+            .unwrap()
+            .remaining,
+        20_000_000
+    );
+    assert_eq!(exchange.balance_of(&user, &base_token.address), 20_000_000);
+
+    // A crossing flip bid takes the tick 10 ask and rests, and later flips, only the rest
+    let flip_id = exchange.place_flip(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10,
+        &30_000_000,
+        &20,
+        &0,
+    );
+    let flip = exchange.get_pending_order(&flip_id).unwrap();
+    assert!(flip.is_flip);
+    assert_eq!((flip.amount, flip.remaining), (10_000_000, 10_000_000));
+    assert_eq!(exchange.balance_of(&user, &base_token.address), 40_000_000);
+    assert_eq!(
+        exchange.balance_of(&maker, &quote_token.address),
+        20_000_000 + 20_020_000
+    );
+
+    // Nothing crossed is left resting
+    let book = exchange.get_orderbook(&base_token.address, &quote_token.address);
+    assert!(!book.has_asks());
+    assert!(!exchange
+        .get_book_health(&base_token.address, &quote_token.address)
+        .is_crossed);
+}
+
+#[test]
+fn test_pair_min_order_size_override() {
+    let (_env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    assert_eq!(
+        exchange.try_create_pair(&base_token.address, &quote_token.address, &Some(0)),
+        Err(Ok(Error::InvalidAmount))
+    );
+    exchange.create_pair(
+        &base_token.address,
+        &quote_token.address,
+        &Some(50_000_000),
+    );
+    assert_eq!(
+        exchange
+            .get_orderbook(&base_token.address, &quote_token.address)
+            .min_order_size,
+        50_000_000
+    );
+    base_admin.mint(&user, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    // Above the global floor but below the pair's
+    assert_eq!(
+        exchange.try_place(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &10,
+            &MIN_ORDER_SIZE,
+            &0
+        ),
+        Err(Ok(Error::OrderTooSmall))
+    );
+    assert_eq!(
+        exchange.try_place_flip(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &0,
+            &MIN_ORDER_SIZE,
+            &10,
+            &0
+        ),
+        Err(Ok(Error::OrderTooSmall))
+    );
+    let order_id = exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &50_000_000,
+        &0,
+    );
+    assert_eq!(
+        exchange.get_pending_order(&order_id).unwrap().amount,
+        50_000_000
+    );
+
+    // Lowering the floor admits the smaller order
+    assert_eq!(
+        exchange.try_set_min_order_size(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &MIN_ORDER_SIZE
+        ),
+        Err(Ok(Error::Unauthorized))
+    );
+    exchange.set_min_order_size(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &MIN_ORDER_SIZE,
+    );
+    exchange.place(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &MIN_ORDER_SIZE,
+        &0,
+    );
+}
+
+#[test]
+fn test_trading_fee_accrues_to_treasury() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    let treasury = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    assert_eq!(exchange.treasury(), admin);
+    exchange.set_treasury(&admin, &treasury);
+    assert_eq!(
+        exchange.try_set_fee_bps(&admin, &base_token.address, &quote_token.address, &101),
+        Err(Ok(Error::FeeTooHigh))
+    );
+    assert_eq!(
+        exchange.try_set_fee_bps(&user, &base_token.address, &quote_token.address, &30),
+        Err(Ok(Error::Unauthorized))
+    );
+    exchange.set_fee_bps(&admin, &base_token.address, &quote_token.address, &30);
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        100_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        10,
+        100_000_000,
+    );
+
+    assert_eq!(
+        exchange.quote_swap_in_with_fee(
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &100_000_000
+        ),
+        (99_700_000, 300_000)
+    );
+
+    // Sell: the bid maker gets all 100M base, the taker 0.3% less quote
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &100_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, 99_700_000);
+    assert_eq!(quote_token.balance(&user), 1_000_000_000 + 99_700_000);
+    assert_eq!(exchange.balance_of(&maker, &base_token.address), 100_000_000);
+    assert_eq!(exchange.balance_of(&treasury, &quote_token.address), 300_000);
+
+    // Buy: the fee is skimmed from the base output instead
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &50_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    let base_bought = calculate_base_amount(50_000_000, 10);
+    let fee = base_bought * 30 / 10_000;
+    assert_eq!(out, base_bought - fee);
+    assert_eq!(base_token.balance(&user), 1_000_000_000 - 100_000_000 + out);
+    assert_eq!(exchange.balance_of(&treasury, &base_token.address), fee);
+}
+
+#[test]
+fn test_fee_in_quote_only() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    let treasury = Address::generate(&env);
+    base_admin.mint(&maker, &1_000_000_000);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    exchange.set_treasury(&admin, &treasury);
+    exchange.set_fee_bps(&admin, &base_token.address, &quote_token.address, &30);
+    assert!(!exchange.fee_in_quote_only());
+    assert_eq!(
+        exchange.try_set_fee_in_quote_only(&user, &true),
+        Err(Ok(Error::Unauthorized))
+    );
+    exchange.set_fee_in_quote_only(&admin, &true);
+    assert!(exchange.fee_in_quote_only());
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        -10,
+        100_000_000,
+    );
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        false,
+        0,
+        100_000_000,
+    );
+
+    // Sell: the base fee is converted at the fill tick and taken from the quote output
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &100_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    let quote_received = calculate_quote_amount(100_000_000, -10);
+    let sell_fee = calculate_quote_amount(100_000_000 * 30 / 10_000, -10);
+    assert_eq!(out, quote_received - sell_fee);
+    assert_eq!(exchange.balance_of(&treasury, &quote_token.address), sell_fee);
+    assert_eq!(exchange.balance_of(&treasury, &base_token.address), 0);
+
+    // Buy: 10.03M quote covers 10M base at parity plus its 0.3% fee in quote
+    assert_eq!(
+        exchange.quote_swap_in_with_fee(
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &10_030_000
+        ),
+        (10_000_000, 30_000)
+    );
+    let base_before = base_token.balance(&user);
+    let quote_before = quote_token.balance(&user);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &10_030_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, 10_000_000);
+    assert_eq!(base_token.balance(&user), base_before + 10_000_000);
+    assert_eq!(quote_token.balance(&user), quote_before - 10_030_000);
+    assert_eq!(exchange.balance_of(&maker, &quote_token.address), 10_000_000);
+    assert_eq!(exchange.balance_of(&treasury, &quote_token.address), sell_fee + 30_000);
+    assert_eq!(exchange.balance_of(&treasury, &base_token.address), 0);
+}
+
+#[test]
+fn test_fee_in_quote_only_sell_converts_fee_at_fill_ticks() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    let treasury = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+
+    exchange.set_treasury(&admin, &treasury);
+    exchange.set_fee_bps(&admin, &base_token.address, &quote_token.address, &30);
+    exchange.set_fee_in_quote_only(&admin, &true);
+
+    for tick in [-10, -20] {
+        place_active(
+            &env,
+            &exchange,
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            true,
+            tick,
+            33_333_333,
+        );
+    }
+
+    // Each tick's base fee (99_999.999 rounds to 99_999) is priced at that tick
+    let gross = calculate_quote_amount(33_333_333, -10) + calculate_quote_amount(33_333_333, -20);
+    let fee = calculate_quote_amount(99_999, -10) + calculate_quote_amount(99_999, -20);
+    assert!(fee <= gross * 30 / 10_000);
+    assert_eq!(
+        exchange.quote_swap_in_with_fee(
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &66_666_666
+        ),
+        (gross - fee, fee)
+    );
+
+    let quote_before = quote_token.balance(&user);
+    let out = exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &66_666_666,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(out, gross - fee);
+    assert_eq!(quote_token.balance(&user), quote_before + gross - fee);
+    assert_eq!(exchange.balance_of(&treasury, &quote_token.address), fee);
+    assert_eq!(exchange.balance_of(&treasury, &base_token.address), 0);
+    // Makers still receive the full base they bought
+    assert_eq!(exchange.balance_of(&maker, &base_token.address), 66_666_666);
+}
+
+#[test]
+fn test_auto_settle_order_pays_maker_wallet() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let settled_maker = Address::generate(&env);
+    let custodial_maker = Address::generate(&env);
+    base_admin.mint(&settled_maker, &100_000_000);
+    base_admin.mint(&custodial_maker, &100_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    let settled = exchange.place_with_auto_settle(
+        &settled_maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &50_000_000,
+        &0,
+    );
+    let custodial = exchange.place(
+        &custodial_maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &0,
+        &50_000_000,
+        &0,
+    );
+    assert!(exchange.get_pending_order(&settled).unwrap().auto_settle);
+    assert!(!exchange.get_pending_order(&custodial).unwrap().auto_settle);
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, settled, custodial],
+    );
+
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &100_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+
+    // Same fill, but only the auto-settle maker is paid in their wallet
+    assert_eq!(quote_token.balance(&settled_maker), 50_000_000);
+    assert_eq!(exchange.balance_of(&settled_maker, &quote_token.address), 0);
+    assert_eq!(quote_token.balance(&custodial_maker), 0);
+    assert_eq!(
+        exchange.balance_of(&custodial_maker, &quote_token.address),
+        50_000_000
+    );
+}
+
+#[test]
+fn test_auto_settle_flip_order_funds_flip_from_proceeds() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    base_admin.mint(&maker, &50_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    // No entry point sets both flags yet, so mark a pending flip ask as auto-settle
+    let pending_id = exchange.place_flip(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &50_000_000,
+        &0,
+        &0,
+    );
+    env.as_contract(&exchange.address, || {
+        let mut pending = crate::order::get_pending_order(&env, pending_id).unwrap();
+        pending.auto_settle = true;
+        env.storage()
+            .persistent()
+            .set(&crate::storage::DataKey::PendingOrder(pending_id), &pending);
+    });
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, pending_id],
+    );
+
+    // Fill it in two swaps; each pays out what its share of the flip does not need
+    let quote_before = quote_token.balance(&user);
+    for amount_in in [20_000_000, 100_000_000] {
+        exchange.swap_exact_in(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &amount_in,
+            &0,
+            &0,
+            &user,
+            &None,
+            &false,
+        );
+    }
+
+    // The flipped bid is escrowed from the proceeds and inherits auto-settle
+    let flipped_id = exchange.get_pending(&0, &10).get(0).unwrap();
+    let flipped = exchange.get_pending_order(&flipped_id).unwrap();
+    assert!(flipped.is_bid);
+    assert_eq!(flipped.tick, 0);
+    assert_eq!(flipped.amount, 50_000_000);
+    assert!(flipped.auto_settle);
+
+    // The wallet got everything the taker paid except the flip's escrow
+    let proceeds = quote_before - quote_token.balance(&user);
+    let escrow = calculate_quote_amount(50_000_000, 0);
+    assert_eq!(quote_token.balance(&maker), proceeds - escrow);
+    assert_eq!(exchange.balance_of(&maker, &quote_token.address), 0);
+}
+
+#[test]
+fn test_get_pending_paginates_remaining_queue() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, _quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    base_admin.mint(&user, &1_000_000_000);
+
+    let mut ids = vec![&env];
+    for i in 0..5 {
+        ids.push_back(exchange.place(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &(10 * (i + 1)),
+            &MIN_ORDER_SIZE,
+            &0,
+        ));
+    }
+    assert_eq!(exchange.get_pending(&0, &10), ids);
+
+    // Activate the first and third, cancel the last
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, ids.get(0).unwrap(), ids.get(2).unwrap()],
+    );
+    exchange.cancel(&user, &ids.get(4).unwrap());
+
+    assert_eq!(
+        exchange.get_pending(&0, &1),
+        vec![&env, ids.get(1).unwrap()]
+    );
+    assert_eq!(
+        exchange.get_pending(&1, &5),
+        vec![&env, ids.get(3).unwrap()]
+    );
+    assert_eq!(exchange.get_pending(&2, &5), vec![&env]);
+}
+
+#[test]
+fn test_get_pending_spans_queue_pages() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, _quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    base_admin.mint(&user, &1_000_000_000_000);
+
+    // Enough orders to fill the first two pages and start a third
+    let mut ids = vec![&env];
+    for _ in 0..150 {
+        ids.push_back(exchange.place(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &10,
+            &MIN_ORDER_SIZE,
+            &0,
+        ));
+    }
+    assert_eq!(exchange.get_pending(&0, &200), ids);
+    assert_eq!(exchange.get_pending(&60, &10), ids.slice(60..70));
+    assert_eq!(exchange.get_pending(&140, &50), ids.slice(140..150));
+
+    // Empty the first page and cancel one order in the middle of the next
+    for id in ids.slice(0..63).iter() {
+        exchange.cancel(&user, &id);
+    }
+    exchange.cancel(&user, &ids.get(100).unwrap());
+
+    let mut remaining = ids.slice(63..100);
+    remaining.append(&ids.slice(101..150));
+    assert_eq!(exchange.get_pending(&0, &200), remaining);
+    assert_eq!(exchange.get_pending(&30, &10), remaining.slice(30..40));
+    assert_eq!(exchange.get_pending(&86, &10), vec![&env]);
+}
+
+#[test]
+fn test_liquidity_in_range_sums_only_in_range_levels() {
+    let (env, exchange, _admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    base_admin.mint(&user, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    for (tick, amount) in [(10, 20_000_000), (30, 30_000_000), (50, 40_000_000), (70, 50_000_000)] {
+        place_active(
+            &env,
+            &exchange,
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            false,
+            tick,
+            amount,
+        );
+    }
+    place_active(
+        &env,
+        &exchange,
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        60_000_000,
+    );
+
+    // Ticks 30 and 50 only; the bid side is summed separately
+    assert_eq!(
+        exchange.liquidity_in_range(&base_token.address, &quote_token.address, &false, &20, &60),
+        70_000_000
+    );
+    assert_eq!(
+        exchange.liquidity_in_range(&base_token.address, &quote_token.address, &false, &30, &30),
+        30_000_000
+    );
+    assert_eq!(
+        exchange.liquidity_in_range(&base_token.address, &quote_token.address, &true, &-100, &60),
+        60_000_000
+    );
+    assert_eq!(
+        exchange.liquidity_in_range(&base_token.address, &quote_token.address, &false, &80, &200),
+        0
+    );
+
+    assert_eq!(
+        exchange.try_liquidity_in_range(&base_token.address, &quote_token.address, &false, &60, &20),
+        Err(Ok(Error::InvalidTick))
+    );
+    assert_eq!(
+        exchange.try_liquidity_in_range(&base_token.address, &quote_token.address, &false, &15, &60),
+        Err(Ok(Error::TickNotAligned))
+    );
+    assert_eq!(
+        exchange.try_liquidity_in_range(&quote_token.address, &base_token.address, &false, &20, &60),
+        Err(Ok(Error::PairNotFound))
+    );
+}
+
+#[test]
+fn test_reduce_only_ask_capped_by_long_position() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+
+    // No position yet, so there is nothing to reduce
+    assert_eq!(
+        exchange.try_place_reduce_only(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &10,
+            &MIN_ORDER_SIZE,
+            &0
+        ),
+        Err(Ok(Error::ReduceOnlyViolated))
+    );
+
+    place_active(
+        &env,
+        &exchange,
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        true,
+        0,
+        50_000_000,
+    );
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &50_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(
+        exchange.position(&maker, &base_token.address, &quote_token.address),
+        50_000_000
+    );
+    assert_eq!(
+        exchange.position(&user, &base_token.address, &quote_token.address),
+        -50_000_000
+    );
+
+    // Selling past the long would flip the maker short; buying adds to it
+    assert_eq!(
+        exchange.try_place_reduce_only(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &10,
+            &50_000_001,
+            &0
+        ),
+        Err(Ok(Error::ReduceOnlyViolated))
+    );
+    assert_eq!(
+        exchange.try_place_reduce_only(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &true,
+            &-10,
+            &MIN_ORDER_SIZE,
+            &0
+        ),
+        Err(Ok(Error::ReduceOnlyViolated))
+    );
+
+    exchange.withdraw(&maker, &base_token.address, &50_000_000);
+    let order_id = exchange.place_reduce_only(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &10,
+        &50_000_000,
+        &0,
+    );
+    assert_eq!(
+        exchange.get_pending_order(&order_id).unwrap().amount,
+        50_000_000
+    );
+
+    // A second ask would take the maker short once both fill
+    let try_reduce = |amount: i128| {
+        exchange.try_place_reduce_only(
+            &maker,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &10,
+            &amount,
+            &0,
+        )
+    };
+    assert_eq!(try_reduce(50_000_000), Err(Ok(Error::ReduceOnlyViolated)));
+    assert_eq!(try_reduce(MIN_ORDER_SIZE), Err(Ok(Error::ReduceOnlyViolated)));
+
+    // Resting and partly filled, the order still holds the rest of the long
+    exchange.execute_block(&admin, &base_token.address, &quote_token.address, &vec![&env, order_id]);
+    quote_admin.mint(&user, &1_000_000_000);
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &20_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    let position = exchange.position(&maker, &base_token.address, &quote_token.address);
+    assert!(position > 0 && position < 50_000_000);
+    assert_eq!(try_reduce(MIN_ORDER_SIZE), Err(Ok(Error::ReduceOnlyViolated)));
+
+    // Canceling it frees the remaining long for a new reduce-only order
+    let active_id = exchange
+        .get_user_summary(&maker, &base_token.address, &quote_token.address)
+        .active_order_ids
+        .get(0)
+        .unwrap();
+    let refund = exchange.cancel(&maker, &active_id);
+    assert_eq!(refund, position);
+    exchange.withdraw(&maker, &base_token.address, &refund);
+    assert_eq!(try_reduce(position + 1), Err(Ok(Error::ReduceOnlyViolated)));
+    assert!(try_reduce(position).is_ok());
+}
+
+#[test]
+fn test_flip_cycle_keeps_contract_solvent() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+    quote_admin.mint(&user, &1_000_000_000);
+
+    // What the contract holds must cover every balance and escrow it owes
+    let assert_solvent = || {
+        let (active_base, active_quote) =
+            exchange.get_pair_escrow(&base_token.address, &quote_token.address);
+        let (pending_base, pending_quote) =
+            exchange.get_pending_escrow(&base_token.address, &quote_token.address);
+        let owed_base = active_base
+            + pending_base
+            + exchange.balance_of(&maker, &base_token.address)
+            + exchange.balance_of(&user, &base_token.address);
+        let owed_quote = active_quote
+            + pending_quote
+            + exchange.balance_of(&maker, &quote_token.address)
+            + exchange.balance_of(&user, &quote_token.address);
+        assert_eq!(base_token.balance(&exchange.address), owed_base);
+        assert_eq!(quote_token.balance(&exchange.address), owed_quote);
+    };
+
+    let order_id = exchange.place_flip(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &0,
+        &50_000_000,
+        &10,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+    assert_solvent();
+
+    // The bid fills; its 50M base proceeds back the flipped ask
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &false,
+        &50_000_000,
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(exchange.balance_of(&maker, &base_token.address), 0);
+    let flipped_id = exchange.get_pending(&0, &1).get(0).unwrap();
+    assert_eq!(exchange.get_pending_order(&flipped_id).unwrap().tick, 10);
+    assert_solvent();
+
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, flipped_id],
+    );
+    exchange.swap_exact_in(
+        &user,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &calculate_quote_amount(50_000_000, 10),
+        &0,
+        &0,
+        &user,
+        &None,
+        &false,
+    );
+    assert_eq!(
+        exchange.balance_of(&maker, &quote_token.address),
+        calculate_quote_amount(50_000_000, 10)
+    );
+    assert_solvent();
+
+    // Everyone can withdraw what they are owed
+    exchange.withdraw(
+        &maker,
+        &quote_token.address,
+        &exchange.balance_of(&maker, &quote_token.address),
+    );
+    assert_solvent();
+}
+
+#[test]
+fn test_flip_skipped_when_proceeds_withdrawn() {
+    let (env, exchange, admin, user, base_token, quote_token, base_admin, quote_admin) =
+        setup_test_env();
+
+    exchange.create_pair(&base_token.address, &quote_token.address, &None);
+    let maker = Address::generate(&env);
+    quote_admin.mint(&maker, &1_000_000_000);
+    base_admin.mint(&user, &1_000_000_000);
+
+    let order_id = exchange.place_flip(
+        &maker,
+        &base_token.address,
+        &quote_token.address,
+        &true,
+        &0,
+        &50_000_000,
+        &10,
+        &0,
+    );
+    exchange.execute_block(
+        &admin,
+        &base_token.address,
+        &quote_token.address,
+        &vec![&env, order_id],
+    );
+
+    let sell = |amount: i128| {
+        exchange.swap_exact_in(
+            &user,
+            &base_token.address,
+            &quote_token.address,
+            &false,
+            &amount,
+            &0,
+            &0,
+            &user,
+            &None,
+            &false,
+        );
+    };
+
+    // Half the proceeds leave before the order completes, so no flip is backed
+    sell(25_000_000);
+    exchange.withdraw(&maker, &base_token.address, &25_000_000);
+    sell(25_000_000);
+
+    assert_eq!(exchange.get_pending(&0, &10), vec![&env]);
+    assert_eq!(exchange.balance_of(&maker, &base_token.address), 25_000_000);
+    assert_eq!(
+        exchange.get_pending_escrow(&base_token.address, &quote_token.address),
+        (0, 0)
+    );
+}